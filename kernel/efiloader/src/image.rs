@@ -1,12 +1,15 @@
-use core::{ptr, slice, str};
+use core::{mem, ptr, slice, str};
 use hal::{
-    boot_info::{LoadedImage, Segment, MAX_CAPABILITY_STREAM_LENGTH},
+    boot_info::{KernelSymbol, KernelSymbols, LoadedImage, Segment, MAX_CAPABILITY_STREAM_LENGTH},
     memory::{Flags, FrameAllocator, FrameSize, Page, PageTable, PhysicalAddress, Size4KiB, VirtualAddress},
 };
 use hal_x86_64::kernel_map;
 use log::info;
 use mer::{
     program::{ProgramHeader, SegmentType},
+    relocation::{R_X86_64_NONE, R_X86_64_RELATIVE},
+    section::SectionType,
+    symbol::SymbolType,
     Elf,
 };
 use pebble_util::math;
@@ -23,17 +26,21 @@ use uefi::{
 pub struct KernelInfo {
     pub entry_point: VirtualAddress,
     pub stack_top: VirtualAddress,
+    pub symbols: Option<KernelSymbols>,
 
-    /// We load the kernel at the base of the kernel address space. We want to put other stuff after it, and so
+    /// We load the kernel near the base of the kernel address space. We want to put other stuff after it, and so
     /// need to know how much memory the loaded image has taken up. During loading, we calculate the address of
     /// the next available page (this) to use.
     pub next_safe_address: VirtualAddress,
 }
 
+/// Load the kernel, `kernel_slide` bytes above the address it was linked at. The kernel is relocated so it can run
+/// at its new address, and all the addresses in the returned `KernelInfo` have already been slid.
 pub fn load_kernel<A, P>(
     boot_services: &BootServices,
     volume_handle: Handle,
     path: &str,
+    kernel_slide: usize,
     page_table: &mut P,
     allocator: &A,
 ) -> KernelInfo
@@ -43,14 +50,15 @@ where
 {
     info!("Loading kernel from: {}", path);
     let (elf, pool_addr) = load_elf(boot_services, volume_handle, path);
-    let entry_point = VirtualAddress::new(elf.entry_point());
+    let entry_point = VirtualAddress::new(elf.entry_point()) + kernel_slide;
 
-    let mut next_safe_address = kernel_map::KERNEL_BASE;
+    let mut next_safe_address = kernel_map::KERNEL_BASE + kernel_slide;
 
     for segment in elf.segments() {
         match segment.segment_type() {
             SegmentType::Load if segment.mem_size > 0 => {
-                let segment = load_segment(boot_services, segment, crate::KERNEL_MEMORY_TYPE, &elf, false);
+                let mut segment = load_segment(boot_services, segment, crate::KERNEL_MEMORY_TYPE, &elf, false);
+                segment.virtual_address += kernel_slide;
 
                 /*
                  * If this segment loads past `next_safe_address`, update it.
@@ -84,21 +92,152 @@ where
         }
     }
 
+    relocate_kernel(&elf, kernel_slide, page_table);
+
     let stack_top = match elf.symbols().find(|symbol| symbol.name(&elf) == Some("_stack_top")) {
-        Some(symbol) => VirtualAddress::new(symbol.value as usize),
+        Some(symbol) => VirtualAddress::new(symbol.value as usize) + kernel_slide,
         None => panic!("Kernel does not have a '_stack_top' symbol!"),
     };
 
     // Unmap the stack guard page
     let guard_page_address = match elf.symbols().find(|symbol| symbol.name(&elf) == Some("_guard_page")) {
-        Some(symbol) => VirtualAddress::new(symbol.value as usize),
+        Some(symbol) => VirtualAddress::new(symbol.value as usize) + kernel_slide,
         None => panic!("Kernel does not have a '_guard_page' symbol!"),
     };
     assert!(guard_page_address.is_aligned(Size4KiB::SIZE), "Guard page address is not page aligned");
     page_table.unmap::<Size4KiB>(Page::starts_with(guard_page_address));
 
+    let symbols =
+        load_kernel_symbols(boot_services, &elf, kernel_slide, &mut next_safe_address, page_table, allocator);
+    match symbols {
+        Some(ref symbols) => info!("Loaded {} kernel symbols", symbols.num_symbols),
+        None => info!("Kernel does not have any symbols. Backtraces will not be symbolized."),
+    }
+
     boot_services.free_pool(pool_addr).unwrap_success();
-    KernelInfo { entry_point, stack_top, next_safe_address }
+    KernelInfo { entry_point, stack_top, symbols, next_safe_address }
+}
+
+/// The kernel is linked as a position-independent executable, so contains a set of relocations that must be
+/// applied for it to run at a different address to the one it was linked at. The kernel must already have been
+/// loaded and mapped into `page_table`.
+fn relocate_kernel<P>(elf: &Elf, kernel_slide: usize, page_table: &P)
+where
+    P: PageTable<Size4KiB>,
+{
+    let mut num_relocations = 0;
+
+    for section in elf.sections().filter(|section| section.section_type() == SectionType::Rela) {
+        for relocation in section.rela_entries(elf) {
+            match relocation.relocation_type() {
+                R_X86_64_NONE => (),
+
+                R_X86_64_RELATIVE => {
+                    /*
+                     * The relocated value is the address the kernel has been loaded at (its link address plus
+                     * the slide), plus the addend. The location to relocate is given as a virtual address in the
+                     * linked kernel, so we slide that too, and then find where it's been loaded in physical
+                     * memory (which we can access directly, as it's identity-mapped).
+                     */
+                    let address = VirtualAddress::new(relocation.offset as usize) + kernel_slide;
+                    let value = (relocation.addend as usize).wrapping_add(kernel_slide);
+                    let physical_address = match page_table.translate(address) {
+                        Some((physical_address, _)) => physical_address,
+                        None => panic!("Kernel relocation at {:#x} is not in a loaded segment!", address),
+                    };
+
+                    unsafe {
+                        ptr::write_unaligned(usize::from(physical_address) as *mut usize, value);
+                    }
+                    num_relocations += 1;
+                }
+
+                other => panic!("Kernel contains relocation of unsupported type: {}", other),
+            }
+        }
+    }
+
+    info!("Applied {} relocations to the kernel", num_relocations);
+}
+
+/// Construct a table of the kernel's function symbols, which the kernel uses to symbolize backtraces, and map it
+/// into the kernel's address space at `next_safe_address`. The symbols' addresses are slid by `kernel_slide`, so
+/// they match the loaded kernel. Returns `None` if the kernel doesn't have any function
+/// symbols (e.g. because it has been stripped).
+fn load_kernel_symbols<A, P>(
+    boot_services: &BootServices,
+    elf: &Elf,
+    kernel_slide: usize,
+    next_safe_address: &mut VirtualAddress,
+    page_table: &mut P,
+    allocator: &A,
+) -> Option<KernelSymbols>
+where
+    A: FrameAllocator<Size4KiB>,
+    P: PageTable<Size4KiB>,
+{
+    let function_symbols = || {
+        elf.symbols().filter_map(move |symbol| match symbol.symbol_type() {
+            SymbolType::Func if symbol.value != 0 => Some((symbol.value, symbol.size, symbol.name(elf)?)),
+            _ => None,
+        })
+    };
+
+    /*
+     * We make two passes over the symbols - the first works out how much space we need, and the second actually
+     * fills in the table. The names are stored directly after the symbols.
+     */
+    let (num_symbols, names_length) =
+        function_symbols().fold((0, 0), |(num_symbols, names_length), (_, _, name)| {
+            (num_symbols + 1, names_length + name.len())
+        });
+    if num_symbols == 0 {
+        return None;
+    }
+
+    let symbols_size = num_symbols * mem::size_of::<KernelSymbol>();
+    let size = math::align_up(symbols_size + names_length, Size4KiB::SIZE);
+    let physical_address = boot_services
+        .allocate_pages(AllocateType::AnyPages, crate::KERNEL_MEMORY_TYPE, size / Size4KiB::SIZE)
+        .expect_success("Failed to allocate memory for kernel symbols");
+
+    let symbols =
+        unsafe { slice::from_raw_parts_mut(physical_address as usize as *mut KernelSymbol, num_symbols) };
+    let names = unsafe {
+        slice::from_raw_parts_mut((physical_address as usize + symbols_size) as *mut u8, names_length)
+    };
+
+    let mut name_offset = 0;
+    for ((address, size, name), entry) in function_symbols().zip(symbols.iter_mut()) {
+        names[name_offset..(name_offset + name.len())].copy_from_slice(name.as_bytes());
+        *entry = KernelSymbol {
+            address: VirtualAddress::new(address as usize) + kernel_slide,
+            size: size as usize,
+            name_offset: name_offset as u32,
+            name_length: name.len() as u32,
+        };
+        name_offset += name.len();
+    }
+    symbols.sort_unstable_by_key(|symbol| symbol.address);
+
+    let virtual_address = *next_safe_address;
+    page_table
+        .map_area(
+            virtual_address,
+            PhysicalAddress::new(physical_address as usize).unwrap(),
+            size,
+            Flags { ..Default::default() },
+            allocator,
+        )
+        .unwrap();
+    *next_safe_address = virtual_address + size;
+
+    Some(KernelSymbols {
+        symbols_address: virtual_address,
+        num_symbols,
+        names_address: virtual_address + symbols_size,
+        names_length,
+    })
 }
 
 pub fn load_image(boot_services: &BootServices, volume_handle: Handle, name: &str, path: &str) -> LoadedImage {
@@ -0,0 +1,108 @@
+//! The loader randomizes the layout of the kernel's address space, so an attacker can't rely on kernel code and
+//! data being at known addresses. This module provides the randomness used to pick the slides.
+//!
+//! We prefer `rdseed`, which returns values straight from the processor's entropy source, and fall back to
+//! `rdrand` (which returns values from a CSPRNG seeded by that source). If the processor supports neither, we
+//! derive values from the TSC. These are not at all unpredictable, but do at least change from boot to boot.
+
+use core::sync::atomic::spin_loop_hint;
+use hal_x86_64::hw::{cpu::CpuInfo, registers::read_tsc};
+use log::{info, warn};
+
+/// `rdrand` and `rdseed` can fail if the processor's entropy source is temporarily exhausted (this happens more
+/// often for `rdseed`), in which case they should be retried. Intel recommends giving up on `rdrand` after 10
+/// attempts, as it should only fail repeatedly if the hardware is broken.
+const RDSEED_RETRIES: usize = 100;
+const RDRAND_RETRIES: usize = 10;
+
+#[derive(Clone, Copy, Debug)]
+enum Source {
+    Rdseed,
+    Rdrand,
+    Tsc,
+}
+
+pub struct Randomness {
+    source: Source,
+}
+
+impl Randomness {
+    pub fn new() -> Randomness {
+        let cpu_info = CpuInfo::new();
+        let source = if cpu_info.supported_features.rdseed {
+            Source::Rdseed
+        } else if cpu_info.supported_features.rdrand {
+            Source::Rdrand
+        } else {
+            warn!("Processor supports neither RDSEED nor RDRAND. The kernel's layout will be easy to predict!");
+            Source::Tsc
+        };
+
+        info!("Randomizing kernel address space layout using: {:?}", source);
+        Randomness { source }
+    }
+
+    pub fn next_u64(&self) -> u64 {
+        match self.source {
+            Source::Rdseed => rdseed().or_else(rdrand).unwrap_or_else(tsc),
+            Source::Rdrand => rdrand().unwrap_or_else(tsc),
+            Source::Tsc => tsc(),
+        }
+    }
+
+    /// Pick a random multiple of `align` that is less than `range`. `range` must be a non-zero multiple of
+    /// `align`.
+    pub fn offset(&self, range: usize, align: usize) -> usize {
+        assert!(range != 0 && range % align == 0, "Range is not a non-zero multiple of its alignment");
+        (self.next_u64() as usize % (range / align)) * align
+    }
+}
+
+fn rdseed() -> Option<u64> {
+    for _ in 0..RDSEED_RETRIES {
+        let value: u64;
+        let success: u8;
+        unsafe {
+            asm!("rdseed {}
+                  setc {}",
+                out(reg) value,
+                out(reg_byte) success,
+                options(nomem, nostack)
+            );
+        }
+
+        if success != 0 {
+            return Some(value);
+        }
+        spin_loop_hint();
+    }
+
+    None
+}
+
+fn rdrand() -> Option<u64> {
+    for _ in 0..RDRAND_RETRIES {
+        let value: u64;
+        let success: u8;
+        unsafe {
+            asm!("rdrand {}
+                  setc {}",
+                out(reg) value,
+                out(reg_byte) success,
+                options(nomem, nostack)
+            );
+        }
+
+        if success != 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+/// Derive a value from the TSC. The low bits of the TSC are the least predictable, so we mix them into the rest
+/// of the value.
+fn tsc() -> u64 {
+    read_tsc().wrapping_mul(0x9e37_79b9_7f4a_7c15).rotate_left(32)
+}
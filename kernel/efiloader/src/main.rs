@@ -4,6 +4,7 @@
 
 mod allocator;
 mod image;
+mod kaslr;
 mod logger;
 
 use allocator::BootFrameAllocator;
@@ -11,7 +12,9 @@ use core::{fmt::Write, mem, panic::PanicInfo, ptr, slice};
 use hal::{
     boot_info::{BootInfo, VideoModeInfo},
     memory::{
+        gibibytes,
         kibibytes,
+        mebibytes,
         Bytes,
         Flags,
         FrameAllocator,
@@ -23,7 +26,8 @@ use hal::{
         VirtualAddress,
     },
 };
-use hal_x86_64::paging::PageTableImpl;
+use hal_x86_64::{kernel_map, paging::PageTableImpl};
+use kaslr::Randomness;
 use log::{error, info};
 use logger::Logger;
 use uefi::{
@@ -44,6 +48,17 @@ pub const BOOT_INFO_MEMORY_TYPE: MemoryType = MemoryType::custom(0x80000004);
 pub const KERNEL_HEAP_MEMORY_TYPE: MemoryType = MemoryType::custom(0x80000005);
 
 const KERNEL_HEAP_SIZE: Bytes = kibibytes(800);
+/// The amount of virtual address space reserved for the kernel heap. Only `KERNEL_HEAP_SIZE` bytes of it are
+/// mapped by the loader - the kernel maps more as the heap grows.
+const KERNEL_HEAP_MAX_SIZE: Bytes = mebibytes(512);
+
+/// The kernel is loaded at a random multiple of `KERNEL_SLIDE_ALIGN` above the address it's linked at, less than
+/// `KERNEL_SLIDE_RANGE`. The kernel is built with the `kernel` code model, so it, and everything we place after
+/// it, must fit in the top 2GiB of the address space.
+const KERNEL_SLIDE_RANGE: Bytes = gibibytes(1);
+const KERNEL_SLIDE_ALIGN: Bytes = mebibytes(2);
+/// A random, page-aligned, gap of less than this is left before the boot info and before the kernel heap.
+const MAX_RANDOM_GAP: Bytes = mebibytes(64);
 
 #[entry]
 fn efi_main(image_handle: Handle, system_table: SystemTable<Boot>) -> Status {
@@ -68,10 +83,18 @@ fn efi_main(image_handle: Handle, system_table: SystemTable<Boot>) -> Status {
     let allocator = BootFrameAllocator::new(system_table.boot_services(), 64);
     let mut page_table = PageTableImpl::new(allocator.allocate(), VirtualAddress::new(0x0));
 
+    /*
+     * Randomize where the kernel is loaded (see `kernel_map` for everything we randomize).
+     */
+    let randomness = Randomness::new();
+    let kernel_slide = randomness.offset(KERNEL_SLIDE_RANGE, KERNEL_SLIDE_ALIGN);
+    info!("Sliding kernel by {:#x}", kernel_slide);
+
     let kernel_info = image::load_kernel(
         system_table.boot_services(),
         loaded_image_protocol.device(),
         "kernel.elf",
+        kernel_slide,
         &mut page_table,
         &allocator,
     );
@@ -98,9 +121,10 @@ fn efi_main(image_handle: Handle, system_table: SystemTable<Boot>) -> Status {
 
         /*
          * But we need to map it into the kernel's part of the address space for when we switch to the new set of
-         * page tables. Choose the next free address and map it there.
+         * page tables. Leave a random gap after the kernel, and map it there.
          */
-        let boot_info_virtual_address = kernel_info.next_safe_address;
+        next_safe_address += randomness.offset(MAX_RANDOM_GAP, Size4KiB::SIZE);
+        let boot_info_virtual_address = next_safe_address;
         next_safe_address += boot_info_needed_frames * Size4KiB::SIZE;
         page_table
             .map_area(
@@ -115,6 +139,8 @@ fn efi_main(image_handle: Handle, system_table: SystemTable<Boot>) -> Status {
     };
     boot_info.magic = hal::boot_info::BOOT_INFO_MAGIC;
     boot_info.video_mode = Some(video_mode);
+    boot_info.kernel_symbols = kernel_info.symbols;
+    boot_info.kernel_slide = kernel_slide;
 
     /*
      * Find the RSDP address and add it to the boot info.
@@ -129,13 +155,15 @@ fn efi_main(image_handle: Handle, system_table: SystemTable<Boot>) -> Status {
     });
 
     /*
-     * Allocate the kernel heap.
+     * Allocate the kernel heap, again leaving a random gap before it.
      */
+    next_safe_address += randomness.offset(MAX_RANDOM_GAP, Size4KiB::SIZE);
     allocate_and_map_heap(
         system_table.boot_services(),
         boot_info,
         &mut next_safe_address,
         KERNEL_HEAP_SIZE,
+        KERNEL_HEAP_MAX_SIZE,
         &mut page_table,
         &allocator,
     );
@@ -200,7 +228,7 @@ fn efi_main(image_handle: Handle, system_table: SystemTable<Boot>) -> Status {
     let (_system_table, memory_map) = system_table
         .exit_boot_services(image_handle, memory_map_buffer)
         .expect_success("Failed to exit boot services");
-    process_memory_map(memory_map, boot_info, &mut page_table, &allocator);
+    process_memory_map(memory_map, boot_info, &randomness, &mut page_table, &allocator);
 
     /*
      * Jump into the kernel!
@@ -238,10 +266,12 @@ fn efi_main(image_handle: Handle, system_table: SystemTable<Boot>) -> Status {
 ///     * We construct the memory map that will be passed to the kernel, which it uses to initialize its physical
 ///       memory manager. This is added directly to the already-allocated boot info.
 ///     * Construct the physical memory mapping - we map the entirity of physical memory into the kernel address
-///       space to make it easy for the kernel to access any address it needs to.
+///       space to make it easy for the kernel to access any address it needs to. This is placed at a random
+///       address, which is passed to the kernel in the boot info.
 fn process_memory_map<'a, A, P>(
     memory_map: impl Iterator<Item = &'a MemoryDescriptor>,
     boot_info: &mut BootInfo,
+    randomness: &Randomness,
     mapper: &mut P,
     allocator: &A,
 ) where
@@ -325,28 +355,38 @@ fn process_memory_map<'a, A, P>(
 
             MemoryType::ACPI_RECLAIM => add_entry!(BootInfoMemoryType::AcpiReclaimable),
 
-            // IMAGE_MEMORY_TYPE => add_entry!(BootInfoMemoryType::LoadedImage),
-            // PAGE_TABLE_MEMORY_TYPE => add_entry!(BootInfoMemoryType::KernelPageTables),
-            // BOOT_INFO_MEMORY_TYPE => add_entry!(BootInfoMemoryType::BootInfo),
-            // KERNEL_HEAP_MEMORY_TYPE => add_entry!(BootInfoMemoryType::KernelHeap),
+            IMAGE_MEMORY_TYPE => add_entry!(BootInfoMemoryType::LoadedImage),
+            PAGE_TABLE_MEMORY_TYPE => add_entry!(BootInfoMemoryType::KernelPageTables),
+            BOOT_INFO_MEMORY_TYPE => add_entry!(BootInfoMemoryType::BootInfo),
+            KERNEL_HEAP_MEMORY_TYPE => add_entry!(BootInfoMemoryType::KernelHeap),
 
-            // Other regions will never be useable by the kernel, so we don't bother including them
+            /*
+             * Other regions will never be useable by the kernel, so we don't bother including them. This includes
+             * the memory the kernel image (and its symbol table) is loaded into, which is in use for the whole
+             * life of the kernel.
+             */
             _ => (),
         }
     }
 
     /*
      * Construct the physical memory mapping. We find the maximum physical address that the memory map contains,
-     * and map that much physical memory.
+     * and map that much physical memory. It can be placed anywhere between the start of the kernel address space
+     * and the task kernel stacks, as long as it's suitably aligned.
      */
-    info!(
-        "Constructing physical mapping 0x0..{:#x} at {:#x}",
-        max_physical_address,
-        hal_x86_64::kernel_map::PHYSICAL_MAPPING_BASE
-    );
+    let align = kernel_map::PHYSICAL_MAPPING_ALIGN;
+    let mapping_size = pebble_util::math::align_up(max_physical_address, align);
+    let available_space = usize::from(kernel_map::KERNEL_STACKS_BASE)
+        .checked_sub(usize::from(kernel_map::KERNEL_ADDRESS_SPACE_START) + mapping_size)
+        .expect("Not enough kernel address space to map all of physical memory!");
+    let physical_mapping_base =
+        kernel_map::KERNEL_ADDRESS_SPACE_START + randomness.offset(available_space + align, align);
+    boot_info.physical_mapping_base = physical_mapping_base;
+
+    info!("Constructing physical mapping 0x0..{:#x} at {:#x}", max_physical_address, physical_mapping_base);
     mapper
         .map_area(
-            hal_x86_64::kernel_map::PHYSICAL_MAPPING_BASE,
+            physical_mapping_base,
             PhysicalAddress::new(0x0).unwrap(),
             max_physical_address,
             Flags { writable: true, ..Default::default() },
@@ -356,12 +396,14 @@ fn process_memory_map<'a, A, P>(
 }
 
 /// Allocate and map the kernel heap. This takes the current next safe virtual address, uses it for the heap, and
-/// updates it.
+/// updates it. `heap_size` bytes of the heap are mapped, but the next safe address is moved past `heap_max_size`
+/// bytes, so the kernel can grow the heap.
 fn allocate_and_map_heap<A, P>(
     boot_services: &BootServices,
     boot_info: &mut BootInfo,
     next_safe_address: &mut VirtualAddress,
     heap_size: usize,
+    heap_max_size: usize,
     mapper: &mut P,
     allocator: &A,
 ) where
@@ -369,6 +411,7 @@ fn allocate_and_map_heap<A, P>(
     P: PageTable<Size4KiB>,
 {
     assert!(heap_size % Size4KiB::SIZE == 0, "Heap will not be page aligned");
+    assert!(heap_max_size >= heap_size, "Initial heap is bigger than its maximum size");
     let frames_needed = Size4KiB::frames_needed(heap_size);
     let physical_start = boot_services
         .allocate_pages(AllocateType::AnyPages, KERNEL_HEAP_MEMORY_TYPE, frames_needed)
@@ -386,13 +429,15 @@ fn allocate_and_map_heap<A, P>(
 
     boot_info.heap_address = *next_safe_address;
     boot_info.heap_size = heap_size;
+    boot_info.heap_max_size = heap_max_size;
     info!(
-        "Mapping heap between {:#x} and {:#x}",
+        "Mapping heap between {:#x} and {:#x} (reserved up to {:#x})",
         boot_info.heap_address,
-        boot_info.heap_address + boot_info.heap_size - 1
+        boot_info.heap_address + boot_info.heap_size - 1,
+        boot_info.heap_address + boot_info.heap_max_size - 1
     );
 
-    *next_safe_address = (Page::<Size4KiB>::contains(*next_safe_address + heap_size) + 1).start;
+    *next_safe_address = (Page::<Size4KiB>::contains(*next_safe_address + heap_max_size) + 1).start;
 }
 
 fn create_framebuffer(
@@ -14,10 +14,26 @@ pub struct BootInfo {
     pub loaded_images: LoadedImages,
     pub video_mode: Option<VideoModeInfo>,
     pub heap_address: VirtualAddress,
+    /// The number of bytes of the heap the loader has mapped.
     pub heap_size: usize,
+    /// The loader reserves this many bytes of virtual address space (starting at `heap_address`) for the heap, so
+    /// the kernel can grow the heap into it.
+    pub heap_max_size: usize,
 
     /// The physical address of the RSDP, the first ACPI table.
     pub rsdp_address: Option<PhysicalAddress>,
+
+    /// A table of the kernel's function symbols, if the loader was able to construct one. This is mapped into the
+    /// kernel's address space, and is used to symbolize backtraces. The symbols' addresses have already been
+    /// adjusted by `kernel_slide`.
+    pub kernel_symbols: Option<KernelSymbols>,
+
+    /// The loader may load the kernel at a higher address than it was linked at, to randomize its position in
+    /// memory. This is the difference between the two, which must be subtracted from an address in the loaded
+    /// kernel to find the matching address in the kernel's ELF (e.g. to symbolize it with a debugger).
+    pub kernel_slide: usize,
+    /// The virtual address that the loader has mapped all of physical memory at.
+    pub physical_mapping_base: VirtualAddress,
 }
 
 pub const MAX_MEMORY_MAP_ENTRIES: usize = 256;
@@ -214,3 +230,28 @@ pub enum PixelFormat {
     /// |--------|--------|--------|--------|
     Bgr32,
 }
+
+/// Describes where the loader has put the kernel's symbol table. The table is an array of `num_symbols`
+/// `KernelSymbol`s at `symbols_address`, sorted by address. The names of the symbols are stored separately at
+/// `names_address`.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct KernelSymbols {
+    pub symbols_address: VirtualAddress,
+    pub num_symbols: usize,
+    pub names_address: VirtualAddress,
+    /// In bytes.
+    pub names_length: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct KernelSymbol {
+    pub address: VirtualAddress,
+    /// In bytes.
+    pub size: usize,
+    /// The offset of this symbol's name from the start of the names. Names are encoded as UTF-8, and are not
+    /// null-terminated.
+    pub name_offset: u32,
+    pub name_length: u32,
+}
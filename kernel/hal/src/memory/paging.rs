@@ -55,12 +55,15 @@ where
     where
         A: FrameAllocator<TableSize>;
 
-    /// Install these page tables as the current set.
-    unsafe fn switch_to(&self);
+    /// Install these page tables as the current set. On platforms that tag TLB entries with the address space
+    /// they belong to, entries left over from the last time these page tables were installed on this CPU are kept
+    /// unless `flush` is set. `flush` must therefore be set if these page tables have been changed (in a way that
+    /// needs TLB entries to be invalidated) since they were last installed on this CPU.
+    unsafe fn switch_to(&self, flush: bool);
 
-    /// Get the physical address that a given virtual address is mapped to, if it's mapped. Returns `None` if the
-    /// address is not mapped into physical memory.
-    fn translate(&self, address: VirtualAddress) -> Option<PhysicalAddress>;
+    /// Get the physical address that a given virtual address is mapped to, and the permissions it's mapped with,
+    /// if it's mapped. Returns `None` if the address is not mapped into physical memory.
+    fn translate(&self, address: VirtualAddress) -> Option<(PhysicalAddress, Flags)>;
 
     /// Map a `Page` to a `Frame` with the given flags.
     fn map<S, A>(
@@ -106,9 +109,15 @@ where
     where
         A: FrameAllocator<TableSize>;
 
+    /// Unmap a single `Page`, returning the `Frame` it was mapped to. Returns `None` if the page isn't mapped,
+    /// including if it's part of a larger page.
     fn unmap<S>(&mut self, page: Page<S>) -> Option<Frame<S>>
     where
         S: FrameSize;
+
+    /// Unmap an area of `size` bytes starting at `virtual_start`, however it was mapped (e.g. by `map_area`).
+    /// Parts of the area that aren't mapped are skipped. The area must not cover only part of a larger page.
+    fn unmap_area(&mut self, virtual_start: VirtualAddress, size: usize);
 }
 
 #[cfg(test)]
@@ -16,6 +16,17 @@ impl VirtualAddress {
         VirtualAddress(address).canonicalise()
     }
 
+    /// Construct a new `VirtualAddress`, if the given value is already canonical. Unlike `new`, this does not
+    /// canonicalise the value, and so should be used for addresses we can't trust (e.g. ones from userspace).
+    pub fn try_new(address: usize) -> Option<VirtualAddress> {
+        let virtual_address = VirtualAddress(address).canonicalise();
+        if virtual_address.0 == address {
+            Some(virtual_address)
+        } else {
+            None
+        }
+    }
+
     pub const fn ptr<T>(self) -> *const T {
         self.0 as *const T
     }
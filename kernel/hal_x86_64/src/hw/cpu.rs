@@ -8,6 +8,17 @@ use core::{arch::x86_64::CpuidResult, str};
 
 pub struct SupportedFeatures {
     pub xsave: bool,
+    pub pcid: bool,
+    /// Supervisor-Mode Execution Prevention
+    pub smep: bool,
+    /// Supervisor-Mode Access Prevention
+    pub smap: bool,
+    /// User-Mode Instruction Prevention
+    pub umip: bool,
+    pub rdrand: bool,
+    pub rdseed: bool,
+    /// TSC-deadline mode for the local APIC timer
+    pub tsc_deadline: bool,
 }
 
 /// Describes information we know about the system we're running on.
@@ -28,7 +39,13 @@ impl CpuInfo {
         let vendor_id_cpuid = cpuid(CpuidEntry::VendorId);
         let vendor = decode_vendor(&vendor_id_cpuid);
         let model_info = decode_model_info(processor_cpuid.eax);
-        let supported_features = decode_supported_features(processor_cpuid.ecx, processor_cpuid.edx);
+        let extended_features_cpuid = if vendor_id_cpuid.eax >= 0x7 {
+            cpuid(CpuidEntry::ExtendedFeatures)
+        } else {
+            CpuidResult { eax: 0, ebx: 0, ecx: 0, edx: 0 }
+        };
+        let supported_features =
+            decode_supported_features(processor_cpuid.ecx, processor_cpuid.edx, &extended_features_cpuid);
         let hypervisor_info = decode_hypervisor_info();
 
         CpuInfo {
@@ -106,20 +123,61 @@ impl CpuInfo {
         }
 
         /*
-         * If the `cpuid` info contains a non-zero core crystal clock frequency, return that.
+         * On processors that report it, the local APIC timer runs at the core crystal clock frequency.
+         */
+        self.core_crystal_frequency()
+    }
+
+    /// Get the frequency the TSC runs at (in Hz), if we can calculate it. If this returns `None`, we have to
+    /// measure it against another timer.
+    pub fn tsc_frequency(&self) -> Option<u64> {
+        if let Some(ref hypervisor_info) = self.hypervisor_info {
+            if let Some(tsc_frequency) = hypervisor_info.tsc_frequency {
+                return Some(tsc_frequency);
+            }
+        }
+
+        /*
+         * The TSC frequency is given as a ratio of the core crystal clock frequency.
          */
         if self.max_supported_standard_level >= 0x15 {
             let tsc_entry = cpuid(CpuidEntry::TscFrequency);
 
-            if tsc_entry.ecx != 0 {
-                return Some(tsc_entry.ecx);
+            if tsc_entry.eax != 0 && tsc_entry.ebx != 0 {
+                let crystal_frequency = self.core_crystal_frequency()? as u64;
+                return Some(crystal_frequency * tsc_entry.ebx as u64 / tsc_entry.eax as u64);
             }
         }
 
-        // TODO: if that leaf is not present, we need to work it out based on what microarch we're
-        // running on.
         None
     }
+
+    /// Get the frequency of the core crystal clock (in Hz). This is reported by `cpuid` on newer Intel processors,
+    /// but some processors that have the leaf report a frequency of `0`. For these, the Intel SDM lists the
+    /// frequency by model.
+    fn core_crystal_frequency(&self) -> Option<u32> {
+        if self.max_supported_standard_level < 0x15 {
+            return None;
+        }
+
+        let tsc_entry = cpuid(CpuidEntry::TscFrequency);
+        if tsc_entry.ecx != 0 {
+            return Some(tsc_entry.ecx);
+        }
+
+        match self.vendor {
+            Vendor::Intel if self.model_info.family == 0x6 => match self.model_info.extended_model {
+                // Skylake, Kaby Lake, and Coffee Lake client processors
+                0x4e | 0x5e | 0x8e | 0x9e => Some(24_000_000),
+                // Skylake server processors
+                0x55 => Some(25_000_000),
+                // Goldmont
+                0x5c => Some(19_200_000),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -181,6 +239,7 @@ pub struct HypervisorInfo {
     pub vendor: HypervisorVendor,
     pub max_leaf: u32,
     pub apic_frequency: Option<u32>,
+    pub tsc_frequency: Option<u64>,
 }
 
 /// This is used to reinterpret the bytes of the vendor strings that are spread across the three
@@ -216,6 +275,7 @@ enum CpuidEntry {
     ///
     /// C = feature info (below are for individual bits. 1 = support)
     ///     0 = SSE3
+    ///     17 = PCID
     ///     19 = SSE4.1
     ///     20 = SSE4.2
     ///     21 = x2APIC
@@ -232,6 +292,19 @@ enum CpuidEntry {
     ///     19 = CLFLUSH
     ProcessorInfo = 0x01,
 
+    /// (sub-leaf 0)
+    /// A = maximum supported sub-leaf
+    ///
+    /// B = feature info (below are for individual bits. 1 = support)
+    ///     7 = SMEP
+    ///     18 = RDSEED
+    ///     20 = SMAP
+    ///
+    /// C = feature info (below are for individual bits. 1 = support)
+    ///     2 = UMIP
+    /// (again, this only includes things we're interested in)
+    ExtendedFeatures = 0x07,
+
     /// A = denominator
     /// B = numerator
     /// C = core crystal clock frequency
@@ -243,7 +316,7 @@ enum CpuidEntry {
     /// B,C,D = vendor ID string
     HypervisorVendor = 0x4000_0000,
 
-    /// A = (virtual) TSC frequency
+    /// A = (virtual) TSC frequency in kHz
     /// B = (virtual) bus (local APIC timer) frequency in kHz
     HypervisorFrequencies = 0x4000_0010,
 }
@@ -271,8 +344,21 @@ fn decode_model_info(model_info: u32) -> ModelInfo {
     ModelInfo { family, model, stepping, extended_family, extended_model }
 }
 
-fn decode_supported_features(processor_info_ecx: u32, _processor_info_edx: u32) -> SupportedFeatures {
-    SupportedFeatures { xsave: processor_info_ecx.get_bit(26) }
+fn decode_supported_features(
+    processor_info_ecx: u32,
+    _processor_info_edx: u32,
+    extended_features: &CpuidResult,
+) -> SupportedFeatures {
+    SupportedFeatures {
+        xsave: processor_info_ecx.get_bit(26),
+        pcid: processor_info_ecx.get_bit(17),
+        smep: extended_features.ebx.get_bit(7),
+        smap: extended_features.ebx.get_bit(20),
+        umip: extended_features.ecx.get_bit(2),
+        rdrand: processor_info_ecx.get_bit(30),
+        rdseed: extended_features.ebx.get_bit(18),
+        tsc_deadline: processor_info_ecx.get_bit(24),
+    }
 }
 
 fn decode_hypervisor_info() -> Option<HypervisorInfo> {
@@ -302,15 +388,25 @@ fn decode_hypervisor_info() -> Option<HypervisorInfo> {
     };
 
     /*
-     * If cpuid has the hypervisor timing leaf, use the bus frequency of that.
+     * If cpuid has the hypervisor timing leaf, use the TSC and bus frequencies from that.
      * NOTE: this is in kHz, so we convert to Hz
      * NOTE: for this to exist under KVM, the `vmware-cpuid-freq` and `invtsc` cpu flags must be
      * set.
      */
-    let apic_frequency =
-        if max_leaf >= 0x4000_0010 { Some(cpuid(CpuidEntry::HypervisorFrequencies).ebx * 1000) } else { None };
+    let (apic_frequency, tsc_frequency) = if max_leaf >= 0x4000_0010 {
+        let frequencies = cpuid(CpuidEntry::HypervisorFrequencies);
+        (Some(frequencies.ebx * 1000), Some(frequencies.eax as u64 * 1000))
+    } else {
+        (None, None)
+    };
+
+    Some(HypervisorInfo { vendor, max_leaf, apic_frequency, tsc_frequency })
+}
 
-    Some(HypervisorInfo { vendor, max_leaf, apic_frequency })
+/// Get the initial APIC ID of the processor this is run on. This is useful to tell which processor we're running
+/// on before we've set up any per-CPU data.
+pub fn current_apic_id() -> u32 {
+    cpuid(CpuidEntry::ProcessorInfo).ebx.get_bits(24..32)
 }
 
 fn cpuid(entry: CpuidEntry) -> CpuidResult {
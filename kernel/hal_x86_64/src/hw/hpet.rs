@@ -0,0 +1,68 @@
+use bit_field::BitField;
+use core::{ptr, time::Duration};
+use hal::memory::VirtualAddress;
+
+/*
+ * Offsets of the HPET's general registers from the base of its register block.
+ */
+const GENERAL_CAPABILITIES: usize = 0x00;
+const GENERAL_CONFIG: usize = 0x10;
+const MAIN_COUNTER: usize = 0xf0;
+
+/// The High Precision Event Timer. We only use its main counter, which increments at a fixed rate, as a reference
+/// to measure the frequencies of other timers against.
+pub struct Hpet {
+    base: VirtualAddress,
+}
+
+impl Hpet {
+    /// Create a new `Hpet` from the virtual address its register block is mapped at.
+    ///
+    /// # Safety
+    /// The HPET's register block must be mapped at `base`.
+    pub unsafe fn new(base: VirtualAddress) -> Hpet {
+        Hpet { base }
+    }
+
+    /// The period of the main counter, in femtoseconds (10^-15 seconds).
+    pub fn counter_period(&self) -> u64 {
+        unsafe { self.read(GENERAL_CAPABILITIES) }.get_bits(32..64)
+    }
+
+    /// Whether the main counter is 64 bits wide. If it isn't, it's only 32 bits wide, and so wraps much sooner.
+    pub fn is_counter_64_bit(&self) -> bool {
+        unsafe { self.read(GENERAL_CAPABILITIES) }.get_bit(13)
+    }
+
+    /// Start the main counter, if it isn't already running.
+    pub fn enable_counter(&self) {
+        unsafe {
+            let mut config = self.read(GENERAL_CONFIG);
+            config.set_bit(0, true);
+            self.write(GENERAL_CONFIG, config);
+        }
+    }
+
+    pub fn read_counter(&self) -> u64 {
+        unsafe { self.read(MAIN_COUNTER) }
+    }
+
+    /// Busy-wait for `duration`, using the main counter. The counter must be running.
+    pub fn wait(&self, duration: Duration) {
+        let ticks = (duration.as_nanos() * 1_000_000 / self.counter_period() as u128) as u64;
+        let mask = if self.is_counter_64_bit() { u64::MAX } else { u32::MAX as u64 };
+        let start = self.read_counter();
+
+        while (self.read_counter().wrapping_sub(start) & mask) < ticks {}
+    }
+
+    unsafe fn read(&self, offset: usize) -> u64 {
+        unsafe { ptr::read_volatile((self.base + offset).ptr()) }
+    }
+
+    unsafe fn write(&self, offset: usize, value: u64) {
+        unsafe {
+            ptr::write_volatile((self.base + offset).mut_ptr(), value);
+        }
+    }
+}
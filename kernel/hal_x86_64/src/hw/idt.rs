@@ -308,6 +308,8 @@ pub macro wrap_handler_with_error_code($name: path) {{
                   pop rbx
                   pop rax
 
+                  // Pop the error code before returning
+                  add rsp, 8
                   iretq",
                 sym $name,
                 options(noreturn)
@@ -1,3 +1,4 @@
+use super::registers::{write_msr, IA32_TSC_DEADLINE};
 use core::ptr;
 use hal::memory::VirtualAddress;
 
@@ -31,6 +32,11 @@ impl LocalApic {
         LocalApic(address)
     }
 
+    /// Get the ID of this local APIC, which is used to direct interrupts to its processor.
+    pub fn id(&self) -> u8 {
+        unsafe { (self.register(0x20).read() >> 24) as u8 }
+    }
+
     pub unsafe fn enable(&self, spurious_vector: u8) {
         /*
          * - Enable the local APIC by setting bit 8
@@ -41,61 +47,87 @@ impl LocalApic {
         }
     }
 
-    /// Set the local APIC timer to interrupt every `duration` ms, and then enable it. The timer
-    /// will signal on the specified vector. The frequency of the local APIC must be passed (in Hz), and
-    /// can sometimes be retrieved from the `CpuInfo`.
-    pub fn enable_timer(&self, duration: u32, apic_frequency: u32, vector: u8) {
+    /// Put the local APIC timer in one-shot mode, where it signals a single interrupt on `vector` when its count
+    /// reaches zero. The timer doesn't start counting until a count is set with `set_timer_count`, and counts down
+    /// once every 16 cycles of the local APIC's clock.
+    pub fn enable_one_shot_timer(&self, vector: u8) {
         /*
-         * Calculate the number of ticks in one millisecond. We also divide by 16 because we will
-         * set the divider to 16, so the given frequency will be 16 times faster than the count
-         * will be decremented by.
+         * We pick 16 as the divider here because some hardware apparently has issues with using a divider of 1,
+         * which would be the simplest. Bits 17-18 of the LVT Timer Register being clear selects one-shot mode.
          */
-        let ticks_in_1ms = apic_frequency / 1000 / 16;
-        let ticks = duration * ticks_in_1ms;
+        unsafe {
+            self.register(0x3e0).write(0x3);
+            self.register(0x320).write(u32::from(vector));
+        }
+    }
+
+    /// Start the one-shot timer counting down from `count`, replacing any count it's already part way through. A
+    /// count of `0` stops the timer without signalling an interrupt.
+    pub fn set_timer_count(&self, count: u32) {
+        unsafe {
+            self.register(0x380).write(count);
+        }
+    }
+
+    /// Put the local APIC timer in TSC-deadline mode, where it signals an interrupt on `vector` when the TSC
+    /// reaches the deadline set with `set_tsc_deadline`. This mode is only supported if the processor reports the
+    /// `tsc_deadline` feature.
+    pub fn enable_tsc_deadline_timer(&self, vector: u8) {
+        /*
+         * Setting bits 17-18 of the LVT Timer Register to `0b10` selects TSC-deadline mode. The Intel SDM says
+         * the write to the LVT must be serialized before the deadline MSR is written, so the timer doesn't
+         * interpret the first deadline in the old mode.
+         */
+        unsafe {
+            self.register(0x320).write(u32::from(vector) | (0b10 << 17));
+            asm!("mfence");
+        }
+    }
 
+    /// Arm the timer to fire when the TSC reaches `deadline`, replacing any deadline that's already set. If the
+    /// deadline has already passed, the timer fires immediately. A deadline of `0` disarms the timer.
+    pub fn set_tsc_deadline(&self, deadline: u64) {
+        unsafe {
+            write_msr(IA32_TSC_DEADLINE, deadline);
+        }
+    }
+
+    /// Start the timer counting down from its maximum count, without signalling an interrupt, so its frequency can
+    /// be measured against another timer. The timer is stopped by `stop_counting`.
+    pub fn start_counting(&self) {
         /*
-         * Start the APIC timer in Periodic mode with a divide value of 16, to interrupt every
-         * `duration` ms.
-         *
-         * We pick 16 as the divider here because some hardware apparently has issues with using a
-         * divider of 1, which would be the simplest.
+         * We use the same divider as `enable_one_shot_timer` (16). Bit 16 of the LVT Timer Register masks the
+         * timer's interrupt, and bits 17-18 being clear puts it in one-shot mode.
          */
         unsafe {
-            self.register(0x3e0).write(0x3); // Step 1: Set the divider to 16
-            self.register(0x320).write(u32::from(vector) | 0x20000); // Step 2: enable the timer
-            self.register(0x380).write(ticks); // Step 3: Set the initial count
+            self.register(0x3e0).write(0x3);
+            self.register(0x320).write(1 << 16);
+            self.register(0x380).write(0xffff_ffff);
         }
+    }
 
+    /// Stop the timer started by `start_counting`, and return how many times it has ticked. The timer ticks once
+    /// every 16 cycles of the local APIC's clock.
+    pub fn stop_counting(&self) -> u32 {
+        unsafe {
+            let remaining = self.register(0x390).read();
+            self.register(0x380).write(0);
+            0xffff_ffff - remaining
+        }
+    }
+
+    /// Send a fixed inter-processor interrupt on `vector` to every processor apart from this one.
+    pub unsafe fn send_ipi_to_all_but_self(&self, vector: u8) {
         /*
-         * TODO: we used to calibrate the timer by timing with the PIC. This could still be a good
-         * backup if the cpuid doesn't have the info we need:
+         * Wait for any IPI we've already sent to be accepted (bit 12 of the ICR is its Delivery Status), and then
+         * write to the low half of the ICR, which sends the IPI. We use the "All Excluding Self" destination
+         * shorthand (bits 18-19), so we don't need to set a destination in the high half of the ICR. Bit 14 must
+         * be set for all IPIs apart from INIT level de-asserts.
          */
-        // unsafe {
-        //     /*
-        //     * Set divide value to 16 and initial counter value to -1. We use 16 because apparently
-        //     * some hardware has issues with other divide values (especially 1, which would be the
-        //     * simplest otherwise). 16 seems to be the most supported.
-        //     */
-        //     Self::register(0x3e0).write(0x3);
-        //     Self::register(0x380).write(0xffff_ffff);
-
-        //     /*
-        //      * Sleep for 10ms with the PIT and then stop the APIC timer
-        //      */
-        //     ::pit::PIT.do_sleep(10);
-        //     Self::register(0x320).write(0x10000);
-
-        //     let ticks_in_10ms = 0xffff_ffff - Self::register(0x390).read() as usize;
-        //     trace!("Timing of local APIC bus frequency complete");
-
-        //     /*
-        //     * Start the APIC timer in Periodic mode with a divide value of 16 again, to interrupt
-        //     * every 10 ms on the given vector.
-        //     */
-        //     Self::register(0x320).write(u32::from(vector) | 0x20000);
-        //     Self::register(0x3e0).write(0x3);
-        //     Self::register(0x380).write(((ticks_in_10ms / 10) * duration) as u32);
-        // }
+        unsafe {
+            while self.register(0x300).read() & (1 << 12) != 0 {}
+            self.register(0x300).write((0b11 << 18) | (1 << 14) | u32::from(vector));
+        }
     }
 
     pub unsafe fn register(&self, offset: usize) -> LocalApicRegister {
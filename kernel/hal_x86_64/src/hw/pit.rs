@@ -0,0 +1,55 @@
+use super::port::Port;
+use core::time::Duration;
+
+/// The frequency of the PIT's input clock, in Hz.
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+/// The legacy Programmable Interval Timer. We only use it as a fallback reference for measuring the frequencies of
+/// other timers against, on systems without an HPET. We use channel 2, because unlike the other channels, its
+/// output can be polled, so we don't need to handle its interrupts.
+pub struct Pit {
+    channel_2_data: Port<u8>,
+    command: Port<u8>,
+    /// The PIT's channel 2 gate is controlled through bit 0 of this port (which also controls the PC speaker, and
+    /// so is usually called the "speaker port"), and the state of its output can be read from bit 5.
+    speaker: Port<u8>,
+}
+
+impl Pit {
+    pub const unsafe fn new() -> Pit {
+        unsafe { Pit { channel_2_data: Port::new(0x42), command: Port::new(0x43), speaker: Port::new(0x61) } }
+    }
+
+    /// Busy-wait for `duration`. The PIT's counter is 16 bits wide, so can only count for around 55ms at once,
+    /// and so longer waits are split into multiple counts.
+    pub fn wait(&mut self, duration: Duration) {
+        let mut ticks = (duration.as_nanos() * PIT_FREQUENCY as u128 / 1_000_000_000) as u64;
+
+        while ticks > 0 {
+            let count = u64::min(ticks, u16::MAX as u64);
+            self.count_down(count as u16);
+            ticks -= count;
+        }
+    }
+
+    fn count_down(&mut self, count: u16) {
+        unsafe {
+            /*
+             * Raise channel 2's gate so it can count, but make sure the speaker stays off (bit 1).
+             */
+            let speaker = self.speaker.read();
+            self.speaker.write((speaker & !0b10) | 0b1);
+
+            /*
+             * Put channel 2 in mode 0 ("interrupt on terminal count"), with the count written as a low byte and
+             * then a high byte. Its output goes low when the count is written, and then high again when the
+             * count reaches zero.
+             */
+            self.command.write(0b1011_0000);
+            self.channel_2_data.write(count as u8);
+            self.channel_2_data.write((count >> 8) as u8);
+
+            while self.speaker.read() & (1 << 5) == 0 {}
+        }
+    }
+}
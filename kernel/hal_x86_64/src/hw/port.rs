@@ -55,7 +55,9 @@ impl PortSize for u32 {
     }
 }
 
-/// Represents an IO port that can be read and written to using the `in` and `out` instructions.
+/// Represents an IO port that can be read and written to using the `in` and `out` instructions. This can also be
+/// used from userspace, once the task has been given access to the port (on Pebble, by mapping an `IoPortRange`
+/// that contains it). Accessing a port the task doesn't have access to causes a General Protection Fault.
 pub struct Port<T: PortSize> {
     port: u16,
     phantom: PhantomData<T>,
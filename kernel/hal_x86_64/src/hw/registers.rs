@@ -103,6 +103,12 @@ impl fmt::Debug for CpuFlags {
     }
 }
 
+/*
+ * Constants for bits in CR0.
+ */
+/// If this is set, Ring 0 can't write to pages that aren't writable.
+pub const CR0_WRITE_PROTECT: usize = 16;
+
 /*
  * Constants for bits in CR4.
  */
@@ -110,7 +116,16 @@ impl fmt::Debug for CpuFlags {
 pub const CR4_RESTRICT_RDTSC: usize = 2;
 pub const CR4_ENABLE_PAE: usize = 5;
 pub const CR4_ENABLE_GLOBAL_PAGES: usize = 7;
+/// If this is set, instructions that reveal the location of system structures (e.g. `sgdt` and `sidt`) can only
+/// be used in Ring 0.
+pub const CR4_ENABLE_UMIP: usize = 11;
+/// If this is set, the bottom 12 bits of `CR3` hold the PCID of the current address space. See `tlb::Pcid`.
+pub const CR4_ENABLE_PCID: usize = 17;
 pub const CR4_XSAVE_ENABLE_BIT: usize = 18;
+/// If this is set, Ring 0 can't execute code in user-accessible pages.
+pub const CR4_ENABLE_SMEP: usize = 20;
+/// If this is set, Ring 0 can only access user-accessible pages while `RFLAGS.AC` is set (see `stac` and `clac`).
+pub const CR4_ENABLE_SMAP: usize = 21;
 
 /// Read a control register. The name of the control register should be passed as any of: `CR0`,
 /// `CR1`, `CR2`, `CR3`, `CR4`, `CR8`.
@@ -169,6 +184,10 @@ pub const IA32_FS_BASE: u32 = 0xc000_0100;
 /// A virtual address can be stored in this MSR, and acts as the base of the GS segment.
 pub const IA32_GS_BASE: u32 = 0xc000_0101;
 
+/// When the local APIC timer is in TSC-deadline mode, it fires when the TSC reaches the value in this MSR. Writing
+/// `0` disarms the timer.
+pub const IA32_TSC_DEADLINE: u32 = 0x6e0;
+
 /// Read from a model-specific register.
 pub fn read_msr(reg: u32) -> u64 {
     let (high, low): (u32, u32);
@@ -193,3 +212,16 @@ pub unsafe fn write_msr(reg: u32, value: u64) {
         );
     }
 }
+
+/// Read the Time Stamp Counter. On processors with an invariant TSC, this increases at a constant rate, regardless
+/// of the processor's power state.
+pub fn read_tsc() -> u64 {
+    let (high, low): (u32, u32);
+    unsafe {
+        asm!("rdtsc",
+            out("eax") low,
+            out("edx") high
+        );
+    }
+    (high as u64) << 32 | (low as u64)
+}
@@ -1,16 +1,89 @@
-use super::registers::{read_control_reg, write_control_reg};
+use super::registers::{read_control_reg, write_control_reg, CR4_ENABLE_GLOBAL_PAGES};
+use core::sync::atomic::{AtomicBool, Ordering};
 use hal::memory::VirtualAddress;
+use pebble_util::bitmap::Bitmap;
+use spin::Mutex;
+
+/// There are 4096 possible PCIDs, as a PCID is held in the bottom 12 bits of `CR3`.
+const NUM_PCIDS: usize = 4096;
+
+/// Set once PCIDs have been enabled by setting `CR4.PCIDE`. Until then, page tables are not given PCIDs.
+static PCIDS_ENABLED: AtomicBool = AtomicBool::new(false);
+/// Tracks which PCIDs are in use.
+static PCID_BITMAP: Mutex<[u64; NUM_PCIDS / 64]> = Mutex::new([0; NUM_PCIDS / 64]);
 
 #[rustfmt::skip]
+#[cfg(not(test))]
 pub fn invalidate_page(address: VirtualAddress) {
     unsafe {
         asm!("invlpg [{}]", in(reg) usize::from(address));
     }
 }
 
+/// `invlpg` is privileged, so can't be used when the paging code is tested in userspace. There's no TLB to
+/// invalidate there anyway.
+#[cfg(test)]
+pub fn invalidate_page(_address: VirtualAddress) {}
+
+/// Flush all the (non-global) entries from the TLB. If PCIDs are enabled, this only flushes the entries of the
+/// current address space.
 pub fn flush() {
     let current_cr3 = read_control_reg!(cr3);
     unsafe {
         write_control_reg!(cr3, current_cr3);
     }
 }
+
+/// Flush every entry from the TLB, including global entries and the entries of every PCID. The kernel's mappings
+/// are shared by every address space, so when one of them is removed or changed, stale entries for it may be
+/// tagged with any PCID, and `invalidate_page` and `flush` only deal with the current one.
+pub fn flush_all() {
+    /*
+     * Changing `CR4.PGE` flushes the whole TLB, so we flip it and then put it back.
+     */
+    let cr4 = read_control_reg!(cr4);
+    unsafe {
+        write_control_reg!(cr4, cr4 ^ (1 << CR4_ENABLE_GLOBAL_PAGES));
+        write_control_reg!(cr4, cr4);
+    }
+}
+
+/// Mark PCIDs as enabled, so that `Pcid::alloc` starts handing them out. This should be called once `CR4.PCIDE`
+/// has been set.
+pub fn enable_pcids() {
+    /*
+     * PCID `0` is never allocated, as it's used by page tables that don't have a PCID of their own (such as the
+     * kernel's).
+     */
+    PCID_BITMAP.lock()[0] |= 0b1;
+    PCIDS_ENABLED.store(true, Ordering::Release);
+}
+
+/// A Process-Context Identifier. When PCIDs are enabled, the TLB tags each entry with the PCID of the address
+/// space it was created in, so entries from multiple address spaces can be kept in the TLB at once, and don't
+/// have to be flushed when we switch between them. The PCID is freed when this is dropped.
+#[derive(Debug)]
+pub struct Pcid(u16);
+
+impl Pcid {
+    /// Allocate a new PCID. Returns `None` if PCIDs have not been enabled, or if they have all been allocated.
+    pub fn alloc() -> Option<Pcid> {
+        if !PCIDS_ENABLED.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let mut bitmap = PCID_BITMAP.lock();
+        bitmap.iter_mut().enumerate().find_map(|(i, word)| Some(Pcid((i * 64 + word.alloc(1)?) as u16)))
+    }
+
+    pub fn value(&self) -> u16 {
+        self.0
+    }
+}
+
+impl Drop for Pcid {
+    fn drop(&mut self) {
+        let index = self.0 as usize;
+        PCID_BITMAP.lock()[index / 64].free(index % 64, 1);
+    }
+}
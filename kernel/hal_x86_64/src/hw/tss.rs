@@ -1,11 +1,21 @@
-use core::{marker::PhantomPinned, pin::Pin};
+use bit_field::BitField;
+use core::{fmt, marker::PhantomPinned, pin::Pin};
 use hal::memory::VirtualAddress;
 use pebble_util::unsafe_unpinned;
 
+/// The number of I/O ports on x86_64. Each one has a bit in the I/O permission bitmap.
+const NUM_IO_PORTS: usize = 0x10000;
+/// The offset of the I/O permission bitmap from the start of the TSS. It directly follows the fixed part of the
+/// TSS.
+const IO_PERMISSION_BITMAP_OFFSET: u16 = 104;
+
 /// Hardware task switching isn't supported on x86_64, so the TSS is just used as a vestigal place
 /// to stick stuff. It's used to store kernel-level stacks that should be used if interrupts occur
-/// (this is used to prevent triple-faults from occuring if we overflow the kernel stack).
-#[derive(Clone, Copy, Debug)]
+/// (this is used to prevent triple-faults from occuring if we overflow the kernel stack), and the I/O
+/// permission bitmap, which controls which I/O ports userspace can access.
+///
+/// This isn't `Copy`, as the I/O permission bitmap makes it over 8KiB, and a copy of the TSS that the task
+/// register doesn't point to is almost certainly a mistake.
 #[repr(C, packed)]
 pub struct Tss {
     _reserved_1: u32,
@@ -15,6 +25,7 @@ pub struct Tss {
     _reserved_3: u64,
     _reserved_4: u16,
     pub iomap_base: u16,
+    io_permission_bitmap: IoPermissionBitmap,
 
     /// The memory pointed to by a task register will be used as the TSS until the task register
     /// contents is replaced. This means the memory must never be moved, because then the task
@@ -26,6 +37,7 @@ pub struct Tss {
 impl Tss {
     unsafe_unpinned!(privilege_stack_table: [VirtualAddress; 3]);
     unsafe_unpinned!(interrupt_stack_table: [VirtualAddress; 7]);
+    unsafe_unpinned!(io_permission_bitmap: IoPermissionBitmap);
 
     pub fn new() -> Tss {
         Tss {
@@ -35,7 +47,8 @@ impl Tss {
             interrupt_stack_table: [VirtualAddress::new(0x0); 7],
             _reserved_3: 0,
             _reserved_4: 0,
-            iomap_base: 0,
+            iomap_base: IO_PERMISSION_BITMAP_OFFSET,
+            io_permission_bitmap: IoPermissionBitmap::new(),
             _pin: PhantomPinned,
         }
     }
@@ -43,4 +56,43 @@ impl Tss {
     pub fn set_kernel_stack(mut self: Pin<&mut Self>, address: VirtualAddress) {
         self.as_mut().privilege_stack_table()[0] = address;
     }
+
+    /// Allow or deny userspace access to the `length` I/O ports starting at `base`. Access to all ports is denied
+    /// by default.
+    pub fn set_io_port_access(mut self: Pin<&mut Self>, base: u16, length: u16, allowed: bool) {
+        let bitmap = &mut self.as_mut().io_permission_bitmap().0;
+        for port in (base as usize)..usize::min(base as usize + length as usize, NUM_IO_PORTS) {
+            /*
+             * A clear bit allows access to the port, and a set bit denies it.
+             */
+            bitmap[port / 8].set_bit(port % 8, !allowed);
+        }
+    }
+}
+
+impl fmt::Debug for Tss {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        /*
+         * The TSS is packed, so we can't take references to its fields, and instead copy them out.
+         */
+        let privilege_stack_table = self.privilege_stack_table;
+        let interrupt_stack_table = self.interrupt_stack_table;
+        let iomap_base = self.iomap_base;
+        f.debug_struct("Tss")
+            .field("privilege_stack_table", &privilege_stack_table)
+            .field("interrupt_stack_table", &interrupt_stack_table)
+            .field("iomap_base", &iomap_base)
+            .finish()
+    }
+}
+
+/// Each I/O port has a bit in the bitmap, which is followed by a byte with all its bits set, which the processor
+/// needs to be there because it can read two bytes of the bitmap at a time.
+#[repr(C, packed)]
+struct IoPermissionBitmap([u8; NUM_IO_PORTS / 8 + 1]);
+
+impl IoPermissionBitmap {
+    fn new() -> IoPermissionBitmap {
+        IoPermissionBitmap([0xff; NUM_IO_PORTS / 8 + 1])
+    }
 }
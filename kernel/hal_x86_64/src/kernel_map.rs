@@ -1,7 +1,7 @@
 //! This module contains constants that define how the kernel address space is laid out on x86_64. The 511th P4
 //! entry (virtual addresses `0xffff_ff80_0000_0000` through `0xffff_ffff_ffff_ffff`) is always mapped to the
-//! kernel P3. The rest of the virtual address space (virtual addresses `0x0000_0000_0000_0000` through
-//! `0xffff_ff7f_ffff_ffff` are free for userspace to use).
+//! kernel P3. Userspace can use the lower half of the address space (virtual addresses `0x0000_0000_0000_0000`
+//! through `0x0000_7fff_ffff_ffff`).
 //!
 //! This gives us 512 GiB of kernel space. The kernel itself is built with the `kernel` mc-model, and so must lie
 //! in the -2GiB of the address space (the top two entries of the kernel P3). The remaining 510 GiB of the kernel
@@ -12,22 +12,61 @@
 //!
 //! This leaves us 382GiB for the physical memory map, which should be sufficient for any system I can imagine us
 //! running on (famous last words).
+//!
+//! To make the kernel harder to exploit, the loader randomizes parts of this layout on every boot:
+//!     - the kernel image is slid up from `KERNEL_BASE` by a random multiple of 2MiB (less than 1GiB), and
+//!       relocated to run at its new address
+//!     - random gaps are left before the boot info and before the kernel heap
+//!     - the physical mapping is placed at a random 1GiB-aligned address in the space available for it, and so
+//!       must be found with `physical_mapping_base` instead of being assumed to start at
+//!       `KERNEL_ADDRESS_SPACE_START`
+//! The slides chosen are passed to the kernel in the boot info.
+//!
+//! No kernel mapping is both writable and executable (this is checked by `PageTableImpl::map`):
+//!     - the kernel's `.text` is mapped executable but read-only, `.rodata` is read-only, and `.data` and `.bss`
+//!       are writable but not executable (see the kernel's linker script)
+//!     - the physical mapping, task kernel stacks, and the kernel heap are writable but not executable
+//!     - the boot info and the kernel's symbol table are read-only and not executable
 
-use hal::memory::{mebibytes, Bytes, PhysicalAddress, VirtualAddress};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use hal::memory::{gibibytes, mebibytes, Bytes, PhysicalAddress, VirtualAddress};
 
 pub const KERNEL_P4_ENTRY: usize = 511;
 pub const KERNEL_ADDRESS_SPACE_START: VirtualAddress = VirtualAddress::new(0xffff_ff80_0000_0000);
 
-pub const PHYSICAL_MAPPING_BASE: VirtualAddress = KERNEL_ADDRESS_SPACE_START;
+/// The highest address userspace can use. User pointers passed to the kernel must lie entirely below this.
+pub const USER_ADDRESS_SPACE_TOP: VirtualAddress = VirtualAddress::new(0x0000_7fff_ffff_ffff);
+
+/// The physical mapping is always placed at an address aligned to this, so it can be mapped with the largest pages
+/// available.
+pub const PHYSICAL_MAPPING_ALIGN: Bytes = gibibytes(1);
+
+/// The address the physical mapping starts at. This is chosen by the loader, and so must be set by the kernel
+/// (with `set_physical_mapping_base`) before the physical mapping is used. Until then, it is assumed to start at
+/// `KERNEL_ADDRESS_SPACE_START`.
+static PHYSICAL_MAPPING_BASE: AtomicUsize = AtomicUsize::new(0xffff_ff80_0000_0000);
+
+pub fn physical_mapping_base() -> VirtualAddress {
+    VirtualAddress::new(PHYSICAL_MAPPING_BASE.load(Ordering::Relaxed))
+}
+
+/// Set the address the physical mapping starts at.
+///
+/// # Safety
+/// Physical memory must be mapped at `base`, and this must be called before anything tries to access physical
+/// memory through the physical mapping.
+pub unsafe fn set_physical_mapping_base(base: VirtualAddress) {
+    PHYSICAL_MAPPING_BASE.store(usize::from(base), Ordering::Relaxed);
+}
 
 /// Access a given physical address through the physical mapping. This cannot be used until the kernel page tables
-/// have been switched to.
+/// have been switched to, and the base of the physical mapping has been set.
 ///
 /// # Safety
 /// This itself is safe, because to cause memory unsafety a raw pointer must be created and accessed from the
 /// `VirtualAddress`, which is unsafe.
 pub fn physical_to_virtual(address: PhysicalAddress) -> VirtualAddress {
-    PHYSICAL_MAPPING_BASE + usize::from(address)
+    physical_mapping_base() + usize::from(address)
 }
 
 pub const KERNEL_STACKS_BASE: VirtualAddress = VirtualAddress::new(0xffff_ffdf_8000_0000);
@@ -38,6 +77,7 @@ pub const KERNEL_STACKS_BASE: VirtualAddress = VirtualAddress::new(0xffff_ffdf_8
 pub const STACK_SLOT_SIZE: Bytes = mebibytes(2);
 pub const MAX_TASKS: usize = 65536;
 
-/// The kernel starts at -2GiB. The kernel image is loaded directly at this address, and the following space until
-/// the top of memory is managed dynamically and contains the boot info structures, memory map, and kernel heap.
+/// The kernel is linked to start at -2GiB. The kernel image is loaded at a random offset from this address (see
+/// the module documentation), and the space following it until the top of memory is managed dynamically and
+/// contains the boot info structures, memory map, and kernel heap.
 pub const KERNEL_BASE: VirtualAddress = VirtualAddress::new(0xffff_ffff_8000_0000);
@@ -1,4 +1,4 @@
-use crate::hw::{registers::write_control_reg, tlb};
+use crate::hw::{registers::write_control_reg, tlb::{self, Pcid}};
 use bit_field::BitField;
 use bitflags::bitflags;
 use core::{
@@ -59,6 +59,17 @@ impl From<Flags> for EntryFlags {
     }
 }
 
+impl From<EntryFlags> for Flags {
+    fn from(flags: EntryFlags) -> Self {
+        Flags {
+            writable: flags.contains(EntryFlags::WRITABLE),
+            executable: !flags.contains(EntryFlags::NO_EXECUTE),
+            user_accessible: flags.contains(EntryFlags::USER_ACCESSIBLE),
+            cached: !flags.contains(EntryFlags::NO_CACHE),
+        }
+    }
+}
+
 /// Represents an entry within a page table of any level. Contains a physical address to the next level (or to the
 /// physical memory region), and some flags.
 #[repr(transparent)]
@@ -193,8 +204,13 @@ where
     L: HierarchicalLevel,
 {
     /// Get a reference to the table at the given `index`, assuming the entirity of
-    /// the physical address space is mapped from `physical_base`.
+    /// the physical address space is mapped from `physical_base`. Returns `None` if the entry is empty, or if it
+    /// maps a huge page instead of pointing to another table.
     pub fn next_table(&self, index: usize, physical_base: VirtualAddress) -> Option<&Table<L::NextLevel>> {
+        if self[index].flags().contains(EntryFlags::HUGE_PAGE) {
+            return None;
+        }
+
         self[index]
             .address()
             .map(|physical_address| physical_base + usize::from(physical_address))
@@ -202,12 +218,17 @@ where
     }
 
     /// Get a mutable reference to the table at the given `index`, assuming the entirity of
-    /// the physical address space is mapped from `physical_base`.
+    /// the physical address space is mapped from `physical_base`. Like `next_table`, this returns `None` if the
+    /// entry maps a huge page.
     pub fn next_table_mut(
         &mut self,
         index: usize,
         physical_base: VirtualAddress,
     ) -> Option<&mut Table<L::NextLevel>> {
+        if self[index].flags().contains(EntryFlags::HUGE_PAGE) {
+            return None;
+        }
+
         self[index]
             .address()
             .map(|physical_address| physical_base + usize::from(physical_address))
@@ -223,6 +244,15 @@ where
     where
         A: FrameAllocator<Size4KiB>,
     {
+        /*
+         * When we're seeing if we need to create a parent table in order to map into lower tables (e.g. creating a
+         * P2 to create a P1 for 4KiB mappings), there might already be a huge page mapped into the parent table.
+         * If this occurs, we error because the whole region has already been mapped.
+         */
+        if self[index].flags().contains(EntryFlags::HUGE_PAGE) {
+            return Err(PagingError::AlreadyMapped);
+        }
+
         if self.next_table(index, physical_base).is_none() {
             /*
              * This entry is empty, so we create a new page table, zero it, and return that.
@@ -233,16 +263,8 @@ where
             Ok(table)
         } else {
             /*
-             * This entry already exists, so we don't need to create another one. However, we do need to detect a
-             * special case here: when we're seeing if we need to create a parent table in order to map into lower
-             * tables (e.g. creating a P2 to create a P1 for 4KiB mappings), there might already be a huge page
-             * mapped into the parent table. If this occurs, we error because the whole region has already been
-             * mapped.
+             * This entry already exists, so we don't need to create another one.
              */
-            if self[index].flags().contains(EntryFlags::HUGE_PAGE) {
-                return Err(PagingError::AlreadyMapped);
-            }
-
             Ok(self.next_table_mut(index, physical_base).unwrap())
         }
     }
@@ -258,11 +280,14 @@ pub struct PageTableImpl {
     /// tables would have a `physical_base` in the higher half in the kernel, after we switch to
     /// the kernel's set of page tables.
     physical_base: VirtualAddress,
+    /// The PCID that the TLB entries of this set of page tables are tagged with, if it has one. Page tables
+    /// without a PCID use PCID `0`, and are always flushed from the TLB when they're switched to.
+    pcid: Option<Pcid>,
 }
 
 impl PageTableImpl {
     pub fn new(p4_frame: Frame, physical_base: VirtualAddress) -> PageTableImpl {
-        let mut table = PageTableImpl { p4_frame, physical_base };
+        let mut table = PageTableImpl { p4_frame, physical_base, pcid: None };
         table.p4_mut().zero();
         table
     }
@@ -272,7 +297,7 @@ impl PageTableImpl {
     /// currently exist that use this same backing frame (as calling `mapper` on both could lead to
     /// two mutable references aliasing the same data to exist, which is UB).
     pub unsafe fn from_frame(p4_frame: Frame, physical_base: VirtualAddress) -> PageTableImpl {
-        PageTableImpl { p4_frame, physical_base }
+        PageTableImpl { p4_frame, physical_base, pcid: None }
     }
 
     pub fn p4(&self) -> &Table<Level4> {
@@ -289,7 +314,7 @@ impl PageTable<Size4KiB> for PageTableImpl {
     where
         A: FrameAllocator<Size4KiB>,
     {
-        let mut page_table = PageTableImpl::new(allocator.allocate(), crate::kernel_map::PHYSICAL_MAPPING_BASE);
+        let mut page_table = PageTableImpl::new(allocator.allocate(), crate::kernel_map::physical_mapping_base());
 
         /*
          * Install the address of the kernel's P3 in every address space, so that the kernel is always mapped.
@@ -300,28 +325,59 @@ impl PageTable<Size4KiB> for PageTableImpl {
         page_table.p4_mut()[crate::kernel_map::KERNEL_P4_ENTRY]
             .set(Some((kernel_p3_address, EntryFlags::WRITABLE)));
 
+        /*
+         * If we've run out of PCIDs, this address space shares PCID `0` with the kernel, and so is flushed from
+         * the TLB every time it's switched to.
+         */
+        page_table.pcid = Pcid::alloc();
+
         page_table
     }
 
-    unsafe fn switch_to(&self) {
+    unsafe fn switch_to(&self, flush: bool) {
+        let mut value = usize::from(self.p4_frame.start) as u64;
+
+        if let Some(ref pcid) = self.pcid {
+            value.set_bits(0..12, u64::from(pcid.value()));
+
+            /*
+             * Setting bit 63 tells the CPU to keep the TLB entries tagged with this PCID, instead of flushing them.
+             */
+            value.set_bit(63, !flush);
+        }
+
         unsafe {
-            write_control_reg!(cr3, usize::from(self.p4_frame.start) as u64);
+            write_control_reg!(cr3, value);
         }
     }
 
-    fn translate(&self, address: VirtualAddress) -> Option<PhysicalAddress> {
-        let p2 = self
-            .p4()
-            .next_table(address.p4_index(), self.physical_base)
-            .and_then(|p3| p3.next_table(address.p3_index(), self.physical_base))?;
+    fn translate(&self, address: VirtualAddress) -> Option<(PhysicalAddress, Flags)> {
+        /*
+         * The permissions of a mapping are determined by just the terminal entry, as we always map non-terminal
+         * tables with the most permissive set of flags.
+         */
+        let p3 = self.p4().next_table(address.p4_index(), self.physical_base)?;
 
+        let p3_entry = p3[address.p3_index()];
+        if p3_entry.flags().contains(EntryFlags::HUGE_PAGE) {
+            return Some((
+                p3_entry.address()? + (usize::from(address) % Size1GiB::SIZE),
+                Flags::from(p3_entry.flags()),
+            ));
+        }
+
+        let p2 = p3.next_table(address.p3_index(), self.physical_base)?;
         let p2_entry = p2[address.p2_index()];
         if p2_entry.flags().contains(EntryFlags::HUGE_PAGE) {
-            return Some(p2_entry.address()? + (usize::from(address) % Size2MiB::SIZE));
+            return Some((
+                p2_entry.address()? + (usize::from(address) % Size2MiB::SIZE),
+                Flags::from(p2_entry.flags()),
+            ));
         }
 
         let p1 = p2.next_table(address.p2_index(), self.physical_base)?;
-        Some(p1[address.p1_index()].address()? + (usize::from(address) % Size4KiB::SIZE))
+        let p1_entry = p1[address.p1_index()];
+        Some((p1_entry.address()? + (usize::from(address) % Size4KiB::SIZE), Flags::from(p1_entry.flags())))
     }
 
     fn map<S, A>(&mut self, page: Page<S>, frame: Frame<S>, flags: Flags, allocator: &A) -> Result<(), PagingError>
@@ -329,6 +385,14 @@ impl PageTable<Size4KiB> for PageTableImpl {
         S: FrameSize,
         A: FrameAllocator<Size4KiB>,
     {
+        /*
+         * Kernel memory is never mapped as both writable and executable, so a kernel bug can't be used to write
+         * code and then run it.
+         */
+        assert!(
+            flags.user_accessible || !(flags.writable && flags.executable),
+            "Tried to map kernel memory as writable and executable"
+        );
         let physical_base = self.physical_base;
 
         if S::SIZE == Size4KiB::SIZE {
@@ -472,28 +536,112 @@ impl PageTable<Size4KiB> for PageTableImpl {
         S: FrameSize,
     {
         let physical_base = self.physical_base;
+        let p3 = self.p4_mut().next_table_mut(page.start.p4_index(), physical_base)?;
 
-        match S::SIZE {
+        /*
+         * We only unmap an entry if it maps a page of the size we've been asked to unmap - if part of a huge page
+         * is unmapped, or a huge page is unmapped where there are actually smaller pages, we return `None`.
+         */
+        let entry = match S::SIZE {
             Size4KiB::SIZE => {
-                let p1 = self
-                    .p4_mut()
-                    .next_table_mut(page.start.p4_index(), physical_base)?
+                let p1 = p3
                     .next_table_mut(page.start.p3_index(), physical_base)?
                     .next_table_mut(page.start.p2_index(), physical_base)?;
-                let frame = Frame::starts_with(p1[page.start.p1_index()].address()?);
-                p1[page.start.p1_index()].set(None);
-                tlb::invalidate_page(page.start);
-
-                Some(frame)
+                &mut p1[page.start.p1_index()]
             }
-            Size2MiB::SIZE => unimplemented!(),
-            Size1GiB::SIZE => unimplemented!(),
+            Size2MiB::SIZE => {
+                let p2 = p3.next_table_mut(page.start.p3_index(), physical_base)?;
+                &mut p2[page.start.p2_index()]
+            }
+            Size1GiB::SIZE => &mut p3[page.start.p3_index()],
 
             _ => panic!("Unimplemented page size!"),
+        };
+
+        if S::SIZE != Size4KiB::SIZE && !entry.flags().contains(EntryFlags::HUGE_PAGE) {
+            return None;
+        }
+
+        let frame = Frame::starts_with(entry.address()?);
+        entry.set(None);
+        tlb::invalidate_page(page.start);
+
+        Some(frame)
+    }
+
+    fn unmap_area(&mut self, virtual_start: VirtualAddress, size: usize) {
+        assert!(virtual_start.is_aligned(Size4KiB::SIZE));
+        assert!(size % Size4KiB::SIZE == 0);
+
+        let physical_base = self.physical_base;
+        let mut cursor = virtual_start;
+        let virtual_end: VirtualAddress = virtual_start + size;
+
+        while cursor < virtual_end {
+            let bytes_left = usize::from(virtual_end) - usize::from(cursor);
+
+            /*
+             * Find the entry that maps `cursor`, and the size of the area it covers. If there's a table missing,
+             * nothing in the area covered by the missing table is mapped, so we skip over it.
+             */
+            let (entry, entry_size) = {
+                let p3 = match self.p4_mut().next_table_mut(cursor.p4_index(), physical_base) {
+                    Some(p3) => p3,
+                    None => {
+                        cursor = next_boundary(cursor, Size1GiB::SIZE * ENTRY_COUNT, virtual_end);
+                        continue;
+                    }
+                };
+                if p3[cursor.p3_index()].flags().contains(EntryFlags::HUGE_PAGE) {
+                    (&mut p3[cursor.p3_index()], Size1GiB::SIZE)
+                } else {
+                    let p2 = match p3.next_table_mut(cursor.p3_index(), physical_base) {
+                        Some(p2) => p2,
+                        None => {
+                            cursor = next_boundary(cursor, Size1GiB::SIZE, virtual_end);
+                            continue;
+                        }
+                    };
+                    if p2[cursor.p2_index()].flags().contains(EntryFlags::HUGE_PAGE) {
+                        (&mut p2[cursor.p2_index()], Size2MiB::SIZE)
+                    } else {
+                        match p2.next_table_mut(cursor.p2_index(), physical_base) {
+                            Some(p1) => (&mut p1[cursor.p1_index()], Size4KiB::SIZE),
+                            None => {
+                                cursor = next_boundary(cursor, Size2MiB::SIZE, virtual_end);
+                                continue;
+                            }
+                        }
+                    }
+                }
+            };
+
+            /*
+             * We can't unmap part of a huge page, so the area must cover all of any huge pages within it.
+             */
+            assert!(
+                cursor.is_aligned(entry_size) && bytes_left >= entry_size,
+                "Tried to unmap part of a huge page at {:#x}",
+                cursor
+            );
+
+            entry.set(None);
+            tlb::invalidate_page(cursor);
+            cursor += entry_size;
         }
     }
 }
 
+/// Get the next address after `address` that is aligned to `align`, or `end` if that's sooner.
+fn next_boundary(address: VirtualAddress, align: usize, end: VirtualAddress) -> VirtualAddress {
+    use pebble_util::math::align_down;
+
+    match usize::from(address).checked_add(align).map(|next| align_down(next, align)) {
+        Some(boundary) if boundary < usize::from(end) => VirtualAddress::new(boundary),
+        _ => end,
+    }
+}
+
 pub trait VirtualAddressIndices {
     fn p4_index(self) -> usize;
     fn p3_index(self) -> usize;
@@ -522,9 +670,23 @@ impl VirtualAddressIndices for VirtualAddress {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use core::ops::Range;
+    use core::{cell::RefCell, ops::Range};
     use hal::memory::FakeFrameAllocator;
-    use std::collections::VecDeque;
+    use std::{boxed::Box, collections::VecDeque, vec::Vec};
+
+    #[test]
+    fn test_entry_flags_round_trip() {
+        let flag_sets = [
+            Flags::default(),
+            Flags { writable: true, ..Default::default() },
+            Flags { executable: true, user_accessible: true, ..Default::default() },
+            Flags { writable: true, user_accessible: true, cached: false, ..Default::default() },
+        ];
+
+        for &flags in flag_sets.iter() {
+            assert_eq!(Flags::from(EntryFlags::from(flags)), flags);
+        }
+    }
 
     #[test]
     fn test_map_area_single_page() {
@@ -643,6 +805,165 @@ mod tests {
         page_table.ensure_all_mappings_made();
     }
 
+    #[test]
+    fn test_translate_huge_pages() {
+        let allocator = TableAllocator::new();
+        let mut page_table = PageTableImpl::new(allocator.allocate(), VirtualAddress::new(0x0));
+        let flags = Flags { writable: true, ..Default::default() };
+
+        page_table
+            .map::<Size1GiB, _>(
+                Page::starts_with(VirtualAddress::new(0x1_0000_0000)),
+                Frame::starts_with(PhysicalAddress::new(0x4000_0000).unwrap()),
+                flags,
+                &allocator,
+            )
+            .unwrap();
+        page_table
+            .map::<Size2MiB, _>(
+                Page::starts_with(VirtualAddress::new(0x4000_0000)),
+                Frame::starts_with(PhysicalAddress::new(0x8020_0000).unwrap()),
+                flags,
+                &allocator,
+            )
+            .unwrap();
+
+        assert_eq!(
+            page_table.translate(VirtualAddress::new(0x1_0012_3456)),
+            Some((PhysicalAddress::new(0x4012_3456).unwrap(), flags))
+        );
+        assert_eq!(
+            page_table.translate(VirtualAddress::new(0x1_3fff_ffff)),
+            Some((PhysicalAddress::new(0x7fff_ffff).unwrap(), flags))
+        );
+        assert_eq!(
+            page_table.translate(VirtualAddress::new(0x4001_2345)),
+            Some((PhysicalAddress::new(0x8021_2345).unwrap(), flags))
+        );
+        assert_eq!(page_table.translate(VirtualAddress::new(0x4020_0000)), None);
+    }
+
+    #[test]
+    fn test_unmap_huge_pages() {
+        let allocator = TableAllocator::new();
+        let mut page_table = PageTableImpl::new(allocator.allocate(), VirtualAddress::new(0x0));
+
+        page_table
+            .map::<Size1GiB, _>(
+                Page::starts_with(VirtualAddress::new(0x1_0000_0000)),
+                Frame::starts_with(PhysicalAddress::new(0x4000_0000).unwrap()),
+                Flags::default(),
+                &allocator,
+            )
+            .unwrap();
+        page_table
+            .map::<Size2MiB, _>(
+                Page::starts_with(VirtualAddress::new(0x4000_0000)),
+                Frame::starts_with(PhysicalAddress::new(0x8020_0000).unwrap()),
+                Flags::default(),
+                &allocator,
+            )
+            .unwrap();
+
+        /*
+         * Unmapping part of a huge page, or a huge page of the wrong size, should leave the mapping alone.
+         */
+        assert_eq!(page_table.unmap::<Size4KiB>(Page::starts_with(VirtualAddress::new(0x4000_1000))), None);
+        assert_eq!(page_table.unmap::<Size2MiB>(Page::starts_with(VirtualAddress::new(0x1_0020_0000))), None);
+        assert!(page_table.translate(VirtualAddress::new(0x4000_1000)).is_some());
+        assert!(page_table.translate(VirtualAddress::new(0x1_0020_0000)).is_some());
+
+        assert_eq!(
+            page_table.unmap::<Size2MiB>(Page::starts_with(VirtualAddress::new(0x4000_0000))),
+            Some(Frame::starts_with(PhysicalAddress::new(0x8020_0000).unwrap()))
+        );
+        assert_eq!(
+            page_table.unmap::<Size1GiB>(Page::starts_with(VirtualAddress::new(0x1_0000_0000))),
+            Some(Frame::starts_with(PhysicalAddress::new(0x4000_0000).unwrap()))
+        );
+        assert_eq!(page_table.translate(VirtualAddress::new(0x4000_1000)), None);
+        assert_eq!(page_table.translate(VirtualAddress::new(0x1_0020_0000)), None);
+    }
+
+    #[test]
+    fn test_unmap_area_huge_pages() {
+        let allocator = TableAllocator::new();
+        let mut page_table = PageTableImpl::new(allocator.allocate(), VirtualAddress::new(0x0));
+
+        /*
+         * This is mapped with a 1GiB page, then a 2MiB page, then a 4KiB page.
+         */
+        let virtual_start = VirtualAddress::new(0x4000_0000);
+        let size = 0x4020_1000;
+        page_table
+            .map_area(
+                virtual_start,
+                PhysicalAddress::new(0x4000_0000).unwrap(),
+                size,
+                Flags::default(),
+                &allocator,
+            )
+            .unwrap();
+        for &address in &[0x4000_0000, 0x7fff_ffff, 0x8000_0000, 0x801f_ffff, 0x8020_0fff] {
+            assert_eq!(
+                page_table.translate(VirtualAddress::new(address)).map(|(physical, _)| physical),
+                Some(PhysicalAddress::new(address).unwrap())
+            );
+        }
+
+        page_table.unmap_area(virtual_start, size);
+        for &address in &[0x4000_0000, 0x7fff_ffff, 0x8000_0000, 0x801f_ffff, 0x8020_0fff] {
+            assert_eq!(page_table.translate(VirtualAddress::new(address)), None);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_unmap_area_part_of_huge_page() {
+        let allocator = TableAllocator::new();
+        let mut page_table = PageTableImpl::new(allocator.allocate(), VirtualAddress::new(0x0));
+
+        page_table
+            .map::<Size2MiB, _>(
+                Page::starts_with(VirtualAddress::new(0x4000_0000)),
+                Frame::starts_with(PhysicalAddress::new(0x8020_0000).unwrap()),
+                Flags::default(),
+                &allocator,
+            )
+            .unwrap();
+        page_table.unmap_area(VirtualAddress::new(0x4000_0000), 0x1000);
+    }
+
+    #[repr(C, align(4096))]
+    struct TableFrame([u8; 4096]);
+
+    /// Allocates frames for page tables from the heap, so the real page tables can be tested. Page tables built
+    /// with this must use a `physical_base` of `0`. Only the tables are ever accessed, so the pages mapped by the
+    /// tests don't need to be backed by real memory.
+    struct TableAllocator {
+        frames: RefCell<Vec<Box<TableFrame>>>,
+    }
+
+    impl TableAllocator {
+        fn new() -> TableAllocator {
+            TableAllocator { frames: RefCell::new(Vec::new()) }
+        }
+    }
+
+    impl FrameAllocator<Size4KiB> for TableAllocator {
+        fn allocate_n(&self, n: usize) -> Range<Frame> {
+            assert_eq!(n, 1);
+            let mut frame = Box::new(TableFrame([0; 4096]));
+            let address = PhysicalAddress::new(frame.0.as_mut_ptr() as usize).unwrap();
+            self.frames.borrow_mut().push(frame);
+            Frame::starts_with(address)..(Frame::starts_with(address) + 1)
+        }
+
+        fn free_n(&self, _start: Frame, _n: usize) {
+            unimplemented!()
+        }
+    }
+
     struct TestPageTable {
         expected_maps: VecDeque<(usize, VirtualAddress, PhysicalAddress)>,
     }
@@ -676,11 +997,11 @@ mod tests {
             unimplemented!()
         }
 
-        unsafe fn switch_to(&self) {
+        unsafe fn switch_to(&self, _flush: bool) {
             unimplemented!()
         }
 
-        fn translate(&self, _address: VirtualAddress) -> Option<PhysicalAddress> {
+        fn translate(&self, _address: VirtualAddress) -> Option<(PhysicalAddress, Flags)> {
             unimplemented!()
         }
 
@@ -841,5 +1162,9 @@ mod tests {
         {
             unimplemented!()
         }
+
+        fn unmap_area(&mut self, _virtual_start: VirtualAddress, _size: usize) {
+            unimplemented!()
+        }
     }
 }
@@ -0,0 +1,18 @@
+//! Helpers for reading fields out of the raw bytes of ACPI tables and resource descriptors, for the parts of them
+//! that the `acpi` crate doesn't parse for us. All fields are little-endian, and may not be aligned.
+
+pub fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+pub fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[offset..(offset + 4)]);
+    u32::from_le_bytes(value)
+}
+
+pub fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes[offset..(offset + 8)]);
+    u64::from_le_bytes(value)
+}
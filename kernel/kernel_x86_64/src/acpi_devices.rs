@@ -0,0 +1,268 @@
+//! Finds the devices described by the AML namespace, so they can be handed to userspace (see `kernel::acpi`). We
+//! only record devices with a Hardware ID (`_HID`) - devices that only have an address (`_ADR`) sit on a bus that
+//! can be enumerated (e.g. PCI), and so are found by the bus driver for that bus instead.
+
+use crate::{
+    acpi_bytes::{read_u16, read_u32},
+    io_ports,
+    mmio,
+};
+use alloc::{string::String, vec::Vec};
+use aml::{value::Args as AmlArgs, AmlContext, AmlError, AmlName, AmlValue, LevelType};
+use bit_field::BitField;
+use core::sync::atomic::AtomicBool;
+use hal::memory::PhysicalAddress;
+use kernel::acpi::{is_pci_root_bridge, AcpiDevice, AcpiInfo, AcpiResource};
+use log::{info, warn};
+
+pub fn resolve(aml_context: &mut AmlContext) -> AcpiInfo {
+    let mut device_paths = Vec::new();
+    let traversal = aml_context.namespace.traverse(|path, level| {
+        if level.typ == LevelType::Device {
+            device_paths.push(path.clone());
+        }
+        Ok(true)
+    });
+    if let Err(err) = traversal {
+        warn!("Failed to traverse AML namespace: {:?}", err);
+    }
+
+    let devices: Vec<AcpiDevice> =
+        device_paths.iter().filter_map(|path| resolve_device(aml_context, path)).collect();
+    info!("Found {} devices in the AML namespace", devices.len());
+    AcpiInfo { devices }
+}
+
+fn resolve_device(aml_context: &mut AmlContext, path: &AmlName) -> Option<AcpiDevice> {
+    let hid = decode_id(&evaluate(aml_context, path, "_HID")?)?;
+
+    /*
+     * Bit 0 of `_STA` is set if the device is present. If a device doesn't have a `_STA` object, it's assumed to
+     * be present.
+     */
+    if let Some(AmlValue::Integer(status)) = evaluate(aml_context, path, "_STA") {
+        if !status.get_bit(0) {
+            return None;
+        }
+    }
+
+    let cids = match evaluate(aml_context, path, "_CID") {
+        Some(AmlValue::Package(ids)) => ids.iter().filter_map(decode_id).collect(),
+        Some(id) => decode_id(&id).into_iter().collect(),
+        None => Vec::new(),
+    };
+
+    let uid = match evaluate(aml_context, path, "_UID") {
+        Some(AmlValue::Integer(uid)) => Some(format!("{}", uid)),
+        Some(AmlValue::String(uid)) => Some(uid),
+        _ => None,
+    };
+
+    /*
+     * The `_CRS` of a PCI root bridge describes the windows of memory and I/O ports its bus decodes. These aren't
+     * the bridge's own registers, and are handed out to the devices on the bus through their BARs, so we don't
+     * pass them on.
+     */
+    let resources = if is_pci_root_bridge(&hid, &cids) {
+        Vec::new()
+    } else {
+        match evaluate(aml_context, path, "_CRS") {
+            Some(AmlValue::Buffer(bytes)) => decode_resources(&bytes),
+            Some(other) => {
+                warn!("_CRS of device {:?} is not a buffer: {:?}", path, other);
+                Vec::new()
+            }
+            None => Vec::new(),
+        }
+    };
+
+    Some(AcpiDevice {
+        path: path.as_string(),
+        hid,
+        cids,
+        uid,
+        resources,
+        resources_claimed: AtomicBool::new(false),
+    })
+}
+
+/// Evaluate the object called `name` in the scope of `device`. Returns `None` if it doesn't exist, or if
+/// evaluating it fails. Objects that would only return a constant are often encoded as that constant rather than
+/// as a method, and so we get their value in that case.
+fn evaluate(aml_context: &mut AmlContext, device: &AmlName, name: &str) -> Option<AmlValue> {
+    let path = AmlName::from_str(name).unwrap().resolve(device).ok()?;
+    match aml_context.invoke_method(&path, AmlArgs::default()) {
+        Ok(value) => Some(value),
+        Err(AmlError::ValueDoesNotExist(_)) => None,
+        Err(err) => {
+            warn!("Failed to evaluate {:?}: {:?}", path, err);
+            None
+        }
+    }
+}
+
+/// Decode a `_HID` or `_CID`, which can either be a string, or an EISA ID encoded as an integer.
+fn decode_id(value: &AmlValue) -> Option<String> {
+    match value {
+        AmlValue::String(id) => Some(id.clone()),
+        AmlValue::Integer(id) => Some(decode_eisa_id(*id as u32)),
+        _ => None,
+    }
+}
+
+/// EISA IDs are made up of three uppercase letters followed by four hex digits (e.g. `PNP0303`). They're
+/// compressed into 32 bits by encoding each letter in 5 bits (with `A` as `1`), and are stored big-endian.
+fn decode_eisa_id(id: u32) -> String {
+    let id = id.swap_bytes();
+    let letter = |bits: u32| (b'@' + bits as u8) as char;
+    format!(
+        "{}{}{}{:04X}",
+        letter(id.get_bits(26..31)),
+        letter(id.get_bits(21..26)),
+        letter(id.get_bits(16..21)),
+        id.get_bits(0..16)
+    )
+}
+
+/// Decode the resource descriptors in a `_CRS` buffer. We only decode descriptors for memory ranges, I/O ports,
+/// and interrupts, which are all userspace drivers currently need.
+fn decode_resources(bytes: &[u8]) -> Vec<AcpiResource> {
+    let mut resources = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let tag = bytes[i];
+
+        if tag.get_bit(7) {
+            /*
+             * Large resource descriptors have their type in bits 0..7 of the tag, followed by a 16-bit length.
+             */
+            let length = match bytes.get((i + 1)..(i + 3)) {
+                Some(length) => read_u16(length, 0) as usize,
+                None => break,
+            };
+            let data = match bytes.get((i + 3)..(i + 3 + length)) {
+                Some(data) => data,
+                None => break,
+            };
+
+            match tag.get_bits(0..7) {
+                // 32-bit Memory Range Descriptor
+                0x05 if length >= 17 => push_memory(
+                    &mut resources,
+                    read_u32(data, 1) as u64,
+                    read_u32(data, 13) as u64,
+                    data[0].get_bit(0),
+                ),
+                // 32-bit Fixed Memory Range Descriptor
+                0x06 if length >= 9 => push_memory(
+                    &mut resources,
+                    read_u32(data, 1) as u64,
+                    read_u32(data, 5) as u64,
+                    data[0].get_bit(0),
+                ),
+                // DWord, Word, and QWord Address Space Descriptors
+                0x07 => decode_address_space(&mut resources, data, 4),
+                0x08 => decode_address_space(&mut resources, data, 2),
+                0x0a => decode_address_space(&mut resources, data, 8),
+                // Extended Interrupt Descriptor
+                0x09 if length >= 2 => {
+                    let count = data[1] as usize;
+                    for interrupt in 0..count {
+                        if let Some(interrupt) = data.get((2 + interrupt * 4)..(6 + interrupt * 4)) {
+                            resources.push(AcpiResource::Irq(read_u32(interrupt, 0)));
+                        }
+                    }
+                }
+                _ => (),
+            }
+
+            i += 3 + length;
+        } else {
+            /*
+             * Small resource descriptors have their type in bits 3..7 of the tag, and their length in bits 0..3.
+             */
+            let length = tag.get_bits(0..3) as usize;
+            let data = match bytes.get((i + 1)..(i + 1 + length)) {
+                Some(data) => data,
+                None => break,
+            };
+
+            match tag.get_bits(3..7) {
+                // IRQ Descriptor
+                0x04 if length >= 2 => {
+                    let mask = read_u16(data, 0);
+                    for irq in (0..16).filter(|&irq| mask.get_bit(irq)) {
+                        resources.push(AcpiResource::Irq(irq as u32));
+                    }
+                }
+                // I/O Port Descriptor
+                0x08 if length >= 7 => push_io(&mut resources, read_u16(data, 1), data[6] as u16),
+                // Fixed Location I/O Port Descriptor
+                0x09 if length >= 3 => push_io(&mut resources, read_u16(data, 0) & 0x3ff, data[2] as u16),
+                // End Tag
+                0x0f => break,
+                _ => (),
+            }
+
+            i += 1 + length;
+        }
+    }
+
+    resources
+}
+
+/// Decode an Address Space Descriptor, whose fields are each `field_size` bytes wide. These describe ranges of
+/// memory or I/O ports.
+fn decode_address_space(resources: &mut Vec<AcpiResource>, data: &[u8], field_size: usize) {
+    /*
+     * The descriptor starts with the type of resource, general flags, and type-specific flags, and is followed by
+     * the range's granularity, minimum, maximum, translation offset, and length.
+     */
+    if data.len() < 3 + 5 * field_size {
+        return;
+    }
+    let field = |index: usize| {
+        let start = 3 + index * field_size;
+        data[start..(start + field_size)].iter().rev().fold(0u64, |value, &byte| (value << 8) | byte as u64)
+    };
+    let (minimum, length) = (field(1), field(4));
+
+    match data[0] {
+        0 => push_memory(resources, minimum, length, data[2].get_bit(0)),
+        1 if length > 0 => push_io(resources, minimum as u16, length as u16),
+        _ => (),
+    }
+}
+
+fn push_io(resources: &mut Vec<AcpiResource>, base: u16, length: u16) {
+    if length == 0 {
+        return;
+    }
+
+    if io_ports::is_kernel_owned(base, length) {
+        warn!("Not passing I/O ports {:#x}..{:#x} to userspace, as the kernel uses them", base, base + length);
+        return;
+    }
+
+    resources.push(AcpiResource::io(base, length));
+}
+
+fn push_memory(resources: &mut Vec<AcpiResource>, address: u64, size: u64, writable: bool) {
+    if size == 0 {
+        return;
+    }
+
+    if let Some(address) = PhysicalAddress::new(address as usize) {
+        if mmio::is_kernel_owned(address, size as usize) {
+            warn!(
+                "Not passing memory {:#x}..{:#x} to userspace, as the kernel uses it",
+                address,
+                usize::from(address) + size as usize
+            );
+            return;
+        }
+
+        resources.push(AcpiResource::memory(address, size as usize, writable));
+    }
+}
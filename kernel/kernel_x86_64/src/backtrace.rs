@@ -0,0 +1,103 @@
+//! The kernel is compiled with frame pointers, so we can print a backtrace by following the chain of saved `rbp`s
+//! up the stack. Each frame is symbolized using the symbol table passed to us by the loader, if we have one.
+
+use core::ops::Range;
+use hal::memory::VirtualAddress;
+use hal_x86_64::kernel_map;
+use kernel::symbols::Symbolized;
+use log::error;
+
+extern "C" {
+    /*
+     * These are defined by the linker script, and bound the stack we're started on. We only ever take their
+     * addresses.
+     */
+    static _stack_bottom: u8;
+    static _stack_top: u8;
+}
+
+/// The maximum number of frames we'll walk. This stops us from looping forever if the stack has been corrupted.
+const MAX_FRAMES: usize = 32;
+
+/// Print a backtrace of the current call stack.
+pub fn print_backtrace() {
+    print_backtrace_from(None, current_rbp());
+}
+
+/// Print a backtrace starting from the frame pointed to by `rbp`. If an instruction pointer is given (e.g. the
+/// address of the instruction that caused an exception), it is printed as the first frame.
+pub fn print_backtrace_from(instruction_pointer: Option<VirtualAddress>, rbp: usize) {
+    error!("Backtrace:");
+    if let Some(instruction_pointer) = instruction_pointer {
+        error!("    ip: {}", Symbolized(instruction_pointer));
+    }
+
+    walk_stack(rbp, |i, return_address| error!("    {}: {}", i, Symbolized(return_address)));
+}
+
+pub fn current_rbp() -> usize {
+    let rbp: usize;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp);
+    }
+    rbp
+}
+
+/// Walk up the stack from the frame pointed to by `rbp`, calling `f` with the index and return address of each
+/// frame. Only frames on the kernel stack we're currently running on are followed.
+pub fn walk_stack<F>(mut rbp: usize, mut f: F)
+where
+    F: FnMut(usize, VirtualAddress),
+{
+    let stack = match current_stack() {
+        Some(stack) => stack,
+        None => return,
+    };
+
+    for i in 0..MAX_FRAMES {
+        /*
+         * Each frame holds the saved `rbp` and the return address, and must lie entirely on the stack. Frames are
+         * pushed further down the stack, so as we walk up it, each frame must also be above the last one - if it
+         * isn't, the chain has been corrupted, or we've got to a frame pointer that's been left in `rbp` by
+         * userspace, and the rest of it can't be trusted.
+         */
+        if rbp % 8 != 0 || rbp < stack.start || rbp.checked_add(16).map_or(true, |frame_end| frame_end > stack.end)
+        {
+            break;
+        }
+
+        let next_rbp = unsafe { *(rbp as *const usize) };
+        let return_address = unsafe { *((rbp + 8) as *const usize) };
+        f(i, VirtualAddress::new(return_address));
+
+        if next_rbp <= rbp {
+            break;
+        }
+        rbp = next_rbp;
+    }
+}
+
+/// Find the part of the kernel stack we're running on that's in use, from the current stack pointer to the top of
+/// the stack. This is either the stack we were started on, or a kernel stack allocated from the kernel stack
+/// slots (a task's, or a CPU's idle stack). Stacks in the slots are mapped from their top down to at least the
+/// stack pointer, so everything in this range can be read.
+fn current_stack() -> Option<Range<usize>> {
+    let rsp: usize;
+    unsafe {
+        asm!("mov {}, rsp", out(reg) rsp);
+    }
+
+    let boot_stack = unsafe { (&_stack_bottom as *const u8 as usize)..(&_stack_top as *const u8 as usize) };
+    let slots_start = usize::from(kernel_map::KERNEL_STACKS_BASE);
+    let slots = slots_start..(slots_start + kernel_map::STACK_SLOT_SIZE * kernel_map::MAX_TASKS);
+
+    if boot_stack.contains(&rsp) {
+        Some(rsp..boot_stack.end)
+    } else if slots.contains(&rsp) {
+        let slot_bottom =
+            slots_start + (rsp - slots_start) / kernel_map::STACK_SLOT_SIZE * kernel_map::STACK_SLOT_SIZE;
+        Some(rsp..(slot_bottom + kernel_map::STACK_SLOT_SIZE))
+    } else {
+        None
+    }
+}
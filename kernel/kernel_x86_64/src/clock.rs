@@ -0,0 +1,96 @@
+//! The kernel keeps time with the TSC, and uses the local APIC timer to interrupt tasks, so needs to know the
+//! frequencies both of them run at. Where we can, we get these from `cpuid`, but many processors (and hypervisors
+//! that aren't configured to) don't report them. We measure the frequencies we can't find against a timer with a
+//! known frequency instead: the HPET if the platform has one, and otherwise the legacy PIT.
+
+use crate::acpi_handler::PebbleAcpiHandler;
+use acpi::{AcpiTables, HpetInfo};
+use core::time::Duration;
+use hal::memory::PhysicalAddress;
+use hal_x86_64::{
+    hw::{cpu::CpuInfo, hpet::Hpet, local_apic::LocalApic, pit::Pit, registers::read_tsc},
+    kernel_map,
+};
+use log::{info, warn};
+use pebble_util::InitGuard;
+
+/// How long we measure the TSC and local APIC timer for. Longer periods give more accurate results, but slow down
+/// boot.
+const MEASUREMENT_PERIOD: Duration = Duration::from_millis(50);
+
+/// The frequencies, in Hz, of the timers we use.
+#[derive(Clone, Copy, Debug)]
+pub struct Frequencies {
+    pub tsc: u64,
+    /// The frequency of the local APIC's clock. The local APIC timer ticks at a fraction of this.
+    pub apic: u32,
+}
+
+static FREQUENCIES: InitGuard<Frequencies> = InitGuard::uninit();
+
+/// Find the frequencies of the TSC and local APIC timer, measuring them if `cpuid` doesn't tell us them. This
+/// should be called on the boot processor, before interrupts are enabled.
+pub fn init(cpu_info: &CpuInfo, acpi_tables: &AcpiTables<PebbleAcpiHandler>, local_apic: &LocalApic) {
+    let frequencies = match (cpu_info.tsc_frequency(), cpu_info.apic_frequency()) {
+        (Some(tsc), Some(apic)) => Frequencies { tsc, apic },
+        (tsc, apic) => {
+            let measured = measure(acpi_tables, local_apic);
+            Frequencies { tsc: tsc.unwrap_or(measured.tsc), apic: apic.unwrap_or(measured.apic) }
+        }
+    };
+
+    info!("TSC frequency: {} Hz, local APIC frequency: {} Hz", frequencies.tsc, frequencies.apic);
+    FREQUENCIES.initialize(frequencies);
+}
+
+pub fn frequencies() -> &'static Frequencies {
+    FREQUENCIES.get()
+}
+
+/// A timer with a known frequency, that we can measure other timers against.
+enum ReferenceTimer {
+    Hpet(Hpet),
+    Pit(Pit),
+}
+
+impl ReferenceTimer {
+    fn new(acpi_tables: &AcpiTables<PebbleAcpiHandler>) -> ReferenceTimer {
+        match HpetInfo::new(acpi_tables) {
+            Ok(hpet_info) => {
+                let base_address = PhysicalAddress::new(hpet_info.base_address).unwrap();
+                let hpet = unsafe { Hpet::new(kernel_map::physical_to_virtual(base_address)) };
+                hpet.enable_counter();
+                ReferenceTimer::Hpet(hpet)
+            }
+            Err(err) => {
+                warn!("Couldn't find HPET ({:?}). Falling back to the PIT to measure timer frequencies.", err);
+                ReferenceTimer::Pit(unsafe { Pit::new() })
+            }
+        }
+    }
+
+    fn wait(&mut self, duration: Duration) {
+        match self {
+            ReferenceTimer::Hpet(hpet) => hpet.wait(duration),
+            ReferenceTimer::Pit(pit) => pit.wait(duration),
+        }
+    }
+}
+
+/// Measure how fast the TSC and local APIC timer tick, by counting their ticks while the reference timer waits
+/// for `MEASUREMENT_PERIOD`.
+fn measure(acpi_tables: &AcpiTables<PebbleAcpiHandler>, local_apic: &LocalApic) -> Frequencies {
+    let mut reference = ReferenceTimer::new(acpi_tables);
+
+    let tsc_start = read_tsc();
+    local_apic.start_counting();
+    reference.wait(MEASUREMENT_PERIOD);
+    let apic_ticks = local_apic.stop_counting();
+    let tsc_ticks = read_tsc() - tsc_start;
+
+    /*
+     * The local APIC timer ticks once every 16 cycles of the local APIC's clock (see `LocalApic::start_counting`).
+     */
+    let periods_per_second = Duration::from_secs(1).as_nanos() as u64 / MEASUREMENT_PERIOD.as_nanos() as u64;
+    Frequencies { tsc: tsc_ticks * periods_per_second, apic: apic_ticks * 16 * periods_per_second as u32 }
+}
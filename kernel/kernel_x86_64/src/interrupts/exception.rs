@@ -2,6 +2,7 @@
 //! exceptions are handled and recovered from, while some are fatal errors and lead to kernel
 //! panics.
 
+use crate::{backtrace, panic_screen, user_access};
 use bit_field::BitField;
 use hal_x86_64::hw::{
     idt::{ExceptionWithErrorStackFrame, InterruptStackFrame},
@@ -16,62 +17,43 @@ pub extern "C" fn nmi_handler(_: &InterruptStackFrame) {
 
 pub extern "C" fn breakpoint_handler(stack_frame: &InterruptStackFrame) {
     info!("BREAKPOINT: {:#x?}", stack_frame);
-
-    /*
-     * TEMP: Do a stacktrace.
-     */
-    let mut rbp = stack_frame.rbp as usize;
-    info!("Starting stacktrace. First frame is at: {:#x}", rbp);
-    if rbp != 0 {
-        for i in 0..16 {
-            let next_rbp = unsafe { *(rbp as *const usize) };
-            let return_address = unsafe { *((rbp + 8) as *const usize) };
-
-            info!("     {}: return address: {:#x}. next frame is at: {:#x}", i, return_address, next_rbp);
-
-            if next_rbp == 0x0 {
-                break;
-            } else {
-                rbp = next_rbp;
-            }
-        }
-    }
+    backtrace::print_backtrace_from(Some(stack_frame.instruction_pointer), stack_frame.rbp as usize);
 }
 
 pub extern "C" fn invalid_opcode_handler(stack_frame: &InterruptStackFrame) {
     error!("INVALID OPCODE AT: {:#x}", stack_frame.instruction_pointer);
     error!("Stack frame: {:x?}", stack_frame);
+    backtrace::print_backtrace_from(Some(stack_frame.instruction_pointer), stack_frame.rbp as usize);
+
+    panic_screen::record_exception("Invalid opcode", stack_frame);
+    panic!("Unrecoverable fault");
+}
 
+pub extern "C" fn general_protection_fault_handler(stack_frame: &mut ExceptionWithErrorStackFrame) {
     /*
-     * TEMP: Do a stacktrace.
+     * Userspace can pass us a non-canonical pointer, which causes a #GP instead of a #PF if we access it.
      */
-    let mut rbp = stack_frame.rbp as usize;
-    info!("Starting stacktrace. First frame is at: {:#x}", rbp);
-    if rbp != 0 {
-        for i in 0..16 {
-            let next_rbp = unsafe { *(rbp as *const usize) };
-            let return_address = unsafe { *((rbp + 8) as *const usize) };
-
-            info!("     {}: return address: {:#x}. next frame is at: {:#x}", i, return_address, next_rbp);
-
-            if next_rbp == 0x0 {
-                break;
-            } else {
-                rbp = next_rbp;
-            }
-        }
+    if let Some(fixup) = user_access::fixup_address(stack_frame.instruction_pointer) {
+        stack_frame.instruction_pointer = fixup;
+        return;
     }
 
-    panic!("Unrecoverable fault");
-}
-
-pub extern "C" fn general_protection_fault_handler(stack_frame: &ExceptionWithErrorStackFrame) {
     error!("General protection fault (error code = {:#x}). Interrupt stack frame: ", stack_frame.error_code);
     error!("{:#x?}", stack_frame);
+    print_backtrace_if_in_kernel(stack_frame);
+    panic_screen::record_exception_with_error("General protection fault", stack_frame);
     panic!("Unrecoverable fault");
 }
 
-pub extern "C" fn page_fault_handler(stack_frame: &ExceptionWithErrorStackFrame) {
+pub extern "C" fn page_fault_handler(stack_frame: &mut ExceptionWithErrorStackFrame) {
+    /*
+     * If we faulted while copying to or from userspace, fail the copy instead. The syscall will return an error.
+     */
+    if let Some(fixup) = user_access::fixup_address(stack_frame.instruction_pointer) {
+        stack_frame.instruction_pointer = fixup;
+        return;
+    }
+
     error!(
         "PAGE_FAULT: {} ({:#x})",
         match (
@@ -103,6 +85,7 @@ pub extern "C" fn page_fault_handler(stack_frame: &ExceptionWithErrorStackFrame)
 
     error!("Error code: {}", BinaryPrettyPrint(stack_frame.error_code));
     error!("{:#x?}", stack_frame);
+    print_backtrace_if_in_kernel(stack_frame);
 
     /*
      * Page-faults can be recovered from and so are faults, but we never will so just give up.
@@ -111,10 +94,21 @@ pub extern "C" fn page_fault_handler(stack_frame: &ExceptionWithErrorStackFrame)
      * In the future, page faults can be used for demand paging and so are recoverable. At the moment, they're
      * always bad, so we panic here.
      */
+    panic_screen::record_exception_with_error("Page fault", stack_frame);
     panic!("Unrecoverable fault");
 }
 
 pub extern "C" fn double_fault_handler(stack_frame: &ExceptionWithErrorStackFrame) {
     error!("EXCEPTION: DOUBLE FAULT   (Error code: {})\n{:#?}", stack_frame.error_code, stack_frame);
+    print_backtrace_if_in_kernel(stack_frame);
+    panic_screen::record_exception_with_error("Double fault", stack_frame);
     panic!("Unrecoverable fault");
 }
+
+/// If an exception occurred in the kernel, print a backtrace of where it happened. If it occurred in userspace,
+/// we can't walk the stack (and the kernel's symbols wouldn't mean anything anyway).
+fn print_backtrace_if_in_kernel(stack_frame: &ExceptionWithErrorStackFrame) {
+    if stack_frame.code_segment.get_bits(0..2) == 0 {
+        backtrace::print_backtrace_from(Some(stack_frame.instruction_pointer), stack_frame.rbp as usize);
+    }
+}
@@ -1,5 +1,7 @@
 mod exception;
+pub mod tlb_shootdown;
 
+use crate::{clock, PlatformImpl};
 use acpi::InterruptModel;
 use aml::{value::Args as AmlArgs, AmlContext, AmlName, AmlValue};
 use core::time::Duration;
@@ -11,10 +13,12 @@ use hal_x86_64::{
         i8259_pic::Pic,
         idt::{wrap_handler, wrap_handler_with_error_code, Idt, InterruptStackFrame},
         local_apic::LocalApic,
+        registers::read_tsc,
     },
     kernel_map,
 };
-use log::warn;
+use kernel::{per_cpu::PerCpu, Platform};
+use log::info;
 use pebble_util::InitGuard;
 
 /// This should only be accessed directly by the bootstrap processor.
@@ -27,6 +31,8 @@ use pebble_util::InitGuard;
 /// |       20-2f      | i8259 PIC Interrupts        |
 /// |       30-??      | IOAPIC Interrupts           |
 /// |        ..        |                             |
+/// |        fc        | IOMMU fault event           |
+/// |        fd        | TLB shootdown IPI           |
 /// |        fe        | Local APIC timer            |
 /// |        ff        | APIC spurious interrupt     |
 /// |------------------|-----------------------------|
@@ -34,15 +40,31 @@ static mut IDT: Idt = Idt::empty();
 
 static LOCAL_APIC: InitGuard<LocalApic> = InitGuard::uninit();
 
+#[derive(Clone, Copy, Debug)]
+enum TimerMode {
+    /// The timer fires when the TSC reaches a deadline.
+    TscDeadline,
+    /// The timer counts down from an initial count, and fires when it reaches zero.
+    OneShot,
+}
+
+static TIMER_MODE: InitGuard<TimerMode> = InitGuard::uninit();
+
 /*
  * These constants define the IDT's layout. Refer to the documentation of the `IDT` static for
  * the full layout.
  */
 const LEGACY_PIC_VECTOR: u8 = 0x20;
 const FREE_VECTORS_START: u8 = 0x30;
+pub const IOMMU_FAULT_VECTOR: u8 = 0xfc;
+const TLB_SHOOTDOWN_VECTOR: u8 = 0xfd;
 const APIC_TIMER_VECTOR: u8 = 0xfe;
 const APIC_SPURIOUS_VECTOR: u8 = 0xff;
 
+/// If the preemption timer fires while the running task is in the kernel, we can't switch away from it, so we try
+/// again after this long.
+const PREEMPTION_RETRY_PERIOD: Duration = Duration::from_millis(1);
+
 pub struct InterruptController {}
 
 impl InterruptController {
@@ -97,13 +119,17 @@ impl InterruptController {
                     .expect("Failed to invoke \\_PIC method");
 
                 /*
-                 * Install handlers for the spurious interrupt and local APIC timer, and then
-                 * enable the local APIC.
-                 * Install a spurious interrupt handler and enable the local APIC.
+                 * Install handlers for the spurious interrupt, local APIC timer, TLB shootdown IPIs, and IOMMU
+                 * fault events, and then enable the local APIC.
                  */
                 unsafe {
+                    IDT[IOMMU_FAULT_VECTOR].set_handler(wrap_handler!(iommu_fault_handler), KERNEL_CODE_SELECTOR);
                     IDT[APIC_TIMER_VECTOR]
                         .set_handler(wrap_handler!(local_apic_timer_handler), KERNEL_CODE_SELECTOR);
+                    IDT[TLB_SHOOTDOWN_VECTOR].set_handler(
+                        wrap_handler!(tlb_shootdown::tlb_shootdown_handler),
+                        KERNEL_CODE_SELECTOR,
+                    );
                     IDT[APIC_SPURIOUS_VECTOR].set_handler(wrap_handler!(spurious_handler), KERNEL_CODE_SELECTOR);
                     LOCAL_APIC.get().enable(APIC_SPURIOUS_VECTOR);
                 }
@@ -115,23 +141,87 @@ impl InterruptController {
         }
     }
 
-    /// Enable the per-CPU timer on the local APIC, so that it ticks every `period` ms. Cannot be
-    /// called before interrupt handlers are installed, because this borrows `self`.
-    pub fn enable_local_timer(&mut self, cpu_info: &CpuInfo, period: Duration) {
+    pub fn local_apic(&self) -> &LocalApic {
+        LOCAL_APIC.get()
+    }
+
+    /// Set up the per-CPU timer on the local APIC, which the scheduler uses to preempt tasks (see `set_timer`).
+    /// Cannot be called before interrupt handlers are installed, because this borrows `self`. The frequencies of
+    /// the TSC and local APIC must already be known (see `clock::init`).
+    pub fn enable_local_timer(&mut self, cpu_info: &CpuInfo) {
         /*
-         * TODO: currently, this relies upon being able to get the frequency from the
-         * CpuInfo. We should probably build a backup to calibrate it using another timer.
+         * We prefer TSC-deadline mode, as we can program deadlines against the same clock we keep time with.
+         * Every processor in the system supports the same features, so all of them can use the same mode.
          */
-        match cpu_info.apic_frequency() {
-            Some(apic_frequency) => {
-                LOCAL_APIC.get().enable_timer(period.as_millis() as u32, apic_frequency, APIC_TIMER_VECTOR);
-            }
-            None => warn!("Couldn't find frequency of APIC from cpuid. Local APIC timer not enabled!"),
+        let mode = if cpu_info.supported_features.tsc_deadline {
+            LOCAL_APIC.get().enable_tsc_deadline_timer(APIC_TIMER_VECTOR);
+            TimerMode::TscDeadline
+        } else {
+            LOCAL_APIC.get().enable_one_shot_timer(APIC_TIMER_VECTOR);
+            TimerMode::OneShot
+        };
+
+        info!("Using local APIC timer in mode: {:?}", mode);
+        TIMER_MODE.initialize(mode);
+    }
+}
+
+/// Arm the local APIC timer to interrupt the running CPU once `duration` has passed, or disarm it if `duration` is
+/// `None`. This replaces any time the timer was already armed for.
+pub fn set_timer(duration: Option<Duration>) {
+    let frequencies = clock::frequencies();
+
+    match TIMER_MODE.get() {
+        TimerMode::TscDeadline => {
+            let deadline = duration.map(|duration| read_tsc() + ticks_in(duration, frequencies.tsc)).unwrap_or(0);
+            LOCAL_APIC.get().set_tsc_deadline(deadline);
+        }
+        TimerMode::OneShot => {
+            /*
+             * The timer counts down once every 16 cycles of the local APIC's clock. A count of `0` stops the
+             * timer, so we round very short durations up to a single tick, and the count register is only 32 bits
+             * wide, so we cap very long durations.
+             */
+            let count = duration
+                .map(|duration| ticks_in(duration, frequencies.apic as u64 / 16).max(1).min(u32::MAX as u64))
+                .unwrap_or(0);
+            LOCAL_APIC.get().set_timer_count(count as u32);
         }
     }
 }
 
-extern "C" fn local_apic_timer_handler(_: &InterruptStackFrame) {
+/// The number of ticks of a clock running at `frequency` Hz that make up `duration`.
+fn ticks_in(duration: Duration, frequency: u64) -> u64 {
+    (duration.as_nanos() * frequency as u128 / 1_000_000_000) as u64
+}
+
+extern "C" fn local_apic_timer_handler(stack_frame: &InterruptStackFrame) {
+    unsafe {
+        LOCAL_APIC.get().send_eoi();
+    }
+
+    /*
+     * The timer is only armed when the running task's time slice should end. The kernel isn't preemptible, so we
+     * can only switch away from the task if we interrupted it in userspace. If we interrupted it in the kernel, we
+     * try again shortly.
+     */
+    let mut per_cpu = PlatformImpl::per_cpu();
+    if stack_frame.code_segment & 0b11 == 3 {
+        /*
+         * We entered the kernel on the stack pointer in the TSS. When we're switched back to, the scheduler sets
+         * that to where this task's context was saved, which would leave less of the kernel stack for each
+         * subsequent interrupt, so we put it back afterwards.
+         */
+        let kernel_stack_pointer = per_cpu.as_mut().kernel_stack_pointer();
+        per_cpu.scheduler().time_slice_expired();
+        PlatformImpl::per_cpu().set_kernel_stack_pointer(kernel_stack_pointer);
+    } else if per_cpu.scheduler().running_task.is_some() {
+        set_timer(Some(PREEMPTION_RETRY_PERIOD));
+    }
+}
+
+extern "C" fn iommu_fault_handler(_: &InterruptStackFrame) {
+    crate::iommu::report_faults();
     unsafe {
         LOCAL_APIC.get().send_eoi();
     }
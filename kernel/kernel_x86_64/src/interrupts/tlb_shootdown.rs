@@ -0,0 +1,100 @@
+//! When a mapping is removed from (or made more restrictive in) an address space that's active on other CPUs,
+//! their TLBs may still hold the old translation. To fix this, we send them an IPI asking them to invalidate it
+//! (a "TLB shootdown"), and then wait for them all to do so.
+//!
+//! The kernel's mappings are shared by every address space, so entries for them can be cached on any CPU, under
+//! any PCID. When they change, every CPU flushes its whole TLB instead (see `shoot_down_kernel`).
+
+use super::{LOCAL_APIC, TLB_SHOOTDOWN_VECTOR};
+use crate::per_cpu;
+use core::sync::atomic::{spin_loop_hint, AtomicU64, AtomicUsize, Ordering};
+use hal::memory::{FrameSize, Page, Size4KiB, VirtualAddress};
+use hal_x86_64::{
+    hw::{idt::InterruptStackFrame, tlb},
+    kernel_map,
+};
+use spin::Mutex;
+
+/// If a shootdown covers more pages than this, each CPU flushes its whole TLB instead of invalidating the pages
+/// one-by-one.
+const MAX_PAGES_TO_INVALIDATE: usize = 32;
+
+/// Only one shootdown can be in progress at once. This is held by the CPU that started it until every CPU taking
+/// part has finished.
+static SHOOTDOWN_LOCK: Mutex<()> = Mutex::new(());
+/// A bitmap of the CPUs that still need to carry out the current shootdown. Each CPU clears its bit once it has.
+static PENDING_CPUS: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_START: AtomicUsize = AtomicUsize::new(0);
+static SHOOTDOWN_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Invalidate the TLB entries for the `size` bytes at `start` on each CPU in the bitmap `cpus`, and wait until
+/// they've all done so. The running CPU should not be included in `cpus`.
+pub fn shoot_down(cpus: u64, start: VirtualAddress, size: usize) {
+    if cpus == 0 || size == 0 {
+        return;
+    }
+
+    /*
+     * If another CPU is already carrying out a shootdown, we might be one of the CPUs it's waiting for. Interrupts
+     * may be disabled here, so we can't rely on its IPI reaching us, and so take part in its shootdown manually
+     * while we wait for it to finish.
+     */
+    let _guard = loop {
+        if let Some(guard) = SHOOTDOWN_LOCK.try_lock() {
+            break guard;
+        }
+        handle_shootdown();
+        spin_loop_hint();
+    };
+
+    SHOOTDOWN_START.store(usize::from(start), Ordering::Relaxed);
+    SHOOTDOWN_SIZE.store(size, Ordering::Relaxed);
+    PENDING_CPUS.store(cpus, Ordering::Release);
+
+    unsafe {
+        LOCAL_APIC.get().send_ipi_to_all_but_self(TLB_SHOOTDOWN_VECTOR);
+    }
+
+    while PENDING_CPUS.load(Ordering::Acquire) != 0 {
+        spin_loop_hint();
+    }
+}
+
+/// Invalidate the TLB entries for the `size` bytes at `start` in the kernel's part of the address space, on every
+/// CPU (including this one) and for every address space, and wait until they've all done so.
+pub fn shoot_down_kernel(start: VirtualAddress, size: usize) {
+    assert!(start >= kernel_map::KERNEL_ADDRESS_SPACE_START);
+    tlb::flush_all();
+    shoot_down(per_cpu::online_cpus() & !(1 << per_cpu::current_cpu_id()), start, size);
+}
+
+/// Carry out the current shootdown on this CPU, if it's one of the CPUs that has been asked to.
+fn handle_shootdown() {
+    let cpu_bit = 1 << per_cpu::current_cpu_id();
+    if PENDING_CPUS.load(Ordering::Acquire) & cpu_bit == 0 {
+        return;
+    }
+
+    let start = VirtualAddress::new(SHOOTDOWN_START.load(Ordering::Relaxed));
+    let size = SHOOTDOWN_SIZE.load(Ordering::Relaxed);
+
+    if start >= kernel_map::KERNEL_ADDRESS_SPACE_START {
+        tlb::flush_all();
+    } else if size / Size4KiB::SIZE > MAX_PAGES_TO_INVALIDATE {
+        tlb::flush();
+    } else {
+        for page in Page::<Size4KiB>::contains(start)..=Page::<Size4KiB>::contains(start + (size - 1)) {
+            tlb::invalidate_page(page.start);
+        }
+    }
+
+    PENDING_CPUS.fetch_and(!cpu_bit, Ordering::Release);
+}
+
+pub extern "C" fn tlb_shootdown_handler(_: &InterruptStackFrame) {
+    handle_shootdown();
+
+    unsafe {
+        LOCAL_APIC.get().send_eoi();
+    }
+}
@@ -0,0 +1,36 @@
+//! Keeps track of the I/O ports the kernel uses itself. Userspace must never be able to access these, so we refuse
+//! to create `IoPortRange`s that overlap them, even if a device's ACPI resources or I/O BARs claim them.
+
+use crate::power;
+use hal_x86_64::hw::serial::COM1;
+
+/// The legacy ports the kernel always uses, as `(base, length)` pairs.
+const LEGACY_PORTS: [(u16, u16); 7] = [
+    // The primary and secondary 8259 PICs, which we disable
+    (0x20, 2),
+    (0xa0, 2),
+    // The PIT, and the port that gates its second channel, which we use to calibrate the other timers
+    (0x40, 4),
+    (0x61, 1),
+    // The keyboard controller, which we can reset the machine through
+    (0x60, 1),
+    (0x64, 1),
+    // The PCI configuration space access mechanism
+    (0xcf8, 8),
+];
+
+/// The number of ports taken up by the serial port we log to.
+const COM1_LENGTH: u16 = 8;
+
+/// Returns `true` if any of the `length` ports starting at `base` are used by the kernel.
+pub fn is_kernel_owned(base: u16, length: u16) -> bool {
+    let overlaps = |(owned_base, owned_length): (u16, u16)| {
+        (base as u32) < (owned_base as u32 + owned_length as u32)
+            && (owned_base as u32) < (base as u32 + length as u32)
+    };
+
+    LEGACY_PORTS.iter().copied().any(overlaps)
+        || overlaps((COM1, COM1_LENGTH))
+        || power::io_ports().into_iter().any(overlaps)
+        || (cfg!(feature = "qemu_exit") && overlaps((0xf4, 4)))
+}
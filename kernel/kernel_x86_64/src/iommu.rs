@@ -0,0 +1,821 @@
+//! Support for Intel's VT-d IOMMU, which translates the addresses devices use for DMA. We give each PCI function
+//! its own domain, with its own set of second-level page tables, and only map the memory its driver has attached
+//! to it (see `kernel::pci::Iommu`). Memory is mapped at the same address as its physical address, so drivers can
+//! keep giving devices physical addresses. Functions behind bridges to conventional PCI share the domain of the
+//! requester ID the bridge gives their DMA, as we can't tell them apart.
+//!
+//! The remapping hardware units, and the devices each one handles, are described by the ACPI DMAR table. We only
+//! use the register-based invalidation interface, and don't support interrupt remapping.
+//!
+//! Each unit records the faults it sees (e.g. a device trying to access memory that hasn't been attached to it),
+//! and signals a fault event interrupt, which we handle by logging and clearing the recorded faults.
+
+use crate::{
+    acpi_bytes::{read_u16, read_u64},
+    acpi_handler::PebbleAcpiHandler,
+    interrupts::{InterruptController, IOMMU_FAULT_VECTOR},
+};
+use acpi::{sdt::Signature, AcpiTables};
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use bit_field::BitField;
+use core::{ops::Range, ptr};
+use hal::memory::{FrameAllocator, FrameSize, PhysicalAddress, Size4KiB, VirtualAddress};
+use hal_x86_64::kernel_map;
+use kernel::pci::{Iommu, PciInfo};
+use log::{info, warn};
+use pci_types::{ConfigRegionAccess, PciAddress};
+use pebble_util::InitGuard;
+
+/*
+ * Offsets of the registers of a remapping hardware unit.
+ */
+const REG_CAPABILITY: usize = 0x08;
+const REG_EXTENDED_CAPABILITY: usize = 0x10;
+const REG_GLOBAL_COMMAND: usize = 0x18;
+const REG_GLOBAL_STATUS: usize = 0x1c;
+const REG_ROOT_TABLE_ADDRESS: usize = 0x20;
+const REG_CONTEXT_COMMAND: usize = 0x28;
+const REG_FAULT_STATUS: usize = 0x34;
+const REG_FAULT_EVENT_CONTROL: usize = 0x38;
+const REG_FAULT_EVENT_DATA: usize = 0x3c;
+const REG_FAULT_EVENT_ADDRESS: usize = 0x40;
+
+/*
+ * Bits of the Global Command and Global Status registers.
+ */
+const GLOBAL_TRANSLATION_ENABLE: usize = 31;
+const GLOBAL_SET_ROOT_TABLE_POINTER: usize = 30;
+const GLOBAL_WRITE_BUFFER_FLUSH: usize = 27;
+/// Clears the bits of the Global Status register that report the status of one-shot commands, so the rest of the
+/// status can be written back to the Global Command register without issuing them again.
+const GLOBAL_STATUS_ONE_SHOT_MASK: u32 = 0x96ff_ffff;
+
+/*
+ * Bits of second-level page-table entries.
+ */
+const ENTRY_READ: usize = 0;
+const ENTRY_WRITE: usize = 1;
+const ENTRY_ADDRESS: Range<usize> = 12..52;
+
+/// Number of entries in each root table, context table, and second-level page table.
+const ENTRIES_PER_TABLE: usize = 512;
+
+/// Where to find the fault recording registers of each remapping unit. The fault event handler reads these
+/// without locking the IOMMU, as the interrupt can arrive while the lock is held.
+static FAULT_RECORDERS: InitGuard<Vec<FaultRecorder>> = InitGuard::uninit();
+
+/// Set up VT-d translation for every remapping hardware unit described by the DMAR table. Returns `None` if the
+/// platform doesn't have a DMAR table, or if none of its units can be used. This must be called after PCI has been
+/// enumerated (so bridges have their bus numbers), and before the ACPI tables are reclaimed.
+pub fn init<A>(
+    acpi_tables: &AcpiTables<PebbleAcpiHandler>,
+    pci_access: &A,
+    interrupt_controller: &InterruptController,
+) -> Option<VtdIommu>
+where
+    A: ConfigRegionAccess,
+{
+    /*
+     * The DMAR is made up of a standard SDT header, the host address width, some flags, and 10 reserved bytes.
+     * It's followed by a list of remapping structures, each of which starts with a 16-bit type and a 16-bit
+     * length.
+     */
+    const STRUCTURES_OFFSET: usize = 48;
+    const STRUCTURE_DRHD: u16 = 0;
+    const STRUCTURE_RMRR: u16 = 1;
+
+    let dmar = acpi_tables.sdts.get(&Signature::DMAR)?;
+    let dmar = unsafe {
+        core::slice::from_raw_parts(
+            kernel_map::physical_to_virtual(PhysicalAddress::new(dmar.physical_address).unwrap()).ptr(),
+            dmar.length as usize,
+        )
+    };
+
+    let mut units = Vec::new();
+    let mut reserved_regions = Vec::new();
+    let mut structures = dmar.get(STRUCTURES_OFFSET..).unwrap_or(&[]);
+
+    while structures.len() >= 4 {
+        let typ = read_u16(structures, 0);
+        let length = read_u16(structures, 2) as usize;
+        let structure = match structures.get(0..length) {
+            Some(structure) if length >= 4 => structure,
+            _ => break,
+        };
+
+        match typ {
+            /*
+             * DMA Remapping Hardware Unit Definition. The unit handles the devices in its device scope, or every
+             * device in its segment group that isn't handled by another unit if its `INCLUDE_PCI_ALL` flag is set.
+             */
+            STRUCTURE_DRHD if length >= 16 => {
+                let include_all = structure[4].get_bit(0);
+                let segment = read_u16(structure, 6);
+                let register_base = PhysicalAddress::new(read_u64(structure, 8) as usize).unwrap();
+                let scope = decode_device_scope(&structure[16..], segment, pci_access);
+
+                match RemappingUnit::new(register_base, segment, include_all, scope) {
+                    Some(unit) => units.push(unit),
+                    None => warn!("VT-d remapping unit at {:#x} can't be used. Ignoring.", register_base),
+                }
+            }
+
+            /*
+             * Reserved Memory Region Reporting. Devices in the region's device scope need to keep accessing the
+             * region (e.g. USB controllers the firmware emulates a PS/2 keyboard with), so we map it into their
+             * domains.
+             */
+            STRUCTURE_RMRR if length >= 24 => {
+                let segment = read_u16(structure, 6);
+                let base = read_u64(structure, 8);
+                let limit = read_u64(structure, 16);
+                let scope = decode_device_scope(&structure[24..], segment, pci_access);
+                reserved_regions.push((base..(limit + 1), scope));
+            }
+
+            _ => (),
+        }
+
+        structures = &structures[length..];
+    }
+
+    if units.is_empty() {
+        return None;
+    }
+
+    let mut iommu = {
+        let pci_info = kernel::PCI_INFO.read();
+        VtdIommu {
+            units,
+            requester_ids: pci_info.as_ref().map(|info| requester_ids(pci_access, info)).unwrap_or_default(),
+        }
+    };
+    {
+        /*
+         * A region's device scope can name whole buses behind a bridge, so we map the region for every function
+         * we've found on them, as well as the functions it names directly.
+         */
+        let pci_info = kernel::PCI_INFO.read();
+        let pci_devices: Vec<PciAddress> =
+            pci_info.as_ref().map(|info| info.devices.keys().copied().collect()).unwrap_or_default();
+
+        for (region, scope) in reserved_regions {
+            let start = PhysicalAddress::new(region.start as usize).unwrap().align_down(Size4KiB::SIZE);
+            let end = PhysicalAddress::new(region.end as usize).unwrap().align_up(Size4KiB::SIZE);
+
+            let mut devices: Vec<PciAddress> = scope
+                .iter()
+                .filter_map(|entry| match entry {
+                    DeviceScope::Device(device) => Some(*device),
+                    DeviceScope::Buses { .. } => None,
+                })
+                .chain(pci_devices.iter().copied().filter(|&device| {
+                    scope.iter().any(|entry| matches!(entry, DeviceScope::Buses { .. }) && entry.contains(device))
+                }))
+                .collect();
+            devices.sort();
+            devices.dedup();
+
+            for device in devices {
+                info!("Mapping reserved memory region {:#x}..{:#x} for PCI device {}", start, end, device);
+                iommu.map(device, start..end, true);
+            }
+        }
+    }
+
+    /*
+     * Fault events are delivered to this processor, which is the boot processor.
+     */
+    FAULT_RECORDERS.initialize(iommu.units.iter().map(RemappingUnit::fault_recorder).collect());
+    let apic_id = interrupt_controller.local_apic().id();
+    for unit in iommu.units.iter_mut() {
+        unit.enable_fault_events(apic_id);
+        unit.enable();
+    }
+
+    info!("Enabled DMA remapping with {} VT-d remapping units", iommu.units.len());
+    Some(iommu)
+}
+
+/// Log and clear the faults recorded by each remapping unit. This is called when a unit signals a fault event.
+pub fn report_faults() {
+    if let Some(recorders) = FAULT_RECORDERS.try_get() {
+        for recorder in recorders.iter() {
+            recorder.report_faults();
+        }
+    }
+}
+
+pub struct VtdIommu {
+    units: Vec<RemappingUnit>,
+    /// The requester IDs DMA from each PCI function we've enumerated can be seen with. See `requester_ids`.
+    requester_ids: BTreeMap<PciAddress, Vec<PciAddress>>,
+}
+
+impl VtdIommu {
+    /// Functions we haven't enumerated (e.g. ones only named by the DMAR) are assumed to use their own ID.
+    fn requester_ids_of(&self, device: PciAddress) -> Vec<PciAddress> {
+        self.requester_ids.get(&device).cloned().unwrap_or_else(|| vec![device])
+    }
+
+    /// Find the remapping unit that handles DMA from `device`. Units that list the device in their device scope
+    /// take priority over a unit that handles all the devices in the segment group.
+    fn unit_for(&mut self, device: PciAddress) -> Option<&mut RemappingUnit> {
+        let in_scope = self.units.iter().position(|unit| unit.scope.iter().any(|scope| scope.contains(device)));
+        let index = in_scope
+            .or_else(|| self.units.iter().position(|unit| unit.include_all && unit.segment == device.segment()))?;
+        Some(&mut self.units[index])
+    }
+}
+
+impl Iommu for VtdIommu {
+    fn map(&mut self, device: PciAddress, range: Range<PhysicalAddress>, writable: bool) {
+        let requester_ids = self.requester_ids_of(device);
+        match self.unit_for(device) {
+            Some(unit) => unit.map(&requester_ids, range, writable),
+            None => warn!("No VT-d remapping unit handles PCI device {}. Its DMA won't be remapped.", device),
+        }
+    }
+
+    fn unmap(&mut self, device: PciAddress, range: Range<PhysicalAddress>) {
+        let requester_ids = self.requester_ids_of(device);
+        if let Some(unit) = self.unit_for(device) {
+            unit.unmap(&requester_ids, range);
+        }
+    }
+}
+
+enum DeviceScope {
+    /// A single PCI function.
+    Device(PciAddress),
+    /// Every PCI function on a range of buses, which sit behind a bridge.
+    Buses { segment: u16, buses: Range<u16> },
+}
+
+impl DeviceScope {
+    fn contains(&self, device: PciAddress) -> bool {
+        match self {
+            DeviceScope::Device(address) => *address == device,
+            DeviceScope::Buses { segment, buses } => {
+                *segment == device.segment() && buses.contains(&(device.bus() as u16))
+            }
+        }
+    }
+}
+
+/// Decode a list of Device Scope structures. Each one describes a device by the bus it starts on, and the path of
+/// device and function numbers through the bridges below that bus, so we need to read the bus numbers the bridges
+/// have been configured with to find where the device actually is.
+fn decode_device_scope<A>(mut bytes: &[u8], segment: u16, pci_access: &A) -> Vec<DeviceScope>
+where
+    A: ConfigRegionAccess,
+{
+    const SCOPE_PCI_ENDPOINT: u8 = 1;
+    const SCOPE_PCI_SUB_HIERARCHY: u8 = 2;
+
+    let mut scope = Vec::new();
+
+    while bytes.len() >= 6 {
+        let typ = bytes[0];
+        let length = bytes[1] as usize;
+        let entry = match bytes.get(0..length) {
+            Some(entry) if length >= 6 => entry,
+            _ => break,
+        };
+
+        /*
+         * Every step of the path but the last has to be a bridge we can read the bus numbers of. If it isn't (e.g.
+         * the firmware describes a device that isn't present, or a bus we can't access), we skip the entry.
+         */
+        let mut bus = entry[5];
+        let path: Vec<(u8, u8)> = entry[6..].chunks_exact(2).map(|step| (step[0], step[1])).collect();
+        for (i, &(device, function)) in path.iter().enumerate() {
+            let address = PciAddress::new(segment, bus, device, function);
+
+            if i + 1 == path.len() {
+                match typ {
+                    SCOPE_PCI_ENDPOINT => scope.push(DeviceScope::Device(address)),
+                    SCOPE_PCI_SUB_HIERARCHY => match bridge_buses(pci_access, address) {
+                        /*
+                         * The bridge itself, and every bus between its secondary and subordinate bus numbers.
+                         */
+                        Some((secondary, subordinate)) => {
+                            scope.push(DeviceScope::Device(address));
+                            scope.push(DeviceScope::Buses {
+                                segment,
+                                buses: (secondary as u16)..(subordinate as u16 + 1),
+                            });
+                        }
+                        None => {
+                            warn!("DMAR device scope names bridge {}, which doesn't exist. Ignoring.", address)
+                        }
+                    },
+                    // IOAPICs, HPETs, and ACPI namespace devices don't need to do DMA
+                    _ => (),
+                }
+            } else {
+                match bridge_buses(pci_access, address) {
+                    Some((secondary, _)) => bus = secondary,
+                    None => {
+                        warn!("DMAR device scope goes through bridge {}, which doesn't exist. Ignoring.", address);
+                        break;
+                    }
+                }
+            }
+        }
+
+        bytes = &bytes[length..];
+    }
+
+    scope
+}
+
+/// Read the secondary and subordinate bus numbers of the PCI-to-PCI bridge at `address`. Returns `None` if there
+/// isn't a bridge there, including if we can't access the bus it would be on.
+fn bridge_buses<A>(pci_access: &A, address: PciAddress) -> Option<(u8, u8)>
+where
+    A: ConfigRegionAccess,
+{
+    const HEADER_TYPE_PCI_TO_PCI_BRIDGE: u32 = 0x01;
+
+    if !pci_access.function_exists(address) || unsafe { pci_access.read(address, 0x00) }.get_bits(0..16) == 0xffff
+    {
+        return None;
+    }
+    if unsafe { pci_access.read(address, 0x0c) }.get_bits(16..23) != HEADER_TYPE_PCI_TO_PCI_BRIDGE {
+        return None;
+    }
+
+    let bus_numbers = unsafe { pci_access.read(address, 0x18) };
+    Some((bus_numbers.get_bits(8..16) as u8, bus_numbers.get_bits(16..24) as u8))
+}
+
+/// Work out the requester IDs the remapping units can see DMA from each PCI function with. PCIe carries the
+/// function's own ID all the way up to the root complex, but conventional PCI doesn't have requester IDs, so when
+/// DMA from a function goes through a bridge to or from conventional PCI, the bridge replaces the ID with its own,
+/// or with that of its secondary bus. Each function's own ID comes first, and then each ID it might be replaced
+/// with on the way up. The last one is the ID the remapping unit actually sees.
+fn requester_ids<A>(pci_access: &A, pci_info: &PciInfo) -> BTreeMap<PciAddress, Vec<PciAddress>>
+where
+    A: ConfigRegionAccess,
+{
+    const CAPABILITY_PCI_EXPRESS: u32 = 0x10;
+    const PORT_TYPE_PCIE_TO_PCI_BRIDGE: u32 = 0x7;
+    const PORT_TYPE_PCI_TO_PCIE_BRIDGE: u32 = 0x8;
+
+    let mut requester_ids = BTreeMap::new();
+    for (&address, device) in pci_info.devices.iter() {
+        let mut ids = vec![address];
+        let mut parent = device.parent;
+
+        while let Some(bridge) = parent {
+            /*
+             * The Device/Port Type of a PCIe function is in bits `4..8` of the PCI Express Capabilities register,
+             * which is the upper half of the first register of its PCI Express capability.
+             */
+            let port_type = kernel::pci::capabilities(pci_access, bridge)
+                .into_iter()
+                .find(|&(_, header)| header.get_bits(0..8) == CAPABILITY_PCI_EXPRESS)
+                .map(|(_, header)| header.get_bits(20..24));
+
+            match port_type {
+                Some(PORT_TYPE_PCIE_TO_PCI_BRIDGE) => {
+                    if let Some((secondary, _)) = bridge_buses(pci_access, bridge) {
+                        ids.push(PciAddress::new(bridge.segment(), secondary, 0, 0));
+                    }
+                }
+                Some(PORT_TYPE_PCI_TO_PCIE_BRIDGE) | None => ids.push(bridge),
+                // Root ports and switch ports pass requester IDs through unchanged
+                Some(_) => (),
+            }
+
+            parent = pci_info.devices.get(&bridge).and_then(|bridge| bridge.parent);
+        }
+
+        requester_ids.insert(address, ids);
+    }
+
+    requester_ids
+}
+
+/// A VT-d DMA Remapping Hardware Unit.
+struct RemappingUnit {
+    registers: VirtualAddress,
+    segment: u16,
+    include_all: bool,
+    scope: Vec<DeviceScope>,
+
+    root_table: PhysicalAddress,
+    /// The number of levels of second-level page tables we use, and the value of the Address Width field of
+    /// context entries that tells the hardware this.
+    levels: usize,
+    address_width: u64,
+    num_domains: usize,
+    /// Offset of the IOTLB Invalidate register from the start of the registers.
+    iotlb_register: usize,
+    /// If the hardware doesn't snoop the processor's caches when it walks the tables, we have to flush them from
+    /// the caches after we change them.
+    coherent: bool,
+    /// In Caching Mode, the hardware can cache entries that aren't present, and so we need to invalidate the
+    /// caches when we add entries, as well as when we remove them.
+    caching_mode: bool,
+    needs_write_buffer_flush: bool,
+    /// Offset of the first fault recording register from the start of the registers, and how many there are.
+    fault_recording_offset: usize,
+    num_fault_records: usize,
+
+    /// The ID and the root of the page tables of each domain, keyed by the requester ID the unit sees DMA from the
+    /// domain's devices with. Devices that end up with the same requester ID share a domain.
+    domains: BTreeMap<PciAddress, (u16, PhysicalAddress)>,
+}
+
+impl RemappingUnit {
+    fn new(
+        register_base: PhysicalAddress,
+        segment: u16,
+        include_all: bool,
+        scope: Vec<DeviceScope>,
+    ) -> Option<RemappingUnit> {
+        let registers = kernel_map::physical_to_virtual(register_base);
+        let capability = unsafe { ptr::read_volatile((registers + REG_CAPABILITY).ptr::<u64>()) };
+        let extended_capability =
+            unsafe { ptr::read_volatile((registers + REG_EXTENDED_CAPABILITY).ptr::<u64>()) };
+
+        /*
+         * We use four-level tables (48-bit addresses) if we can, and three-level tables (39-bit addresses)
+         * otherwise. These are the values of the Address Width field for each.
+         */
+        let supported_address_widths = capability.get_bits(8..13);
+        let (levels, address_width) = if supported_address_widths.get_bit(2) {
+            (4, 2)
+        } else if supported_address_widths.get_bit(1) {
+            (3, 1)
+        } else {
+            return None;
+        };
+
+        let mut unit = RemappingUnit {
+            registers,
+            segment,
+            include_all,
+            scope,
+            root_table: PhysicalAddress::new(0x0).unwrap(),
+            levels,
+            address_width,
+            num_domains: 1 << (4 + 2 * capability.get_bits(0..3)),
+            iotlb_register: extended_capability.get_bits(8..18) as usize * 16 + 8,
+            coherent: extended_capability.get_bit(0),
+            caching_mode: capability.get_bit(7),
+            needs_write_buffer_flush: capability.get_bit(4),
+            fault_recording_offset: capability.get_bits(24..34) as usize * 16,
+            num_fault_records: capability.get_bits(40..48) as usize + 1,
+            domains: BTreeMap::new(),
+        };
+        unit.root_table = unit.alloc_table();
+        Some(unit)
+    }
+
+    /// Install the root table, and turn on translation. From this point, devices handled by this unit can only
+    /// access the memory mapped into their domains.
+    fn enable(&mut self) {
+        unsafe {
+            self.write_register_u64(REG_ROOT_TABLE_ADDRESS, usize::from(self.root_table) as u64);
+            self.global_command(GLOBAL_SET_ROOT_TABLE_POINTER, true);
+        }
+        self.invalidate_all();
+
+        unsafe {
+            self.global_command(GLOBAL_TRANSLATION_ENABLE, true);
+        }
+    }
+
+    fn fault_recorder(&self) -> FaultRecorder {
+        FaultRecorder {
+            registers: self.registers,
+            segment: self.segment,
+            fault_recording_offset: self.fault_recording_offset,
+            num_fault_records: self.num_fault_records,
+        }
+    }
+
+    /// Ask the unit to signal a fault event interrupt to the processor with local APIC ID `apic_id` when it
+    /// records a fault. The interrupt is delivered like an MSI.
+    fn enable_fault_events(&mut self, apic_id: u8) {
+        unsafe {
+            self.write_register_u32(REG_FAULT_EVENT_DATA, IOMMU_FAULT_VECTOR as u32);
+            self.write_register_u32(REG_FAULT_EVENT_ADDRESS, 0xfee0_0000 | ((apic_id as u32) << 12));
+            /*
+             * Clearing the Interrupt Mask bit (bit 31) enables the interrupt.
+             */
+            self.write_register_u32(REG_FAULT_EVENT_CONTROL, 0);
+        }
+    }
+
+    fn map(&mut self, requester_ids: &[PciAddress], range: Range<PhysicalAddress>, writable: bool) {
+        let page_table = match self.domain_for(requester_ids) {
+            Some(page_table) => page_table,
+            None => {
+                warn!(
+                    "Run out of VT-d domain IDs. PCI device {} won't be able to access any memory.",
+                    requester_ids[0]
+                );
+                return;
+            }
+        };
+        let mut address = range.start;
+
+        while address < range.end {
+            let entry = self.walk(page_table, address, true).unwrap();
+            let mut value = 0u64;
+            value.set_bit(ENTRY_READ, true);
+            value.set_bit(ENTRY_WRITE, writable);
+            value.set_bits(ENTRY_ADDRESS, usize::from(address) as u64 >> 12);
+            self.write_entry(entry, value);
+
+            address = address + Size4KiB::SIZE;
+        }
+
+        if self.caching_mode {
+            self.invalidate_all();
+        } else {
+            self.flush_write_buffer();
+        }
+    }
+
+    fn unmap(&mut self, requester_ids: &[PciAddress], range: Range<PhysicalAddress>) {
+        let page_table = match self.domains.get(requester_ids.last().unwrap()) {
+            Some(&(_, page_table)) => page_table,
+            None => return,
+        };
+        let mut address = range.start;
+
+        while address < range.end {
+            if let Some(entry) = self.walk(page_table, address, false) {
+                self.write_entry(entry, 0);
+            }
+            address = address + Size4KiB::SIZE;
+        }
+
+        self.invalidate_all();
+    }
+
+    /// Get the root of the page tables of the domain of the device with `requester_ids` (see `requester_ids`),
+    /// creating the domain if it doesn't exist yet, and pointing the context entry of each of the IDs at it.
+    /// Returns `None` if we need a new domain, but have run out of domain IDs.
+    fn domain_for(&mut self, requester_ids: &[PciAddress]) -> Option<PhysicalAddress> {
+        let key = *requester_ids.last().unwrap();
+        let (id, page_table) = match self.domains.get(&key) {
+            Some(&domain) => domain,
+            None => {
+                /*
+                 * Domain 0 is reserved in Caching Mode, so we never use it.
+                 */
+                let id = self.domains.len() + 1;
+                if id >= self.num_domains {
+                    return None;
+                }
+                let domain = (id as u16, self.alloc_table());
+                self.domains.insert(key, domain);
+                domain
+            }
+        };
+
+        let mut changed = false;
+        for &requester_id in requester_ids {
+            changed |= self.set_context_entry(requester_id, id, page_table);
+        }
+        if changed {
+            self.invalidate_all();
+        }
+        Some(page_table)
+    }
+
+    /// Point the context entry for `requester_id` at the domain with ID `id` and page tables `page_table`, if it
+    /// isn't set up already. Returns `true` if the entry has been changed.
+    fn set_context_entry(&mut self, requester_id: PciAddress, id: u16, page_table: PhysicalAddress) -> bool {
+        /*
+         * The root table has an entry for each bus, which points to a context table with an entry for each device
+         * and function on the bus. Each entry is 128 bits.
+         */
+        let root_entry = self.table_entry(self.root_table, requester_id.bus() as usize * 2);
+        let context_table = match unsafe { ptr::read_volatile(root_entry) } {
+            entry if entry.get_bit(0) => PhysicalAddress::new(entry as usize & !0xfff).unwrap(),
+            _ => {
+                let context_table = self.alloc_table();
+                self.write_entry(root_entry, usize::from(context_table) as u64 | 0b1);
+                context_table
+            }
+        };
+
+        let context_index = ((requester_id.device() as usize) << 3) | requester_id.function() as usize;
+        let context_entry = self.table_entry(context_table, context_index * 2);
+        if unsafe { ptr::read_volatile(context_entry) }.get_bit(0) {
+            return false;
+        }
+
+        let mut high = 0u64;
+        high.set_bits(0..3, self.address_width);
+        high.set_bits(8..24, id as u64);
+        /*
+         * The low half has the Present bit, a Translation Type of `0` (untranslated requests are translated using
+         * the second-level tables), and the address of the second-level tables.
+         */
+        self.write_entry(unsafe { context_entry.add(1) }, high);
+        self.write_entry(context_entry, usize::from(page_table) as u64 | 0b1);
+        true
+    }
+
+    /// Walk the second-level page tables starting at `page_table`, and return a pointer to the entry that maps
+    /// `address`. If `create` is set, missing tables are created, and otherwise `None` is returned if they don't
+    /// exist.
+    fn walk(&self, page_table: PhysicalAddress, address: PhysicalAddress, create: bool) -> Option<*mut u64> {
+        let mut table = page_table;
+
+        for level in (1..=self.levels).rev() {
+            let index = (usize::from(address) >> (12 + 9 * (level - 1))) & (ENTRIES_PER_TABLE - 1);
+            let entry = self.table_entry(table, index);
+            if level == 1 {
+                return Some(entry);
+            }
+
+            let value = unsafe { ptr::read_volatile(entry) };
+            table = if value.get_bit(ENTRY_READ) {
+                PhysicalAddress::new((value.get_bits(ENTRY_ADDRESS) << 12) as usize).unwrap()
+            } else if create {
+                /*
+                 * Permissions are checked at each level, so tables are always readable and writable, and the
+                 * last-level entries control what the device can actually do.
+                 */
+                let next = self.alloc_table();
+                let mut value = 0u64;
+                value.set_bit(ENTRY_READ, true);
+                value.set_bit(ENTRY_WRITE, true);
+                value.set_bits(ENTRY_ADDRESS, usize::from(next) as u64 >> 12);
+                self.write_entry(entry, value);
+                next
+            } else {
+                return None;
+            };
+        }
+
+        unreachable!()
+    }
+
+    /// Allocate a zeroed frame for a table. Tables are never freed.
+    // TODO: free a domain's tables when the last handle to its device is dropped
+    fn alloc_table(&self) -> PhysicalAddress {
+        let frame: hal::memory::Frame<Size4KiB> = kernel::PHYSICAL_MEMORY_MANAGER.get().allocate();
+        unsafe {
+            ptr::write_bytes(kernel_map::physical_to_virtual(frame.start).mut_ptr::<u8>(), 0, Size4KiB::SIZE);
+        }
+        if !self.coherent {
+            flush_cache(kernel_map::physical_to_virtual(frame.start), Size4KiB::SIZE);
+        }
+        frame.start
+    }
+
+    fn table_entry(&self, table: PhysicalAddress, index: usize) -> *mut u64 {
+        unsafe { kernel_map::physical_to_virtual(table).mut_ptr::<u64>().add(index) }
+    }
+
+    fn write_entry(&self, entry: *mut u64, value: u64) {
+        unsafe {
+            ptr::write_volatile(entry, value);
+        }
+        if !self.coherent {
+            flush_cache(VirtualAddress::new(entry as usize), 8);
+        }
+    }
+
+    /// Invalidate the unit's context cache and IOTLB, so it picks up any changes we've made to the tables.
+    fn invalidate_all(&mut self) {
+        self.flush_write_buffer();
+
+        unsafe {
+            /*
+             * Issue a global invalidation of the context cache, and wait for it to complete.
+             */
+            let mut command = 0u64;
+            command.set_bit(63, true);
+            command.set_bits(61..63, 0b01);
+            self.write_register_u64(REG_CONTEXT_COMMAND, command);
+            while self.read_register_u64(REG_CONTEXT_COMMAND).get_bit(63) {}
+
+            /*
+             * Then do the same for the IOTLB, draining any reads and writes that are in flight.
+             */
+            let mut command = 0u64;
+            command.set_bit(63, true);
+            command.set_bits(60..62, 0b01);
+            command.set_bit(49, true);
+            command.set_bit(48, true);
+            self.write_register_u64(self.iotlb_register, command);
+            while self.read_register_u64(self.iotlb_register).get_bit(63) {}
+        }
+    }
+
+    /// Some units buffer writes to the tables, and need to be told to flush them before they'll see our changes.
+    fn flush_write_buffer(&mut self) {
+        if self.needs_write_buffer_flush {
+            unsafe {
+                self.global_command(GLOBAL_WRITE_BUFFER_FLUSH, false);
+            }
+        }
+    }
+
+    /// Issue a command through the Global Command register, and wait for the matching bit of the Global Status
+    /// register to be set (if `wait_for_set` is set), or cleared, which shows the command has been done.
+    unsafe fn global_command(&self, bit: usize, wait_for_set: bool) {
+        let status = ptr::read_volatile((self.registers + REG_GLOBAL_STATUS).ptr::<u32>());
+        let mut command = status & GLOBAL_STATUS_ONE_SHOT_MASK;
+        command.set_bit(bit, true);
+        ptr::write_volatile((self.registers + REG_GLOBAL_COMMAND).mut_ptr::<u32>(), command);
+
+        while ptr::read_volatile((self.registers + REG_GLOBAL_STATUS).ptr::<u32>()).get_bit(bit) != wait_for_set {}
+    }
+
+    unsafe fn read_register_u64(&self, offset: usize) -> u64 {
+        ptr::read_volatile((self.registers + offset).ptr::<u64>())
+    }
+
+    unsafe fn write_register_u64(&self, offset: usize, value: u64) {
+        ptr::write_volatile((self.registers + offset).mut_ptr::<u64>(), value)
+    }
+
+    unsafe fn write_register_u32(&self, offset: usize, value: u32) {
+        ptr::write_volatile((self.registers + offset).mut_ptr::<u32>(), value)
+    }
+}
+
+/// The parts of a remapping unit needed to read the faults it has recorded.
+struct FaultRecorder {
+    registers: VirtualAddress,
+    segment: u16,
+    fault_recording_offset: usize,
+    num_fault_records: usize,
+}
+
+impl FaultRecorder {
+    fn report_faults(&self) {
+        const FAULT_STATUS_OVERFLOW: usize = 0;
+        const FAULT_STATUS_INDEX: Range<usize> = 8..16;
+
+        unsafe {
+            let status = ptr::read_volatile((self.registers + REG_FAULT_STATUS).ptr::<u32>());
+            if status.get_bit(FAULT_STATUS_OVERFLOW) {
+                warn!("VT-d remapping unit at {:#x} ran out of space to record faults", self.registers);
+            }
+
+            /*
+             * The fault recording registers are used as a ring, and the unit tells us which one holds the first
+             * fault we haven't cleared yet. Each one is 128 bits, and the Fault bit (bit 127) is set if it holds
+             * a fault. We clear a fault by writing 1 to its Fault bit.
+             */
+            let mut index = status.get_bits(FAULT_STATUS_INDEX) as usize % self.num_fault_records;
+            for _ in 0..self.num_fault_records {
+                let record = self.registers + self.fault_recording_offset + index * 16;
+                let high = ptr::read_volatile((record + 8).ptr::<u64>());
+                if !high.get_bit(63) {
+                    break;
+                }
+                let low = ptr::read_volatile(record.ptr::<u64>());
+
+                let source = high.get_bits(0..16) as u16;
+                let device = PciAddress::new(
+                    self.segment,
+                    source.get_bits(8..16) as u8,
+                    source.get_bits(3..8) as u8,
+                    source.get_bits(0..3) as u8,
+                );
+                warn!(
+                    "DMA remapping fault: PCI device {} tried to {} {:#x} (fault reason = {:#x})",
+                    device,
+                    if high.get_bit(62) { "read from" } else { "write to" },
+                    low & !0xfff,
+                    high.get_bits(32..40)
+                );
+
+                ptr::write_volatile((record + 8).mut_ptr::<u64>(), 1 << 63);
+                index = (index + 1) % self.num_fault_records;
+            }
+
+            /*
+             * Clear the overflow bit, so the unit can record faults again.
+             */
+            ptr::write_volatile((self.registers + REG_FAULT_STATUS).mut_ptr::<u32>(), 1 << FAULT_STATUS_OVERFLOW);
+        }
+    }
+}
+
+fn flush_cache(start: VirtualAddress, length: usize) {
+    const CACHE_LINE_SIZE: usize = 64;
+
+    let mut line = usize::from(start) & !(CACHE_LINE_SIZE - 1);
+    while line < usize::from(start) + length {
+        unsafe {
+            asm!("clflush [{}]", in(reg) line);
+        }
+        line += CACHE_LINE_SIZE;
+    }
+}
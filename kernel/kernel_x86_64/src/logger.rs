@@ -1,8 +1,9 @@
-use hal_x86_64::hw::serial::SerialPort;
+use crate::{per_cpu, PlatformImpl};
+use hal_x86_64::hw::{cpu, registers::read_tsc, serial::SerialPort};
 use log::{Log, Metadata, Record};
 
 /// This handles calls to the log macros throughout the kernel, and writes logging to the COM1
-/// serial port.
+/// serial port. Each record is also added to the kernel log (see `kernel::kernel_log`), so userspace can read it.
 pub struct KernelLogger;
 
 impl Log for KernelLogger {
@@ -18,6 +19,14 @@ impl Log for KernelLogger {
             serial_port
                 .write_fmt(format_args!("[{}][{}] {}\n", record.level(), record.target(), record.args()))
                 .unwrap();
+
+            kernel::kernel_log::add_entry::<PlatformImpl>(
+                read_tsc(),
+                cpu::current_apic_id(),
+                record.level(),
+                per_cpu::running_task_name(),
+                record.args(),
+            );
         }
     }
 
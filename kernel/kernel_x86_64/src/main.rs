@@ -5,35 +5,54 @@
 extern crate alloc;
 extern crate rlibc;
 
+mod acpi_bytes;
+mod acpi_devices;
 mod acpi_handler;
+mod backtrace;
+mod clock;
 mod interrupts;
+mod io_ports;
+mod iommu;
 mod logger;
+mod mmio;
+mod panic_screen;
 mod pci;
 mod per_cpu;
+mod power;
 mod task;
 mod topo;
+mod user_access;
 
-use acpi::{AcpiTables, PciConfigRegions};
+use acpi::{AcpiTables, AmlTable, PciConfigRegions};
 use acpi_handler::{AmlHandler, PebbleAcpiHandler};
 use alloc::{boxed::Box, sync::Arc};
-use aml::AmlContext;
+use aml::{AmlContext, AmlError};
 use core::{panic::PanicInfo, pin::Pin, time::Duration};
 use hal::{
     boot_info::BootInfo,
-    memory::{Frame, PhysicalAddress, VirtualAddress},
+    memory::{Flags, Frame, PageTable, PhysicalAddress, VirtualAddress},
+};
+use hal_x86_64::{
+    hw::registers::{read_control_reg, CpuFlags},
+    kernel_map,
+    paging::PageTableImpl,
 };
-use hal_x86_64::{hw::registers::read_control_reg, kernel_map, paging::PageTableImpl};
 use interrupts::InterruptController;
 use kernel::{
-    memory::{KernelStackAllocator, PhysicalMemoryManager, Stack},
+    memory::{KernelStackAllocator, PhysicalMemoryManager, Stack, Zone},
     object::{memory_object::MemoryObject, KernelObjectId},
     Platform,
 };
 use log::{error, info};
 use pci::PciResolver;
+use pebble_util::InitGuard;
 use spin::Mutex;
 use topo::Topology;
 
+/// The AML namespace, which is kept around after boot so we can evaluate the AML objects that control things like
+/// sleep states.
+pub static AML_CONTEXT: InitGuard<Mutex<AmlContext>> = InitGuard::uninit();
+
 pub struct PlatformImpl {
     kernel_page_table: PageTableImpl,
     topology: Topology,
@@ -44,6 +63,8 @@ impl Platform for PlatformImpl {
     type PageTable = PageTableImpl;
     type PerCpu = per_cpu::PerCpuImpl;
 
+    const USER_ADDRESS_SPACE_TOP: VirtualAddress = kernel_map::USER_ADDRESS_SPACE_TOP;
+
     fn kernel_page_table(&mut self) -> &mut Self::PageTable {
         &mut self.kernel_page_table
     }
@@ -52,6 +73,18 @@ impl Platform for PlatformImpl {
         unsafe { per_cpu::get_per_cpu_data() }
     }
 
+    fn current_cpu_id() -> usize {
+        per_cpu::current_cpu_id()
+    }
+
+    fn tlb_shootdown(cpus: u64, start: VirtualAddress, size: usize) {
+        interrupts::tlb_shootdown::shoot_down(cpus, start, size)
+    }
+
+    fn kernel_tlb_shootdown(start: VirtualAddress, size: usize) {
+        interrupts::tlb_shootdown::shoot_down_kernel(start, size)
+    }
+
     unsafe fn initialize_task_stacks(
         kernel_stack: &Stack,
         user_stack: &Stack,
@@ -60,6 +93,10 @@ impl Platform for PlatformImpl {
         task::initialize_stacks(kernel_stack, user_stack, task_entry_point)
     }
 
+    unsafe fn initialize_idle_stack(stack: &Stack) -> VirtualAddress {
+        task::initialize_idle_stack(stack)
+    }
+
     unsafe fn context_switch(current_kernel_stack: *mut VirtualAddress, new_kernel_stack: VirtualAddress) {
         task::context_switch(current_kernel_stack, new_kernel_stack)
     }
@@ -67,6 +104,47 @@ impl Platform for PlatformImpl {
     unsafe fn drop_into_userspace() -> ! {
         task::drop_into_userspace()
     }
+
+    fn read_timestamp() -> u64 {
+        hal_x86_64::hw::registers::read_tsc()
+    }
+
+    fn set_preemption_timer(duration: Option<Duration>) {
+        interrupts::set_timer(duration)
+    }
+
+    fn without_interrupts<F, R>(f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let were_enabled = CpuFlags::read().interrupts_enabled();
+        unsafe {
+            asm!("cli");
+        }
+        let result = f();
+        if were_enabled {
+            unsafe {
+                asm!("sti");
+            }
+        }
+        result
+    }
+
+    fn power_off() {
+        power::shutdown()
+    }
+
+    fn reboot() -> ! {
+        power::reboot()
+    }
+
+    unsafe fn copy_from_user(dst: *mut u8, src: *const u8, length: usize) -> Result<(), ()> {
+        user_access::copy_from_user(dst, src, length)
+    }
+
+    unsafe fn copy_to_user(dst: *mut u8, src: *const u8, length: usize) -> Result<(), ()> {
+        user_access::copy_to_user(dst, src, length)
+    }
 }
 
 #[no_mangle]
@@ -79,6 +157,26 @@ pub extern "C" fn kentry(boot_info: &BootInfo) -> ! {
         panic!("Boot info magic is not correct!");
     }
 
+    /*
+     * The loader randomizes where the physical mapping is placed, so we need to know where it is before we try to
+     * access any physical memory.
+     */
+    unsafe {
+        kernel_map::set_physical_mapping_base(boot_info.physical_mapping_base);
+    }
+    info!(
+        "Kernel slid by {:#x}, physical mapping is at {:#x}",
+        boot_info.kernel_slide, boot_info.physical_mapping_base
+    );
+
+    /*
+     * Install the kernel's symbols as early as we can, so backtraces can be symbolized. The loader maps the symbol
+     * table into the kernel's address space alongside the kernel image, so it will never be unmapped.
+     */
+    if let Some(ref kernel_symbols) = boot_info.kernel_symbols {
+        kernel::symbols::KERNEL_SYMBOLS.initialize(unsafe { kernel::symbols::SymbolTable::new(kernel_symbols) });
+    }
+
     use gfxconsole::{Bgr32, Format, Framebuffer, Pixel};
     assert_eq!(boot_info.video_mode.as_ref().unwrap().pixel_format, hal::boot_info::PixelFormat::Bgr32);
     let framebuffer = Framebuffer {
@@ -93,6 +191,10 @@ pub extern "C" fn kentry(boot_info: &BootInfo) -> ! {
     framebuffer.clear(Bgr32::pixel(0xff, 0, 0, 0xff));
     framebuffer.draw_string("Hello from the kernel", 100, 100, Bgr32::pixel(0, 0, 0xff, 0xff));
 
+    if let Some(ref video_mode) = boot_info.video_mode {
+        panic_screen::install(video_mode);
+    }
+
     /*
      * Initialise the heap allocator. After this, the kernel is free to use collections etc. that
      * can allocate on the heap through the global allocator.
@@ -103,6 +205,11 @@ pub extern "C" fn kentry(boot_info: &BootInfo) -> ! {
 
     kernel::PHYSICAL_MEMORY_MANAGER.initialize(PhysicalMemoryManager::new(boot_info));
 
+    /*
+     * Now we can allocate physical memory, the heap can grow into the space the loader reserved for it.
+     */
+    kernel::ALLOCATOR.lock().enable_growth(boot_info.heap_max_size, map_kernel_heap);
+
     /*
      * Create our version of the kernel page table. This assumes that the loader has correctly installed a
      * set of page tables, including a full physical mapping at the correct location. Strange things will happen
@@ -111,7 +218,7 @@ pub extern "C" fn kentry(boot_info: &BootInfo) -> ! {
     let kernel_page_table = unsafe {
         PageTableImpl::from_frame(
             Frame::starts_with(PhysicalAddress::new(read_control_reg!(cr3) as usize).unwrap()),
-            kernel_map::PHYSICAL_MAPPING_BASE,
+            kernel_map::physical_mapping_base(),
         )
     };
 
@@ -146,23 +253,31 @@ pub extern "C" fn kentry(boot_info: &BootInfo) -> ! {
      * boot processor.
      */
     let topology = topo::build_topology(&acpi_platform_info);
+
+    /*
+     * Now the boot processor's per-CPU data is installed, we can tell which CPU we're running on, so the slab
+     * caches can be used.
+     * XXX: application processors must install their per-CPU data before they allocate anything.
+     */
+    kernel::memory::slab_cache::init::<PlatformImpl>(per_cpu::current_cpu_id, alloc_slab);
     let pci_access = pci::EcamAccess::new(PciConfigRegions::new(&acpi_tables).unwrap());
+    let pci_segment_groups = pci::segment_groups(&acpi_tables);
 
     /*
-     * Parse the DSDT.
+     * Parse the DSDT, and then the SSDTs, which add to the namespace the DSDT defines.
      */
     // TODO: if we're on ACPI 1.0 - pass true as legacy mode.
     let mut aml_context =
         AmlContext::new(Box::new(AmlHandler::new(pci_access.clone())), aml::DebugVerbosity::None);
     if let Some(ref dsdt) = acpi_tables.dsdt {
-        let virtual_address = kernel_map::physical_to_virtual(PhysicalAddress::new(dsdt.address).unwrap());
-        info!(
-            "DSDT parse: {:?}",
-            aml_context
-                .parse_table(unsafe { core::slice::from_raw_parts(virtual_address.ptr(), dsdt.length as usize) })
-        );
+        let dsdt_result = parse_aml_table(&mut aml_context, dsdt);
+        info!("DSDT parse: {:?}", dsdt_result);
 
-        // TODO: we should parse the SSDTs here. Only bother if we've managed to parse the DSDT.
+        if dsdt_result.is_ok() {
+            for ssdt in acpi_tables.ssdts.iter() {
+                info!("SSDT parse: {:?}", parse_aml_table(&mut aml_context, ssdt));
+            }
+        }
 
         // info!("----- Printing AML namespace -----");
         // info!("{:#?}", aml_context.namespace);
@@ -170,31 +285,73 @@ pub extern "C" fn kentry(boot_info: &BootInfo) -> ! {
     }
 
     /*
-     * Resolve all the PCI info.
-     * XXX: not sure this is the right place to do this just yet.
+     * Initialize devices defined in AML.
+     * TODO: We should probably call `_REG` on all the op-regions we allow access to at this point before this.
      */
-    // TODO: this whole situation is a bit gross and needs more thought I think
-    *kernel::PCI_INFO.write() = Some(PciResolver::resolve(pci_access.clone()));
-    kernel::PCI_ACCESS.initialize(Some(Mutex::new(Box::new(pci_access))));
+    aml_context.initialize_objects().expect("Failed to initialize AML objects");
 
+    /*
+     * Find out how to turn the machine off and reset it, while we still have the ACPI tables.
+     */
+    power::init(&acpi_tables, &aml_context);
 
     /*
-     * Initialize devices defined in AML.
-     * TODO: We should probably call `_REG` on all the op-regions we allow access to at this point before this.
+     * Find the memory-mapped registers the kernel uses, so they aren't handed out to userspace as part of any
+     * device's resources.
      */
-    aml_context.initialize_objects().expect("Failed to initialize AML objects");
+    mmio::init(&acpi_tables, &acpi_platform_info.interrupt_model);
 
     /*
-     * Initialise the interrupt controller, which enables interrupts, and start the per-cpu timer.
+     * Initialise the interrupt controller, which enables interrupts, and set up the per-cpu timer. We need to know
+     * how fast the TSC and local APIC run to program the timer, so we measure them first if we need to.
      */
     let mut interrupt_controller =
         InterruptController::init(&acpi_platform_info.interrupt_model, &mut aml_context);
-    interrupt_controller.enable_local_timer(&topology.cpu_info, Duration::from_millis(10));
+    clock::init(&topology.cpu_info, &acpi_tables, interrupt_controller.local_apic());
+    interrupt_controller.enable_local_timer(&topology.cpu_info);
+
+    /*
+     * Find the devices described by the AML namespace, so userspace can find drivers for them. This has to happen
+     * after `_PIC` has been called by the interrupt controller, as that can change the resources they report.
+     */
+    let acpi_info = acpi_devices::resolve(&mut aml_context);
+
+    /*
+     * Resolve all the PCI info. This also needs `_PIC` to have been called, so we can work out how the devices'
+     * interrupt pins are routed to the IOAPICs.
+     * XXX: not sure this is the right place to do this just yet.
+     */
+    // TODO: this whole situation is a bit gross and needs more thought I think
+    *kernel::PCI_INFO.write() =
+        Some(PciResolver::resolve(pci_access.clone(), &pci_segment_groups, &acpi_info, &mut aml_context));
+
+    /*
+     * Set up the IOMMU, if the platform has one. From now on, devices can only access memory that has been
+     * attached to them. This needs the bridges to have been enumerated, so we can find the devices the DMAR
+     * describes.
+     */
+    let iommu = iommu::init(&acpi_tables, &pci_access, &interrupt_controller);
+    kernel::IOMMU.initialize(iommu.map(|iommu| Mutex::new(Box::new(iommu) as Box<dyn kernel::pci::Iommu>)));
+    kernel::PCI_ACCESS.initialize(Some(Mutex::new(Box::new(pci_access))));
+
+    /*
+     * We keep the AML context around, as we need it to do things like put the machine to sleep.
+     */
+    *kernel::ACPI_INFO.write() = Some(acpi_info);
+    AML_CONTEXT.initialize(Mutex::new(aml_context));
 
     task::install_syscall_handler();
 
     let mut platform = PlatformImpl { kernel_page_table, topology };
 
+    /*
+     * Create the boot processor's idle context, which the scheduler switches to when there's nothing to run.
+     */
+    let idle_stack = kernel_stack_allocator
+        .alloc_kernel_stack(0x4000, kernel::PHYSICAL_MEMORY_MANAGER.get(), platform.kernel_page_table())
+        .expect("Failed to allocate idle stack");
+    PlatformImpl::per_cpu().scheduler().create_idle_context(idle_stack);
+
     /*
      * Create kernel objects from loaded images and schedule them.
      */
@@ -212,6 +369,14 @@ pub extern "C" fn kentry(boot_info: &BootInfo) -> ! {
         kernel::create_framebuffer(video_info);
     }
 
+    /*
+     * We've now copied everything we need out of the boot info and the ACPI tables, so we can reclaim the memory
+     * they occupy. `boot_info` and `acpi_tables` must not be used after this.
+     */
+    unsafe {
+        kernel::PHYSICAL_MEMORY_MANAGER.get().reclaim_boot_memory();
+    }
+
     /*
      * Drop into userspace!
      */
@@ -219,10 +384,78 @@ pub extern "C" fn kentry(boot_info: &BootInfo) -> ! {
     PlatformImpl::per_cpu().scheduler().drop_to_userspace()
 }
 
+fn parse_aml_table(aml_context: &mut AmlContext, table: &AmlTable) -> Result<(), AmlError> {
+    let virtual_address = kernel_map::physical_to_virtual(PhysicalAddress::new(table.address).unwrap());
+    aml_context.parse_table(unsafe { core::slice::from_raw_parts(virtual_address.ptr(), table.length as usize) })
+}
+
+/// Map more memory for the kernel heap to grow into. This maps the memory into the kernel page table that's
+/// currently installed, which is fine because every address space shares the kernel's P3 (see `kernel_map`).
+fn map_kernel_heap(address: VirtualAddress, size: usize) -> Result<(), ()> {
+    /*
+     * XXX: we can't borrow the platform's `kernel_page_table` from here, so we create another view of the kernel
+     * page table. This means the two can race, which we'll need to fix with a lock once we're multi-core.
+     *
+     * The bottom 12 bits of `CR3` hold the PCID of the current address space, so we mask them off.
+     */
+    let mut page_table = unsafe {
+        PageTableImpl::from_frame(
+            Frame::starts_with(PhysicalAddress::new(read_control_reg!(cr3) as usize & !0xfff).unwrap()),
+            kernel_map::physical_mapping_base(),
+        )
+    };
+    let physical_memory_manager = kernel::PHYSICAL_MEMORY_MANAGER.get();
+    let allocation = physical_memory_manager.alloc_bytes_noncontiguous(size, Zone::Normal).ok_or(())?;
+
+    let mut virtual_start = address;
+    for range in allocation.ranges() {
+        let range_size = usize::from(range.end) - usize::from(range.start);
+        if page_table
+            .map_area(
+                virtual_start,
+                range.start,
+                range_size,
+                Flags { writable: true, ..Default::default() },
+                physical_memory_manager,
+            )
+            .is_err()
+        {
+            /*
+             * Unmap what we've mapped so far (including any of the range that failed), so the memory isn't left
+             * mapped when it's freed. Nothing has accessed it yet, so there can't be any TLB entries for it.
+             */
+            page_table.unmap_area(address, usize::from(virtual_start) - usize::from(address) + range_size);
+            return Err(());
+        }
+        virtual_start += range_size;
+    }
+
+    /*
+     * The heap never shrinks, so the memory is owned by the heap for the rest of the kernel's life.
+     */
+    allocation.leak();
+    Ok(())
+}
+
+/// Allocate a slab for the kernel's slab caches. We take fresh frames from the physical memory manager, and
+/// access them through the physical mapping, so the slab doesn't need mapping.
+fn alloc_slab() -> Option<VirtualAddress> {
+    let allocation =
+        kernel::PHYSICAL_MEMORY_MANAGER.get().try_alloc_bytes(kernel::memory::slab_cache::SLAB_SIZE)?;
+
+    /*
+     * Slabs belong to their cache for the rest of the kernel's life.
+     */
+    Some(kernel_map::physical_to_virtual(allocation.leak()))
+}
+
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     error!("KERNEL PANIC: {}", info);
+    backtrace::print_backtrace();
+    kernel::syscall::trace::dump_trace_buffer();
+    panic_screen::draw(info);
 
     /*
      * If the `qemu_exit` feature is set, we use the debug port to exit.
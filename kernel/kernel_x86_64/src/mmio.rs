@@ -0,0 +1,100 @@
+//! Keeps track of the memory-mapped registers the kernel uses itself. Like the I/O ports in `io_ports`, userspace
+//! must never be able to access these, so we refuse to hand out memory resources that overlap them, even if a
+//! device's ACPI resources claim them.
+
+use crate::{
+    acpi_bytes::{read_u16, read_u64},
+    acpi_handler::PebbleAcpiHandler,
+};
+use acpi::{sdt::Signature, AcpiTables, HpetInfo, InterruptModel};
+use alloc::vec::Vec;
+use bit_field::BitField;
+use core::ops::Range;
+use hal::memory::{FrameSize, PhysicalAddress, Size4KiB};
+use hal_x86_64::kernel_map;
+use log::info;
+use pebble_util::InitGuard;
+
+static KERNEL_OWNED: InitGuard<Vec<Range<usize>>> = InitGuard::uninit();
+
+/// Find the memory-mapped registers the kernel uses: the local APIC, the IOAPICs, the HPET, the PCI configuration
+/// space (which userspace accesses through the kernel), and the registers of the IOMMU's remapping units. This
+/// must be called before the ACPI tables are reclaimed, and before any resources are checked with
+/// `is_kernel_owned`.
+pub fn init(acpi_tables: &AcpiTables<PebbleAcpiHandler>, interrupt_model: &InterruptModel) {
+    let mut owned = Vec::new();
+    let mut add = |base: usize, size: usize| owned.push(base..(base + size));
+
+    if let InterruptModel::Apic(info) = interrupt_model {
+        add(info.local_apic_address as usize, Size4KiB::SIZE);
+        for io_apic in info.io_apics.iter() {
+            add(io_apic.address as usize, Size4KiB::SIZE);
+        }
+    }
+
+    if let Ok(hpet_info) = HpetInfo::new(acpi_tables) {
+        add(hpet_info.base_address, Size4KiB::SIZE);
+    }
+
+    /*
+     * Each MCFG entry gives the base address of a segment group's ECAM region, which has 1MiB of configuration
+     * space for each bus, starting from bus 0, and the range of buses that are actually decoded.
+     */
+    if let Some(mcfg) = sdt_bytes(acpi_tables, Signature::MCFG) {
+        const ENTRIES_OFFSET: usize = 44;
+        const ENTRY_SIZE: usize = 16;
+        const BUS_SIZE: usize = 1 << 20;
+
+        for entry in mcfg.get(ENTRIES_OFFSET..).unwrap_or(&[]).chunks_exact(ENTRY_SIZE) {
+            let base = read_u64(entry, 0) as usize;
+            let (start_bus, end_bus) = (entry[10] as usize, entry[11] as usize);
+            if end_bus >= start_bus {
+                add(base + start_bus * BUS_SIZE, (end_bus + 1 - start_bus) * BUS_SIZE);
+            }
+        }
+    }
+
+    /*
+     * Each DRHD in the DMAR gives the base address of a remapping unit's registers, and the size of them as a power
+     * of two number of pages. See `iommu::init` for the layout.
+     */
+    if let Some(dmar) = sdt_bytes(acpi_tables, Signature::DMAR) {
+        const STRUCTURES_OFFSET: usize = 48;
+        const STRUCTURE_DRHD: u16 = 0;
+
+        let mut structures = dmar.get(STRUCTURES_OFFSET..).unwrap_or(&[]);
+        while structures.len() >= 4 {
+            let length = read_u16(structures, 2) as usize;
+            let structure = match structures.get(0..length) {
+                Some(structure) if length >= 4 => structure,
+                _ => break,
+            };
+
+            if read_u16(structure, 0) == STRUCTURE_DRHD && length >= 16 {
+                let num_pages = 1 << structure[5].get_bits(0..4);
+                add(read_u64(structure, 8) as usize, num_pages * Size4KiB::SIZE);
+            }
+            structures = &structures[length..];
+        }
+    }
+
+    info!("Memory-mapped registers owned by the kernel: {:x?}", owned);
+    KERNEL_OWNED.initialize(owned);
+}
+
+/// Returns `true` if any of the `size` bytes of physical memory at `address` hold registers the kernel uses.
+pub fn is_kernel_owned(address: PhysicalAddress, size: usize) -> bool {
+    let start = usize::from(address);
+    let end = start.saturating_add(size);
+    KERNEL_OWNED.get().iter().any(|owned| start < owned.end && owned.start < end)
+}
+
+fn sdt_bytes<'a>(acpi_tables: &'a AcpiTables<PebbleAcpiHandler>, signature: Signature) -> Option<&'a [u8]> {
+    let sdt = acpi_tables.sdts.get(&signature)?;
+    Some(unsafe {
+        core::slice::from_raw_parts(
+            kernel_map::physical_to_virtual(PhysicalAddress::new(sdt.physical_address).unwrap()).ptr(),
+            sdt.length as usize,
+        )
+    })
+}
@@ -0,0 +1,166 @@
+//! When the kernel panics, we take over the framebuffer (if the loader created one) and draw a screen with the
+//! details of the panic. This makes crashes diagnosable on real hardware, where there often isn't a serial port to
+//! read the log from.
+
+use crate::{backtrace, per_cpu::running_task_name};
+use core::{fmt, fmt::Write, panic::PanicInfo};
+use gfxconsole::{Bgr32, Format, Framebuffer, GfxConsole, Pixel, Rgb32};
+use hal::boot_info::{PixelFormat, VideoModeInfo};
+use hal_x86_64::{
+    hw::{
+        cpu,
+        idt::{ExceptionWithErrorStackFrame, InterruptStackFrame},
+        registers::read_control_reg,
+    },
+    kernel_map,
+};
+use kernel::symbols::Symbolized;
+use pebble_util::InitGuard;
+use spin::Mutex;
+
+static VIDEO_MODE: InitGuard<VideoModeInfo> = InitGuard::uninit();
+/// If the kernel panics because of an unrecoverable exception, the exception handler records the state of the CPU
+/// when the exception occurred here, so it can be shown on the panic screen.
+static EXCEPTION_STATE: Mutex<Option<ExceptionState>> = Mutex::new(None);
+
+/// Tell the panic screen about the framebuffer. Until this is called, panics are only logged.
+pub fn install(video_mode: &VideoModeInfo) {
+    VIDEO_MODE.initialize(*video_mode);
+}
+
+#[derive(Clone, Copy)]
+struct ExceptionState {
+    name: &'static str,
+    error_code: Option<u64>,
+    registers: [(&'static str, u64); 20],
+}
+
+macro registers($frame: expr) {
+    [
+        ("rax", $frame.rax),
+        ("rbx", $frame.rbx),
+        ("rcx", $frame.rcx),
+        ("rdx", $frame.rdx),
+        ("rsi", $frame.rsi),
+        ("rdi", $frame.rdi),
+        ("rbp", $frame.rbp),
+        ("rsp", usize::from($frame.stack_pointer) as u64),
+        ("r8", $frame.r8),
+        ("r9", $frame.r9),
+        ("r10", $frame.r10),
+        ("r11", $frame.r11),
+        ("r12", $frame.r12),
+        ("r13", $frame.r13),
+        ("r14", $frame.r14),
+        ("r15", $frame.r15),
+        ("rip", usize::from($frame.instruction_pointer) as u64),
+        ("rflags", u64::from($frame.cpu_flags)),
+        ("cs", $frame.code_segment),
+        ("ss", $frame.stack_segment),
+    ]
+}
+
+/// Record the state of the CPU when an exception that we're about to panic because of occurred.
+pub fn record_exception(name: &'static str, stack_frame: &InterruptStackFrame) {
+    *EXCEPTION_STATE.lock() = Some(ExceptionState { name, error_code: None, registers: registers!(stack_frame) });
+}
+
+/// Like `record_exception`, but for exceptions that push an error code.
+pub fn record_exception_with_error(name: &'static str, stack_frame: &ExceptionWithErrorStackFrame) {
+    *EXCEPTION_STATE.lock() = Some(ExceptionState {
+        name,
+        error_code: Some(stack_frame.error_code),
+        registers: registers!(stack_frame),
+    });
+}
+
+/// Draw the panic screen, if we have a framebuffer to draw it on.
+pub fn draw(info: &PanicInfo) {
+    let video_mode = match VIDEO_MODE.try_get() {
+        Some(video_mode) => video_mode,
+        None => return,
+    };
+
+    match video_mode.pixel_format {
+        PixelFormat::Rgb32 => draw_with_format::<Rgb32>(video_mode, info),
+        PixelFormat::Bgr32 => draw_with_format::<Bgr32>(video_mode, info),
+    }
+}
+
+fn draw_with_format<F>(video_mode: &VideoModeInfo, info: &PanicInfo)
+where
+    F: Format,
+{
+    let framebuffer = Framebuffer {
+        ptr: kernel_map::physical_to_virtual(video_mode.framebuffer_address).mut_ptr() as *mut Pixel<F>,
+        width: video_mode.width,
+        height: video_mode.height,
+        stride: video_mode.stride,
+    };
+    let mut console =
+        GfxConsole::new(framebuffer, F::pixel(0xaa, 0x00, 0x00, 0xff), F::pixel(0xff, 0xff, 0xff, 0xff));
+
+    /*
+     * There's nothing useful we can do if this fails, and we've already logged the panic.
+     */
+    let _ = write_panic_screen(&mut console, info);
+}
+
+fn write_panic_screen<W>(w: &mut W, info: &PanicInfo) -> fmt::Result
+where
+    W: Write,
+{
+    writeln!(w, "KERNEL PANIC\n")?;
+    writeln!(w, "{}\n", info)?;
+
+    writeln!(w, "CPU: APIC ID {}", cpu::current_apic_id())?;
+    match running_task_name() {
+        Some(name) => writeln!(w, "Running task: {}\n", name)?,
+        None => writeln!(w, "Running task: none\n")?,
+    }
+
+    /*
+     * We can't easily get the registers at the point of a normal panic, but if the panic was caused by an
+     * exception, we can show the state of the CPU when it occurred.
+     */
+    if let Some(state) = EXCEPTION_STATE.try_lock().and_then(|state| *state) {
+        match state.error_code {
+            Some(error_code) => writeln!(w, "Exception: {} (error code = {:#x})", state.name, error_code)?,
+            None => writeln!(w, "Exception: {}", state.name)?,
+        }
+
+        write_registers(w, &state.registers)?;
+    }
+    write_registers(
+        w,
+        &[
+            ("cr0", read_control_reg!(cr0)),
+            ("cr2", read_control_reg!(cr2)),
+            ("cr3", read_control_reg!(cr3)),
+            ("cr4", read_control_reg!(cr4)),
+        ],
+    )?;
+    writeln!(w)?;
+
+    writeln!(w, "Backtrace:")?;
+    let mut result = Ok(());
+    backtrace::walk_stack(backtrace::current_rbp(), |i, return_address| {
+        if result.is_ok() {
+            result = writeln!(w, "    {}: {}", i, Symbolized(return_address));
+        }
+    });
+    result
+}
+
+fn write_registers<W>(w: &mut W, registers: &[(&str, u64)]) -> fmt::Result
+where
+    W: Write,
+{
+    for row in registers.chunks(3) {
+        for (name, value) in row {
+            write!(w, "{:>6}: {:#018x}    ", name, value)?;
+        }
+        writeln!(w)?;
+    }
+    Ok(())
+}
@@ -1,10 +1,28 @@
-use acpi::PciConfigRegions;
-use alloc::collections::BTreeMap;
+use crate::{acpi_bytes::read_u16, acpi_handler::PebbleAcpiHandler, io_ports};
+use acpi::{sdt::Signature, AcpiTables, PciConfigRegions};
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    vec::Vec,
+};
+use aml::{
+    pci_routing::{PciRoutingTable, Pin},
+    resource::{InterruptPolarity, InterruptTrigger},
+    value::Args as AmlArgs,
+    AmlContext,
+    AmlError,
+    AmlName,
+    AmlValue,
+};
+use bit_field::BitField;
 use core::ptr;
 use hal::memory::PhysicalAddress;
 use hal_x86_64::kernel_map;
-use kernel::pci::{PciDevice, PciInfo};
-use log::info;
+use kernel::{
+    acpi::{is_pci_root_bridge, AcpiInfo},
+    object::{pci_device, SENTINEL_KERNEL_ID},
+    pci::{PciBar, PciDevice, PciInfo, PciInterrupt, BASE_CLASS_BRIDGE},
+};
+use log::{info, warn};
 use pci_types::{Bar, ConfigRegionAccess, EndpointHeader, PciAddress, PciHeader};
 
 #[derive(Clone)]
@@ -44,46 +62,122 @@ impl ConfigRegionAccess for EcamAccess {
     }
 }
 
-pub struct PciResolver<A>
+/// A range of buses in a PCI segment group, whose configuration space can be accessed through ECAM.
+#[derive(Clone, Copy, Debug)]
+pub struct SegmentGroup {
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+/// Find the segment groups described by the MCFG. This must be called before the ACPI tables are reclaimed.
+pub fn segment_groups(acpi_tables: &AcpiTables<PebbleAcpiHandler>) -> Vec<SegmentGroup> {
+    /*
+     * The MCFG is made up of a standard SDT header, 8 reserved bytes, and then a list of 16-byte entries, each of
+     * which describe the ECAM region of a range of buses in a segment group.
+     */
+    const ENTRIES_OFFSET: usize = 44;
+    const ENTRY_SIZE: usize = 16;
+
+    let mcfg = match acpi_tables.sdts.get(&Signature::MCFG) {
+        Some(mcfg) => unsafe {
+            core::slice::from_raw_parts(
+                kernel_map::physical_to_virtual(PhysicalAddress::new(mcfg.physical_address).unwrap()).ptr(),
+                mcfg.length as usize,
+            )
+        },
+        None => return Vec::new(),
+    };
+
+    mcfg.get(ENTRIES_OFFSET..)
+        .unwrap_or(&[])
+        .chunks_exact(ENTRY_SIZE)
+        .map(|entry| SegmentGroup { segment: read_u16(entry, 8), start_bus: entry[10], end_bus: entry[11] })
+        .collect()
+}
+
+pub struct PciResolver<'a, A>
 where
     A: ConfigRegionAccess,
 {
     access: A,
     info: PciInfo,
+    aml_context: &'a mut AmlContext,
+    /// The root bridges described by the ACPI namespace, keyed by the segment group and bus number of the bus
+    /// they bridge to, along with their `_PRT`s, if they have them.
+    root_bridges: BTreeMap<(u16, u8), Option<PciRoutingTable>>,
+    /// The PCI-to-PCI bridge that each non-root bus is behind, keyed by segment group and bus number.
+    bridges: BTreeMap<(u16, u8), PciAddress>,
+    scanned_buses: BTreeSet<(u16, u8)>,
 }
 
-impl<A> PciResolver<A>
+impl<'a, A> PciResolver<'a, A>
 where
     A: ConfigRegionAccess,
 {
-    pub fn resolve(access: A) -> PciInfo {
-        let mut resolver = Self { access, info: PciInfo { devices: BTreeMap::new() } };
+    /// Find all the PCI devices in the given segment groups, and work out which platform interrupts their
+    /// interrupt pins are routed to. This needs `\_PIC` to have already been called, so the `_PRT`s describe the
+    /// routing to the IOAPICs.
+    pub fn resolve(
+        access: A,
+        segment_groups: &[SegmentGroup],
+        acpi_info: &AcpiInfo,
+        aml_context: &'a mut AmlContext,
+    ) -> PciInfo {
+        let root_bridges = find_root_bridges(acpi_info, aml_context);
+        let mut resolver = Self {
+            access,
+            info: PciInfo { devices: BTreeMap::new() },
+            aml_context,
+            root_bridges,
+            bridges: BTreeMap::new(),
+            scanned_buses: BTreeSet::new(),
+        };
+
+        for group in segment_groups {
+            /*
+             * If the device at the start of the segment group has multiple functions, there are multiple PCI host
+             * controllers, so we need to check all the functions.
+             */
+            let root = PciAddress::new(group.segment, group.start_bus, 0, 0);
+            if resolver.access.function_exists(root)
+                && PciHeader::new(root).has_multiple_functions(&resolver.access)
+            {
+                for bus in group.start_bus..=group.end_bus.min(group.start_bus.saturating_add(7)) {
+                    resolver.check_bus(group.segment, bus);
+                }
+            } else {
+                resolver.check_bus(group.segment, group.start_bus);
+            }
+        }
 
         /*
-         * If the device at 0:0:0:0 has multiple functions, there are multiple PCI host controllers, so we need to
-         * check all the functions.
+         * Some platforms have extra root bridges that can't be found from the start of each segment group (e.g.
+         * the ones created by QEMU's `pxb-pcie` device), so we also scan the buses of any root bridges the ACPI
+         * namespace tells us about.
          */
-        if PciHeader::new(PciAddress::new(0, 0, 0, 0)).has_multiple_functions(&resolver.access) {
-            for bus in 0..8 {
-                resolver.check_bus(bus);
-            }
-        } else {
-            resolver.check_bus(0);
+        let root_buses: Vec<(u16, u8)> = resolver.root_bridges.keys().copied().collect();
+        for (segment, bus) in root_buses {
+            resolver.check_bus(segment, bus);
         }
 
         resolver.info
     }
 
-    fn check_bus(&mut self, bus: u8) {
+    fn check_bus(&mut self, segment: u16, bus: u8) {
+        if !self.scanned_buses.insert((segment, bus)) {
+            return;
+        }
+
         for device in 0..32 {
-            self.check_device(bus, device);
+            self.check_device(segment, bus, device);
         }
     }
 
-    fn check_device(&mut self, bus: u8, device: u8) {
-        let address = PciAddress::new(0, bus, device, 0);
+    fn check_device(&mut self, segment: u16, bus: u8, device: u8) {
+        let address = PciAddress::new(segment, bus, device, 0);
         if self.access.function_exists(address) {
-            self.check_function(bus, device, 0);
+            self.check_function(segment, bus, device, 0);
 
             let header = PciHeader::new(address);
             if header.has_multiple_functions(&self.access) {
@@ -91,14 +185,14 @@ where
                  * The device is multi-function. We need to check the rest.
                  */
                 for function in 1..8 {
-                    self.check_function(bus, device, function);
+                    self.check_function(segment, bus, device, function);
                 }
             }
         }
     }
 
-    fn check_function(&mut self, bus: u8, device: u8, function: u8) {
-        let address = PciAddress::new(0, bus, device, function);
+    fn check_function(&mut self, segment: u16, bus: u8, device: u8, function: u8) {
+        let address = PciAddress::new(segment, bus, device, function);
         if self.access.function_exists(address) {
             let header = PciHeader::new(address);
             let (vendor_id, device_id) = header.id(&self.access);
@@ -109,42 +203,65 @@ where
             }
 
             info!(
-                "Found PCI device (bus={}, device={}, function={}): (vendor = {:#x}, device = {:#x})",
-                bus, device, function, vendor_id, device_id
+                "Found PCI device (segment={}, bus={}, device={}, function={}): (vendor = {:#x}, device = {:#x})",
+                segment, bus, device, function, vendor_id, device_id
             );
 
-            match header.header_type(&self.access) {
+            let bars = match header.header_type(&self.access) {
                 pci_types::HEADER_TYPE_ENDPOINT => {
                     let endpoint_header = EndpointHeader::from_header(header, &self.access).unwrap();
-                    let bars = {
-                        let mut bars = [None; 6];
-
-                        let mut skip_next = false;
-                        for i in 0..6 {
-                            if skip_next {
-                                continue;
-                            }
+                    let mut bars = [None; 6];
 
-                            let bar = endpoint_header.bar(i, &self.access);
-                            skip_next = match bar {
-                                Some(Bar::Memory64 { .. }) => true,
-                                _ => false,
-                            };
-                            bars[i as usize] = bar;
+                    let mut skip_next = false;
+                    for i in 0..6 {
+                        if skip_next {
+                            skip_next = false;
+                            continue;
                         }
 
-                        bars
-                    };
+                        bars[i as usize] = match endpoint_header.bar(i, &self.access) {
+                            Some(Bar::Memory32 { address, size, prefetchable }) => {
+                                Some(PciBar::Memory32 { address, size, prefetchable })
+                            }
+                            Some(Bar::Memory64 { address, size, prefetchable }) => {
+                                skip_next = true;
+                                Some(PciBar::Memory64 { address, size, prefetchable })
+                            }
+                            Some(Bar::Io { port }) => {
+                                let size = self.io_bar_size(address, i);
+                                if io_ports::is_kernel_owned(port as u16, size) {
+                                    warn!(
+                                        "I/O BAR {} of {} overlaps ports used by the kernel. Ignoring it.",
+                                        i, address
+                                    );
+                                    None
+                                } else {
+                                    Some(PciBar::Io { port: port as u16, size })
+                                }
+                            }
+                            None => None,
+                        };
+                    }
 
-                    self.info.devices.insert(
-                        address,
-                        PciDevice { vendor_id, device_id, revision, class, sub_class, interface, bars },
-                    );
+                    bars
                 }
 
                 pci_types::HEADER_TYPE_PCI_PCI_BRIDGE => {
-                    // TODO: call check_bus on the bridge's secondary bus number
-                    todo!()
+                    /*
+                     * We rely on the firmware to have assigned bus numbers to the bridges, and so just follow the
+                     * bridge's secondary bus number. A bridge that hasn't been configured will have a secondary
+                     * bus number of `0`.
+                     */
+                    let secondary_bus = unsafe { self.access.read(address, 0x18) }.get_bits(8..16) as u8;
+                    if secondary_bus > bus {
+                        self.bridges.insert((segment, secondary_bus), address);
+                        self.check_bus(segment, secondary_bus);
+                    } else {
+                        warn!("PCI-to-PCI bridge at {} has invalid secondary bus: {}", address, secondary_bus);
+                    }
+
+                    // TODO: decode the bridge's BARs
+                    [None; 6]
                 }
 
                 pci_types::HEADER_TYPE_CARDBUS_BRIDGE => {
@@ -153,7 +270,148 @@ where
                 }
 
                 reserved => panic!("PCI function has reserved header type: {:#x}", reserved),
+            };
+
+            let interrupt = self.route_interrupt(address);
+            let parent = self.bridges.get(&(segment, bus)).copied();
+            let object = if class == BASE_CLASS_BRIDGE {
+                None
+            } else {
+                Some(pci_device::PciDevice::new(SENTINEL_KERNEL_ID, address))
+            };
+            self.info.devices.insert(
+                address,
+                PciDevice {
+                    vendor_id,
+                    device_id,
+                    revision,
+                    class,
+                    sub_class,
+                    interface,
+                    bars,
+                    interrupt,
+                    parent,
+                    object,
+                },
+            );
+        }
+    }
+
+    /// Find the number of ports decoded by the I/O BAR in slot `slot`. `pci_types` only gives us the base port of
+    /// I/O BARs, so we size them ourselves.
+    fn io_bar_size(&self, address: PciAddress, slot: u8) -> u16 {
+        let offset = 0x10 + 4 * slot as u16;
+
+        /*
+         * We size the BAR by writing all ones to it, and reading back which bits stick - the bits below the size
+         * of the range are hardwired to zero. The function must not decode I/O accesses while we do this, so we
+         * disable I/O space in the Command register first. The upper half of that register is the Status
+         * register, where writing ones clears bits, so we write zeros to it.
+         */
+        unsafe {
+            let command = self.access.read(address, 0x04).get_bits(0..16);
+            self.access.write(address, 0x04, command & !0b1);
+
+            let original = self.access.read(address, offset);
+            self.access.write(address, offset, 0xffffffff);
+            let mask = self.access.read(address, offset);
+            self.access.write(address, offset, original);
+
+            self.access.write(address, 0x04, command);
+            (!(mask as u16 & !0b11)).wrapping_add(1)
+        }
+    }
+
+    /// Find out which platform interrupt the function's interrupt pin is connected to. Returns `None` if the
+    /// function doesn't use an interrupt pin, or if we can't work out how it's routed.
+    fn route_interrupt(&mut self, address: PciAddress) -> Option<PciInterrupt> {
+        /*
+         * The Interrupt Pin register is `0` if the function doesn't use an interrupt pin, and `1` to `4` for
+         * `INTA#` to `INTD#`.
+         */
+        let mut pin = match unsafe { self.access.read(address, 0x3c) }.get_bits(8..16) as u8 {
+            0 => return None,
+            pin @ 1..=4 => pin,
+            other => {
+                warn!("PCI function {} has invalid interrupt pin: {}", address, other);
+                return None;
             }
+        };
+
+        /*
+         * Walk up the bridges until we find a root bus, which has a `_PRT` that says how its devices' pins are
+         * routed. Each bridge connects the pins of the devices behind it to its own pins, rotated by the device
+         * number of the device (the standard "swizzle" from the PCI-to-PCI Bridge Specification).
+         */
+        // TODO: bridges can have their own `_PRT`s, which we should use instead of swizzling if they exist
+        let mut device = address;
+        while let Some(&bridge) = self.bridges.get(&(device.segment(), device.bus())) {
+            pin = ((pin - 1 + device.device()) % 4) + 1;
+            device = bridge;
         }
+
+        let routing_table = self.root_bridges.get(&(device.segment(), device.bus()))?.as_ref()?;
+        let pin = match pin {
+            1 => Pin::IntA,
+            2 => Pin::IntB,
+            3 => Pin::IntC,
+            _ => Pin::IntD,
+        };
+        match routing_table.route(device.device() as u16, device.function() as u16, pin, self.aml_context) {
+            Ok(descriptor) => Some(PciInterrupt {
+                gsi: descriptor.irq,
+                level_triggered: matches!(descriptor.trigger, InterruptTrigger::Level),
+                active_low: matches!(descriptor.polarity, InterruptPolarity::ActiveLow),
+            }),
+            Err(err) => {
+                warn!("Failed to route interrupt of PCI function {}: {:?}", address, err);
+                None
+            }
+        }
+    }
+}
+
+/// Find the PCI root bridges in the ACPI namespace, and their `_PRT`s. These describe which platform interrupts
+/// the interrupt pins of the devices on the bridge's bus are routed to, either directly or through a link device
+/// (e.g. `LNKA`), which `PciRoutingTable` follows for us.
+fn find_root_bridges(
+    acpi_info: &AcpiInfo,
+    aml_context: &mut AmlContext,
+) -> BTreeMap<(u16, u8), Option<PciRoutingTable>> {
+    let mut root_bridges = BTreeMap::new();
+
+    for device in acpi_info.devices.iter() {
+        if !is_pci_root_bridge(&device.hid, &device.cids) {
+            continue;
+        }
+
+        let path = AmlName::from_str(&device.path).unwrap();
+        /*
+         * `_SEG` and `_BBN` give the segment group and bus number of the root bridge's bus. If they're not
+         * present, they're assumed to be `0`.
+         */
+        let segment = evaluate_integer(aml_context, &path, "_SEG").unwrap_or(0) as u16;
+        let bus = evaluate_integer(aml_context, &path, "_BBN").unwrap_or(0) as u8;
+
+        let prt_path = AmlName::from_str("_PRT").unwrap().resolve(&path).unwrap();
+        let routing_table = match PciRoutingTable::from_prt_path(&prt_path, aml_context) {
+            Ok(routing_table) => Some(routing_table),
+            Err(AmlError::ValueDoesNotExist(_)) => None,
+            Err(err) => {
+                warn!("Failed to parse _PRT of PCI root bridge {}: {:?}", device.path, err);
+                None
+            }
+        };
+        root_bridges.insert((segment, bus), routing_table);
+    }
+
+    root_bridges
+}
+
+fn evaluate_integer(aml_context: &mut AmlContext, device: &AmlName, name: &str) -> Option<u64> {
+    let path = AmlName::from_str(name).unwrap().resolve(device).ok()?;
+    match aml_context.invoke_method(&path, AmlArgs::default()) {
+        Ok(AmlValue::Integer(value)) => Some(value),
+        _ => None,
     }
 }
@@ -1,13 +1,22 @@
-use alloc::boxed::Box;
-use core::{marker::PhantomPinned, mem, pin::Pin};
+use crate::topo::CpuId;
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::{
+    marker::PhantomPinned,
+    mem,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+};
 use hal::memory::VirtualAddress;
 use hal_x86_64::hw::{
     gdt::{SegmentSelector, TssSegment},
     tss::Tss,
 };
-use kernel::{per_cpu::PerCpu, scheduler::Scheduler};
+use kernel::{object::io_port_range::IoPortRange, per_cpu::PerCpu, scheduler::Scheduler};
 use pebble_util::{unsafe_pinned, unsafe_unpinned};
 
+/// A bitmap of the CPUs that have installed their per-CPU data, and so can take part in TLB shootdowns.
+static ONLINE_CPUS: AtomicU64 = AtomicU64::new(0);
+
 /// Get a mutable reference to the per-CPU data of the running CPU. This is unsafe because it is the caller's
 /// responsibility to ensure that only one mutable reference to the per-CPU data exists at any one time. It is also
 /// unsafe to call this before the per-CPU data has been installed.
@@ -17,6 +26,40 @@ pub unsafe fn get_per_cpu_data<'a>() -> Pin<&'a mut PerCpuImpl> {
     Pin::new_unchecked(&mut *(ptr as *mut PerCpuImpl))
 }
 
+/// Get the name of the task running on this CPU, if there is one. Unlike `get_per_cpu_data`, this is safe to call
+/// before the per-CPU data has been installed (it returns `None`), so it can be used from places like the logger
+/// and the panic handler.
+pub fn running_task_name() -> Option<&'static str> {
+    use hal_x86_64::hw::registers::{read_msr, IA32_GS_BASE};
+
+    /*
+     * Before the per-CPU data is installed, `GS` won't point to anything.
+     */
+    if read_msr(IA32_GS_BASE) == 0 {
+        return None;
+    }
+
+    unsafe { get_per_cpu_data() }.scheduler().get_mut().running_task.as_ref().map(|task| task.name.as_str())
+}
+
+/// Get the ID of the CPU this is run on (see `topo::Cpu`). This can only be called once the per-CPU data has been
+/// installed on the running CPU.
+pub fn current_cpu_id() -> usize {
+    let id: usize;
+    unsafe {
+        asm!("mov {}, gs:0x18", out(reg) id);
+    }
+    id
+}
+
+/// Get a bitmap of the CPUs that are online (see `ONLINE_CPUS`).
+pub fn online_cpus() -> u64 {
+    ONLINE_CPUS.load(Ordering::SeqCst)
+}
+
+/// The per-CPU data, which is pointed to by `GS`. This is `repr(C)`, so the fields we access through `gs` stay at
+/// the offsets we expect.
+#[repr(C)]
 pub struct PerCpuImpl {
     /// The first field of the per-cpu structure must be a pointer to itself. This is used to access the info by
     /// reading from `gs:0x0`. This means the structure must be pinned, as it is self-referential.
@@ -28,8 +71,13 @@ pub struct PerCpuImpl {
     current_task_kernel_rsp: VirtualAddress,
     /// This field must remain at `gs:0x10`, and so cannot be moved.
     current_task_user_rsp: VirtualAddress,
+    /// This field must remain at `gs:0x18`, as it's read by `current_cpu_id`.
+    cpu_id: usize,
 
     tss: Tss,
+    /// The I/O port ranges (as `(base, length)`) that are currently allowed in the TSS's I/O permission bitmap.
+    /// These are closed again when we switch to a task that hasn't mapped them.
+    open_io_port_ranges: Vec<(u16, u16)>,
 
     scheduler: Scheduler<crate::PlatformImpl>,
 }
@@ -38,9 +86,13 @@ impl PerCpuImpl {
     unsafe_unpinned!(current_task_kernel_rsp: VirtualAddress);
     unsafe_unpinned!(current_task_user_rsp: VirtualAddress);
     unsafe_pinned!(tss: Tss);
+    unsafe_unpinned!(open_io_port_ranges: Vec<(u16, u16)>);
     unsafe_pinned!(pub scheduler: Scheduler<crate::PlatformImpl>);
 
-    pub fn new(scheduler: Scheduler<crate::PlatformImpl>) -> (Pin<Box<PerCpuImpl>>, SegmentSelector) {
+    pub fn new(
+        cpu_id: CpuId,
+        scheduler: Scheduler<crate::PlatformImpl>,
+    ) -> (Pin<Box<PerCpuImpl>>, SegmentSelector) {
         let tss = Tss::new();
         let mut per_cpu = Box::pin(PerCpuImpl {
             _self_pointer: 0x0 as *mut PerCpuImpl,
@@ -48,7 +100,9 @@ impl PerCpuImpl {
 
             current_task_kernel_rsp: VirtualAddress::new(0x0),
             current_task_user_rsp: VirtualAddress::new(0x0),
+            cpu_id: cpu_id as usize,
             tss,
+            open_io_port_ranges: Vec::new(),
 
             scheduler,
         });
@@ -70,12 +124,19 @@ impl PerCpuImpl {
         (per_cpu, tss_selector)
     }
 
+    /// Get the kernel stack pointer of the running task. This is the stack pointer the task's kernel stack is
+    /// reset to each time it returns to userspace.
+    pub fn kernel_stack_pointer(mut self: Pin<&mut Self>) -> VirtualAddress {
+        *self.as_mut().current_task_kernel_rsp()
+    }
+
     pub fn install(self: Pin<&mut Self>) {
         use hal_x86_64::hw::registers::{write_msr, IA32_GS_BASE};
 
         unsafe {
             write_msr(IA32_GS_BASE, self.as_ref()._self_pointer as usize as u64);
         }
+        ONLINE_CPUS.fetch_or(1 << current_cpu_id(), Ordering::SeqCst);
     }
 }
 
@@ -96,6 +157,25 @@ impl PerCpu<crate::PlatformImpl> for PerCpuImpl {
     fn set_user_stack_pointer(mut self: Pin<&mut Self>, stack_pointer: VirtualAddress) {
         *self.as_mut().current_task_user_rsp() = stack_pointer;
     }
+
+    fn set_io_port_ranges(mut self: Pin<&mut Self>, ranges: &[Arc<IoPortRange>]) {
+        /*
+         * Most tasks don't use any I/O ports, so we avoid touching the bitmap at all when switching between them.
+         */
+        if self.as_mut().open_io_port_ranges().is_empty() && ranges.is_empty() {
+            return;
+        }
+
+        let old_ranges = mem::replace(self.as_mut().open_io_port_ranges(), Vec::new());
+        for (base, length) in old_ranges {
+            self.as_mut().tss().set_io_port_access(base, length, false);
+        }
+
+        for range in ranges {
+            self.as_mut().tss().set_io_port_access(range.base, range.length, true);
+            self.as_mut().open_io_port_ranges().push((range.base, range.length));
+        }
+    }
 }
 
 /*
@@ -0,0 +1,377 @@
+//! ACPI power management. We turn the machine off by putting it into the `S5` ("soft-off") sleep state, which
+//! needs the `SLP_TYP` values from the `\_S5` object in the AML namespace, and the locations of the sleep control
+//! registers from the FADT. We reboot it by writing to the reset register described by the FADT, falling back to
+//! pulsing the reset line through the keyboard controller, and then to triple-faulting.
+//!
+//! The other sleep states (`S1` to `S4`) are not supported, as we can't resume from them: that would need us to save
+//! and restore the state of the processors and devices, and to handle the firmware waking vector.
+//!
+//! The ACPI tables are reclaimed once the kernel has finished booting, so `init` copies everything we need out of
+//! them.
+
+use crate::{
+    acpi_bytes::{read_u32, read_u64},
+    acpi_handler::PebbleAcpiHandler,
+    clock,
+    AML_CONTEXT,
+};
+use acpi::{sdt::Signature, AcpiTables};
+use alloc::vec::Vec;
+use aml::{value::Args as AmlArgs, AmlContext, AmlError, AmlName, AmlValue};
+use bit_field::BitField;
+use core::{convert::TryFrom, ptr, time::Duration};
+use hal::memory::{PhysicalAddress, VirtualAddress};
+use hal_x86_64::{
+    hw::{port::Port, registers::read_tsc, DescriptorTablePointer},
+    kernel_map,
+};
+use log::{info, warn};
+use pebble_util::InitGuard;
+
+/*
+ * Offsets of the fields we use from the start of the FADT. Fields from `RESET_REGISTER` onwards were added in later
+ * revisions, so we check the FADT is long enough to contain them before reading them.
+ */
+const SMI_COMMAND: usize = 48;
+const ACPI_ENABLE: usize = 52;
+const PM1A_CONTROL_BLOCK: usize = 64;
+const PM1B_CONTROL_BLOCK: usize = 68;
+const FLAGS: usize = 112;
+const RESET_REGISTER: usize = 116;
+const RESET_VALUE: usize = 128;
+const X_PM1A_CONTROL_BLOCK: usize = 172;
+const X_PM1B_CONTROL_BLOCK: usize = 184;
+const SLEEP_CONTROL_REGISTER: usize = 244;
+
+const GENERIC_ADDRESS_SIZE: usize = 12;
+
+/*
+ * Bits of the FADT's `Flags` field.
+ */
+const RESET_REGISTER_SUPPORTED: usize = 10;
+const HARDWARE_REDUCED_ACPI: usize = 20;
+
+/// How long we give each method of turning off or resetting the machine to work before trying the next one.
+const POWER_ACTION_TIMEOUT: Duration = Duration::from_millis(500);
+
+static POWER_INFO: InitGuard<PowerInfo> = InitGuard::uninit();
+
+/// A register described by a Generic Address Structure in the FADT.
+#[derive(Clone, Copy, Debug)]
+enum Register {
+    Io(u16),
+    Memory(PhysicalAddress),
+}
+
+impl Register {
+    /// Decode the Generic Address Structure at the start of `gas`. Returns `None` if the register isn't present
+    /// (its address is zero), or if it's in an address space we don't support accessing it through.
+    fn from_generic_address(gas: &[u8]) -> Option<Register> {
+        let address = read_u64(gas, 4);
+        if address == 0 {
+            return None;
+        }
+
+        match gas[0] {
+            0 => Some(Register::Memory(PhysicalAddress::new(address as usize)?)),
+            1 => Some(Register::Io(u16::try_from(address).ok()?)),
+            space => {
+                warn!("ACPI register at {:#x} is in unsupported address space: {}", address, space);
+                None
+            }
+        }
+    }
+
+    unsafe fn read_u16(self) -> u16 {
+        match self {
+            Register::Io(port) => Port::<u16>::new(port).read(),
+            Register::Memory(address) => ptr::read_volatile(kernel_map::physical_to_virtual(address).ptr()),
+        }
+    }
+
+    unsafe fn write_u16(self, value: u16) {
+        match self {
+            Register::Io(port) => Port::<u16>::new(port).write(value),
+            Register::Memory(address) => {
+                ptr::write_volatile(kernel_map::physical_to_virtual(address).mut_ptr(), value)
+            }
+        }
+    }
+
+    unsafe fn write_u8(self, value: u8) {
+        match self {
+            Register::Io(port) => Port::<u8>::new(port).write(value),
+            Register::Memory(address) => {
+                ptr::write_volatile(kernel_map::physical_to_virtual(address).mut_ptr(), value)
+            }
+        }
+    }
+}
+
+/// The registers used to put the system into a sleep state.
+#[derive(Clone, Copy, Debug)]
+enum SleepControl {
+    /// The PM1 control registers. The `b` register is optional, and if present, is written alongside the `a`
+    /// register. To enter the `S5` state from legacy mode, we first need to switch to ACPI mode by writing
+    /// `acpi_enable` to the `smi_command` port.
+    Pm1 { a: Register, b: Option<Register>, smi_command: u16, acpi_enable: u8 },
+    /// On hardware-reduced ACPI platforms, there are no PM1 registers, and the FADT instead describes a single
+    /// sleep control register.
+    HardwareReduced(Register),
+}
+
+struct PowerInfo {
+    sleep_control: Option<SleepControl>,
+    /// The `SLP_TYPa` and `SLP_TYPb` values to write to the sleep control registers to enter the `S5` state.
+    s5_sleep_types: Option<(u8, u8)>,
+    /// The reset register, and the value to write to it to reset the system.
+    reset: Option<(Register, u8)>,
+}
+
+/// Copy what we need to turn off and reset the machine out of the FADT and the AML namespace. This must be called
+/// before the ACPI tables are reclaimed, and after the DSDT has been parsed.
+pub fn init(acpi_tables: &AcpiTables<PebbleAcpiHandler>, aml_context: &AmlContext) {
+    let fadt = match acpi_tables.sdts.get(&Signature::FADT) {
+        Some(fadt) => unsafe {
+            core::slice::from_raw_parts(
+                kernel_map::physical_to_virtual(PhysicalAddress::new(fadt.physical_address).unwrap()).ptr(),
+                fadt.length as usize,
+            )
+        },
+        None => {
+            warn!("Platform doesn't have a FADT. Shutting down and rebooting through ACPI won't work.");
+            POWER_INFO.initialize(PowerInfo { sleep_control: None, s5_sleep_types: None, reset: None });
+            return;
+        }
+    };
+    let flags = read_u32(fadt, FLAGS);
+
+    let sleep_control = if flags.get_bit(HARDWARE_REDUCED_ACPI) {
+        generic_address(fadt, SLEEP_CONTROL_REGISTER).map(SleepControl::HardwareReduced)
+    } else {
+        /*
+         * We prefer the extended (64-bit) addresses of the PM1 control blocks, but they aren't present in older
+         * FADTs, or can be zero, in which case we fall back to the legacy I/O port addresses.
+         */
+        let pm1_control_block = |extended_offset, legacy_offset| {
+            generic_address(fadt, extended_offset).or_else(|| match read_u32(fadt, legacy_offset) {
+                0 => None,
+                port => u16::try_from(port).ok().map(Register::Io),
+            })
+        };
+
+        pm1_control_block(X_PM1A_CONTROL_BLOCK, PM1A_CONTROL_BLOCK).map(|a| SleepControl::Pm1 {
+            a,
+            b: pm1_control_block(X_PM1B_CONTROL_BLOCK, PM1B_CONTROL_BLOCK),
+            smi_command: read_u32(fadt, SMI_COMMAND) as u16,
+            acpi_enable: fadt[ACPI_ENABLE],
+        })
+    };
+
+    let reset = if flags.get_bit(RESET_REGISTER_SUPPORTED) && fadt.len() > RESET_VALUE {
+        generic_address(fadt, RESET_REGISTER).map(|register| (register, fadt[RESET_VALUE]))
+    } else {
+        None
+    };
+
+    let s5_sleep_types = match aml_context.namespace.get_by_path(&AmlName::from_str("\\_S5").unwrap()) {
+        Ok(AmlValue::Package(elements)) => match (elements.get(0), elements.get(1)) {
+            (Some(AmlValue::Integer(a)), Some(AmlValue::Integer(b))) => Some((*a as u8, *b as u8)),
+            _ => {
+                warn!("\\_S5 object is malformed: {:?}", elements);
+                None
+            }
+        },
+        Ok(value) => {
+            warn!("\\_S5 object is not a package: {:?}", value);
+            None
+        }
+        Err(err) => {
+            warn!("Failed to find \\_S5 object: {:?}", err);
+            None
+        }
+    };
+
+    info!(
+        "ACPI power management: sleep control = {:?}, S5 sleep types = {:?}, reset register = {:?}",
+        sleep_control, s5_sleep_types, reset
+    );
+    POWER_INFO.initialize(PowerInfo { sleep_control, s5_sleep_types, reset });
+}
+
+/// The I/O ports used to turn off and reset the machine, as `(base, length)` pairs. These are owned by the kernel,
+/// and so must never be handed out to userspace.
+pub fn io_ports() -> Vec<(u16, u16)> {
+    let info = POWER_INFO.get();
+    let mut ports = Vec::new();
+
+    match info.sleep_control {
+        Some(SleepControl::Pm1 { a, b, smi_command, .. }) => {
+            for register in core::iter::once(a).chain(b) {
+                if let Register::Io(port) = register {
+                    ports.push((port, 2));
+                }
+            }
+            if smi_command != 0 {
+                ports.push((smi_command, 1));
+            }
+        }
+        Some(SleepControl::HardwareReduced(Register::Io(port))) => ports.push((port, 1)),
+        _ => (),
+    }
+    if let Some((Register::Io(port), _)) = info.reset {
+        ports.push((port, 1));
+    }
+
+    ports
+}
+
+/// Turn the machine off, by entering the `S5` sleep state. This only returns if that fails.
+pub fn shutdown() {
+    let info = POWER_INFO.get();
+    let (sleep_control, (sleep_type_a, sleep_type_b)) = match (info.sleep_control, info.s5_sleep_types) {
+        (Some(sleep_control), Some(sleep_types)) => (sleep_control, sleep_types),
+        _ => {
+            warn!("Can't shut down: ACPI doesn't describe how to enter the S5 state");
+            return;
+        }
+    };
+
+    /*
+     * Tell the firmware we're about to enter the sleep state, so it can do any platform-specific preparation. Most
+     * platforms don't need this, so it's fine if `\_PTS` doesn't exist.
+     */
+    if let Some(aml_context) = AML_CONTEXT.try_get() {
+        let args = AmlArgs { arg_0: Some(AmlValue::Integer(5)), ..Default::default() };
+        match aml_context.lock().invoke_method(&AmlName::from_str("\\_PTS").unwrap(), args) {
+            Ok(_) | Err(AmlError::ValueDoesNotExist(_)) => (),
+            Err(err) => warn!("Failed to evaluate \\_PTS: {:?}", err),
+        }
+    }
+
+    info!("Shutting down");
+    unsafe {
+        asm!("cli");
+    }
+
+    match sleep_control {
+        SleepControl::Pm1 { a, b, smi_command, acpi_enable } => unsafe {
+            enable_acpi_mode(a, smi_command, acpi_enable);
+
+            /*
+             * `SLP_TYP` is in bits 10..13 of the PM1 control registers. Setting `SLP_EN` (bit 13) then makes the
+             * system enter the sleep state.
+             */
+            let enter_sleep_state = |register: Register, sleep_type: u8| {
+                let mut value = register.read_u16();
+                value.set_bits(10..13, sleep_type as u16);
+                value.set_bit(13, true);
+                register.write_u16(value);
+            };
+            enter_sleep_state(a, sleep_type_a);
+            if let Some(b) = b {
+                enter_sleep_state(b, sleep_type_b);
+            }
+        },
+        SleepControl::HardwareReduced(register) => unsafe {
+            /*
+             * `SLP_TYP` is in bits 2..5 of the sleep control register, and `SLP_EN` is bit 5.
+             */
+            let mut value = 0u8;
+            value.set_bits(2..5, sleep_type_a);
+            value.set_bit(5, true);
+            register.write_u8(value);
+        },
+    }
+
+    wait(POWER_ACTION_TIMEOUT);
+    warn!("Failed to shut down: the machine didn't enter the S5 state");
+}
+
+/// Reset the machine.
+pub fn reboot() -> ! {
+    info!("Rebooting");
+    unsafe {
+        asm!("cli");
+    }
+
+    if let Some((register, value)) = POWER_INFO.try_get().and_then(|info| info.reset) {
+        unsafe {
+            register.write_u8(value);
+        }
+        wait(POWER_ACTION_TIMEOUT);
+        warn!("Resetting through the ACPI reset register failed. Trying the keyboard controller.");
+    }
+
+    /*
+     * Writing `0xfe` to the keyboard controller's command port pulses the CPU's reset line. We need to wait for
+     * the controller's input buffer to be empty (bit 1 of the status register to be clear) before sending it a
+     * command, but if it never empties (e.g. because there isn't a keyboard controller), we try anyway.
+     */
+    unsafe {
+        let mut keyboard_controller = Port::<u8>::new(0x64);
+        for _ in 0..10000 {
+            if !keyboard_controller.read().get_bit(1) {
+                break;
+            }
+        }
+        keyboard_controller.write(0xfe);
+    }
+    wait(POWER_ACTION_TIMEOUT);
+    warn!("Resetting through the keyboard controller failed. Triple-faulting.");
+
+    /*
+     * If all else fails, load an empty IDT and cause an exception. The processor can't find a handler for either
+     * it, or the double fault that causes, and so triple-faults, which resets the system.
+     */
+    unsafe {
+        let idt_pointer = DescriptorTablePointer { limit: 0, base: VirtualAddress::new(0x0) };
+        asm!("lidt [{}]
+              int3",
+            in(reg) &idt_pointer
+        );
+    }
+
+    loop {
+        unsafe {
+            asm!("hlt");
+        }
+    }
+}
+
+/// Switch the system from legacy mode into ACPI mode, if it isn't already, so that writes to the PM1 control
+/// registers take effect. `SCI_EN` (bit 0 of the PM1 control register) is set by the firmware once it has done
+/// so.
+unsafe fn enable_acpi_mode(pm1a_control: Register, smi_command: u16, acpi_enable: u8) {
+    /*
+     * If either of these are zero, the system only supports ACPI mode, and so must already be in it.
+     */
+    if smi_command == 0 || acpi_enable == 0 || pm1a_control.read_u16().get_bit(0) {
+        return;
+    }
+
+    Port::<u8>::new(smi_command).write(acpi_enable);
+
+    let start = read_tsc();
+    while !pm1a_control.read_u16().get_bit(0) {
+        if read_tsc() - start > ticks_in(POWER_ACTION_TIMEOUT) {
+            warn!("Firmware didn't switch into ACPI mode. Trying to enter sleep state anyway.");
+            return;
+        }
+    }
+}
+
+/// Busy-wait for `duration`, using the TSC.
+fn wait(duration: Duration) {
+    let start = read_tsc();
+    while read_tsc() - start < ticks_in(duration) {}
+}
+
+fn ticks_in(duration: Duration) -> u64 {
+    (duration.as_nanos() * clock::frequencies().tsc as u128 / 1_000_000_000) as u64
+}
+
+/// Read the Generic Address Structure at `offset` in the FADT, if the FADT is long enough to contain it.
+fn generic_address(fadt: &[u8], offset: usize) -> Option<Register> {
+    fadt.get(offset..(offset + GENERIC_ADDRESS_SIZE)).and_then(Register::from_generic_address)
+}
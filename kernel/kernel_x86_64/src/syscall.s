@@ -21,9 +21,10 @@
  *     r10 = c
  *     r8  = d
  *     r9  = e
+ *     rax = f
  * This is only different from the Sys-V ABI in that `c` is in `r10` and not `rcx` (because `rcx` is being
- * used by syscall). To call into the Rust function (as long as it is using the C ABI), we only need to
- * move that one parameter.
+ * used by syscall), and that `f` is in `rax`. To call into the Rust function (as long as it is using the C ABI),
+ * we need to move `c` into `rcx`, and pass `f` on the stack, as it's the seventh argument.
  */
 .global syscall_handler
 syscall_handler:
@@ -44,8 +45,13 @@ syscall_handler:
     // stack.
     mov rcx, r10
 
+    // Pass `f` on the stack. We push an extra 8 bytes first, so the stack stays 16-byte aligned for the call.
+    sub rsp, 8
+    push rax
+
     // Call the Rust handler. From this point, `rax` contains the return value, so musn't be trashed!
     call rust_syscall_entry
+    add rsp, 16
 
     // Zero registers trashed by the Rust code before we return to userspace
     xor rsi, rsi
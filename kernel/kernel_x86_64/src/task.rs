@@ -1,3 +1,4 @@
+use crate::PlatformImpl;
 use alloc::sync::Arc;
 use core::{mem, ptr};
 use hal::memory::VirtualAddress;
@@ -5,6 +6,7 @@ use hal_x86_64::hw::registers::{write_msr, CpuFlags};
 use kernel::{
     memory::Stack,
     object::{memory_object::MemoryObject, KernelObjectId},
+    Platform,
 };
 
 global_asm!(include_str!("task.s"));
@@ -32,8 +34,16 @@ extern "C" {
 /// This function is called by `syscall_handler` to enter Rust. This is just required to call the correct `Platform`
 /// monomorphization of the common syscall handler.
 #[no_mangle]
-extern "C" fn rust_syscall_entry(number: usize, a: usize, b: usize, c: usize, d: usize, e: usize) -> usize {
-    kernel::syscall::handle_syscall::<crate::PlatformImpl>(number, a, b, c, d, e)
+extern "C" fn rust_syscall_entry(
+    number: usize,
+    a: usize,
+    b: usize,
+    c: usize,
+    d: usize,
+    e: usize,
+    f: usize,
+) -> usize {
+    kernel::syscall::handle_syscall::<crate::PlatformImpl>(number, a, b, c, d, e, f)
 }
 
 /// This is the layout of the stack that we expect to be present when we switch to a task. It is
@@ -105,6 +115,50 @@ pub unsafe fn initialize_stacks(
     (kernel_stack_pointer, user_stack_pointer)
 }
 
+/// Returns the kernel stack pointer to switch to to enter the idle loop.
+pub unsafe fn initialize_idle_stack(stack: &Stack) -> VirtualAddress {
+    /*
+     * This mirrors the kernel stack of a task that hasn't been run before (see `initialize_stacks`), except the
+     * context-switch frame returns straight into the idle loop, which never returns itself.
+     */
+    let mut stack_pointer = stack.top.align_down(16);
+
+    stack_pointer -= 8;
+    ptr::write(stack_pointer.mut_ptr() as *mut u64, 0x0);
+
+    stack_pointer -= mem::size_of::<ContextSwitchFrame>();
+    ptr::write(
+        stack_pointer.mut_ptr() as *mut ContextSwitchFrame,
+        ContextSwitchFrame { return_address: idle_loop as u64, ..Default::default() },
+    );
+
+    stack_pointer
+}
+
+/// The idle context of each CPU runs this loop. It's switched to by the scheduler when there's nothing to run,
+/// and switches back out as soon as there is.
+extern "C" fn idle_loop() -> ! {
+    loop {
+        /*
+         * Tasks become ready from interrupt handlers, so we disable interrupts while we check if there's anything
+         * to run. Otherwise, a task could become ready after we've checked but before we've halted, and we
+         * wouldn't notice until the next interrupt.
+         */
+        unsafe {
+            asm!("cli");
+        }
+        PlatformImpl::per_cpu().scheduler().switch_from_idle();
+
+        /*
+         * Interrupts aren't recognised until the instruction after `sti` has executed, so no interrupt can arrive
+         * between enabling them and halting.
+         */
+        unsafe {
+            asm!("sti; hlt");
+        }
+    }
+}
+
 pub unsafe fn context_switch(current_kernel_stack: *mut VirtualAddress, new_kernel_stack: VirtualAddress) {
     do_context_switch(current_kernel_stack, new_kernel_stack);
 }
@@ -173,13 +227,16 @@ pub fn install_syscall_handler() {
      *
      * Importantly, we disable interrupts because they're not safe until we've stopped messing about with
      * stacks.
+     *
+     * We also clear `AC`, as userspace could otherwise set it to allow the kernel to access user memory directly
+     * even when SMAP is enabled.
      */
     let flags_mask = CpuFlags::STATUS_MASK
-        | CpuFlags::TRAP_FLAG
-        | CpuFlags::INTERRUPT_ENABLE_FLAG
+        | (1 << CpuFlags::TRAP_FLAG)
+        | (1 << CpuFlags::INTERRUPT_ENABLE_FLAG)
         | CpuFlags::IO_PRIVILEGE_MASK
-        | CpuFlags::NESTED_TASK_FLAG
-        | CpuFlags::ALIGNMENT_CHECK_FLAG;
+        | (1 << CpuFlags::NESTED_TASK_FLAG)
+        | (1 << CpuFlags::ALIGNMENT_CHECK_FLAG);
 
     unsafe {
         write_msr(IA32_STAR, selectors);
@@ -18,7 +18,7 @@ pub struct Cpu {
 impl Cpu {
     /// Create a new `Cpu`. This also creates a TSS for the CPU and installs it into the GDT.
     pub fn new(id: CpuId, local_apic_id: u8) -> Cpu {
-        let (per_cpu, tss_selector) = PerCpuImpl::new(Scheduler::new());
+        let (per_cpu, tss_selector) = PerCpuImpl::new(id, Scheduler::new());
         Cpu { id, local_apic_id, per_cpu, tss_selector }
     }
 }
@@ -108,7 +108,12 @@ fn check_support_and_enable_features(cpu_info: &CpuInfo) {
         read_msr,
         write_control_reg,
         write_msr,
+        CR0_WRITE_PROTECT,
         CR4_ENABLE_GLOBAL_PAGES,
+        CR4_ENABLE_PCID,
+        CR4_ENABLE_SMAP,
+        CR4_ENABLE_SMEP,
+        CR4_ENABLE_UMIP,
         CR4_RESTRICT_RDTSC,
         CR4_XSAVE_ENABLE_BIT,
         EFER,
@@ -119,15 +124,50 @@ fn check_support_and_enable_features(cpu_info: &CpuInfo) {
     if !cpu_info.supported_features.xsave {
         panic!("Processor does not support xsave instruction!");
     }
+    if !cpu_info.supported_features.smep {
+        warn!("Processor does not support SMEP. The kernel will be able to execute user memory!");
+    }
+    if !cpu_info.supported_features.smap {
+        warn!("Processor does not support SMAP. The kernel will be able to access user memory directly!");
+    }
+
+    /*
+     * Make the kernel respect read-only mappings. Without this, the kernel can write to any page that's mapped.
+     */
+    let mut cr0 = read_control_reg!(CR0);
+    cr0.set_bit(CR0_WRITE_PROTECT, true);
+    unsafe {
+        write_control_reg!(CR0, cr0);
+    }
 
     let mut cr4 = read_control_reg!(CR4);
     cr4.set_bit(CR4_XSAVE_ENABLE_BIT, true);
     cr4.set_bit(CR4_ENABLE_GLOBAL_PAGES, true);
     cr4.set_bit(CR4_RESTRICT_RDTSC, true);
+    /*
+     * PCIDs can only be enabled while the current PCID is `0`, which is the case as the kernel's page tables are
+     * installed.
+     */
+    cr4.set_bit(CR4_ENABLE_PCID, cpu_info.supported_features.pcid);
+    /*
+     * Stop the kernel from executing or accessing user memory, so a kernel bug faults instead of being able to
+     * run or read whatever userspace has put there. User memory must be accessed with the routines in
+     * `user_access`, which allow the access for just long enough to copy to or from userspace.
+     */
+    cr4.set_bit(CR4_ENABLE_SMEP, cpu_info.supported_features.smep);
+    cr4.set_bit(CR4_ENABLE_SMAP, cpu_info.supported_features.smap);
+    cr4.set_bit(CR4_ENABLE_UMIP, cpu_info.supported_features.umip);
     unsafe {
         write_control_reg!(CR4, cr4);
     }
 
+    if cpu_info.supported_features.pcid {
+        hal_x86_64::hw::tlb::enable_pcids();
+    }
+    if cpu_info.supported_features.smap {
+        crate::user_access::enable_smap();
+    }
+
     let mut efer = read_msr(EFER);
     efer.set_bit(EFER_ENABLE_SYSCALL, true);
     efer.set_bit(EFER_ENABLE_NX_BIT, true);
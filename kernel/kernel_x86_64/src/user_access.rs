@@ -0,0 +1,66 @@
+//! The kernel never accesses user memory directly. Instead, it copies data to and from userspace using the
+//! routines in this module, which can recover from faults caused by userspace pulling memory out from under us.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use hal::memory::VirtualAddress;
+
+global_asm!(include_str!("user_access.s"));
+extern "C" {
+    fn copy_user_bytes(dst: *mut u8, src: *const u8, length: usize) -> u32;
+
+    /*
+     * These aren't really functions - they're labels inside `copy_user_bytes`. We only ever take their addresses.
+     */
+    fn copy_user_bytes_access();
+    fn copy_user_bytes_fixup();
+}
+
+/// Set once SMAP has been enabled, after which the kernel can only access user memory while `RFLAGS.AC` is set.
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Tell the user access routines that SMAP has been enabled. `stac` and `clac` can't be used on processors that
+/// don't support SMAP, so this must only be called once it has been enabled.
+pub fn enable_smap() {
+    SMAP_ENABLED.store(true, Ordering::Release);
+}
+
+pub unsafe fn copy_from_user(dst: *mut u8, src: *const u8, length: usize) -> Result<(), ()> {
+    copy_with_user_access(dst, src, length)
+}
+
+pub unsafe fn copy_to_user(dst: *mut u8, src: *const u8, length: usize) -> Result<(), ()> {
+    copy_with_user_access(dst, src, length)
+}
+
+/// Copy to or from userspace. If SMAP is enabled, the kernel is only allowed to access user memory for the
+/// duration of the copy.
+unsafe fn copy_with_user_access(dst: *mut u8, src: *const u8, length: usize) -> Result<(), ()> {
+    let smap_enabled = SMAP_ENABLED.load(Ordering::Acquire);
+
+    if smap_enabled {
+        asm!("stac");
+    }
+    /*
+     * If the copy faults, the page fault handler returns to the end of `copy_user_bytes`, so we always get back
+     * here to clear `RFLAGS.AC` again.
+     */
+    let result = copy_user_bytes(dst, src, length);
+    if smap_enabled {
+        asm!("clac");
+    }
+
+    match result {
+        0 => Ok(()),
+        _ => Err(()),
+    }
+}
+
+/// If a fault occurred at `instruction_pointer` while accessing user memory, get the address execution should be
+/// resumed at so the access fails gracefully. Returns `None` if the fault didn't happen during a user access.
+pub fn fixup_address(instruction_pointer: VirtualAddress) -> Option<VirtualAddress> {
+    if usize::from(instruction_pointer) == copy_user_bytes_access as usize {
+        Some(VirtualAddress::new(copy_user_bytes_fixup as usize))
+    } else {
+        None
+    }
+}
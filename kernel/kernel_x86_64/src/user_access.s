@@ -0,0 +1,23 @@
+.intel_syntax noprefix
+.code64
+
+/*
+ * Copy `rdx` bytes from `rsi` to `rdi`, where one of the pointers is into userspace. Returns `0` in `rax` if the
+ * copy succeeded, and `1` if it faulted.
+ *
+ * The kernel checks user pointers before accessing them, but userspace can still unmap memory from another thread
+ * while we're copying. If the copy faults, the page fault handler notices that the faulting instruction is the
+ * one labelled `copy_user_bytes_access`, and returns to `copy_user_bytes_fixup` instead of panicking.
+ */
+.global copy_user_bytes
+.global copy_user_bytes_access
+.global copy_user_bytes_fixup
+copy_user_bytes:
+    mov rcx, rdx
+copy_user_bytes_access:
+    rep movsb
+    xor eax, eax
+    ret
+copy_user_bytes_fixup:
+    mov eax, 1
+    ret
@@ -0,0 +1,72 @@
+//! Devices described by the platform's ACPI namespace. The platform walks the namespace and records the devices it
+//! finds here, so they can be handed to the userspace ACPI bus driver, which publishes them to the Platform Bus.
+//! This lets userspace drivers find devices that can't be discovered by enumerating a bus, such as the RTC and the
+//! PS/2 controller.
+
+use crate::object::{io_port_range::IoPortRange, memory_object::MemoryObject, SENTINEL_KERNEL_ID};
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::sync::atomic::AtomicBool;
+use hal::memory::{Flags, FrameSize, PhysicalAddress, Size4KiB};
+use pebble_util::math::align_up;
+
+/// The Hardware IDs of PCI and PCI Express root bridges in the ACPI namespace.
+pub const PCI_ROOT_BRIDGE_IDS: [&str; 2] = ["PNP0A03", "PNP0A08"];
+
+pub struct AcpiDevice {
+    /// The absolute path of the device's object in the namespace (e.g. `\_SB_.PCI0.SF8_.KBD_`).
+    pub path: String,
+    /// The device's Hardware ID (`_HID`). EISA IDs are decoded into their string form (e.g. `PNP0303`).
+    pub hid: String,
+    /// The device's Compatible IDs (`_CID`), which identify other devices it's compatible with.
+    pub cids: Vec<String>,
+    /// The device's Unique ID (`_UID`), which distinguishes it from other devices with the same `_HID`.
+    pub uid: Option<String>,
+    /// The resources the device is currently using, from its `_CRS` object.
+    pub resources: Vec<AcpiResource>,
+    /// Set once handles to the device's resources have been handed out by `acpi_get_info`. Each resource is only
+    /// handed out once, so only the driver that's given the device can access it.
+    pub resources_claimed: AtomicBool,
+}
+
+/// A resource used by a device. The kernel objects that give userspace access to memory and I/O port resources
+/// are created along with them, and handles to them are only handed out once (see
+/// `AcpiDevice::resources_claimed`). Like PCI BARs, the kernel owns these objects, as the resources belong to the
+/// device, not the task that asks for them.
+pub enum AcpiResource {
+    /// A range of memory-mapped registers. Memory objects have to start and end on page boundaries, but registers
+    /// often don't, so the registers start `offset` bytes into `memory_object`.
+    Memory {
+        memory_object: Arc<MemoryObject>,
+        offset: usize,
+        size: usize,
+    },
+    Io(Arc<IoPortRange>),
+    Irq(u32),
+}
+
+impl AcpiResource {
+    pub fn memory(address: PhysicalAddress, size: usize, writable: bool) -> AcpiResource {
+        let start = address.align_down(Size4KiB::SIZE);
+        let offset = usize::from(address) - usize::from(start);
+        let flags = Flags { writable, executable: false, user_accessible: true, cached: false };
+        let memory_object =
+            MemoryObject::new(SENTINEL_KERNEL_ID, None, start, align_up(offset + size, Size4KiB::SIZE), flags);
+
+        AcpiResource::Memory { memory_object, offset, size }
+    }
+
+    pub fn io(base: u16, length: u16) -> AcpiResource {
+        AcpiResource::Io(IoPortRange::new(SENTINEL_KERNEL_ID, base, length))
+    }
+}
+
+/// Returns `true` if a device with Hardware ID `hid` and Compatible IDs `cids` is a PCI root bridge. The resources
+/// of a root bridge are the windows its bus decodes, and are handed out to the devices on the bus through their
+/// BARs instead.
+pub fn is_pci_root_bridge(hid: &str, cids: &[String]) -> bool {
+    PCI_ROOT_BRIDGE_IDS.contains(&hid) || cids.iter().any(|cid| PCI_ROOT_BRIDGE_IDS.contains(&cid.as_str()))
+}
+
+pub struct AcpiInfo {
+    pub devices: Vec<AcpiDevice>,
+}
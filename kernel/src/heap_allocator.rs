@@ -1,16 +1,53 @@
+//! The kernel heap is managed by a first-fit allocator that keeps a list of the holes in the heap. The loader maps
+//! an initial heap for us, and the heap can grow (up to a maximum size) by asking the platform to map more memory
+//! directly after it, once the platform can do so (see `HoleAllocator::enable_growth`).
+
 use core::{
     alloc::{AllocError, GlobalAlloc, Layout},
-    cmp::max,
+    cmp::{max, min},
     mem::{self, size_of},
     ops::Deref,
+    ptr::{self, NonNull},
 };
-use hal::memory::VirtualAddress;
+use crate::memory::slab_cache;
+use hal::memory::{kibibytes, FrameSize, Size4KiB, VirtualAddress};
+use log::trace;
 use pebble_util::math::align_up;
 use spin::Mutex;
 
+/// The heap is grown by at least this much at a time, so we don't need to grow it for every allocation.
+const HEAP_GROWTH_STEP: usize = kibibytes(256);
+/// If there's less than this much free space in the heap after an allocation, we grow it straight away. This
+/// means there's usually space for any allocations made while the heap is being grown (e.g. by the physical
+/// memory manager).
+const HEAP_LOW_WATERMARK: usize = kibibytes(64);
+
+/// Maps `size` bytes of memory at `address` so the heap can grow into it. `address` and `size` are both
+/// page-aligned. This is provided by the platform, and is called without the heap's lock held, so it can make
+/// allocations on the heap itself.
+pub type MapHeapFn = fn(address: VirtualAddress, size: usize) -> Result<(), ()>;
+
+#[derive(Clone, Copy, Debug)]
+pub struct HeapStats {
+    /// The number of bytes of the heap that are currently mapped.
+    pub size: usize,
+    /// The size the heap is allowed to grow to.
+    pub max_size: usize,
+    pub used: usize,
+    pub free: usize,
+    /// The size of the largest hole in the heap. If this is much smaller than `free`, the heap is fragmented.
+    pub largest_hole: usize,
+}
+
 pub struct HoleAllocator {
     heap_bottom: VirtualAddress,
+    /// The number of bytes of the heap that are currently mapped.
     heap_size: usize,
+    max_size: usize,
+    map_heap: Option<MapHeapFn>,
+    /// Set while the heap is being grown, so that allocations made while we do so don't try to grow it again.
+    growing: bool,
+    used: usize,
     holes: Option<HoleList>,
 }
 
@@ -18,7 +55,15 @@ impl HoleAllocator {
     /// Create a new, uninitialized `HoleAllocator`. Before heap allocations can be made, `init`
     /// must be called.
     pub const fn new_uninitialized() -> HoleAllocator {
-        HoleAllocator { heap_bottom: VirtualAddress::new(0), heap_size: 0, holes: None }
+        HoleAllocator {
+            heap_bottom: VirtualAddress::new(0),
+            heap_size: 0,
+            max_size: 0,
+            map_heap: None,
+            growing: false,
+            used: 0,
+            holes: None,
+        }
     }
 
     /// Initialise the `HoleAllocator`. This should only be called once, and constructs the
@@ -27,8 +72,27 @@ impl HoleAllocator {
         assert!(self.holes.is_none());
         self.heap_bottom = heap_bottom;
         self.heap_size = heap_size;
+        self.max_size = heap_size;
         self.holes = Some(HoleList::new(self.heap_bottom, heap_size));
     }
+
+    /// Allow the heap to grow, until it is `max_size` bytes in size, by mapping more memory with `map_heap`. The
+    /// virtual address space after the initial heap, up to `max_size`, must be reserved for the heap.
+    pub fn enable_growth(&mut self, max_size: usize, map_heap: MapHeapFn) {
+        assert!(max_size >= self.heap_size);
+        self.max_size = max_size;
+        self.map_heap = Some(map_heap);
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            size: self.heap_size,
+            max_size: self.max_size,
+            used: self.used,
+            free: self.heap_size - self.used,
+            largest_hole: self.holes.as_ref().map_or(0, |holes| holes.largest_hole()),
+        }
+    }
 }
 
 pub struct LockedHoleAllocator(Mutex<HoleAllocator>);
@@ -37,6 +101,81 @@ impl LockedHoleAllocator {
     pub const fn new_uninitialized() -> LockedHoleAllocator {
         LockedHoleAllocator(Mutex::new(HoleAllocator::new_uninitialized()))
     }
+
+    /// Allocate directly from the heap's holes, growing the heap if there isn't a hole big enough. This bypasses
+    /// the slab caches.
+    pub fn alloc_from_holes(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let layout = HoleList::adjust_layout(layout);
+
+        loop {
+            let mut heap = self.0.lock();
+            let result = match heap.holes {
+                Some(ref mut holes) => holes.allocate_first_fit(layout),
+                None => panic!("Tried to allocate on the heap before initializing allocator!"),
+            };
+
+            match result {
+                Ok(ptr) => {
+                    heap.used += layout.size();
+                    let should_grow = heap.heap_size - heap.used < HEAP_LOW_WATERMARK;
+                    drop(heap);
+
+                    if should_grow {
+                        self.grow(0);
+                    }
+                    return NonNull::new(ptr);
+                }
+                Err(AllocError) => {
+                    /*
+                     * There isn't a hole big enough, so try to grow the heap. We may need space for alignment, as
+                     * well as for the allocation itself.
+                     */
+                    drop(heap);
+                    if !self.grow(layout.size() + layout.align()) {
+                        return None;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Try to grow the heap by at least `min_bytes`. Returns `false` if the heap couldn't be grown (because it
+    /// can't grow any more, or because it's already being grown).
+    fn grow(&self, min_bytes: usize) -> bool {
+        let (address, size, map_heap) = {
+            let mut heap = self.0.lock();
+            let map_heap = match heap.map_heap {
+                Some(map_heap) if !heap.growing => map_heap,
+                _ => return false,
+            };
+
+            let size =
+                min(align_up(max(min_bytes, HEAP_GROWTH_STEP), Size4KiB::SIZE), heap.max_size - heap.heap_size);
+            if size == 0 || size < min_bytes {
+                return false;
+            }
+
+            heap.growing = true;
+            (heap.heap_bottom + heap.heap_size, size, map_heap)
+        };
+
+        /*
+         * Map the new memory without holding the lock, as mapping it may need to allocate.
+         */
+        let mapped = map_heap(address, size).is_ok();
+
+        let mut heap = self.0.lock();
+        heap.growing = false;
+        if mapped {
+            unsafe {
+                heap.holes.as_mut().unwrap().free(address.mut_ptr(), Layout::from_size_align(size, 1).unwrap());
+            }
+            heap.heap_size += size;
+            trace!("Grew kernel heap by {:#x} bytes to {:#x} bytes", size, heap.heap_size);
+        }
+
+        mapped
+    }
 }
 
 impl Deref for LockedHoleAllocator {
@@ -49,25 +188,27 @@ impl Deref for LockedHoleAllocator {
 
 unsafe impl GlobalAlloc for LockedHoleAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let size =
-            align_up(max(layout.size() as usize, HoleList::get_min_size()), mem::align_of::<Hole>() as usize);
-        let layout = Layout::from_size_align(size as usize, layout.align()).unwrap();
-
-        match self.0.lock().holes {
-            Some(ref mut holes) => holes.allocate_first_fit(layout).unwrap_or(0x0 as *mut u8),
-            None => panic!("Tried to allocate on the heap before initializing allocator!"),
+        if let Some((cache, cpu)) = slab_cache::cache_for(layout) {
+            return cache.alloc(cpu, slab_cache::alloc_slab).map_or(ptr::null_mut(), NonNull::as_ptr);
         }
+
+        self.alloc_from_holes(layout).map_or(ptr::null_mut(), NonNull::as_ptr)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let size =
-            align_up(max(layout.size() as usize, HoleList::get_min_size()), mem::align_of::<Hole>() as usize);
-        let layout = Layout::from_size_align(size as usize, layout.align()).unwrap();
+        if let Some((cache, cpu)) = slab_cache::cache_for(layout) {
+            cache.free(cpu, NonNull::new_unchecked(ptr));
+            return;
+        }
 
-        match self.0.lock().holes {
+        let layout = HoleList::adjust_layout(layout);
+        let mut heap = self.0.lock();
+
+        match heap.holes {
             Some(ref mut holes) => holes.free(ptr, layout),
             None => panic!("Tried to allocate on the heap before initializing allocator!"),
         }
+        heap.used -= layout.size();
     }
 }
 
@@ -138,6 +279,25 @@ impl HoleList {
     pub fn get_min_size() -> usize {
         (size_of::<VirtualAddress>() + size_of::<usize>()) as usize
     }
+
+    /// Every allocation must be large enough to hold a `Hole` once it's freed, and must keep the holes after it
+    /// correctly aligned, so we adjust the layouts of allocations to make sure they are.
+    fn adjust_layout(layout: Layout) -> Layout {
+        let size = align_up(max(layout.size(), Self::get_min_size()), mem::align_of::<Hole>());
+        Layout::from_size_align(size, layout.align()).unwrap()
+    }
+
+    pub fn largest_hole(&self) -> usize {
+        let mut largest = 0;
+        let mut hole = self.first.next.as_ref();
+
+        while let Some(current) = hole {
+            largest = max(largest, current.size);
+            hole = current.next.as_ref();
+        }
+
+        largest
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -312,5 +472,11 @@ fn free(mut hole: &mut Hole, addr: VirtualAddress, mut size: usize) {
 #[cfg(not(test))]
 #[alloc_error_handler]
 fn handle_alloc_error(layout: Layout) -> ! {
-    panic!("Alloc error: {:?}", layout);
+    /*
+     * We don't wait for the heap's lock here, in case we failed to allocate while holding it.
+     */
+    match crate::ALLOCATOR.try_lock() {
+        Some(heap) => panic!("Alloc error: {:?} (heap: {:?})", layout, heap.stats()),
+        None => panic!("Alloc error: {:?}", layout),
+    }
 }
@@ -0,0 +1,183 @@
+//! The kernel keeps its most recent log entries (including messages logged by tasks with `early_log`) in a ring
+//! buffer, as well as sending them wherever the platform logs to. Userspace can drain the buffer with the
+//! `drain_kernel_log` system call, which allows the system's logs to be shown without a host attached to the
+//! serial port.
+
+use crate::Platform;
+use alloc::vec::Vec;
+use core::{fmt, fmt::Write};
+use libpebble::syscall::{
+    kernel_log::KERNEL_LOG_TASK_NAME_MAX_LENGTH,
+    KernelLogEntry,
+    LogLevel,
+};
+use spin::Mutex;
+
+pub const KERNEL_LOG_LENGTH: usize = 256;
+
+static KERNEL_LOG: Mutex<KernelLog> = Mutex::new(KernelLog::new());
+
+/// Add an entry to the kernel log. If the log is full, the oldest entry is overwritten.
+///
+/// The kernel log's lock is only ever held with interrupts disabled, so we can't deadlock by logging from an
+/// interrupt handler that interrupted code holding the lock on the same CPU.
+pub fn add_entry<P>(timestamp: u64, cpu: u32, level: log::Level, task_name: Option<&str>, message: &fmt::Arguments)
+where
+    P: Platform,
+{
+    let mut entry = KernelLogEntry::EMPTY;
+    entry.timestamp = timestamp;
+    entry.cpu = cpu;
+    entry.level = match level {
+        log::Level::Error => LogLevel::Error,
+        log::Level::Warn => LogLevel::Warn,
+        log::Level::Info => LogLevel::Info,
+        log::Level::Debug => LogLevel::Debug,
+        log::Level::Trace => LogLevel::Trace,
+    };
+
+    if let Some(task_name) = task_name {
+        let length = usize::min(task_name.len(), KERNEL_LOG_TASK_NAME_MAX_LENGTH);
+        entry.task_name[0..length].copy_from_slice(&task_name.as_bytes()[0..length]);
+        entry.task_name_length = length as u8;
+    }
+
+    let mut writer = TruncatingWriter { buffer: &mut entry.message, length: 0, truncated: false };
+    let _ = writer.write_fmt(*message);
+    entry.message_length = writer.length as u16;
+
+    P::without_interrupts(|| KERNEL_LOG.lock().push(entry));
+}
+
+/// Get (up to) the `max` oldest entries in the kernel log, without removing them. Once they have been dealt with,
+/// they should be removed with `remove_entries_up_to`.
+pub fn peek_entries<P>(max: usize) -> Vec<KernelLogEntry>
+where
+    P: Platform,
+{
+    /*
+     * The heap allocator logs when it grows the heap, so we must allocate the space for the entries before we
+     * take the lock. There can't be more than `KERNEL_LOG_LENGTH` of them, so it won't need to grow after that.
+     */
+    let mut entries = Vec::with_capacity(usize::min(max, KERNEL_LOG_LENGTH));
+    P::without_interrupts(|| entries.extend(KERNEL_LOG.lock().iter().take(max).copied()));
+    entries
+}
+
+/// Remove all the entries with sequence numbers up to and including `sequence` from the kernel log. Entries may
+/// have been added (and so older entries overwritten) since they were peeked at, so we go by sequence number
+/// instead of removing a number of entries.
+pub fn remove_entries_up_to<P>(sequence: u64)
+where
+    P: Platform,
+{
+    P::without_interrupts(|| {
+        let mut log = KERNEL_LOG.lock();
+        while log.len > 0 && log.entries[log.head].sequence <= sequence {
+            log.head = (log.head + 1) % KERNEL_LOG_LENGTH;
+            log.len -= 1;
+        }
+    });
+}
+
+struct KernelLog {
+    entries: [KernelLogEntry; KERNEL_LOG_LENGTH],
+    /// The index of the oldest entry.
+    head: usize,
+    len: usize,
+    next_sequence: u64,
+}
+
+impl KernelLog {
+    const fn new() -> KernelLog {
+        KernelLog { entries: [KernelLogEntry::EMPTY; KERNEL_LOG_LENGTH], head: 0, len: 0, next_sequence: 0 }
+    }
+
+    fn push(&mut self, mut entry: KernelLogEntry) {
+        entry.sequence = self.next_sequence;
+        self.next_sequence += 1;
+
+        if self.len < KERNEL_LOG_LENGTH {
+            self.entries[(self.head + self.len) % KERNEL_LOG_LENGTH] = entry;
+            self.len += 1;
+        } else {
+            self.entries[self.head] = entry;
+            self.head = (self.head + 1) % KERNEL_LOG_LENGTH;
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &KernelLogEntry> {
+        (0..self.len).map(move |i| &self.entries[(self.head + i) % KERNEL_LOG_LENGTH])
+    }
+}
+
+/// Writes into a fixed-size buffer, silently dropping anything that doesn't fit.
+struct TruncatingWriter<'a> {
+    buffer: &'a mut [u8],
+    length: usize,
+    truncated: bool,
+}
+
+impl<'a> fmt::Write for TruncatingWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        /*
+         * Once we've had to truncate something, drop everything after it, even if it would fit.
+         */
+        if self.truncated {
+            return Ok(());
+        }
+        let space = self.buffer.len() - self.length;
+
+        /*
+         * Make sure we don't truncate in the middle of a character, so the message stays valid UTF-8.
+         */
+        let mut length = usize::min(s.len(), space);
+        while !s.is_char_boundary(length) {
+            length -= 1;
+        }
+
+        self.buffer[self.length..(self.length + length)].copy_from_slice(&s.as_bytes()[0..length]);
+        self.length += length;
+        self.truncated = length < s.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(message: &str) -> KernelLogEntry {
+        let mut entry = KernelLogEntry::EMPTY;
+        entry.message[0..message.len()].copy_from_slice(message.as_bytes());
+        entry.message_length = message.len() as u16;
+        entry
+    }
+
+    #[test]
+    fn test_kernel_log_wraps() {
+        let mut log = KernelLog::new();
+        for i in 0..(KERNEL_LOG_LENGTH + 3) {
+            log.push(entry(&format!("{}", i)));
+        }
+
+        assert_eq!(log.len, KERNEL_LOG_LENGTH);
+        assert_eq!(log.iter().next().unwrap().sequence, 3);
+        assert_eq!(log.iter().next().unwrap().message(), "3");
+        assert_eq!(log.iter().last().unwrap().sequence, KERNEL_LOG_LENGTH as u64 + 2);
+    }
+
+    #[test]
+    fn test_truncating_writer() {
+        let mut buffer = [0u8; 5];
+        let mut writer = TruncatingWriter { buffer: &mut buffer, length: 0, truncated: false };
+        write!(writer, "abcd{}{}", "éf", "g").unwrap();
+
+        /*
+         * `é` is two bytes long, so doesn't fit in the last byte. We shouldn't split it, and shouldn't write `g`
+         * after it either.
+         */
+        let length = writer.length;
+        assert_eq!(core::str::from_utf8(&buffer[0..length]).unwrap(), "abcd");
+    }
+}
@@ -15,25 +15,30 @@
 #[macro_use]
 extern crate alloc;
 
+pub mod acpi;
 mod heap_allocator;
+pub mod kernel_log;
 pub mod memory;
 pub mod object;
 pub mod pci;
 pub mod per_cpu;
 pub mod scheduler;
+pub mod symbols;
 pub mod syscall;
 
 use crate::memory::Stack;
+use acpi::AcpiInfo;
 use alloc::{boxed::Box, sync::Arc};
-use core::pin::Pin;
+use core::{pin::Pin, time::Duration};
 use hal::{
     boot_info::LoadedImage,
     memory::{FrameSize, PageTable, VirtualAddress},
 };
+pub use heap_allocator::HeapStats;
 use heap_allocator::LockedHoleAllocator;
 use memory::{KernelStackAllocator, PhysicalMemoryManager};
 use object::{address_space::AddressSpace, memory_object::MemoryObject, task::Task, KernelObject, KernelObjectId};
-use pci::PciInfo;
+use pci::{Iommu, PciInfo};
 use pci_types::ConfigRegionAccess as PciConfigRegionAccess;
 use pebble_util::InitGuard;
 use per_cpu::PerCpu;
@@ -47,19 +52,40 @@ pub static ALLOCATOR: LockedHoleAllocator = LockedHoleAllocator::new_uninitializ
 pub static PHYSICAL_MEMORY_MANAGER: InitGuard<PhysicalMemoryManager> = InitGuard::uninit();
 pub static FRAMEBUFFER: InitGuard<(libpebble::syscall::FramebufferInfo, Arc<MemoryObject>)> = InitGuard::uninit();
 pub static PCI_INFO: RwLock<Option<PciInfo>> = RwLock::new(None);
+pub static ACPI_INFO: RwLock<Option<AcpiInfo>> = RwLock::new(None);
 pub static PCI_ACCESS: InitGuard<Option<Mutex<Box<dyn PciConfigRegionAccess>>>> = InitGuard::uninit();
+/// The platform's IOMMU, if it has one. If it doesn't, devices can access any memory through DMA.
+pub static IOMMU: InitGuard<Option<Mutex<Box<dyn Iommu>>>> = InitGuard::uninit();
 
 pub trait Platform: Sized + 'static {
     type PageTableSize: FrameSize;
     type PageTable: PageTable<Self::PageTableSize> + Send;
     type PerCpu: PerCpu<Self>;
 
+    /// The highest virtual address that userspace is allowed to access. Pointers passed to the kernel from
+    /// userspace are rejected if they reach above this.
+    const USER_ADDRESS_SPACE_TOP: VirtualAddress;
+
     fn kernel_page_table(&mut self) -> &mut Self::PageTable;
 
     /// Get the per-CPU info for the current CPU. To make this safe, the per-CPU info must be installed before the
     /// `Platform` implementation is created.
     fn per_cpu<'a>() -> Pin<&'a mut Self::PerCpu>;
 
+    /// Get the ID of the CPU we're running on. CPUs are numbered from `0`, so this can be used to index per-CPU
+    /// bitmaps (such as the CPUs an `AddressSpace` is active on).
+    fn current_cpu_id() -> usize;
+
+    /// Invalidate any TLB entries covering the `size` bytes at `start` on each CPU in the bitmap `cpus`, and wait
+    /// until they have all done so. The running CPU should not be included in `cpus`.
+    fn tlb_shootdown(cpus: u64, start: VirtualAddress, size: usize);
+
+    /// Invalidate any TLB entries covering the `size` bytes at `start` in the kernel's part of the address space,
+    /// on every CPU (including the running one) and in every address space, and wait until they have all done so.
+    /// Every address space shares the kernel's mappings, so this must be used instead of `tlb_shootdown` when they
+    /// are removed or changed.
+    fn kernel_tlb_shootdown(start: VirtualAddress, size: usize);
+
     /// Often, the platform will need to put stuff on either the kernel or the user stack before a task is run for
     /// the first time. `task_entry_point` is the virtual address that should be jumped to in usermode when the
     /// task is run for the first time.
@@ -71,10 +97,47 @@ pub trait Platform: Sized + 'static {
         task_entry_point: VirtualAddress,
     ) -> (VirtualAddress, VirtualAddress);
 
+    /// Prepare the kernel stack of a CPU's idle context, which the scheduler switches to when there's nothing to
+    /// run. The idle context should loop, calling `Scheduler::switch_from_idle` and then waiting for an interrupt
+    /// (in a low-power state, if possible). Returns the kernel stack pointer to switch to.
+    unsafe fn initialize_idle_stack(stack: &Stack) -> VirtualAddress;
+
     /// Do the final part of a context switch: save all the state that needs to be to the current kernel stack,
     /// switch to a new kernel stack, and restore all the state from that stack.
     unsafe fn context_switch(current_kernel_stack: *mut VirtualAddress, new_kernel_stack: VirtualAddress);
 
+    /// Read a counter that increases monotonically at a constant rate, to time how long things take. The rate is
+    /// platform-specific, and the counter is not necessarily synchronized between CPUs.
+    fn read_timestamp() -> u64;
+
+    /// Arrange for the running task to be preempted once `duration` has passed, replacing any previous request.
+    /// When the time is up, the platform should call `Scheduler::time_slice_expired` as soon as it is safe to
+    /// switch away from the task. `None` cancels any request, and the platform should then not interrupt the CPU
+    /// at all for preemption.
+    fn set_preemption_timer(duration: Option<Duration>);
+
+    /// Run `f` with interrupts disabled on the current CPU, restoring them to how they were afterwards. This is
+    /// used around locks that can also be taken from interrupt handlers, so a handler can't interrupt code holding
+    /// the lock on the same CPU and deadlock.
+    fn without_interrupts<F, R>(f: F) -> R
+    where
+        F: FnOnce() -> R;
+
+    /// Turn the machine off. This only returns if the platform failed to do so.
+    fn power_off();
+
+    /// Reset the machine.
+    fn reboot() -> !;
+
+    /// Copy `length` bytes from userspace at `src` into the kernel at `dst`. The caller must have checked that the
+    /// source is mapped and accessible from userspace, but if accessing it faults anyway (e.g. because the mapping
+    /// has since been changed), the fault is recovered from and this returns `Err(())`.
+    unsafe fn copy_from_user(dst: *mut u8, src: *const u8, length: usize) -> Result<(), ()>;
+
+    /// Copy `length` bytes from the kernel at `src` to userspace at `dst`. This has the same requirements and
+    /// fault handling as `copy_from_user`.
+    unsafe fn copy_to_user(dst: *mut u8, src: *const u8, length: usize) -> Result<(), ()>;
+
     /// Do the actual drop into usermode. This assumes that the task's page tables have already been installed,
     /// and that an initial frame has been put into the task's kernel stack that this will use to enter userspace.
     unsafe fn drop_into_userspace() -> !;
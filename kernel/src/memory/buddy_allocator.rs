@@ -36,20 +36,19 @@
 //! free, at which point the block is added to the correct bin.
 //!
 //! Overall, the buddy allocator is an efficient allocator that has a much lower cost than other
-//! algorithms such as first-fit. It also helps reduce external fragmentation, but can suffer from
-//! internal fragmentation in the case of allocations that are slightly larger than a block size
-//! (e.g. allocating 17 frames actually returns 32, wasting 15 frames). If the kernel needs to make
-//! many allocations of a constant, "known bad" size (e.g. 3 frames at a time), it would be better
-//! served allocating a larger block of frames at a time, and using a slab allocator to make the
-//! individual allocations.
+//! algorithms such as first-fit. It also helps reduce external fragmentation. The allocator itself
+//! can only hand out whole blocks, but the `PhysicalMemoryManager` avoids wasting the end of a block
+//! when an allocation is slightly larger than a block size (e.g. 17 frames) by allocating the next
+//! block size up and freeing the frames it doesn't need back into the allocator (see `add_range`).
 
 use alloc::collections::BTreeSet;
 use core::{cmp::min, ops::Range};
 use hal::memory::{Bytes, Frame, FrameSize, PhysicalAddress, Size4KiB};
 use pebble_util::math::flooring_log2;
 
-/// The largest block stored by the buddy allocator is `2^MAX_ORDER`.
-const MAX_ORDER: usize = 10;
+/// The largest block stored by the buddy allocator is `2^MAX_ORDER` frames. This is `1GiB` with the current
+/// `BASE_SIZE`.
+pub const MAX_ORDER: usize = 18;
 const NUM_BINS: usize = MAX_ORDER + 1;
 
 /// The "base" block size - the smallest block size this allocator tracks. This is chosen at the moment to be
@@ -71,7 +70,8 @@ impl BuddyAllocator {
         BuddyAllocator { bins: Default::default() }
     }
 
-    /// Add a range of `Frame`s to this allocator, marking them free to allocate.
+    /// Add a range of `Frame`s to this allocator, marking them free to allocate. This is also used to free
+    /// ranges of frames that aren't a single whole block.
     pub fn add_range(&mut self, range: Range<Frame>) {
         // XXX: if we ever change BASE_SIZE, this needs to be adjusted, so we assert here
         assert_eq!(BASE_SIZE, Size4KiB::SIZE);
@@ -85,10 +85,12 @@ impl BuddyAllocator {
         while block_start < range.end {
             /*
              * Pick the largest order block that fits in the remaining area, but cap it at the
-             * largest order the allocator can manage.
+             * largest order the allocator can manage. Blocks must also be aligned to their size, or we
+             * won't be able to find their buddies.
              */
             let num_frames = (block_start..range.end).count();
-            let order = min(MAX_ORDER, flooring_log2(num_frames));
+            let alignment = (usize::from(block_start.start) / BASE_SIZE).trailing_zeros() as usize;
+            let order = min(MAX_ORDER, min(alignment, flooring_log2(num_frames)));
 
             self.free_block(block_start.start, order);
             block_start += 1 << order;
@@ -292,10 +294,10 @@ mod tests {
     fn test_bigger_block_binning() {
         let mut allocator = BuddyAllocator::new();
         allocator.add_range(n_frames_at(0x2000, 1));
-        allocator.add_range(n_frames_at(0x6000, 4));
-        allocator.add_range(n_frames_at(0x10000, 64));
+        allocator.add_range(n_frames_at(0x8000, 4));
+        allocator.add_range(n_frames_at(0x40000, 64));
         assert_eq!(allocator.available_bytes(), (1 + 4 + 64) * BASE_SIZE);
-        check_bins(allocator, vec![Block::new(0, 0x2000), Block::new(2, 0x6000), Block::new(6, 0x10000)]);
+        check_bins(allocator, vec![Block::new(0, 0x2000), Block::new(2, 0x8000), Block::new(6, 0x40000)]);
     }
 
     /// Test the splitting of weird-sized ranges into blocks.
@@ -312,18 +314,42 @@ mod tests {
          * Split 523 frames into 4 blocks of orders: 9, then 3, then 1, then 0.
          */
         let mut allocator = BuddyAllocator::new();
-        allocator.add_range(n_frames_at(0x40000, 523));
+        allocator.add_range(n_frames_at(0x200000, 523));
         check_bins(
             allocator,
             vec![
-                Block::new(9, 0x40000),
-                Block::new(3, 0x240000),
-                Block::new(1, 0x248000),
-                Block::new(0, 0x24a000),
+                Block::new(9, 0x200000),
+                Block::new(3, 0x400000),
+                Block::new(1, 0x408000),
+                Block::new(0, 0x40a000),
             ],
         );
     }
 
+    /// Test that ranges that don't start on a nicely-aligned frame are split into blocks that are aligned to their
+    /// size.
+    #[test]
+    fn test_unaligned_range_binning() {
+        /*
+         * 7 frames starting at frame 1 can't be an order-2 block at frame 1, and must instead be split into
+         * blocks of orders: 0, then 1, then 2.
+         */
+        let mut allocator = BuddyAllocator::new();
+        allocator.add_range(n_frames_at(0x1000, 7));
+        check_bins(allocator, vec![Block::new(0, 0x1000), Block::new(1, 0x2000), Block::new(2, 0x4000)]);
+
+        /*
+         * Freeing the end of a block (like the `PhysicalMemoryManager` does when an allocation doesn't need a
+         * whole block) should result in the same blocks as if the whole block had never been allocated.
+         */
+        let mut allocator = BuddyAllocator::new();
+        allocator.add_range(n_frames_at(0x0, 32));
+        let block = allocator.allocate_n(32 * BASE_SIZE).unwrap();
+        allocator.add_range(n_frames_at(usize::from(block) + 17 * BASE_SIZE, 15));
+        allocator.add_range(n_frames_at(usize::from(block), 17));
+        check_bins(allocator, vec![Block::new(5, 0x0)]);
+    }
+
     #[test]
     fn test_block_coalescing() {
         /*
@@ -376,12 +402,14 @@ mod tests {
          * `None` even if we could service the request overall.
          */
         let mut allocator = BuddyAllocator::new();
-        allocator.add_range(n_frames_at(0x0, 4096)); // Allocate 4 blocks of the maximum order (currently 10)
-        assert_eq!(allocator.allocate_block(12), None);
+        allocator.add_range(n_frames_at(0x0, 4 << MAX_ORDER)); // Allocate 4 blocks of the maximum order
+        assert_eq!(allocator.available_bytes(), (4 << MAX_ORDER) * BASE_SIZE);
+        assert_eq!(allocator.allocate_block(MAX_ORDER + 2), None);
 
         /*
-         * Currently, `allocate_n` can only allocate contiguous bytes, but this could be relaxed in the future.
+         * `allocate_n` can only allocate contiguous bytes. Larger allocations can be made from several blocks by
+         * the `PhysicalMemoryManager`.
          */
-        assert_eq!(allocator.allocate_n(2048 * Size4KiB::SIZE), None);
+        assert_eq!(allocator.allocate_n((2 << MAX_ORDER) * Size4KiB::SIZE), None);
     }
 }
@@ -1,5 +1,6 @@
-use super::{PhysicalMemoryManager, SlabAllocator, Stack};
+use super::{PhysicalAllocation, PhysicalMemoryManager, SlabAllocator, Stack};
 use crate::Platform;
+use alloc::sync::Arc;
 use core::marker::PhantomData;
 use hal::memory::VirtualAddress;
 use spin::Mutex;
@@ -8,7 +9,7 @@ pub struct KernelStackAllocator<P>
 where
     P: Platform,
 {
-    kernel_stack_slots: Mutex<SlabAllocator>,
+    kernel_stack_slots: Arc<Mutex<SlabAllocator>>,
     slot_size: usize,
     _phantom: PhantomData<P>,
 }
@@ -23,7 +24,7 @@ where
         slot_size: usize,
     ) -> KernelStackAllocator<P> {
         KernelStackAllocator {
-            kernel_stack_slots: Mutex::new(SlabAllocator::new(stacks_bottom, stacks_top, slot_size)),
+            kernel_stack_slots: Arc::new(Mutex::new(SlabAllocator::new(stacks_bottom, stacks_top, slot_size))),
             slot_size,
             _phantom: PhantomData,
         }
@@ -34,24 +35,43 @@ where
         initial_size: usize,
         physical_memory_manager: &PhysicalMemoryManager,
         kernel_page_table: &mut P::PageTable,
-    ) -> Option<Stack> {
+    ) -> Option<KernelStack> {
         use hal::memory::{Flags, PageTable};
 
         let slot_bottom = self.kernel_stack_slots.lock().alloc()?;
         let top = slot_bottom + self.slot_size - 1;
         let stack_bottom = top - initial_size + 1;
 
-        let physical_start = physical_memory_manager.alloc_bytes(initial_size);
+        let memory = physical_memory_manager.alloc_bytes(initial_size);
         kernel_page_table
             .map_area(
                 stack_bottom,
-                physical_start,
+                memory.start(),
                 initial_size,
                 Flags { writable: true, ..Default::default() },
                 physical_memory_manager,
             )
             .unwrap();
 
-        Some(Stack { top, slot_bottom, stack_bottom, physical_start })
+        Some(KernelStack {
+            stack: Stack { top, slot_bottom, stack_bottom, physical_start: memory.start() },
+            memory,
+            slots: self.kernel_stack_slots.clone(),
+        })
+    }
+}
+
+/// A stack allocated by a `KernelStackAllocator`. When this is dropped, the memory backing the stack is freed,
+/// and its slot can be used for another stack, so the stack must be unmapped (with
+/// `AddressSpace::free_kernel_stack`) first.
+pub struct KernelStack {
+    pub stack: Stack,
+    memory: PhysicalAllocation,
+    slots: Arc<Mutex<SlabAllocator>>,
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        self.slots.lock().free(self.stack.slot_bottom);
     }
 }
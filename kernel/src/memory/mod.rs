@@ -1,45 +1,281 @@
 mod buddy_allocator;
 mod kernel_stack_allocator;
 mod slab_allocator;
+pub mod slab_cache;
 
-pub use kernel_stack_allocator::KernelStackAllocator;
+pub use kernel_stack_allocator::{KernelStack, KernelStackAllocator};
 pub use slab_allocator::SlabAllocator;
 
-use buddy_allocator::BuddyAllocator;
-use core::ops::Range;
+use alloc::vec::Vec;
+use buddy_allocator::{BuddyAllocator, MAX_ORDER};
+use core::{cmp::min, ops::Range};
 use hal::{
     boot_info::BootInfo,
-    memory::{Frame, FrameAllocator, FrameSize, PhysicalAddress, VirtualAddress},
+    memory::{gibibytes, mebibytes, Frame, FrameAllocator, FrameSize, PhysicalAddress, Size4KiB, VirtualAddress},
 };
 use log::info;
+use pebble_util::math::{align_up, flooring_log2};
 use spin::Mutex;
 
+/// Physical memory is split into zones, so that allocations can be restricted to memory that devices with limited
+/// addressing capabilities can access.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Zone {
+    /// Memory below `16MiB`, which can be accessed by legacy ISA DMA.
+    Dma = 0,
+    /// Memory below `4GiB`, which can be accessed by devices that can only perform 32-bit DMA.
+    Dma32 = 1,
+    /// All other memory.
+    Normal = 2,
+}
+
+const NUM_ZONES: usize = 3;
+const ZONES: [Zone; NUM_ZONES] = [Zone::Dma, Zone::Dma32, Zone::Normal];
+
+impl Zone {
+    /// The first physical address that is not in this zone.
+    fn end(self) -> usize {
+        match self {
+            Zone::Dma => mebibytes(16),
+            Zone::Dma32 => gibibytes(4),
+            Zone::Normal => usize::MAX,
+        }
+    }
+
+    fn of(address: PhysicalAddress) -> Zone {
+        ZONES.iter().copied().find(|zone| usize::from(address) < zone.end()).unwrap()
+    }
+}
+
 pub struct PhysicalMemoryManager {
-    buddy: Mutex<BuddyAllocator>,
+    /// A buddy allocator for each zone, indexed by `Zone`. No block crosses the boundary between two zones.
+    zones: Mutex<[BuddyAllocator; NUM_ZONES]>,
+    /// Memory that is in use while we're booting (e.g. by the ACPI tables, or the boot info), and that can be
+    /// reclaimed with `reclaim_boot_memory` once we've finished with it.
+    boot_memory: Mutex<Vec<Range<PhysicalAddress>>>,
 }
 
 impl PhysicalMemoryManager {
     pub fn new(boot_info: &BootInfo) -> PhysicalMemoryManager {
-        let mut buddy_allocator = BuddyAllocator::new();
+        use hal::boot_info::MemoryType;
+
+        let mut zones = [BuddyAllocator::new(), BuddyAllocator::new(), BuddyAllocator::new()];
+        let mut boot_memory = Vec::new();
+        let mut boot_memory_bytes = 0;
+        let mut image_bytes = 0;
+        let mut in_use_bytes = 0;
 
         for entry in boot_info.memory_map.entries() {
-            if entry.memory_type == hal::boot_info::MemoryType::Conventional {
-                buddy_allocator.add_range(entry.frame_range());
+            let range = entry.start..(entry.start + entry.size);
+
+            match entry.memory_type {
+                MemoryType::Conventional => add_range_to_zones(&mut zones, range),
+                MemoryType::AcpiReclaimable | MemoryType::BootInfo => {
+                    boot_memory_bytes += entry.size;
+                    boot_memory.push(range);
+                }
+                /*
+                 * The memory of loaded images is owned by the `MemoryObject`s created for their segments, and is
+                 * freed when they're dropped.
+                 */
+                MemoryType::LoadedImage => image_bytes += entry.size,
+                /*
+                 * We can't reclaim the page tables or heap the loader created for us, as they're still in use.
+                 */
+                MemoryType::KernelPageTables | MemoryType::KernelHeap => in_use_bytes += entry.size,
             }
         }
-        info!("Buddy allocator has {} bytes", buddy_allocator.available_bytes());
 
-        PhysicalMemoryManager { buddy: Mutex::new(buddy_allocator) }
+        log_available_memory(&zones);
+        info!("{} bytes of memory can be reclaimed after boot", boot_memory_bytes);
+        info!("{} bytes of memory are used by loaded images", image_bytes);
+        info!("{} bytes of memory are used by the kernel's page tables and heap", in_use_bytes);
+
+        PhysicalMemoryManager { zones: Mutex::new(zones), boot_memory: Mutex::new(boot_memory) }
     }
 
-    pub fn alloc_bytes(&self, num_bytes: usize) -> PhysicalAddress {
-        /*
-         * For now, we always use the buddy allocator.
-         * TODO: this isn't very good. We can only allocate a whole block at a time, and always allocate a
-         * contiguous block of memory even when we don't need one. This should return an "owned" allocation that
-         * can hold a list of non-contiguous ranges of frames, plus information needed to free the allocation.
-         */
-        self.buddy.lock().allocate_n(num_bytes).expect("Failed to allocate physical memory!")
+    /// Free the memory that is only needed while we're booting (the ACPI tables and the boot info) into the
+    /// allocator.
+    ///
+    /// # Safety
+    /// Nothing may access the boot info, or the ACPI tables, after this is called. Anything that is still needed
+    /// from them must have been copied out first.
+    pub unsafe fn reclaim_boot_memory(&self) {
+        let mut reclaimed = 0;
+        for range in self.boot_memory.lock().drain(..) {
+            reclaimed += usize::from(range.end) - usize::from(range.start);
+            self.free_range(range);
+        }
+
+        info!("Reclaimed {} bytes of boot memory", reclaimed);
+        log_available_memory(&self.zones.lock());
+    }
+
+    /// Allocate `num_bytes` bytes of physically-contiguous memory. The memory is freed when the returned
+    /// `PhysicalAllocation` is dropped.
+    pub fn alloc_bytes(&self, num_bytes: usize) -> PhysicalAllocation {
+        self.try_alloc_bytes(num_bytes).expect("Failed to allocate physical memory!")
+    }
+
+    /// Like `alloc_bytes`, but returns `None` if there isn't enough free memory, instead of panicking.
+    pub fn try_alloc_bytes(&self, num_bytes: usize) -> Option<PhysicalAllocation> {
+        self.alloc_bytes_in(num_bytes, Zone::Normal)
+    }
+
+    /// Allocate `num_bytes` bytes of physically-contiguous memory, which must lie in `highest_zone` or a zone
+    /// below it. Memory from the highest zone allowed is preferred, so memory that devices with limited addressing
+    /// need is only used when there's nothing else. Returns `None` if there isn't enough free memory.
+    pub fn alloc_bytes_in(&self, num_bytes: usize, highest_zone: Zone) -> Option<PhysicalAllocation> {
+        let range = self.alloc_contiguous(align_up(num_bytes, Size4KiB::SIZE), highest_zone)?;
+        Some(PhysicalAllocation { ranges: vec![range], size: num_bytes })
+    }
+
+    /// Allocate `num_bytes` bytes of memory that doesn't need to be physically contiguous, from `highest_zone` or
+    /// a zone below it. The allocation is made from as few blocks as possible, but can be satisfied when there
+    /// isn't a contiguous area of memory large enough. Returns `None` if there isn't enough free memory.
+    pub fn alloc_bytes_noncontiguous(&self, num_bytes: usize, highest_zone: Zone) -> Option<PhysicalAllocation> {
+        let mut remaining = align_up(num_bytes, Size4KiB::SIZE) / Size4KiB::SIZE;
+        let mut ranges: Vec<Range<PhysicalAddress>> = Vec::new();
+
+        while remaining > 0 {
+            /*
+             * Try to allocate the largest block that doesn't exceed what we still need, and try smaller blocks if
+             * we can't.
+             */
+            let mut order = min(MAX_ORDER, flooring_log2(remaining));
+            let start = loop {
+                if let Some(start) = self.alloc_block(Size4KiB::SIZE << order, highest_zone) {
+                    break start;
+                }
+
+                if order == 0 {
+                    /*
+                     * Free the blocks we've already allocated, by dropping them as an allocation.
+                     */
+                    drop(PhysicalAllocation { ranges, size: 0 });
+                    return None;
+                }
+                order -= 1;
+            };
+            let end = start + (Size4KiB::SIZE << order);
+
+            /*
+             * Blocks are often next to each other, so merge them into a single range if we can.
+             */
+            match ranges.last_mut() {
+                Some(last) if last.end == start => last.end = end,
+                _ => ranges.push(start..end),
+            }
+            remaining -= 1 << order;
+        }
+
+        Some(PhysicalAllocation { ranges, size: num_bytes })
+    }
+
+    /// Allocate a contiguous area of `size` bytes. `size` must be a multiple of the base frame size, but doesn't
+    /// have to be a whole block - the end of the block we allocate is freed if we don't need it.
+    fn alloc_contiguous(&self, size: usize, highest_zone: Zone) -> Option<Range<PhysicalAddress>> {
+        let block_size = size.next_power_of_two();
+        let start = self.alloc_block(block_size, highest_zone)?;
+
+        if block_size > size {
+            self.free_range((start + size)..(start + block_size));
+        }
+
+        Some(start..(start + size))
+    }
+
+    /// Allocate a single block of `block_size` bytes from the highest zone allowed that can satisfy it.
+    fn alloc_block(&self, block_size: usize, highest_zone: Zone) -> Option<PhysicalAddress> {
+        let mut zones = self.zones.lock();
+        ZONES
+            .iter()
+            .rev()
+            .filter(|&&zone| zone <= highest_zone)
+            .find_map(|&zone| zones[zone as usize].allocate_n(block_size))
+    }
+
+    fn free_range(&self, range: Range<PhysicalAddress>) {
+        add_range_to_zones(&mut self.zones.lock(), range);
+    }
+}
+
+/// Add a range of memory to the allocators of the zones it lies in, splitting it at the boundaries between zones.
+fn add_range_to_zones(zones: &mut [BuddyAllocator; NUM_ZONES], range: Range<PhysicalAddress>) {
+    let mut start = range.start;
+
+    while start < range.end {
+        let zone = Zone::of(start);
+        let end = if usize::from(range.end) > zone.end() {
+            PhysicalAddress::new(zone.end()).unwrap()
+        } else {
+            range.end
+        };
+
+        zones[zone as usize].add_range(Frame::starts_with(start)..Frame::starts_with(end));
+        start = end;
+    }
+}
+
+fn log_available_memory(zones: &[BuddyAllocator; NUM_ZONES]) {
+    for &zone in ZONES.iter() {
+        info!("Buddy allocator for zone {:?} has {} bytes", zone, zones[zone as usize].available_bytes());
+    }
+}
+
+/// An allocation of physical memory made by the `PhysicalMemoryManager`. The memory is returned to the
+/// `PhysicalMemoryManager` when this is dropped, so anything that refers to the memory (e.g. a mapping of it into
+/// an address space) must not outlive it.
+#[derive(Debug)]
+pub struct PhysicalAllocation {
+    /// The areas of memory that make up the allocation. Contiguous allocations only have one.
+    ranges: Vec<Range<PhysicalAddress>>,
+    /// The size that was asked for. The allocation may be slightly larger than this, as it's made up of whole
+    /// frames.
+    size: usize,
+}
+
+impl PhysicalAllocation {
+    /// The start of the allocation. For non-contiguous allocations, this is the start of the first area of memory
+    /// that makes up the allocation - use `ranges` to get the rest.
+    pub fn start(&self) -> PhysicalAddress {
+        self.ranges[0].start
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn ranges(&self) -> &[Range<PhysicalAddress>] {
+        &self.ranges
+    }
+
+    /// Take ownership of an area of memory that wasn't allocated by the `PhysicalMemoryManager` (e.g. memory that
+    /// the loader allocated), so that it's freed into the `PhysicalMemoryManager` when the allocation is dropped.
+    ///
+    /// # Safety
+    /// The memory must be usable RAM, must be page-aligned, and must not be used by anything else once the
+    /// allocation is dropped.
+    pub unsafe fn adopt(range: Range<PhysicalAddress>) -> PhysicalAllocation {
+        let size = usize::from(range.end) - usize::from(range.start);
+        PhysicalAllocation { ranges: vec![range], size }
+    }
+
+    /// Consume the allocation without freeing its memory. This is used for memory that needs to stay around for
+    /// the rest of the kernel's life.
+    pub fn leak(mut self) -> PhysicalAddress {
+        let start = self.start();
+        self.ranges.clear();
+        start
+    }
+}
+
+impl Drop for PhysicalAllocation {
+    fn drop(&mut self) {
+        let manager = crate::PHYSICAL_MEMORY_MANAGER.get();
+        for range in self.ranges.drain(..) {
+            manager.free_range(range);
+        }
     }
 }
 
@@ -48,12 +284,16 @@ where
     S: FrameSize,
 {
     fn allocate_n(&self, n: usize) -> Range<Frame<S>> {
-        let start = self.buddy.lock().allocate_n(n * S::SIZE).expect("Failed to allocate physical memory!");
-        Frame::<S>::starts_with(start)..(Frame::<S>::starts_with(start) + n)
+        /*
+         * Blocks are aligned to their size, and the block we allocate is at least as large as `S`, so the start of
+         * the range is correctly aligned to be the start of a frame of size `S`.
+         */
+        let range = self.alloc_contiguous(n * S::SIZE, Zone::Normal).expect("Failed to allocate physical memory!");
+        Frame::<S>::starts_with(range.start)..(Frame::<S>::starts_with(range.start) + n)
     }
 
     fn free_n(&self, start: Frame<S>, num_frames: usize) {
-        self.buddy.lock().free_n(start.start, num_frames * S::SIZE);
+        self.free_range(start.start..(start.start + num_frames * S::SIZE));
     }
 }
 
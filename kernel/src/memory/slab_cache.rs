@@ -0,0 +1,300 @@
+//! Slab caches make allocating the kernel objects that are created and destroyed most often (`Task`s,
+//! `AddressSpace`s, `ChannelEnd`s, `MemoryObject`s and `Message`s) cheap, and stop them fragmenting the heap.
+//! Each cache hands out objects of a single layout.
+//!
+//! Free objects are kept in per-CPU magazines, so most allocations and frees only need to lock the running CPU's
+//! magazine, and not the heap. When a magazine is empty (or full), objects are moved into (or out of) the cache's
+//! depot, and when the depot runs out of objects, a slab of fresh pages is taken from the physical memory manager
+//! and split into objects. Memory is never freed once it belongs to a cache.
+//!
+//! Kernel objects are allocated as `Arc`s (and queued `Message`s as `Box`es), which allocate through the global
+//! allocator, so the heap allocator checks the caches first: an allocation with the same layout as a cache's
+//! objects is made from that cache.
+
+use crate::{
+    object::{
+        address_space::AddressSpace,
+        channel::{ChannelEnd, Message},
+        memory_object::MemoryObject,
+        task::Task,
+    },
+    Platform,
+};
+use core::{
+    alloc::Layout,
+    cmp::max,
+    mem::size_of,
+    ptr::NonNull,
+    sync::atomic::AtomicUsize,
+};
+use hal::memory::{kibibytes, VirtualAddress};
+use log::info;
+use pebble_util::{math::align_up, InitGuard};
+use spin::Mutex;
+
+/// Each cache has this many magazines. CPUs with IDs higher than this share magazines with other CPUs, which is
+/// still correct, but means those CPUs can contend on a magazine's lock.
+pub const MAX_CPUS: usize = 16;
+const MAGAZINE_SIZE: usize = 32;
+/// When a cache's depot is empty, it takes a slab of this many bytes of fresh pages to split into new objects.
+pub const SLAB_SIZE: usize = kibibytes(16);
+
+pub static TASK_CACHE: SlabCache = SlabCache::new("Task");
+pub static ADDRESS_SPACE_CACHE: SlabCache = SlabCache::new("AddressSpace");
+pub static CHANNEL_END_CACHE: SlabCache = SlabCache::new("ChannelEnd");
+pub static MEMORY_OBJECT_CACHE: SlabCache = SlabCache::new("MemoryObject");
+pub static MESSAGE_CACHE: SlabCache = SlabCache::new("Message");
+
+static CACHES: [&SlabCache; 5] =
+    [&TASK_CACHE, &ADDRESS_SPACE_CACHE, &CHANNEL_END_CACHE, &MEMORY_OBJECT_CACHE, &MESSAGE_CACHE];
+/// Gets the ID of the CPU we're running on. This is provided by the platform when the caches are initialized.
+static CURRENT_CPU: InitGuard<fn() -> usize> = InitGuard::uninit();
+/// Allocates a new slab. This is provided by the platform when the caches are initialized.
+static ALLOC_SLAB: InitGuard<fn() -> Option<VirtualAddress>> = InitGuard::uninit();
+
+/// Initialize the slab caches. Until this is called, all allocations are made directly from the heap.
+/// `current_cpu` must return the ID of the CPU it's called on, and must work from anywhere that can allocate.
+/// `alloc_slab` must return the address of `SLAB_SIZE` bytes of fresh, page-aligned memory, or `None` if there
+/// isn't any left.
+pub fn init<P>(current_cpu: fn() -> usize, alloc_slab: fn() -> Option<VirtualAddress>)
+where
+    P: Platform,
+{
+    TASK_CACHE.init_for_arc::<Task<P>>();
+    ADDRESS_SPACE_CACHE.init_for_arc::<AddressSpace<P>>();
+    CHANNEL_END_CACHE.init_for_arc::<ChannelEnd>();
+    MEMORY_OBJECT_CACHE.init_for_arc::<MemoryObject>();
+    MESSAGE_CACHE.init(Layout::new::<Message>());
+    ALLOC_SLAB.initialize(alloc_slab);
+    CURRENT_CPU.initialize(current_cpu);
+}
+
+/// Find the cache that allocations with the given layout should be made from, if there is one. Also returns the
+/// ID of the current CPU, to pass to the cache.
+pub fn cache_for(layout: Layout) -> Option<(&'static SlabCache, usize)> {
+    let current_cpu = CURRENT_CPU.try_get()?;
+    let cache = CACHES.iter().copied().find(|cache| cache.layout.try_get() == Some(&layout))?;
+    Some((cache, current_cpu()))
+}
+
+/// Allocate a slab of fresh pages for a cache, using the function provided by the platform.
+pub fn alloc_slab() -> Option<VirtualAddress> {
+    (ALLOC_SLAB.get())()
+}
+
+pub struct SlabCache {
+    name: &'static str,
+    /// The layout of the allocations this cache is used for.
+    layout: InitGuard<Layout>,
+    /// The distance between the start of consecutive objects in a slab.
+    stride: InitGuard<usize>,
+    magazines: [Mutex<Magazine>; MAX_CPUS],
+    depot: Mutex<Depot>,
+}
+
+impl SlabCache {
+    pub const fn new(name: &'static str) -> SlabCache {
+        const EMPTY_MAGAZINE: Mutex<Magazine> = Mutex::new(Magazine::new());
+        SlabCache {
+            name,
+            layout: InitGuard::uninit(),
+            stride: InitGuard::uninit(),
+            magazines: [EMPTY_MAGAZINE; MAX_CPUS],
+            depot: Mutex::new(Depot::new()),
+        }
+    }
+
+    /// Use this cache for allocations made by `Arc<T>`.
+    pub fn init_for_arc<T>(&self) {
+        /*
+         * `Arc` allocates its two reference counts alongside the object (see `alloc::sync::ArcInner`), so this
+         * must match how it lays them out.
+         */
+        let layout = Layout::new::<[AtomicUsize; 2]>().extend(Layout::new::<T>()).unwrap().0.pad_to_align();
+        self.init(layout);
+    }
+
+    /// Use this cache for allocations with the given layout.
+    pub fn init(&self, layout: Layout) {
+        /*
+         * Free objects hold a pointer to the next free object, so must be big enough (and aligned enough) for one.
+         */
+        let align = max(layout.align(), size_of::<usize>());
+        self.stride.initialize(align_up(max(layout.size(), size_of::<usize>()), align));
+        self.layout.initialize(layout);
+        info!("Slab cache for {} objects has layout {:?}", self.name, layout);
+    }
+
+    /// Allocate an object from this cache, taking a fresh slab from `alloc_slab` if the cache is out of free
+    /// objects. Returns `None` if we're out of memory.
+    pub fn alloc<F>(&self, cpu: usize, alloc_slab: F) -> Option<NonNull<u8>>
+    where
+        F: Fn() -> Option<VirtualAddress>,
+    {
+        let magazine = &self.magazines[cpu % MAX_CPUS];
+
+        loop {
+            {
+                let mut magazine = magazine.lock();
+                if let Some(object) = magazine.pop() {
+                    return Some(object);
+                }
+
+                /*
+                 * The magazine is empty, so refill it from the depot. We only fill it halfway, so that freeing a
+                 * few objects doesn't immediately need to move them back to the depot.
+                 */
+                let mut depot = self.depot.lock();
+                while magazine.count < MAGAZINE_SIZE / 2 {
+                    match depot.pop() {
+                        Some(object) => magazine.push(object),
+                        None => break,
+                    }
+                }
+
+                if let Some(object) = magazine.pop() {
+                    return Some(object);
+                }
+            }
+
+            /*
+             * The depot is empty too, so we need a fresh slab. We don't hold any of the cache's locks while
+             * allocating it, so the platform is free to make allocations (that may need this cache) to do so.
+             * Slabs are page-aligned, so are aligned enough for any object.
+             */
+            let stride = *self.stride.get();
+            let slab = alloc_slab()?;
+
+            let mut depot = self.depot.lock();
+            for i in 0..(SLAB_SIZE / stride) {
+                unsafe {
+                    depot.push(NonNull::new_unchecked((slab + i * stride).mut_ptr()));
+                }
+            }
+        }
+    }
+
+    /// Free an object back into this cache.
+    ///
+    /// # Safety
+    /// `object` must be a block of memory with the layout of this cache's objects, and must not be used after it
+    /// has been freed.
+    pub unsafe fn free(&self, cpu: usize, object: NonNull<u8>) {
+        let mut magazine = self.magazines[cpu % MAX_CPUS].lock();
+
+        if magazine.count == MAGAZINE_SIZE {
+            let mut depot = self.depot.lock();
+            while magazine.count > MAGAZINE_SIZE / 2 {
+                depot.push(magazine.pop().unwrap());
+            }
+        }
+
+        magazine.push(object);
+    }
+}
+
+/// A small stack of free objects that belongs to a single CPU.
+struct Magazine {
+    objects: [Option<NonNull<u8>>; MAGAZINE_SIZE],
+    count: usize,
+}
+
+/*
+ * The objects in a magazine aren't owned by anything else, so can be moved between CPUs.
+ */
+unsafe impl Send for Magazine {}
+
+impl Magazine {
+    const fn new() -> Magazine {
+        Magazine { objects: [None; MAGAZINE_SIZE], count: 0 }
+    }
+
+    fn push(&mut self, object: NonNull<u8>) {
+        self.objects[self.count] = Some(object);
+        self.count += 1;
+    }
+
+    fn pop(&mut self) -> Option<NonNull<u8>> {
+        if self.count == 0 {
+            return None;
+        }
+
+        self.count -= 1;
+        self.objects[self.count].take()
+    }
+}
+
+/// The free objects of a cache that aren't in a magazine. These are kept in a list threaded through the objects
+/// themselves, so the depot can hold any number of objects without allocating.
+struct Depot {
+    head: Option<NonNull<u8>>,
+}
+
+unsafe impl Send for Depot {}
+
+impl Depot {
+    const fn new() -> Depot {
+        Depot { head: None }
+    }
+
+    /// Add a free object to the depot.
+    ///
+    /// # Safety
+    /// `object` must be a free object of the cache this depot belongs to.
+    unsafe fn push(&mut self, object: NonNull<u8>) {
+        *(object.as_ptr() as *mut Option<NonNull<u8>>) = self.head;
+        self.head = Some(object);
+    }
+
+    fn pop(&mut self) -> Option<NonNull<u8>> {
+        let object = self.head?;
+        self.head = unsafe { *(object.as_ptr() as *const Option<NonNull<u8>>) };
+        Some(object)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{boxed::Box, vec::Vec};
+    use core::cell::Cell;
+
+    #[repr(C, align(4096))]
+    struct Slab([u8; SLAB_SIZE]);
+
+    #[test]
+    fn test_slab_cache() {
+        /*
+         * Only hand out a single slab.
+         */
+        let mut slab = Box::new(Slab([0; SLAB_SIZE]));
+        let slab_address = VirtualAddress::from(slab.0.as_mut_ptr());
+        let slab_taken = Cell::new(false);
+        let alloc_slab = || if slab_taken.replace(true) { None } else { Some(slab_address) };
+
+        let cache = SlabCache::new("Test");
+        cache.init(Layout::from_size_align(48, 16).unwrap());
+        let objects_per_slab = SLAB_SIZE / 48;
+
+        /*
+         * Allocate every object from the first slab, and check they don't overlap.
+         */
+        let mut objects: Vec<NonNull<u8>> =
+            (0..objects_per_slab).map(|_| cache.alloc(0, &alloc_slab).unwrap()).collect();
+        let mut addresses: Vec<usize> = objects.iter().map(|object| object.as_ptr() as usize).collect();
+        addresses.sort();
+        assert!(addresses.windows(2).all(|pair| pair[1] - pair[0] >= 48));
+        assert!(addresses.iter().all(|address| address % 16 == 0));
+
+        /*
+         * There isn't another slab, so allocating another object should fail, but objects that are freed
+         * should be reused.
+         */
+        assert_eq!(cache.alloc(0, &alloc_slab), None);
+        let freed = objects.pop().unwrap();
+        unsafe {
+            cache.free(0, freed);
+        }
+        assert_eq!(cache.alloc(0, &alloc_slab), Some(freed));
+    }
+}
@@ -1,10 +1,11 @@
 use super::{alloc_kernel_object_id, memory_object::MemoryObject, KernelObject, KernelObjectId};
 use crate::{
-    memory::{PhysicalMemoryManager, Stack},
+    memory::{KernelStack, PhysicalAllocation, PhysicalMemoryManager, Stack},
     Platform,
 };
 use alloc::{sync::Arc, vec::Vec};
-use hal::memory::{mebibytes, Bytes, FrameAllocator, PageTable, VirtualAddress};
+use core::sync::atomic::{AtomicU64, Ordering};
+use hal::memory::{mebibytes, Bytes, FrameAllocator, FrameSize, Page, PageTable, Size4KiB, VirtualAddress};
 use libpebble::syscall::MapMemoryObjectError;
 use pebble_util::bitmap::Bitmap;
 use spin::Mutex;
@@ -17,15 +18,12 @@ const USER_STACK_BOTTOM: VirtualAddress = VirtualAddress::new(0x00000002_0000000
 const USER_STACK_TOP: VirtualAddress = VirtualAddress::new(0x00000003_ffffffff);
 const USER_STACK_SLOT_SIZE: Bytes = mebibytes(4);
 
-#[derive(PartialEq, Eq, Debug)]
-pub enum State {
-    NotActive,
-    Active,
-}
-
 pub struct TaskSlot {
     pub index: usize,
     pub user_stack: Stack,
+    /// The memory backing the user stack. This is freed when the slot is dropped, so the slot must be freed with
+    /// `AddressSpace::free_task_slot` (which unmaps the stack) first.
+    user_stack_memory: PhysicalAllocation,
 }
 
 pub struct AddressSpace<P>
@@ -34,7 +32,11 @@ where
 {
     pub id: KernelObjectId,
     pub owner: KernelObjectId,
-    pub state: Mutex<State>,
+    /// A bitmap of the CPUs this address space is currently installed on.
+    active_cpus: AtomicU64,
+    /// A bitmap of the CPUs whose TLBs may hold out-of-date entries for this address space. When this address
+    /// space is next switched to on one of these CPUs, its TLB entries must be flushed.
+    stale_cpus: AtomicU64,
     pub memory_objects: Mutex<Vec<Arc<MemoryObject>>>,
     page_table: Mutex<P::PageTable>,
     slot_bitmap: Mutex<u64>,
@@ -51,7 +53,12 @@ where
         Arc::new(AddressSpace {
             id: alloc_kernel_object_id(),
             owner,
-            state: Mutex::new(State::NotActive),
+            active_cpus: AtomicU64::new(0),
+            /*
+             * The platform may reuse the TLB tags of address spaces that no longer exist, so any CPU could have
+             * entries left over from the last address space that used them.
+             */
+            stale_cpus: AtomicU64::new(!0),
             memory_objects: Mutex::new(vec![]),
             page_table: Mutex::new(P::PageTable::new_with_kernel_mapped(kernel_page_table, allocator)),
             slot_bitmap: Mutex::new(0),
@@ -90,6 +97,27 @@ where
         Ok(())
     }
 
+    /// Check that every page of the region `start..=end` is mapped into this address space, and can be accessed
+    /// by userspace. If `needs_write` is set, the pages must also be writable.
+    pub fn is_region_accessible(&self, start: VirtualAddress, end: VirtualAddress, needs_write: bool) -> bool {
+        let page_table = self.page_table.lock();
+        let mut page = Page::<Size4KiB>::contains(start);
+
+        while page.start <= end {
+            match page_table.translate(page.start) {
+                Some((_, flags)) if flags.user_accessible && (flags.writable || !needs_write) => (),
+                _ => return false,
+            }
+
+            match page.start.checked_add(Size4KiB::SIZE) {
+                Some(next) => page = Page::starts_with(next),
+                None => break,
+            }
+        }
+
+        true
+    }
+
     /// Try to allocate a slot for a Task. Creates a user stack with `initial_stack_size` bytes initially
     /// allocated. Returs `None` if no more tasks can be created in this Address Space.
     pub fn alloc_task_slot(
@@ -101,40 +129,80 @@ where
 
         let index = self.slot_bitmap.lock().alloc(1)?;
 
-        let user_stack = {
-            let slot_bottom = USER_STACK_BOTTOM + USER_STACK_SLOT_SIZE * index;
-            let top = slot_bottom + USER_STACK_SLOT_SIZE - 1;
-            let stack_bottom = (top + 1) - initial_stack_size;
-
-            let physical_start = allocator.alloc_bytes(initial_stack_size);
-            self.page_table
-                .lock()
-                .map_area(
-                    stack_bottom,
-                    physical_start,
-                    initial_stack_size,
-                    Flags { writable: true, user_accessible: true, ..Default::default() },
-                    allocator,
-                )
-                .unwrap();
-
-            Stack { top, slot_bottom, stack_bottom, physical_start }
-        };
+        let slot_bottom = USER_STACK_BOTTOM + USER_STACK_SLOT_SIZE * index;
+        let top = slot_bottom + USER_STACK_SLOT_SIZE - 1;
+        let stack_bottom = (top + 1) - initial_stack_size;
 
-        Some(TaskSlot { index, user_stack })
+        let user_stack_memory = allocator.alloc_bytes(initial_stack_size);
+        self.page_table
+            .lock()
+            .map_area(
+                stack_bottom,
+                user_stack_memory.start(),
+                initial_stack_size,
+                Flags { writable: true, user_accessible: true, ..Default::default() },
+                allocator,
+            )
+            .unwrap();
+
+        let user_stack = Stack { top, slot_bottom, stack_bottom, physical_start: user_stack_memory.start() };
+        Some(TaskSlot { index, user_stack, user_stack_memory })
+    }
+
+    /// Unmap the user stack of a task slot, and mark the slot as free so it can be used by another task. The
+    /// memory backing the stack is freed when the `TaskSlot` is dropped.
+    pub fn free_task_slot(&self, slot: &TaskSlot) {
+        let stack = &slot.user_stack;
+        let stack_size = usize::from(stack.top + 1) - usize::from(stack.stack_bottom);
+        self.page_table.lock().unmap_area(stack.stack_bottom, stack_size);
+        self.invalidate_tlbs(stack.stack_bottom, stack_size);
+
+        self.slot_bitmap.lock().free(slot.index, 1);
     }
 
+    /// Unmap a task's kernel stack. Every address space shares the kernel's mappings, so this unmaps the stack
+    /// from all of them. The stack's memory and slot are freed when the `KernelStack` is dropped.
+    pub fn free_kernel_stack(&self, kernel_stack: &KernelStack) {
+        let stack = &kernel_stack.stack;
+        let stack_size = usize::from(stack.top + 1) - usize::from(stack.stack_bottom);
+        self.page_table.lock().unmap_area(stack.stack_bottom, stack_size);
+        P::kernel_tlb_shootdown(stack.stack_bottom, stack_size);
+    }
+
+    /// Make sure no CPU's TLB holds an entry for the `size` bytes at `start` from before the mappings there were
+    /// removed or changed. This must be called after unmapping memory, or making a mapping more restrictive, and
+    /// before the memory that was mapped is reused.
+    ///
+    /// The page table invalidates the running CPU's entries itself, so this only deals with other CPUs. This is only
+    /// suitable for the userspace part of the address space - see `Platform::kernel_tlb_shootdown`.
+    fn invalidate_tlbs(&self, start: VirtualAddress, size: usize) {
+        /*
+         * Mark every CPU as stale before looking at which CPUs are active. A CPU that switches to this address
+         * space after we've looked will then flush its TLB, and every CPU it was active on before that is sent a
+         * shootdown.
+         */
+        self.stale_cpus.store(!0, Ordering::SeqCst);
+        let active_cpus = self.active_cpus.load(Ordering::SeqCst);
+        P::tlb_shootdown(active_cpus & !(1 << P::current_cpu_id()), start, size);
+    }
+
+    /// Install this address space on the running CPU.
     pub fn switch_to(&self) {
-        assert_eq!(*self.state.lock(), State::NotActive);
+        let cpu_bit = 1 << P::current_cpu_id();
+        let previously_active = self.active_cpus.fetch_or(cpu_bit, Ordering::SeqCst);
+        assert_eq!(previously_active & cpu_bit, 0);
+
+        let stale = self.stale_cpus.fetch_and(!cpu_bit, Ordering::SeqCst) & cpu_bit != 0;
         unsafe {
-            self.page_table.lock().switch_to();
+            self.page_table.lock().switch_to(stale);
         }
-        *self.state.lock() = State::Active;
     }
 
+    /// Called when this address space is uninstalled from the running CPU.
     pub fn switch_from(&self) {
-        assert_eq!(*self.state.lock(), State::Active);
-        *self.state.lock() = State::NotActive;
+        let cpu_bit = 1 << P::current_cpu_id();
+        let previously_active = self.active_cpus.fetch_and(!cpu_bit, Ordering::SeqCst);
+        assert_ne!(previously_active & cpu_bit, 0);
     }
 }
 
@@ -1,19 +1,51 @@
-use super::{alloc_kernel_object_id, KernelObject, KernelObjectId};
+use super::{alloc_kernel_object_id, task::Wake, KernelObject, KernelObjectId};
 use alloc::{
+    boxed::Box,
     collections::VecDeque,
     sync::{Arc, Weak},
     vec::Vec,
 };
-use libpebble::syscall::{GetMessageError, SendMessageError, CHANNEL_MAX_NUM_HANDLES};
+use core::sync::atomic::{AtomicU16, Ordering};
+use libpebble::syscall::{GetMessageError, SendMessageError, TransactionId, CHANNEL_MAX_NUM_HANDLES};
 use log::warn;
 use spin::Mutex;
 
 pub struct ChannelEnd {
     pub id: KernelObjectId,
     pub owner: KernelObjectId,
-    messages: Mutex<VecDeque<Message>>,
+    /// The messages waiting to be received. These are boxed, so they're allocated from the `Message` slab cache,
+    /// and so moving them around the queue is cheap.
+    messages: Mutex<VecDeque<Box<Message>>>,
     /// The other end of the channel. If this is `None`, the channel's messages come from the kernel.
     other_end: Option<Weak<ChannelEnd>>,
+    /// The next transaction ID to allocate to a call made through this end. `0` is never allocated.
+    next_transaction_id: AtomicU16,
+    /// The tasks waiting for messages to arrive at this end. They're woken when a message they're waiting for is
+    /// added, and callers are also woken if the other end is dropped.
+    waiters: Mutex<Vec<Waiter>>,
+}
+
+struct Waiter {
+    /// The transaction this task has made a call for, and is waiting for the reply to. If this is `0`, the task is
+    /// waiting for any message that isn't a reply.
+    transaction_id: TransactionId,
+    task: Arc<dyn Wake>,
+}
+
+/// Registers a task as waiting on a `ChannelEnd`, until this is dropped. See `ChannelEnd::wait`.
+pub struct WaitGuard<'a> {
+    channel: &'a ChannelEnd,
+    transaction_id: TransactionId,
+    task_id: KernelObjectId,
+}
+
+impl<'a> Drop for WaitGuard<'a> {
+    fn drop(&mut self) {
+        self.channel
+            .waiters
+            .lock()
+            .retain(|waiter| !(waiter.transaction_id == self.transaction_id && waiter.task.id() == self.task_id));
+    }
 }
 
 impl ChannelEnd {
@@ -23,6 +55,8 @@ impl ChannelEnd {
             owner,
             messages: Mutex::new(VecDeque::new()),
             other_end: Some(Weak::default()),
+            next_transaction_id: AtomicU16::new(1),
+            waiters: Mutex::new(Vec::new()),
         });
 
         let end_b = Arc::new(ChannelEnd {
@@ -30,6 +64,8 @@ impl ChannelEnd {
             owner,
             messages: Mutex::new(VecDeque::new()),
             other_end: Some(Arc::downgrade(&end_a)),
+            next_transaction_id: AtomicU16::new(1),
+            waiters: Mutex::new(Vec::new()),
         });
 
         // TODO: is there a nicer way of doing this?
@@ -46,29 +82,74 @@ impl ChannelEnd {
             owner,
             messages: Mutex::new(VecDeque::new()),
             other_end: None,
+            next_transaction_id: AtomicU16::new(1),
+            waiters: Mutex::new(Vec::new()),
         })
     }
 
+    /// Get the ID of the other end of the Channel. Returns `None` if this is a kernel channel, or if the other end
+    /// has been dropped.
+    pub fn other_end_id(&self) -> Option<KernelObjectId> {
+        self.other_end.as_ref().and_then(|other_end| other_end.upgrade()).map(|other_end| other_end.id)
+    }
+
+    /// Allocate a transaction ID for a call made through this `ChannelEnd`. These wrap around, but skip `0`, which
+    /// is used to mark messages that aren't part of a transaction.
+    pub fn alloc_transaction_id(&self) -> TransactionId {
+        loop {
+            let id = self.next_transaction_id.fetch_add(1, Ordering::Relaxed);
+            if id != 0 {
+                return id;
+            }
+        }
+    }
+
     /// Add a message *to* this `ChannelEnd`. Use `send` if you want to send a message *through* this
-    /// `ChannelEnd` (i.e. to the other end of the Channel).
-    pub fn add_message(&self, message: Message) {
-        self.messages.lock().push_back(message);
+    /// `ChannelEnd` (i.e. to the other end of the Channel). The tasks waiting for the message are woken, and the
+    /// ID of one of them is returned, so the CPU can be handed straight to it.
+    pub fn add_message(&self, message: Message) -> Option<KernelObjectId> {
+        /*
+         * A reply wakes the task that made the call. Any other message wakes the tasks waiting for messages.
+         */
+        let wake_transaction = if message.is_reply { message.transaction_id } else { 0 };
+        self.messages.lock().push_back(Box::new(message));
+
+        let mut woken = None;
+        for waiter in self.waiters.lock().iter().filter(|waiter| waiter.transaction_id == wake_transaction) {
+            waiter.task.wake();
+            woken.get_or_insert(waiter.task.id());
+        }
+        woken
     }
 
     /// Send a message through this `ChannelEnd`, to be received by the other end. If this is a kernel channel, the
-    /// message is discarded.
-    pub fn send(&self, message: Message) -> Result<(), SendMessageError> {
+    /// message is discarded. Returns the ID of a task woken to receive the message, if there is one.
+    pub fn send(&self, message: Message) -> Result<Option<KernelObjectId>, SendMessageError> {
         if let Some(ref other_end) = self.other_end {
             match other_end.upgrade() {
-                Some(other_end) => {
-                    other_end.add_message(message);
-                    Ok(())
-                }
+                Some(other_end) => Ok(other_end.add_message(message)),
                 None => Err(SendMessageError::OtherEndDisconnected),
             }
         } else {
             warn!("Discarding message sent down kernel channel");
-            Ok(())
+            Ok(None)
+        }
+    }
+
+    /// Register `task` as waiting for a message to arrive at this end, until the returned guard is dropped. If
+    /// `transaction_id` isn't `0`, the task is waiting for the reply to that call, which is then only received by
+    /// `receive_reply`. The task should be registered before it checks for the message, so it can't be missed.
+    pub fn wait(&self, transaction_id: TransactionId, task: Arc<dyn Wake>) -> WaitGuard<'_> {
+        let task_id = task.id();
+        self.waiters.lock().push(Waiter { transaction_id, task });
+        WaitGuard { channel: self, transaction_id, task_id }
+    }
+
+    /// Wake the tasks waiting for replies to calls made through this end. This is done when the other end is
+    /// dropped, as nothing can reply to them now.
+    fn wake_callers(&self) {
+        for waiter in self.waiters.lock().iter().filter(|waiter| waiter.transaction_id != 0) {
+            waiter.task.wake();
         }
     }
 
@@ -77,21 +158,67 @@ impl ChannelEnd {
     /// fails (for example, the buffer to put it into is too small), the passed function can return it with
     /// `Err((message, some_error))`, and the message will be placed back into the queue (preserving message
     /// order), and the error will be returned.
+    ///
+    /// Replies to calls that are still waiting for them are skipped, so they're only received by the caller.
     pub fn receive<F, R>(&self, f: F) -> Result<R, GetMessageError>
     where
         F: FnOnce(Message) -> Result<R, (Message, GetMessageError)>,
     {
         let mut message_queue = self.messages.lock();
-        match f(message_queue.pop_front().ok_or(GetMessageError::NoMessage)?) {
+        let index = {
+            let waiters = self.waiters.lock();
+            message_queue
+                .iter()
+                .position(|message| {
+                    !message.is_reply
+                        || !waiters.iter().any(|waiter| waiter.transaction_id == message.transaction_id)
+                })
+                .ok_or(GetMessageError::NoMessage)?
+        };
+
+        match f(*message_queue.remove(index).unwrap()) {
+            Ok(value) => Ok(value),
+            Err((message, err)) => {
+                message_queue.insert(index, Box::new(message));
+                Err(err)
+            }
+        }
+    }
+
+    /// Like `receive`, but only considers the reply to the transaction with the given ID, leaving any other
+    /// messages in the queue. Returns `GetMessageError::NoMessage` if the reply hasn't arrived yet.
+    pub fn receive_reply<F, R>(&self, transaction_id: TransactionId, f: F) -> Result<R, GetMessageError>
+    where
+        F: FnOnce(Message) -> Result<R, (Message, GetMessageError)>,
+    {
+        let mut message_queue = self.messages.lock();
+        let index = message_queue
+            .iter()
+            .position(|message| message.is_reply && message.transaction_id == transaction_id)
+            .ok_or(GetMessageError::NoMessage)?;
+
+        match f(*message_queue.remove(index).unwrap()) {
             Ok(value) => Ok(value),
             Err((message, err)) => {
-                message_queue.push_front(message);
+                message_queue.insert(index, Box::new(message));
                 Err(err)
             }
         }
     }
 }
 
+impl Drop for ChannelEnd {
+    fn drop(&mut self) {
+        /*
+         * Nothing can reply to calls made through the other end now, so wake up any tasks waiting for replies, so
+         * they can return an error.
+         */
+        if let Some(other_end) = self.other_end.as_ref().and_then(Weak::upgrade) {
+            other_end.wake_callers();
+        }
+    }
+}
+
 impl KernelObject for ChannelEnd {
     fn id(&self) -> KernelObjectId {
         self.id
@@ -99,6 +226,11 @@ impl KernelObject for ChannelEnd {
 }
 
 pub struct Message {
+    /// If this message is part of a call, this is the ID of the transaction it belongs to. Otherwise, it's `0`.
+    pub transaction_id: TransactionId,
+    /// Whether this message is the reply to a call, rather than the call itself. Transaction IDs are only unique
+    /// to the end that made the call, so a reply can't be told apart from a call made by the other end otherwise.
+    pub is_reply: bool,
     pub bytes: Vec<u8>,
     /// The actual objects extracted from the handles transferred by a message. When a task receives this message,
     /// these objects are added to that task, and the new handles are put into the message. The non-`None` entries
@@ -0,0 +1,24 @@
+use super::{alloc_kernel_object_id, KernelObject, KernelObjectId};
+use alloc::sync::Arc;
+
+/// A range of I/O ports that can be accessed by a device driver. These are created for a device's I/O BARs by
+/// `pci_get_info`, and for the I/O resources of ACPI devices by `acpi_get_info`. Once a task has mapped one with
+/// `map_io_port_range`, it can access the ports directly with `in` and `out` instructions.
+pub struct IoPortRange {
+    pub id: KernelObjectId,
+    pub owner: KernelObjectId,
+    pub base: u16,
+    pub length: u16,
+}
+
+impl IoPortRange {
+    pub fn new(owner: KernelObjectId, base: u16, length: u16) -> Arc<IoPortRange> {
+        Arc::new(IoPortRange { id: alloc_kernel_object_id(), owner, base, length })
+    }
+}
+
+impl KernelObject for IoPortRange {
+    fn id(&self) -> KernelObjectId {
+        self.id
+    }
+}
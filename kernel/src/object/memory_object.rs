@@ -1,9 +1,12 @@
 use super::{alloc_kernel_object_id, KernelObject, KernelObjectId};
-use alloc::sync::Arc;
+use crate::memory::PhysicalAllocation;
+use alloc::{sync::Arc, vec::Vec};
+use core::ops::Range;
 use hal::{
     boot_info::Segment,
-    memory::{Flags, PhysicalAddress, VirtualAddress},
+    memory::{Flags, FrameSize, PhysicalAddress, Size4KiB, VirtualAddress},
 };
+use pebble_util::math::align_up;
 
 pub struct MemoryObject {
     pub id: KernelObjectId,
@@ -15,9 +18,14 @@ pub struct MemoryObject {
     /// Size of this MemoryObject in bytes.
     pub size: usize,
     pub flags: Flags,
+    /// If the memory was allocated for this `MemoryObject`, this owns the allocation, and so the memory is freed
+    /// when the `MemoryObject` is dropped. `MemoryObject`s that describe memory that they don't own (e.g. a
+    /// device's BARs, or the framebuffer) don't have an allocation, and their memory is never freed.
+    allocation: Option<PhysicalAllocation>,
 }
 
 impl MemoryObject {
+    /// Create a `MemoryObject` that describes memory it doesn't own, and so won't free when it's dropped.
     pub fn new(
         owner: KernelObjectId,
         virtual_address: Option<VirtualAddress>,
@@ -32,6 +40,26 @@ impl MemoryObject {
             physical_address,
             size,
             flags,
+            allocation: None,
+        })
+    }
+
+    /// Create a `MemoryObject` backed by memory allocated from the `PhysicalMemoryManager`, which is freed when
+    /// the `MemoryObject` is dropped.
+    pub fn from_allocation(
+        owner: KernelObjectId,
+        virtual_address: Option<VirtualAddress>,
+        allocation: PhysicalAllocation,
+        flags: Flags,
+    ) -> Arc<MemoryObject> {
+        Arc::new(MemoryObject {
+            id: alloc_kernel_object_id(),
+            owner,
+            virtual_address,
+            physical_address: allocation.start(),
+            size: allocation.size(),
+            flags,
+            allocation: Some(allocation),
         })
     }
 
@@ -43,8 +71,29 @@ impl MemoryObject {
             physical_address: segment.physical_address,
             size: segment.size,
             flags: segment.flags,
+            /*
+             * The loader allocates a separate area of memory for each segment, so the `MemoryObject` can take
+             * ownership of it, and free it when it's no longer needed.
+             */
+            allocation: Some(unsafe {
+                PhysicalAllocation::adopt(segment.physical_address..(segment.physical_address + segment.size))
+            }),
         })
     }
+
+    /// Returns `true` if the memory was allocated for this `MemoryObject`, rather than being memory it doesn't own,
+    /// such as a device's registers.
+    pub fn owns_memory(&self) -> bool {
+        self.allocation.is_some()
+    }
+
+    /// The areas of physical memory that make up this `MemoryObject`, each rounded up to whole frames.
+    pub fn physical_ranges(&self) -> Vec<Range<PhysicalAddress>> {
+        match self.allocation {
+            Some(ref allocation) => allocation.ranges().to_vec(),
+            None => vec![self.physical_address..(self.physical_address + align_up(self.size, Size4KiB::SIZE))],
+        }
+    }
 }
 
 impl KernelObject for MemoryObject {
@@ -1,6 +1,8 @@
 pub mod address_space;
 pub mod channel;
+pub mod io_port_range;
 pub mod memory_object;
+pub mod pci_device;
 pub mod task;
 
 use core::sync::atomic::{AtomicU64, Ordering};
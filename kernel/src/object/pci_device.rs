@@ -0,0 +1,57 @@
+use super::{alloc_kernel_object_id, memory_object::MemoryObject, KernelObject, KernelObjectId};
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::AtomicBool;
+use pci_types::PciAddress;
+use spin::Mutex;
+
+/// Gives access to the configuration space of a single PCI function. One of these is created for each function
+/// when the platform enumerates the PCI bus. A handle to each one is created by `pci_get_info` the first time it's
+/// called, and is passed to the PCI bus driver, which hands it to the Platform Bus, which then hands it to the
+/// device driver that claims the device. This means only the driver that owns a device can configure it.
+pub struct PciDevice {
+    pub id: KernelObjectId,
+    pub owner: KernelObjectId,
+    pub address: PciAddress,
+    /// Set when a handle to the device, and to its BARs, has been handed out by `pci_get_info`. Later calls still
+    /// describe the device, but don't give out any more handles to it.
+    pub claimed: AtomicBool,
+    /// The memory objects the device's driver has allowed it to access through DMA, using
+    /// `pci_attach_dma_memory`. We hold onto them so their memory can't be freed while the device can still
+    /// access it.
+    pub dma_memory: Mutex<Vec<Arc<MemoryObject>>>,
+}
+
+impl PciDevice {
+    pub fn new(owner: KernelObjectId, address: PciAddress) -> Arc<PciDevice> {
+        Arc::new(PciDevice {
+            id: alloc_kernel_object_id(),
+            owner,
+            address,
+            claimed: AtomicBool::new(false),
+            dma_memory: Mutex::new(Vec::new()),
+        })
+    }
+}
+
+impl KernelObject for PciDevice {
+    fn id(&self) -> KernelObjectId {
+        self.id
+    }
+}
+
+impl Drop for PciDevice {
+    fn drop(&mut self) {
+        /*
+         * The memory objects are about to be dropped, which may free their memory, so the device must not be able
+         * to access it any more.
+         */
+        if let Some(Some(iommu)) = crate::IOMMU.try_get() {
+            let mut iommu = iommu.lock();
+            for memory_object in self.dma_memory.get_mut().iter() {
+                for range in memory_object.physical_ranges() {
+                    iommu.unmap(self.address, range);
+                }
+            }
+        }
+    }
+}
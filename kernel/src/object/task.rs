@@ -1,25 +1,37 @@
 use super::{
     address_space::{AddressSpace, TaskSlot},
     alloc_kernel_object_id,
+    io_port_range::IoPortRange,
     memory_object::MemoryObject,
     KernelObject,
     KernelObjectId,
 };
 use crate::{
-    memory::{KernelStackAllocator, PhysicalMemoryManager, Stack},
+    memory::{KernelStack, KernelStackAllocator, PhysicalMemoryManager},
     Platform,
 };
-use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
 use core::{
     cell::UnsafeCell,
-    sync::atomic::{AtomicU32, Ordering},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
 };
 use hal::memory::VirtualAddress;
-use libpebble::{caps::Capability, Handle};
+use libpebble::{caps::Capability, syscall::TransactionId, Handle};
 use spin::{Mutex, RwLock};
 
 #[derive(Clone, PartialEq, Eq, Debug)]
-pub enum TaskBlock {}
+pub enum TaskBlock {
+    /// The task is waiting for a message to arrive on the `ChannelEnd` with the given ID.
+    WaitForMessage(KernelObjectId),
+    /// The task has made a call through the `ChannelEnd` with the given ID, and is waiting for the reply with the
+    /// given transaction ID.
+    WaitForReply(KernelObjectId, TransactionId),
+}
 
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub enum TaskState {
@@ -28,6 +40,15 @@ pub enum TaskState {
     Blocked(TaskBlock),
 }
 
+/// Lets kernel objects wake the tasks that are blocked on them. Kernel objects aren't generic over the platform,
+/// so they keep track of the tasks waiting on them through this.
+pub trait Wake: KernelObject {
+    /// Wake the task. This only touches the task itself, and not any scheduler, so it can be done from any CPU,
+    /// and from any context. If the task is blocked, the scheduler it's blocked on notices it's been woken the next
+    /// time it looks for something to run. If the task hasn't blocked yet, it won't (see `Task::prepare_to_wait`).
+    fn wake(&self);
+}
+
 #[derive(Debug)]
 pub enum TaskCreationError {
     /// The task name is not valid UTF-8.
@@ -52,15 +73,25 @@ where
     pub name: String,
     pub address_space: Arc<AddressSpace<P>>,
     pub state: Mutex<TaskState>,
+    /// Set when the task is woken, and cleared by `prepare_to_wait`. If this is set when the task tries to block,
+    /// it carries on running instead, so wakeups that arrive between it checking whether it needs to block and
+    /// actually blocking aren't lost.
+    wake_pending: AtomicBool,
     pub capabilities: Vec<Capability>,
 
     pub user_slot: Mutex<TaskSlot>,
-    pub kernel_stack: Mutex<Stack>,
+    pub kernel_stack: Mutex<KernelStack>,
     pub kernel_stack_pointer: UnsafeCell<VirtualAddress>,
     pub user_stack_pointer: UnsafeCell<VirtualAddress>,
 
     pub handles: RwLock<BTreeMap<Handle, Arc<dyn KernelObject>>>,
     next_handle: AtomicU32,
+
+    /// The I/O port ranges this task has mapped with `map_io_port_range`. The task can access these ports
+    /// directly while it's running.
+    pub io_port_ranges: Mutex<Vec<Arc<IoPortRange>>>,
+
+    pub stats: TaskStats,
 }
 
 /*
@@ -84,16 +115,21 @@ where
         kernel_stack_allocator: &mut KernelStackAllocator<P>,
     ) -> Result<Arc<Task<P>>, TaskCreationError> {
         let id = alloc_kernel_object_id();
+        let capabilities = decode_capabilities(&image.capability_stream)?;
 
         // TODO: better way of getting initial stack sizes
         let task_slot =
             address_space.alloc_task_slot(0x4000, allocator).ok_or(TaskCreationError::AddressSpaceFull)?;
-        let kernel_stack = kernel_stack_allocator
-            .alloc_kernel_stack(0x4000, allocator, kernel_page_table)
-            .ok_or(TaskCreationError::NoKernelStackSlots)?;
+        let kernel_stack = match kernel_stack_allocator.alloc_kernel_stack(0x4000, allocator, kernel_page_table) {
+            Some(kernel_stack) => kernel_stack,
+            None => {
+                address_space.free_task_slot(&task_slot);
+                return Err(TaskCreationError::NoKernelStackSlots);
+            }
+        };
 
         let (kernel_stack_pointer, user_stack_pointer) =
-            unsafe { P::initialize_task_stacks(&kernel_stack, &task_slot.user_stack, image.entry_point) };
+            unsafe { P::initialize_task_stacks(&kernel_stack.stack, &task_slot.user_stack, image.entry_point) };
 
         Ok(Arc::new(Task {
             id,
@@ -101,7 +137,8 @@ where
             name: String::from(image.name()),
             address_space,
             state: Mutex::new(TaskState::Ready),
-            capabilities: decode_capabilities(&image.capability_stream)?,
+            wake_pending: AtomicBool::new(false),
+            capabilities,
             user_slot: Mutex::new(task_slot),
             kernel_stack: Mutex::new(kernel_stack),
             kernel_stack_pointer: UnsafeCell::new(kernel_stack_pointer),
@@ -109,6 +146,8 @@ where
             handles: RwLock::new(BTreeMap::new()),
             // XXX: 0 is a special handle value, so start at 1
             next_handle: AtomicU32::new(1),
+            io_port_ranges: Mutex::new(Vec::new()),
+            stats: TaskStats::new(),
         }))
     }
 
@@ -117,6 +156,111 @@ where
         self.handles.write().insert(Handle(handle_num), object);
         Handle(handle_num)
     }
+
+    /// Called by the task before it checks whether it needs to block (e.g. whether a message has arrived yet).
+    /// Any wakeups after this stop the task from blocking, so it checks again instead.
+    pub fn prepare_to_wait(&self) {
+        self.wake_pending.store(false, Ordering::Release);
+    }
+
+    /// Whether the task has been woken since it last called `prepare_to_wait`. This must be checked with the lock
+    /// on `state` held, so a wakeup can't arrive between checking this and blocking.
+    pub fn wake_pending(&self) -> bool {
+        self.wake_pending.load(Ordering::Acquire)
+    }
+
+    /// Forget the mapped I/O port ranges that the task no longer has a handle to (e.g. because it's sent them to
+    /// another task), so its access to the ports is tied to its handles. Returns `true` if any were forgotten, in
+    /// which case the I/O ports the running task can access need to be updated.
+    pub fn forget_unreferenced_io_port_ranges(&self) -> bool {
+        let handles = self.handles.read();
+        let mut io_port_ranges = self.io_port_ranges.lock();
+        let num_ranges = io_port_ranges.len();
+        io_port_ranges.retain(|range| handles.values().any(|object| object.id() == range.id));
+        io_port_ranges.len() != num_ranges
+    }
+
+    /// The amount of CPU time this task has used, in the platform's timestamp units, including the time it has
+    /// been running for if it's currently running.
+    pub fn cpu_time(&self) -> u64 {
+        let cpu_time = self.stats.cpu_time.load(Ordering::Relaxed);
+
+        if *self.state.lock() == TaskState::Running {
+            cpu_time + (P::read_timestamp() - self.stats.running_since.load(Ordering::Relaxed))
+        } else {
+            cpu_time
+        }
+    }
+
+    /// The number of bytes of physical memory committed to `MemoryObject`s owned by this task. This counts
+    /// objects that are mapped into the task's address space, and objects the task has handles to, but each
+    /// object is only counted once.
+    pub fn committed_memory(&self) -> usize {
+        let mut counted = BTreeSet::new();
+        let mut committed = 0;
+
+        let mapped = self.address_space.memory_objects.lock().clone();
+        let handles = self.handles.read();
+        let held = handles.values().filter_map(|object| object.clone().downcast_arc::<MemoryObject>().ok());
+
+        for memory_object in mapped.into_iter().chain(held) {
+            if memory_object.owner == self.id && counted.insert(memory_object.id) {
+                committed += memory_object.size;
+            }
+        }
+
+        committed
+    }
+}
+
+/// Statistics about a task, which can be queried by userspace with the `get_task_info` system call. These are
+/// updated by the scheduler and the system call handler.
+pub struct TaskStats {
+    /// The amount of CPU time the task has used, in the platform's timestamp units. This is only updated when the
+    /// task is switched away from, and so doesn't include the time the task has been running for if it's currently
+    /// running - use `Task::cpu_time` for that.
+    cpu_time: AtomicU64,
+    /// The timestamp at which the task was last scheduled onto a CPU.
+    running_since: AtomicU64,
+    /// The number of times the task has been scheduled onto a CPU.
+    pub context_switches: AtomicU64,
+    pub syscalls: AtomicU64,
+}
+
+impl TaskStats {
+    fn new() -> TaskStats {
+        TaskStats {
+            cpu_time: AtomicU64::new(0),
+            running_since: AtomicU64::new(0),
+            context_switches: AtomicU64::new(0),
+            syscalls: AtomicU64::new(0),
+        }
+    }
+
+    /// Called by the scheduler when the task is scheduled onto a CPU at `timestamp`.
+    pub fn switched_to(&self, timestamp: u64) {
+        self.running_since.store(timestamp, Ordering::Relaxed);
+        self.context_switches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Called by the scheduler when the task is switched away from at `timestamp`.
+    pub fn switched_from(&self, timestamp: u64) {
+        let running_since = self.running_since.load(Ordering::Relaxed);
+        self.cpu_time.fetch_add(timestamp - running_since, Ordering::Relaxed);
+    }
+}
+
+impl<P> Drop for Task<P>
+where
+    P: Platform,
+{
+    fn drop(&mut self) {
+        /*
+         * Unmap the task's stacks, so their memory can be freed when the slot and kernel stack are dropped.
+         */
+        self.address_space.free_task_slot(self.user_slot.get_mut());
+        self.address_space.free_kernel_stack(self.kernel_stack.get_mut());
+    }
 }
 
 impl<P> KernelObject for Task<P>
@@ -128,6 +272,21 @@ where
     }
 }
 
+impl<P> Wake for Task<P>
+where
+    P: Platform,
+{
+    fn wake(&self) {
+        let mut state = self.state.lock();
+        self.wake_pending.store(true, Ordering::Release);
+
+        if let TaskState::Blocked(_) = *state {
+            *state = TaskState::Ready;
+            crate::scheduler::tasks_woken();
+        }
+    }
+}
+
 /// Decode a capability stream (as found in a task's image) into a set of capabilities as they're
 /// represented in the kernel. For the format that's being decoded here, refer to the
 /// `(3.1) Userspace/Capabilities` section of the Book.
@@ -151,6 +310,10 @@ fn decode_capabilities(mut cap_stream: &[u8]) -> Result<Vec<Capability>, TaskCre
             CAP_SERVICE_PROVIDER => one_byte_cap!(Capability::ServiceProvider),
             CAP_SERVICE_USER => one_byte_cap!(Capability::ServiceUser),
             CAP_PCI_BUS_DRIVER => one_byte_cap!(Capability::PciBusDriver),
+            CAP_SYSCALL_TRACING => one_byte_cap!(Capability::SyscallTracing),
+            CAP_READ_KERNEL_LOG => one_byte_cap!(Capability::ReadKernelLog),
+            CAP_POWER_CONTROL => one_byte_cap!(Capability::PowerControl),
+            CAP_ACPI_BUS_DRIVER => one_byte_cap!(Capability::AcpiBusDriver),
 
             // We skip `0x00` as the first byte of a capability, as it is just used to pad the
             // stream and so has no meaning
@@ -1,5 +1,22 @@
-use alloc::collections::BTreeMap;
-use pci_types::{Bar, BaseClass, DeviceId, DeviceRevision, Interface, PciAddress, SubClass, VendorId, MAX_BARS};
+use crate::object::pci_device;
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use bit_field::BitField;
+use core::ops::Range;
+use hal::memory::PhysicalAddress;
+use pci_types::{
+    BaseClass,
+    ConfigRegionAccess,
+    DeviceId,
+    DeviceRevision,
+    Interface,
+    PciAddress,
+    SubClass,
+    VendorId,
+    MAX_BARS,
+};
+
+/// The base class of bridges, including host bridges and PCI-to-PCI bridges.
+pub const BASE_CLASS_BRIDGE: BaseClass = 0x06;
 
 pub struct PciDevice {
     pub vendor_id: VendorId,
@@ -8,9 +25,110 @@ pub struct PciDevice {
     pub class: BaseClass,
     pub sub_class: SubClass,
     pub interface: Interface,
-    pub bars: [Option<Bar>; MAX_BARS],
+    pub bars: [Option<PciBar>; MAX_BARS],
+    /// The platform interrupt the function's interrupt pin is routed to. This is `None` if the function doesn't
+    /// use an interrupt pin, or if the platform couldn't work out how it's routed.
+    // TODO: this is only passed to userspace as a description. We need an interrupt object that the device's
+    // driver can claim, and that programs the IOAPIC and signals the driver when the interrupt fires.
+    pub interrupt: Option<PciInterrupt>,
+    /// The address of the PCI-to-PCI bridge the function is behind. This is `None` for functions on a root bus.
+    pub parent: Option<PciAddress>,
+    /// The kernel object that gives access to the function's configuration space. There's only one per function,
+    /// and `pci_get_info` only hands out a handle to it once. This is `None` for bridges, as we don't give out
+    /// access to them.
+    pub object: Option<Arc<pci_device::PciDevice>>,
+}
+
+/// A Base Address Register of a PCI function, which describes a range of memory or I/O ports the function's
+/// registers are accessed through. A 64-bit memory BAR takes up two BAR slots, and so the slot after it is
+/// always `None`.
+#[derive(Clone, Copy, Debug)]
+pub enum PciBar {
+    Memory32 { address: u32, size: u32, prefetchable: bool },
+    Memory64 { address: u64, size: u64, prefetchable: bool },
+    Io { port: u16, size: u16 },
+}
+
+/// A platform interrupt that a PCI function's legacy interrupt pin (`INTA#` to `INTD#`) is routed to. Functions
+/// that don't support MSI or MSI-X have to use these.
+#[derive(Clone, Copy, Debug)]
+pub struct PciInterrupt {
+    /// The Global System Interrupt the pin is connected to.
+    pub gsi: u32,
+    pub level_triggered: bool,
+    pub active_low: bool,
 }
 
+/// Describes the PCI functions on the platform. The topology of the PCI hierarchy can be reconstructed from the
+/// `parent` of each function, as bridges are also included.
 pub struct PciInfo {
     pub devices: BTreeMap<PciAddress, PciDevice>,
 }
+
+/// Find the capabilities in the standard capability list of `device`, which is a linked list in the first 256 bytes
+/// of configuration space. Returns the offset of each capability, and the first 32-bit register of it, which
+/// holds the capability's ID in bits `0..8`.
+pub fn capabilities(access: &dyn ConfigRegionAccess, device: PciAddress) -> Vec<(u16, u32)> {
+    let mut capabilities = Vec::new();
+
+    /*
+     * Bit 4 of the Status register says whether the list exists, and its first entry is pointed to from offset
+     * `0x34`. We limit how many entries we look at, in case the list loops.
+     */
+    if !unsafe { access.read(device, 0x04) }.get_bit(20) {
+        return capabilities;
+    }
+    let mut pointer = unsafe { access.read(device, 0x34) }.get_bits(0..8) as u16 & !0b11;
+    for _ in 0..48 {
+        if pointer < 0x40 {
+            break;
+        }
+
+        let header = unsafe { access.read(device, pointer) };
+        capabilities.push((pointer, header));
+        pointer = header.get_bits(8..16) as u16 & !0b11;
+    }
+
+    capabilities
+}
+
+/// Returns `true` if the 32-bit register at `offset` in the configuration space of `device` is part of one of the
+/// capabilities drivers are allowed to configure: power management, MSI, and MSI-X. The other capabilities either
+/// affect how the device decodes its BARs (e.g. Resizable BAR and SR-IOV), or are managed by the platform, so
+/// drivers can't write to them.
+pub fn is_driver_capability_register(access: &dyn ConfigRegionAccess, device: PciAddress, offset: u16) -> bool {
+    const CAPABILITY_POWER_MANAGEMENT: u32 = 0x01;
+    const CAPABILITY_MSI: u32 = 0x05;
+    const CAPABILITY_MSI_X: u32 = 0x11;
+
+    capabilities(access, device).into_iter().any(|(pointer, header)| {
+        let size = match header.get_bits(0..8) {
+            CAPABILITY_POWER_MANAGEMENT => 8,
+            CAPABILITY_MSI => {
+                /*
+                 * The size of the MSI capability depends on whether it supports 64-bit addresses, and whether
+                 * it supports masking each vector.
+                 */
+                let control = header.get_bits(16..32);
+                let address_size = if control.get_bit(7) { 8 } else { 4 };
+                let masking_size = if control.get_bit(8) { 10 } else { 0 };
+                6 + address_size + masking_size
+            }
+            CAPABILITY_MSI_X => 12,
+            _ => 0,
+        };
+        offset >= pointer && offset < pointer + size
+    })
+}
+
+/// Implemented by platforms with an IOMMU, which restricts the memory each PCI function can access through DMA.
+/// Each function gets its own domain, which starts off empty, and so the function can't access any memory until
+/// some is mapped into it. Functions the IOMMU can't tell apart (e.g. those behind a PCIe-to-PCI bridge, whose DMA
+/// all looks like it comes from the bridge) have to share a domain. Memory is mapped at the same addresses as its physical addresses, so drivers can keep
+/// giving devices physical addresses.
+pub trait Iommu: Send {
+    /// Allow `device` to access the memory in `range` through DMA. `range` must be page-aligned.
+    fn map(&mut self, device: PciAddress, range: Range<PhysicalAddress>, writable: bool);
+    /// Stop `device` from accessing the memory in `range`. This must be called before the memory is freed.
+    fn unmap(&mut self, device: PciAddress, range: Range<PhysicalAddress>);
+}
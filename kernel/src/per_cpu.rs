@@ -1,4 +1,5 @@
-use crate::{scheduler::Scheduler, Platform};
+use crate::{object::io_port_range::IoPortRange, scheduler::Scheduler, Platform};
+use alloc::sync::Arc;
 use core::pin::Pin;
 use hal::memory::VirtualAddress;
 
@@ -10,4 +11,7 @@ where
     fn set_kernel_stack_pointer(self: Pin<&mut Self>, stack_pointer: VirtualAddress);
     fn get_user_stack_pointer(self: Pin<&mut Self>) -> VirtualAddress;
     fn set_user_stack_pointer(self: Pin<&mut Self>, stack_pointer: VirtualAddress);
+    /// Allow the running task to access the I/O ports in `ranges`, and no others. This is called each time we
+    /// switch to a task, and when the running task maps a new range. Platforms without I/O ports can ignore this.
+    fn set_io_port_ranges(self: Pin<&mut Self>, ranges: &[Arc<IoPortRange>]);
 }
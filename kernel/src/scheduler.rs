@@ -1,12 +1,34 @@
 use crate::{
-    object::task::{Task, TaskState},
+    memory::KernelStack,
+    object::{
+        task::{Task, TaskState},
+        KernelObject,
+        KernelObjectId,
+    },
     per_cpu::PerCpu,
     Platform,
 };
 use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
 use hal::memory::VirtualAddress;
 use log::trace;
 
+/// How long a task is allowed to run for before it's preempted, if there are other tasks waiting to run. If
+/// there aren't, the running task is left to run until it blocks or yields, and the CPU isn't interrupted at all.
+pub const TIME_SLICE: Duration = Duration::from_millis(10);
+
+/// Incremented each time a blocked task is woken. Each scheduler remembers the value it last saw, so it only has
+/// to look through its blocked queue for woken tasks when something has actually been woken.
+static WAKE_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Called when a blocked task is woken (see `Task::wake`).
+pub(crate) fn tasks_woken() {
+    WAKE_GENERATION.fetch_add(1, Ordering::Release);
+}
+
 pub struct Scheduler<P>
 where
     P: Platform,
@@ -15,7 +37,18 @@ where
     /// List of Tasks ready to be scheduled. Every kernel object in this list must be a Task.
     /// Backed by a `VecDeque` so we can rotate objects in the queue efficiently.
     ready_queue: VecDeque<Arc<Task<P>>>,
+    /// The tasks that have blocked on this CPU. Tasks are woken without touching the scheduler, by moving them to
+    /// `TaskState::Ready`, so this can contain tasks that are ready to run until `collect_woken` is called.
     blocked_queue: Vec<Arc<Task<P>>>,
+    /// The value of `WAKE_GENERATION` when we last looked for woken tasks.
+    seen_wake_generation: u64,
+
+    /// Each CPU has an idle context, which is switched to when there's nothing to run. It runs on its own kernel
+    /// stack, and waits for interrupts in a platform-specific loop until a task becomes ready (see
+    /// `Platform::initialize_idle_stack`). This is `None` until `create_idle_context` has been called.
+    idle_stack: Option<KernelStack>,
+    idle_kernel_stack_pointer: VirtualAddress,
+    preemption_timer_armed: bool,
 }
 
 impl<P> Scheduler<P>
@@ -23,13 +56,31 @@ where
     P: Platform,
 {
     pub fn new() -> Scheduler<P> {
-        Scheduler { running_task: None, ready_queue: VecDeque::new(), blocked_queue: Vec::new() }
+        Scheduler {
+            running_task: None,
+            ready_queue: VecDeque::new(),
+            blocked_queue: Vec::new(),
+            seen_wake_generation: 0,
+            idle_stack: None,
+            idle_kernel_stack_pointer: VirtualAddress::new(0x0),
+            preemption_timer_armed: false,
+        }
+    }
+
+    /// Create this CPU's idle context on `stack`. This must be done before any task can block.
+    pub fn create_idle_context(&mut self, stack: KernelStack) {
+        assert!(self.idle_stack.is_none());
+        self.idle_kernel_stack_pointer = unsafe { P::initialize_idle_stack(&stack.stack) };
+        self.idle_stack = Some(stack);
     }
 
     pub fn add_task(&mut self, task: Arc<Task<P>>) {
         let current_state = task.state.lock().clone();
         match current_state {
-            TaskState::Ready => self.ready_queue.push_back(task),
+            TaskState::Ready => {
+                self.ready_queue.push_back(task);
+                self.ready_queue_grew();
+            }
             TaskState::Blocked(_) => self.blocked_queue.push(task),
             TaskState::Running => panic!("Tried to schedule task that's already running!"),
         }
@@ -52,8 +103,11 @@ where
         trace!("Dropping into usermode into task: '{}'", task.name);
 
         *task.state.lock() = TaskState::Running;
+        task.stats.switched_to(P::read_timestamp());
         self.running_task = Some(task.clone());
         task.address_space.switch_to();
+        self.update_preemption_timer();
+        P::per_cpu().set_io_port_ranges(&task.io_port_ranges.lock());
 
         unsafe {
             let kernel_stack_pointer: VirtualAddress = *task.kernel_stack_pointer.get();
@@ -66,70 +120,203 @@ where
         }
     }
 
-    /// Switch to the next scheduled task. This is called when a task yields, or when we pre-empt a
-    /// task that is hogging CPU time. If there is nothing to schedule, this is free to idle the
-    /// CPU (including managing power), or steal work from another scheduling unit.
+    /// Switch to the next scheduled task. This is called when a task yields or blocks, or when we pre-empt a
+    /// task that has used up its time slice. If there is nothing else to run, a task that is yielding is returned
+    /// to, and otherwise we switch to the idle context until there is something to run.
     ///
     /// The task being switched away from is moved to state `new_state` (this allows you to block the current task.
     /// If it's just being preempted or has yielded, use `TaskState::Ready`).
     pub fn switch_to_next(&mut self, new_state: TaskState) {
         assert!(self.running_task.is_some());
 
+        /*
+         * A task that's blocking is marked as blocked straight away, so any wakeup from now on moves it back to
+         * `Ready`. If it's already been woken since it called `prepare_to_wait`, it doesn't block at all, and
+         * carries on running so it can check again for whatever it was waiting for.
+         */
+        if let TaskState::Blocked(ref block) = new_state {
+            let task = self.running_task.as_ref().unwrap();
+            let mut state = task.state.lock();
+            if task.wake_pending() {
+                return;
+            }
+            *state = TaskState::Blocked(block.clone());
+        }
+
         /*
          * Select the next task to run.
          * NOTE: in the future, this could be more complex, e.g. by taking priority into account.
          */
-        if let Some(next_task) = self.choose_next() {
-            /*
-             * We're switching task! We sort out the internal scheduler state, and then ask the
-             * platform to perform the context switch for us!
-             * NOTE: This temporarily allows `running_task` to be `None`.
-             */
-            trace!("Switching to task: {}", next_task.name);
-            let old_task = self.running_task.take().unwrap();
-            assert_eq!(*old_task.state.lock(), TaskState::Running);
-            assert_eq!(*next_task.state.lock(), TaskState::Ready);
-
-            self.running_task = Some(next_task.clone());
-            *self.running_task.as_ref().unwrap().state.lock() = TaskState::Running;
-            match new_state {
-                TaskState::Running => panic!("Tried to switch away from a task to state of Running!"),
-                TaskState::Ready => {
-                    *old_task.state.lock() = TaskState::Ready;
+        let next_task = self.choose_next();
+        if next_task.is_none() && new_state == TaskState::Ready {
+            trace!("No more schedulable tasks. Returning to current one!");
+            return;
+        }
+
+        /*
+         * We're switching away from the current task! We sort out the internal scheduler state, and then ask the
+         * platform to perform the context switch for us.
+         * NOTE: This allows `running_task` to be `None`, either temporarily or while we're idle.
+         */
+        let old_task = self.running_task.take().unwrap();
+
+        let now = P::read_timestamp();
+        old_task.stats.switched_from(now);
+
+        match new_state {
+            TaskState::Running => panic!("Tried to switch away from a task to state of Running!"),
+            TaskState::Ready => {
+                let mut state = old_task.state.lock();
+                assert_eq!(*state, TaskState::Running);
+                *state = TaskState::Ready;
+                drop(state);
+                self.ready_queue.push_back(old_task.clone());
+            }
+            TaskState::Blocked(_) => {
+                /*
+                 * The task may have been woken since it was marked as blocked, in which case it's ready to run
+                 * again. Otherwise, it's picked up from the blocked queue by `collect_woken` when it's woken. We
+                 * hold the lock while we decide, so the wakeup can't be missed by both.
+                 */
+                let state = old_task.state.lock();
+                if *state == TaskState::Ready {
                     self.ready_queue.push_back(old_task.clone());
-                }
-                TaskState::Blocked(block) => {
+                } else {
                     trace!("Blocking task: {}", old_task.name);
-                    *old_task.state.lock() = TaskState::Blocked(block);
                     self.blocked_queue.push(old_task.clone());
                 }
             }
+        }
+
+        old_task.address_space.switch_from();
+        let old_kernel_stack: *mut VirtualAddress = old_task.kernel_stack_pointer.get();
+        unsafe {
+            *old_task.user_stack_pointer.get() = P::per_cpu().get_user_stack_pointer();
+        }
+
+        match next_task {
+            Some(next_task) => unsafe { self.switch_to(next_task, now, old_kernel_stack) },
+            None => {
+                /*
+                 * Nothing can run, so we idle until something becomes ready. There's no time slice to end, so
+                 * this stops the timer, and the CPU isn't woken up needlessly.
+                 */
+                trace!("No more schedulable tasks. Idling!");
+                assert!(self.idle_stack.is_some(), "Tried to idle before the idle context was created!");
+                self.update_preemption_timer();
+                unsafe {
+                    P::context_switch(old_kernel_stack, self.idle_kernel_stack_pointer);
+                }
+            }
+        }
+    }
+
+    /// Called from the idle context, to switch to the next ready task if there is one. If there isn't, this
+    /// returns, and the idle context should wait for an interrupt before trying again.
+    pub fn switch_from_idle(&mut self) {
+        assert!(self.running_task.is_none());
+
+        if let Some(next_task) = self.choose_next() {
+            let idle_kernel_stack: *mut VirtualAddress = &mut self.idle_kernel_stack_pointer;
+            unsafe { self.switch_to(next_task, P::read_timestamp(), idle_kernel_stack) };
+        }
+    }
+
+    /// Called by the platform when the preemption timer fires, to switch away from a task that has used up its
+    /// time slice. The platform should only call this if it is safe to switch away from the running task at the
+    /// point it was interrupted.
+    pub fn time_slice_expired(&mut self) {
+        self.preemption_timer_armed = false;
+        if self.running_task.is_some() {
+            self.switch_to_next(TaskState::Ready);
+        }
+    }
 
-            old_task.address_space.switch_from();
-            next_task.address_space.switch_to();
+    /// Make `next_task` the running task, and switch to it. The current context (either a task that has already
+    /// been switched away from, or the idle context) is saved to `current_kernel_stack`.
+    ///
+    /// # Safety
+    /// `current_kernel_stack` must be where the current context's kernel stack pointer is kept, so it can be
+    /// switched back to.
+    unsafe fn switch_to(&mut self, next_task: Arc<Task<P>>, now: u64, current_kernel_stack: *mut VirtualAddress) {
+        trace!("Switching to task: {}", next_task.name);
+        assert_eq!(*next_task.state.lock(), TaskState::Ready);
+
+        next_task.stats.switched_to(now);
+        *next_task.state.lock() = TaskState::Running;
+        self.running_task = Some(next_task.clone());
+        next_task.address_space.switch_to();
+        self.update_preemption_timer();
+        P::per_cpu().set_io_port_ranges(&next_task.io_port_ranges.lock());
 
-            let old_kernel_stack: *mut VirtualAddress = old_task.kernel_stack_pointer.get();
-            let new_kernel_stack = unsafe { *self.running_task.as_ref().unwrap().kernel_stack_pointer.get() };
-            let new_user_stack = unsafe { *self.running_task.as_ref().unwrap().user_stack_pointer.get() };
+        let new_kernel_stack = *next_task.kernel_stack_pointer.get();
+        let new_user_stack = *next_task.user_stack_pointer.get();
 
-            unsafe {
-                *old_task.user_stack_pointer.get() = P::per_cpu().get_user_stack_pointer();
-                trace!("Setting stacks - kernel: {:#x}, user: {:#x}", new_kernel_stack, new_user_stack);
-                P::per_cpu().set_kernel_stack_pointer(new_kernel_stack);
-                P::per_cpu().set_user_stack_pointer(new_user_stack);
-                P::context_switch(old_kernel_stack, new_kernel_stack);
+        /*
+         * The context switch won't return until we're switched back to, so we don't hold onto our reference to
+         * the task across it.
+         */
+        drop(next_task);
+
+        trace!("Setting stacks - kernel: {:#x}, user: {:#x}", new_kernel_stack, new_user_stack);
+        P::per_cpu().set_kernel_stack_pointer(new_kernel_stack);
+        P::per_cpu().set_user_stack_pointer(new_user_stack);
+        P::context_switch(current_kernel_stack, new_kernel_stack);
+    }
+
+    /// Move any tasks that have been woken (see `Task::wake`) from the blocked queue into the ready queue. If
+    /// `handoff` is the ID of one of them, it's placed at the front of the queue, so it's the next task to run -
+    /// this is used to hand the CPU straight to a task we know is waiting for something the current task has just
+    /// provided (e.g. the receiver of a call).
+    pub fn collect_woken(&mut self, handoff: Option<KernelObjectId>) {
+        let generation = WAKE_GENERATION.load(Ordering::Acquire);
+        if generation == self.seen_wake_generation {
+            return;
+        }
+        self.seen_wake_generation = generation;
+
+        let mut woken_any = false;
+        let mut i = 0;
+        while i < self.blocked_queue.len() {
+            if *self.blocked_queue[i].state.lock() == TaskState::Ready {
+                let task = self.blocked_queue.remove(i);
+                trace!("Unblocking task: {}", task.name);
+
+                if handoff == Some(task.id()) {
+                    self.ready_queue.push_front(task);
+                } else {
+                    self.ready_queue.push_back(task);
+                }
+                woken_any = true;
+            } else {
+                i += 1;
             }
-        } else {
-            /*
-             * There aren't any schedulable tasks. For now, we just return to the current one (by
-             * doing nothing here).
-             * TODO: this should catch up on any kernel bookkeeping, then idle to minimise power use.
-             */
-            trace!("No more schedulable tasks. Returning to current one!");
+        }
+
+        if woken_any {
+            self.ready_queue_grew();
         }
     }
 
     fn choose_next(&mut self) -> Option<Arc<Task<P>>> {
+        self.collect_woken(None);
         self.ready_queue.pop_front()
     }
+
+    /// Arm the preemption timer if the running task should be preempted at the end of its time slice (because
+    /// other tasks are waiting to run), and disarm it otherwise.
+    fn update_preemption_timer(&mut self) {
+        self.preemption_timer_armed = self.running_task.is_some() && !self.ready_queue.is_empty();
+        P::set_preemption_timer(if self.preemption_timer_armed { Some(TIME_SLICE) } else { None });
+    }
+
+    /// Called when tasks have been added to the ready queue. If the running task was being left to run without a
+    /// time slice, because nothing else was waiting, it needs to be given one now.
+    // TODO: when we're multi-core, idle CPUs should be woken up to run the new tasks instead, including when a
+    // task blocked on an idle CPU is woken from another one
+    fn ready_queue_grew(&mut self) {
+        if self.running_task.is_some() && !self.preemption_timer_armed {
+            self.update_preemption_timer();
+        }
+    }
 }
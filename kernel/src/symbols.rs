@@ -0,0 +1,220 @@
+//! The loader passes us a table of the kernel's function symbols (see `hal::boot_info::KernelSymbols`), which we
+//! use to print the frames of backtraces as `function+offset`, instead of raw addresses.
+
+use core::{fmt, slice, str};
+use hal::{
+    boot_info::{KernelSymbol, KernelSymbols},
+    memory::VirtualAddress,
+};
+use pebble_util::InitGuard;
+
+pub static KERNEL_SYMBOLS: InitGuard<SymbolTable> = InitGuard::uninit();
+
+pub struct SymbolTable {
+    /// Sorted by address.
+    symbols: &'static [KernelSymbol],
+    names: &'static [u8],
+}
+
+impl SymbolTable {
+    /// Create a `SymbolTable` from the table described by the loader.
+    ///
+    /// # Safety
+    /// The table described by `info` must be mapped, and must not be unmapped or modified for the rest of the
+    /// kernel's life.
+    pub unsafe fn new(info: &KernelSymbols) -> SymbolTable {
+        SymbolTable {
+            symbols: slice::from_raw_parts(info.symbols_address.ptr() as *const KernelSymbol, info.num_symbols),
+            names: slice::from_raw_parts(info.names_address.ptr() as *const u8, info.names_length),
+        }
+    }
+
+    /// Find the function that contains `address`. Returns the (mangled) name of the function, and the offset of
+    /// `address` into it.
+    pub fn lookup(&self, address: VirtualAddress) -> Option<(&'static str, usize)> {
+        let index = match self.symbols.binary_search_by_key(&address, |symbol| symbol.address) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        let symbol = &self.symbols[index];
+
+        let offset = usize::from(address) - usize::from(symbol.address);
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+
+        let name_start = symbol.name_offset as usize;
+        let name_end = name_start + symbol.name_length as usize;
+        let name = str::from_utf8(self.names.get(name_start..name_end)?).ok()?;
+        Some((name, offset))
+    }
+}
+
+/// Formats an address as `function+offset` if it lies within a function in the kernel's symbol table, and as
+/// just the raw address if it doesn't (or if the loader didn't give us a symbol table).
+pub struct Symbolized(pub VirtualAddress);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match KERNEL_SYMBOLS.try_get().and_then(|symbols| symbols.lookup(self.0)) {
+            Some((name, offset)) => write!(f, "{:#x} - {}+{:#x}", self.0, Demangle(name), offset),
+            None => write!(f, "{:#x} - <unknown>", self.0),
+        }
+    }
+}
+
+/// Formats a symbol name that has been mangled with Rust's legacy mangling scheme (which looks like
+/// `_ZN6kernel7syscall14handle_syscall17h0123456789abcdefE`) as a path (e.g. `kernel::syscall::handle_syscall`).
+/// The hash at the end of the name is left off. Names that aren't mangled like this are formatted unchanged.
+pub struct Demangle<'a>(pub &'a str);
+
+impl<'a> fmt::Display for Demangle<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mangled = match self.0.strip_prefix("_ZN").and_then(|name| name.strip_suffix('E')) {
+            Some(mangled) => mangled,
+            None => return f.write_str(self.0),
+        };
+
+        /*
+         * Check the whole name is well-formed before we write anything, so we can fall back to writing the
+         * mangled name if it's not.
+         */
+        let mut rest = mangled;
+        while !rest.is_empty() {
+            match split_component(rest) {
+                Some((_, next)) => rest = next,
+                None => return f.write_str(self.0),
+            }
+        }
+
+        let mut rest = mangled;
+        let mut first = true;
+        while let Some((component, next)) = split_component(rest) {
+            rest = next;
+
+            if rest.is_empty() && is_hash(component) {
+                break;
+            }
+            if !first {
+                f.write_str("::")?;
+            }
+            write_component(f, component)?;
+            first = false;
+        }
+
+        Ok(())
+    }
+}
+
+/// Split a length-prefixed component (e.g. `6kernel`) off the start of a mangled name.
+fn split_component(name: &str) -> Option<(&str, &str)> {
+    let num_digits = name.bytes().take_while(u8::is_ascii_digit).count();
+    let length: usize = name[..num_digits].parse().ok()?;
+    let rest = &name[num_digits..];
+
+    if length > rest.len() || !rest.is_char_boundary(length) {
+        return None;
+    }
+    Some((&rest[..length], &rest[length..]))
+}
+
+fn is_hash(component: &str) -> bool {
+    component.len() == 17 && component.starts_with('h') && component[1..].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn write_component(f: &mut fmt::Formatter, mut component: &str) -> fmt::Result {
+    use fmt::Write;
+
+    /*
+     * Components that would otherwise start with a `$` are prefixed with an `_`.
+     */
+    if component.starts_with("_$") {
+        component = &component[1..];
+    }
+
+    while !component.is_empty() {
+        if component.starts_with("..") {
+            f.write_str("::")?;
+            component = &component[2..];
+        } else if component.starts_with('$') {
+            let end = match component[1..].find('$') {
+                Some(end) => end + 1,
+                None => return f.write_str(component),
+            };
+
+            match &component[1..end] {
+                "SP" => f.write_char('@')?,
+                "BP" => f.write_char('*')?,
+                "RF" => f.write_char('&')?,
+                "LT" => f.write_char('<')?,
+                "GT" => f.write_char('>')?,
+                "LP" => f.write_char('(')?,
+                "RP" => f.write_char(')')?,
+                "C" => f.write_char(',')?,
+                escape => match escape
+                    .strip_prefix('u')
+                    .and_then(|code| u32::from_str_radix(code, 16).ok())
+                    .and_then(core::char::from_u32)
+                {
+                    Some(c) => f.write_char(c)?,
+                    None => f.write_str(&component[..=end])?,
+                },
+            }
+            component = &component[(end + 1)..];
+        } else {
+            let end = match component.find(|c| c == '$' || c == '.') {
+                Some(0) => 1,
+                Some(end) => end,
+                None => component.len(),
+            };
+            f.write_str(&component[..end])?;
+            component = &component[end..];
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_demangle() {
+        macro test($mangled: expr, $demangled: expr) {
+            assert_eq!(format!("{}", Demangle($mangled)), $demangled);
+        }
+
+        test!("_ZN6kernel7syscall14handle_syscall17h0123456789abcdefE", "kernel::syscall::handle_syscall");
+        test!("_ZN4core9panicking5panic17h4b5b7a1c5e6f9d0aE", "core::panicking::panic");
+        test!(
+            "_ZN4core3ptr71drop_in_place$LT$alloc..sync..Arc$LT$kernel..object..task..Task$GT$$GT$17h0123456789abcdefE",
+            "core::ptr::drop_in_place<alloc::sync::Arc<kernel::object::task::Task>>"
+        );
+        test!(
+            "_ZN64_$LT$kernel..symbols..Demangle$u20$as$u20$core..fmt..Display$GT$3fmt17h0123456789abcdefE",
+            "<kernel::symbols::Demangle as core::fmt::Display>::fmt"
+        );
+        test!("_ZN6kernel7syscall14handle_syscallE", "kernel::syscall::handle_syscall");
+        test!("kentry", "kentry");
+        test!("_ZN6kernel7syscall99E", "_ZN6kernel7syscall99E");
+    }
+
+    #[test]
+    fn test_lookup() {
+        static NAMES: &[u8] = b"firstsecond";
+        static SYMBOLS: [KernelSymbol; 2] = [
+            KernelSymbol { address: VirtualAddress::new(0x1000), size: 0x100, name_offset: 0, name_length: 5 },
+            KernelSymbol { address: VirtualAddress::new(0x2000), size: 0x80, name_offset: 5, name_length: 6 },
+        ];
+        let table = SymbolTable { symbols: &SYMBOLS, names: NAMES };
+
+        assert_eq!(table.lookup(VirtualAddress::new(0x0fff)), None);
+        assert_eq!(table.lookup(VirtualAddress::new(0x1000)), Some(("first", 0x0)));
+        assert_eq!(table.lookup(VirtualAddress::new(0x10ff)), Some(("first", 0xff)));
+        assert_eq!(table.lookup(VirtualAddress::new(0x1100)), None);
+        assert_eq!(table.lookup(VirtualAddress::new(0x2042)), Some(("second", 0x42)));
+        assert_eq!(table.lookup(VirtualAddress::new(0x2080)), None);
+    }
+}
@@ -1,35 +1,54 @@
+pub mod trace;
 mod validation;
 
 use crate::{
+    memory::Zone,
     object::{
         address_space::AddressSpace,
         channel::{ChannelEnd, Message},
+        io_port_range::IoPortRange,
         memory_object::MemoryObject,
-        task::{Task, TaskState},
+        pci_device::PciDevice,
+        task::{Task, TaskBlock, TaskState},
         KernelObject,
     },
     per_cpu::PerCpu,
     Platform,
 };
-use alloc::{collections::BTreeMap, string::String, sync::Arc};
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
 use bit_field::BitField;
-use core::convert::TryFrom;
+use core::{convert::TryFrom, sync::atomic::Ordering};
 use hal::memory::{Flags, PhysicalAddress, VirtualAddress};
 use libpebble::{
     caps::Capability,
     syscall::{
         self,
+        acpi::{AcpiString, ACPI_ID_MAX_LENGTH},
         result::{handle_to_syscall_repr, status_to_syscall_repr, status_with_payload_to_syscall_repr},
+        AcpiGetInfoError,
+        ChannelCallBuffers,
+        ChannelCallError,
         CreateMemoryObjectError,
+        DrainKernelLogError,
         EarlyLogError,
         FramebufferInfo,
         GetFramebufferError,
         GetMessageError,
+        GetTaskInfoError,
+        KernelLogEntry,
+        MapIoPortRangeError,
         MapMemoryObjectError,
+        PciAttachDmaMemoryError,
+        PciConfigError,
         PciGetInfoError,
+        PowerAction,
+        PowerControlError,
         RegisterServiceError,
         SendMessageError,
+        SetSyscallTracingError,
         SubscribeToServiceError,
+        TaskInfo,
+        TransactionId,
         CHANNEL_MAX_NUM_HANDLES,
     },
     Handle,
@@ -37,7 +56,7 @@ use libpebble::{
 };
 use log::{info, trace, warn};
 use spin::Mutex;
-use validation::{UserPointer, UserSlice, UserString};
+use validation::{validate_user_region, UserPointer, UserSlice, UserString};
 
 /// Maps the name of a service to the channel used to register new service users.
 static SERVICE_MAP: Mutex<BTreeMap<String, Arc<ChannelEnd>>> = Mutex::new(BTreeMap::new());
@@ -51,32 +70,64 @@ const NONE_BAR: Option<libpebble::syscall::pci::Bar> = None;
 /// receives the syscall (each architecture is free to do this however it wishes). The only
 /// parameter that is guaranteed to be valid is `number`; the meaning of the rest may be undefined
 /// depending on how many parameters the specific system call takes.
-pub fn handle_syscall<P>(number: usize, a: usize, b: usize, c: usize, d: usize, e: usize) -> usize
+pub fn handle_syscall<P>(number: usize, a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -> usize
 where
     P: Platform,
 {
-    // info!("Syscall! number = {}, a = {}, b = {}, c = {}, d = {}, e = {}", number, a, b, c, d, e);
     let task = P::per_cpu().scheduler().get_mut().running_task.as_ref().unwrap();
+    let trace_start = if trace::is_traced(&task.name) { Some(P::read_timestamp()) } else { None };
+    task.stats.syscalls.fetch_add(1, Ordering::Relaxed);
 
-    match number {
+    let result = match number {
         syscall::SYSCALL_YIELD => yield_syscall::<P>(),
         syscall::SYSCALL_EARLY_LOG => status_to_syscall_repr(early_log(task, a, b)),
         syscall::SYSCALL_GET_FRAMEBUFFER => handle_to_syscall_repr(get_framebuffer(task, a)),
         syscall::SYSCALL_CREATE_MEMORY_OBJECT => handle_to_syscall_repr(create_memory_object(task, a, b, c, d)),
         syscall::SYSCALL_MAP_MEMORY_OBJECT => status_to_syscall_repr(map_memory_object(task, a, b, c, d)),
         syscall::SYSCALL_CREATE_CHANNEL => todo!(),
-        syscall::SYSCALL_SEND_MESSAGE => status_to_syscall_repr(send_message(task, a, b, c, d, e)),
+        syscall::SYSCALL_SEND_MESSAGE => status_to_syscall_repr(send_message(task, a, b, c, d, e, f)),
         syscall::SYSCALL_GET_MESSAGE => status_with_payload_to_syscall_repr(get_message(task, a, b, c, d, e)),
-        syscall::SYSCALL_WAIT_FOR_MESSAGE => todo!(),
+        syscall::SYSCALL_WAIT_FOR_MESSAGE => {
+            status_with_payload_to_syscall_repr(wait_for_message(task, a, b, c, d, e))
+        }
         syscall::SYSCALL_REGISTER_SERVICE => handle_to_syscall_repr(register_service(task, a, b)),
         syscall::SYSCALL_SUBSCRIBE_TO_SERVICE => handle_to_syscall_repr(subscribe_to_service(task, a, b)),
         syscall::SYSCALL_PCI_GET_INFO => status_with_payload_to_syscall_repr(pci_get_info(task, a, b)),
+        syscall::SYSCALL_CHANNEL_CALL => status_with_payload_to_syscall_repr(channel_call(task, a, b)),
+        syscall::SYSCALL_SET_SYSCALL_TRACING => status_to_syscall_repr(set_syscall_tracing(task, a, b, c)),
+        syscall::SYSCALL_DRAIN_KERNEL_LOG => status_with_payload_to_syscall_repr(drain_kernel_log(task, a, b)),
+        syscall::SYSCALL_GET_TASK_INFO => status_to_syscall_repr(get_task_info(task, a, b)),
+        syscall::SYSCALL_POWER_CONTROL => status_to_syscall_repr(power_control(task, a)),
+        syscall::SYSCALL_ACPI_GET_INFO => status_with_payload_to_syscall_repr(acpi_get_info(task, a, b)),
+        syscall::SYSCALL_PCI_CONFIG_READ => status_with_payload_to_syscall_repr(pci_config_read(task, a, b)),
+        syscall::SYSCALL_PCI_CONFIG_WRITE => status_to_syscall_repr(pci_config_write(task, a, b, c)),
+        syscall::SYSCALL_MAP_IO_PORT_RANGE => status_to_syscall_repr(map_io_port_range(task, a)),
+        syscall::SYSCALL_PCI_ATTACH_DMA_MEMORY => status_to_syscall_repr(pci_attach_dma_memory(task, a, b)),
 
         _ => {
             warn!("Process made system call with invalid syscall number: {}", number);
             usize::MAX
         }
+    };
+
+    /*
+     * The system call may have woken tasks (e.g. by dropping the last handle to a channel end that other tasks
+     * were making calls through), so make sure they're ready to run.
+     */
+    P::per_cpu().scheduler().collect_woken(None);
+
+    if let Some(trace_start) = trace_start {
+        trace::record(trace::TraceRecord {
+            task_id: task.id(),
+            task_name: task.name.clone(),
+            number,
+            params: [a, b, c, d, e, f],
+            result,
+            duration: P::read_timestamp() - trace_start,
+        });
     }
+
+    result
 }
 
 fn yield_syscall<P>() -> usize
@@ -103,7 +154,7 @@ where
     }
 
     // Check the message is valid UTF-8
-    let message = UserString::new(str_address as *mut u8, str_length)
+    let message = UserString::new(&task.address_space, str_address as *mut u8, str_length)
         .validate()
         .map_err(|_| EarlyLogError::MessageNotValidUtf8)?;
 
@@ -125,7 +176,7 @@ where
     let (info, memory_object) = crate::FRAMEBUFFER.try_get().ok_or(GetFramebufferError::NoFramebufferCreated)?;
     let handle = task.add_handle(memory_object.clone());
 
-    UserPointer::new(info_address as *mut FramebufferInfo, true)
+    UserPointer::new(&task.address_space, info_address as *mut FramebufferInfo, true)
         .write(*info)
         .map_err(|()| GetFramebufferError::InfoAddressIsInvalid)?;
 
@@ -147,23 +198,36 @@ where
 
     let writable = flags.get_bit(0);
     let executable = flags.get_bit(1);
+    let zone = match flags.get_bits(2..4) {
+        0 => Zone::Normal,
+        1 => Zone::Dma32,
+        2 => Zone::Dma,
+        _ => return Err(CreateMemoryObjectError::InvalidFlags),
+    };
+
+    if size == 0 {
+        return Err(CreateMemoryObjectError::InvalidSize);
+    }
 
     // TODO: should we require that the size be multiple of the page size, or just up it here?
     let size = align_up(size, Size4KiB::SIZE);
+    let virtual_address = validate_user_region::<P>(virtual_address, size)
+        .map_err(|()| CreateMemoryObjectError::InvalidVirtualAddress)?;
 
-    // TODO: do something more sensible with this when we have a concept of physical memory "ownership"
-    let physical_start = crate::PHYSICAL_MEMORY_MANAGER.get().alloc_bytes(size);
-
-    let memory_object = MemoryObject::new(
+    let allocation = crate::PHYSICAL_MEMORY_MANAGER
+        .get()
+        .alloc_bytes_in(size, zone)
+        .ok_or(CreateMemoryObjectError::InvalidSize)?;
+    let physical_start = allocation.start();
+    let memory_object = MemoryObject::from_allocation(
         task.id(),
-        Some(VirtualAddress::new(virtual_address)),
-        physical_start,
-        size,
+        Some(virtual_address),
+        allocation,
         Flags { writable, executable, user_accessible: true, ..Default::default() },
     );
 
     if physical_address_ptr != 0x0 {
-        UserPointer::new(physical_address_ptr as *mut PhysicalAddress, true)
+        UserPointer::new(&task.address_space, physical_address_ptr as *mut PhysicalAddress, true)
             .write(physical_start)
             .map_err(|()| CreateMemoryObjectError::InvalidPhysicalAddressPointer)?;
     }
@@ -205,8 +269,10 @@ where
         if memory_object.virtual_address.is_some() {
             return Err(MapMemoryObjectError::VirtualAddressShouldNotBeSupplied);
         }
-        // TODO: we need to actually validate that the supplied address is canonical and all that jazz
-        Some(VirtualAddress::new(virtual_address))
+        Some(
+            validate_user_region::<P>(virtual_address, memory_object.size)
+                .map_err(|()| MapMemoryObjectError::VirtualAddressNotValid)?,
+        )
     };
 
     if address_space_handle == ZERO_HANDLE {
@@ -222,7 +288,7 @@ where
     } else {
         task.handles
             .read()
-            .get(&memory_object_handle)
+            .get(&address_space_handle)
             .ok_or(MapMemoryObjectError::InvalidHandle)?
             .clone()
             .downcast_arc::<AddressSpace<P>>()
@@ -240,7 +306,7 @@ where
      * address, so don't bother writing it back.
      */
     if address_ptr != 0x0 {
-        let mut address_ptr = UserPointer::new(address_ptr as *mut VirtualAddress, true);
+        let mut address_ptr = UserPointer::new(&task.address_space, address_ptr as *mut VirtualAddress, true);
         address_ptr
             .write(supplied_virtual_address.unwrap_or_else(|| memory_object.virtual_address.unwrap()))
             .map_err(|()| MapMemoryObjectError::AddressPointerInvalid)?;
@@ -249,6 +315,34 @@ where
     Ok(())
 }
 
+fn map_io_port_range<P>(task: &Arc<Task<P>>, io_port_range_handle: usize) -> Result<(), MapIoPortRangeError>
+where
+    P: Platform,
+{
+    let io_port_range_handle =
+        Handle::try_from(io_port_range_handle).map_err(|_| MapIoPortRangeError::InvalidHandle)?;
+    let io_port_range = task
+        .handles
+        .read()
+        .get(&io_port_range_handle)
+        .ok_or(MapIoPortRangeError::InvalidHandle)?
+        .clone()
+        .downcast_arc::<IoPortRange>()
+        .ok()
+        .ok_or(MapIoPortRangeError::NotAnIoPortRange)?;
+
+    let mut io_port_ranges = task.io_port_ranges.lock();
+    if !io_port_ranges.iter().any(|range| range.id == io_port_range.id) {
+        io_port_ranges.push(io_port_range);
+    }
+
+    /*
+     * The task is running, so the ranges it can access need to be updated now, rather than on the next switch.
+     */
+    P::per_cpu().set_io_port_ranges(&io_port_ranges);
+    Ok(())
+}
+
 fn send_message<P>(
     task: &Arc<Task<P>>,
     channel_handle: usize,
@@ -256,7 +350,53 @@ fn send_message<P>(
     num_bytes: usize,
     handles_address: usize,
     num_handles: usize,
+    transaction_id: usize,
 ) -> Result<(), SendMessageError>
+where
+    P: Platform,
+{
+    let channel_handle = Handle::try_from(channel_handle).map_err(|_| SendMessageError::InvalidChannelHandle)?;
+    /*
+     * If this message is a reply to a call, this is the transaction ID of the call.
+     */
+    let transaction_id =
+        TransactionId::try_from(transaction_id).map_err(|_| SendMessageError::InvalidTransactionId)?;
+
+    let is_reply = transaction_id != 0;
+    let message =
+        make_message(task, transaction_id, is_reply, byte_address, num_bytes, handles_address, num_handles)?;
+    let channel = task
+        .handles
+        .read()
+        .get(&channel_handle)
+        .ok_or(SendMessageError::InvalidChannelHandle)?
+        .clone()
+        .downcast_arc::<ChannelEnd>()
+        .ok()
+        .ok_or(SendMessageError::NotAChannel)?;
+
+    let woken = channel.send(message)?;
+
+    /*
+     * Sending the message wakes up anything waiting for it on the other end. If this is a reply, we hand the CPU
+     * straight back to the caller the next time we switch task.
+     */
+    P::per_cpu().scheduler().collect_woken(if is_reply { woken } else { None });
+
+    Ok(())
+}
+
+/// Construct a `Message` from the bytes and handles that a task wants to send. The objects of the transferred
+/// handles are moved into the message, and so the handles are removed from the sending task.
+fn make_message<P>(
+    task: &Arc<Task<P>>,
+    transaction_id: TransactionId,
+    is_reply: bool,
+    byte_address: usize,
+    num_bytes: usize,
+    handles_address: usize,
+    num_handles: usize,
+) -> Result<Message, SendMessageError>
 where
     P: Platform,
 {
@@ -269,19 +409,18 @@ where
         return Err(SendMessageError::TooManyHandles);
     }
 
-    let channel_handle = Handle::try_from(channel_handle).map_err(|_| SendMessageError::InvalidChannelHandle)?;
     let bytes = if num_bytes == 0 {
-        &[]
+        Vec::new()
     } else {
-        UserSlice::new(byte_address as *mut u8, num_bytes)
-            .validate_read()
+        UserSlice::new(&task.address_space, byte_address as *mut u8, num_bytes)
+            .read()
             .map_err(|()| SendMessageError::BytesAddressInvalid)?
     };
     let handles = if num_handles == 0 {
-        &[]
+        Vec::new()
     } else {
-        UserSlice::new(handles_address as *mut Handle, num_handles)
-            .validate_read()
+        UserSlice::new(&task.address_space, handles_address as *mut Handle, num_handles)
+            .read()
             .map_err(|()| SendMessageError::HandlesAddressInvalid)?
     };
     let handle_objects = {
@@ -300,18 +439,45 @@ where
         arr
     };
 
-    task.handles
+    /*
+     * If the task has sent away its last handle to an I/O port range it's mapped, it loses access to its ports.
+     * The sending task is running, so this has to take effect now.
+     */
+    if num_handles > 0 && task.forget_unreferenced_io_port_ranges() {
+        P::per_cpu().set_io_port_ranges(&task.io_port_ranges.lock());
+    }
+
+    Ok(Message { transaction_id, is_reply, bytes, handle_objects })
+}
+
+fn get_message<P>(
+    task: &Arc<Task<P>>,
+    channel_handle: usize,
+    bytes_address: usize,
+    bytes_len: usize,
+    handles_address: usize,
+    handles_len: usize,
+) -> Result<usize, GetMessageError>
+where
+    P: Platform,
+{
+    let channel_handle = Handle::try_from(channel_handle).map_err(|_| GetMessageError::InvalidChannelHandle)?;
+
+    let channel = task
+        .handles
         .read()
         .get(&channel_handle)
-        .ok_or(SendMessageError::InvalidChannelHandle)?
+        .ok_or(GetMessageError::InvalidChannelHandle)?
         .clone()
         .downcast_arc::<ChannelEnd>()
         .ok()
-        .ok_or(SendMessageError::NotAChannel)?
-        .send(Message { bytes: bytes.to_vec(), handle_objects })
+        .ok_or(GetMessageError::NotAChannel)?;
+
+    channel
+        .receive(|message| receive_message(task, message, bytes_address, bytes_len, handles_address, handles_len))
 }
 
-fn get_message<P>(
+fn wait_for_message<P>(
     task: &Arc<Task<P>>,
     channel_handle: usize,
     bytes_address: usize,
@@ -334,41 +500,174 @@ where
         .ok()
         .ok_or(GetMessageError::NotAChannel)?;
 
-    channel.receive(|message| {
-        let num_handles = message.num_handles();
-
-        if message.bytes.len() > bytes_len {
-            return Err((message, GetMessageError::BytesBufferTooSmall));
+    let _wait = channel.wait(0, task.clone());
+    loop {
+        task.prepare_to_wait();
+        match channel.receive(|message| {
+            receive_message(task, message, bytes_address, bytes_len, handles_address, handles_len)
+        }) {
+            Err(GetMessageError::NoMessage) => {
+                /*
+                 * Block until a message is sent down the channel. If one arrived after we checked, or there's
+                 * nothing else to run, this returns straight away, so we just try again.
+                 */
+                P::per_cpu().scheduler().switch_to_next(TaskState::Blocked(TaskBlock::WaitForMessage(channel.id)));
+            }
+            result => return result,
         }
-        if num_handles > handles_len {
-            return Err((message, GetMessageError::HandlesBufferTooSmall));
+    }
+}
+
+fn channel_call<P>(
+    task: &Arc<Task<P>>,
+    channel_handle: usize,
+    buffers_address: usize,
+) -> Result<usize, ChannelCallError>
+where
+    P: Platform,
+{
+    let channel_handle = Handle::try_from(channel_handle).map_err(|_| ChannelCallError::InvalidChannelHandle)?;
+    let buffers = UserPointer::new(&task.address_space, buffers_address as *mut ChannelCallBuffers, false)
+        .read()
+        .map_err(|()| ChannelCallError::BuffersAddressInvalid)?;
+
+    let channel = task
+        .handles
+        .read()
+        .get(&channel_handle)
+        .ok_or(ChannelCallError::InvalidChannelHandle)?
+        .clone()
+        .downcast_arc::<ChannelEnd>()
+        .ok()
+        .ok_or(ChannelCallError::NotAChannel)?;
+    channel.other_end_id().ok_or(ChannelCallError::CannotCallKernelChannel)?;
+
+    let transaction_id = channel.alloc_transaction_id();
+    let message = make_message(
+        task,
+        transaction_id,
+        false,
+        buffers.send_bytes as usize,
+        buffers.send_bytes_len,
+        buffers.send_handles as usize,
+        buffers.send_handles_len,
+    )
+    .map_err(|err| match err {
+        SendMessageError::BytesAddressInvalid => ChannelCallError::SendBytesAddressInvalid,
+        SendMessageError::TooManyBytes => ChannelCallError::TooManyBytes,
+        SendMessageError::HandlesAddressInvalid => ChannelCallError::SendHandlesAddressInvalid,
+        SendMessageError::TooManyHandles => ChannelCallError::TooManyHandles,
+        _ => ChannelCallError::InvalidTransferredHandle,
+    })?;
+
+    /*
+     * We start waiting for the reply before the call is sent, so we can't miss it, and so it isn't received by
+     * anything else.
+     */
+    let _wait = channel.wait(transaction_id, task.clone());
+    let woken = channel.send(message).map_err(|_| ChannelCallError::OtherEndDisconnected)?;
+
+    /*
+     * If the receiver was blocked waiting for a message, we put it at the front of the ready queue, so we switch
+     * straight to it when we block below.
+     */
+    P::per_cpu().scheduler().collect_woken(woken);
+
+    loop {
+        task.prepare_to_wait();
+        let result = channel.receive_reply(transaction_id, |message| {
+            receive_message(
+                task,
+                message,
+                buffers.reply_bytes as usize,
+                buffers.reply_bytes_len,
+                buffers.reply_handles as usize,
+                buffers.reply_handles_len,
+            )
+        });
+
+        match result {
+            Ok(status) => return Ok(status),
+            Err(GetMessageError::NoMessage) => {
+                /*
+                 * If the other end has been dropped, the reply is never going to arrive. The dropped end wakes us
+                 * up if this happens while we're blocked.
+                 */
+                if channel.other_end_id().is_none() {
+                    return Err(ChannelCallError::OtherEndDisconnected);
+                }
+
+                P::per_cpu()
+                    .scheduler()
+                    .switch_to_next(TaskState::Blocked(TaskBlock::WaitForReply(channel.id, transaction_id)));
+            }
+            Err(GetMessageError::BytesAddressInvalid) => return Err(ChannelCallError::ReplyBytesAddressInvalid),
+            Err(GetMessageError::BytesBufferTooSmall) => return Err(ChannelCallError::ReplyBytesBufferTooSmall),
+            Err(GetMessageError::HandlesAddressInvalid) => {
+                return Err(ChannelCallError::ReplyHandlesAddressInvalid)
+            }
+            Err(GetMessageError::HandlesBufferTooSmall) => {
+                return Err(ChannelCallError::ReplyHandlesBufferTooSmall)
+            }
+            Err(GetMessageError::InvalidChannelHandle) | Err(GetMessageError::NotAChannel) => unreachable!(),
         }
+    }
+}
 
-        if bytes_len > 0 && bytes_address != 0x0 {
-            let byte_buffer = match UserSlice::new(bytes_address as *mut u8, message.bytes.len()).validate_write()
-            {
-                Ok(buffer) => buffer,
-                Err(()) => return Err((message, GetMessageError::BytesAddressInvalid)),
-            };
-            byte_buffer.copy_from_slice(&message.bytes);
+/// Copy a received message into a task's buffers, and add the transferred handles to the task. If the message
+/// can't be received, it is passed back with the error so it can be put back into the queue. On success,
+/// returns the status to pass back to userspace.
+fn receive_message<P>(
+    task: &Arc<Task<P>>,
+    message: Message,
+    bytes_address: usize,
+    bytes_len: usize,
+    handles_address: usize,
+    handles_len: usize,
+) -> Result<usize, (Message, GetMessageError)>
+where
+    P: Platform,
+{
+    let num_handles = message.num_handles();
+
+    if message.bytes.len() > bytes_len {
+        return Err((message, GetMessageError::BytesBufferTooSmall));
+    }
+    if num_handles > handles_len {
+        return Err((message, GetMessageError::HandlesBufferTooSmall));
+    }
+
+    if bytes_len > 0 && bytes_address != 0x0 {
+        let mut byte_buffer = UserSlice::new(&task.address_space, bytes_address as *mut u8, bytes_len);
+        if byte_buffer.write(&message.bytes).is_err() {
+            return Err((message, GetMessageError::BytesAddressInvalid));
         }
+    }
 
-        if handles_len > 0 && handles_address != 0x0 {
-            let handles_buffer = match UserSlice::new(handles_address as *mut Handle, num_handles).validate_write()
-            {
-                Ok(buffer) => buffer,
-                Err(()) => return Err((message, GetMessageError::HandlesAddressInvalid)),
-            };
-            for i in 0..num_handles {
-                handles_buffer[i] = task.add_handle(message.handle_objects[i].as_ref().unwrap().clone());
+    if handles_len > 0 && handles_address != 0x0 {
+        let handles: Vec<Handle> = message.handle_objects[0..num_handles]
+            .iter()
+            .map(|object| task.add_handle(object.as_ref().unwrap().clone()))
+            .collect();
+
+        let mut handles_buffer = UserSlice::new(&task.address_space, handles_address as *mut Handle, handles_len);
+        if handles_buffer.write(&handles).is_err() {
+            /*
+             * The message is put back in the queue, so remove the handles we've just created to its objects.
+             */
+            let mut task_handles = task.handles.write();
+            for handle in handles.iter() {
+                task_handles.remove(handle);
             }
+            return Err((message, GetMessageError::HandlesAddressInvalid));
         }
+    }
 
-        let mut status = 0;
-        status.set_bits(16..32, message.bytes.len());
-        status.set_bits(32..48, num_handles);
-        Ok(status)
-    })
+    let mut status = 0;
+    status.set_bits(16..32, message.bytes.len());
+    status.set_bits(32..48, num_handles);
+    status.set_bits(48..64, message.transaction_id as usize);
+    Ok(status)
 }
 
 fn register_service<P>(
@@ -391,13 +690,13 @@ where
         return Err(RegisterServiceError::NameLengthNotValid);
     }
 
-    let service_name = UserString::new(name_ptr as *mut u8, name_length)
+    let service_name = UserString::new(&task.address_space, name_ptr as *mut u8, name_length)
         .validate()
         .map_err(|()| RegisterServiceError::NamePointerNotValid)?;
 
     info!("Task {} has registered a service called {}", task.name, service_name);
     let channel = ChannelEnd::new_kernel_channel(task.id());
-    SERVICE_MAP.lock().insert(task.name.clone() + "." + service_name, channel.clone());
+    SERVICE_MAP.lock().insert(task.name.clone() + "." + &service_name, channel.clone());
 
     Ok(task.add_handle(channel))
 }
@@ -422,11 +721,11 @@ where
         return Err(SubscribeToServiceError::NameLengthNotValid);
     }
 
-    let service_name = UserString::new(name_ptr as *mut u8, name_length)
+    let service_name = UserString::new(&task.address_space, name_ptr as *mut u8, name_length)
         .validate()
         .map_err(|()| SubscribeToServiceError::NamePointerNotValid)?;
 
-    if let Some(register_channel) = SERVICE_MAP.lock().get(service_name) {
+    if let Some(register_channel) = SERVICE_MAP.lock().get(&service_name) {
         // Create new channel to allow the two tasks to communicate
         let (provider_end, user_end) = ChannelEnd::new_channel(task.id());
 
@@ -439,7 +738,13 @@ where
          */
         let mut handle_objects = [NONE_OBJECT; CHANNEL_MAX_NUM_HANDLES];
         handle_objects[0] = Some(provider_end as Arc<dyn KernelObject>);
-        register_channel.add_message(Message { bytes: [ptah::make_handle_slot(0)].to_vec(), handle_objects });
+        register_channel.add_message(Message {
+            transaction_id: 0,
+            is_reply: false,
+            bytes: [ptah::make_handle_slot(0)].to_vec(),
+            handle_objects,
+        });
+        P::per_cpu().scheduler().collect_woken(None);
 
         // Return the user's end of the new channel to it
         Ok(task.add_handle(user_end))
@@ -456,93 +761,508 @@ fn pci_get_info<P>(
 where
     P: Platform,
 {
-    use libpebble::syscall::PciDeviceInfo;
-    use pci_types::{Bar, MAX_BARS};
+    use crate::object::SENTINEL_KERNEL_ID;
+    use crate::pci::PciBar;
+    use libpebble::syscall::{pci::PciInterrupt, PciDeviceInfo};
+    use pci_types::MAX_BARS;
 
     // Check that the task has the 'PciBusDriver' capability
     if !task.capabilities.contains(&Capability::PciBusDriver) {
         return Err(PciGetInfoError::TaskDoesNotHaveCorrectCapability);
     }
 
-    if let Some(ref pci_info) = *crate::PCI_INFO.read() {
-        let num_descriptors = pci_info.devices.len();
+    let pci_info = crate::PCI_INFO.read();
+    let pci_info = pci_info.as_ref().ok_or(PciGetInfoError::PlatformDoesNotSupportPci)?;
+    let num_descriptors = pci_info.devices.len();
+
+    if buffer_size == 0 || buffer_address == 0x0 || buffer_size < num_descriptors {
+        return Err(PciGetInfoError::BufferNotLargeEnough(num_descriptors as u32));
+    }
+
+    /*
+     * Check the buffer before we hand anything out, so we don't create handles the task never finds out about.
+     */
+    let mut buffer = UserSlice::new(&task.address_space, buffer_address as *mut PciDeviceInfo, buffer_size);
+    buffer.validate_write(num_descriptors).map_err(|()| PciGetInfoError::BufferPointerInvalid)?;
+
+    let mut added_handles = Vec::new();
+    let mut claimed_devices = Vec::new();
+    let mut descriptors = Vec::with_capacity(num_descriptors);
+    for (&address, device) in pci_info.devices.iter() {
+        /*
+         * Like the BAR memory objects, the kernel owns the device objects. A handle to each one, and to its BARs,
+         * is only handed out once, and is passed on to the driver that claims the device, so only that driver can
+         * touch its configuration space and registers. Later calls still describe the device, but without handles.
+         *
+         * Bridges (including host bridges) are configured by the firmware, and their bus numbers and windows
+         * decide where every device behind them is, so they don't have a device object, and we don't give out
+         * access to their configuration space or BARs at all.
+         */
+        let object = device.object.as_ref().filter(|object| !object.claimed.swap(true, Ordering::AcqRel));
+        if let Some(object) = object {
+            claimed_devices.push(object);
+        }
+        let mut add_handle = |object: Arc<dyn KernelObject>| {
+            let handle = task.add_handle(object);
+            added_handles.push(handle);
+            handle
+        };
+
+        let mut device_descriptor = libpebble::syscall::PciDeviceInfo {
+            address,
+            device: object.map_or(ZERO_HANDLE, |object| add_handle(object.clone())),
+            vendor_id: device.vendor_id,
+            device_id: device.device_id,
+            revision: device.revision,
+            class: device.class,
+            sub_class: device.sub_class,
+            interface: device.interface,
+            bars: [NONE_BAR; MAX_BARS],
+            interrupt: match device.interrupt {
+                Some(interrupt) => PciInterrupt {
+                    gsi: interrupt.gsi,
+                    routed: true,
+                    level_triggered: interrupt.level_triggered,
+                    active_low: interrupt.active_low,
+                    _reserved: 0,
+                },
+                None => PciInterrupt::NOT_ROUTED,
+            },
+        };
+
+        for i in 0..MAX_BARS {
+            /*
+             * The kernel owns the BAR memory objects and I/O port ranges, as they belong to the device, not the
+             * requesting task.
+             */
+            let mut add_memory_handle = |address: usize, size: usize, prefetchable: bool| {
+                if object.is_none() {
+                    return ZERO_HANDLE;
+                }
+                let flags =
+                    Flags { writable: true, executable: false, user_accessible: true, cached: prefetchable };
+                add_handle(MemoryObject::new(
+                    SENTINEL_KERNEL_ID,
+                    None,
+                    PhysicalAddress::new(address).unwrap(),
+                    size,
+                    flags,
+                ))
+            };
+
+            device_descriptor.bars[i] = match device.bars[i] {
+                Some(PciBar::Memory32 { address, size, prefetchable }) => {
+                    Some(libpebble::syscall::pci::Bar::Memory32 {
+                        memory_object: add_memory_handle(address as usize, size as usize, prefetchable),
+                        size,
+                    })
+                }
+                Some(PciBar::Memory64 { address, size, prefetchable }) => {
+                    Some(libpebble::syscall::pci::Bar::Memory64 {
+                        memory_object: add_memory_handle(address as usize, size as usize, prefetchable),
+                        size,
+                    })
+                }
+                Some(PciBar::Io { port, size }) => Some(libpebble::syscall::pci::Bar::Io {
+                    io_port_range: match object {
+                        Some(_) => add_handle(IoPortRange::new(SENTINEL_KERNEL_ID, port, size)),
+                        None => ZERO_HANDLE,
+                    },
+                    port,
+                    size,
+                }),
+                None => None,
+            };
+        }
+
+        descriptors.push(device_descriptor);
+    }
+
+    /*
+     * The write can still fail if the task's mappings changed since we checked. If it does, take back what we
+     * handed out, so it can be claimed again.
+     */
+    if buffer.write(&descriptors).is_err() {
+        let mut task_handles = task.handles.write();
+        for handle in added_handles {
+            task_handles.remove(&handle);
+        }
+        for device in claimed_devices {
+            device.claimed.store(false, Ordering::Release);
+        }
+        return Err(PciGetInfoError::BufferPointerInvalid);
+    }
+
+    let mut status = 0;
+    status.set_bits(16..48, num_descriptors);
+    Ok(status)
+}
+
+/// The size of a PCI function's configuration space, when accessed through ECAM.
+const PCI_CONFIG_SPACE_SIZE: usize = 0x1000;
+
+fn pci_config_read<P>(task: &Arc<Task<P>>, device_handle: usize, offset: usize) -> Result<usize, PciConfigError>
+where
+    P: Platform,
+{
+    let device = pci_device_from_handle(task, device_handle)?;
+    if offset % 4 != 0 || offset >= PCI_CONFIG_SPACE_SIZE {
+        return Err(PciConfigError::InvalidOffset);
+    }
+
+    let access = crate::PCI_ACCESS.get().as_ref().ok_or(PciConfigError::PlatformDoesNotSupportPci)?;
+    let value = unsafe { access.lock().read(device.address, offset as u16) };
+
+    let mut status = 0;
+    status.set_bits(16..48, value as usize);
+    Ok(status)
+}
+
+fn pci_config_write<P>(
+    task: &Arc<Task<P>>,
+    device_handle: usize,
+    offset: usize,
+    value: usize,
+) -> Result<(), PciConfigError>
+where
+    P: Platform,
+{
+    let device = pci_device_from_handle(task, device_handle)?;
+    if offset % 4 != 0 || offset >= PCI_CONFIG_SPACE_SIZE {
+        return Err(PciConfigError::InvalidOffset);
+    }
+
+    /*
+     * Drivers can only write to the Command and Status registers (e.g. to enable bus mastering), and to the
+     * power management, MSI, and MSI-X capabilities. The rest of the header is either read-only, or controls
+     * things that are managed by the platform, such as the BARs, and the bus numbers and windows of bridges, which
+     * other devices rely on. Other capabilities can also change how the device decodes its BARs.
+     */
+    let access = crate::PCI_ACCESS.get().as_ref().ok_or(PciConfigError::PlatformDoesNotSupportPci)?;
+    let access = access.lock();
+    if offset != 0x04 && !crate::pci::is_driver_capability_register(&**access, device.address, offset as u16) {
+        return Err(PciConfigError::RegisterNotWritable);
+    }
+
+    unsafe {
+        access.write(device.address, offset as u16, value as u32);
+    }
+    Ok(())
+}
+
+fn pci_device_from_handle<P>(task: &Arc<Task<P>>, device_handle: usize) -> Result<Arc<PciDevice>, PciConfigError>
+where
+    P: Platform,
+{
+    let device_handle = Handle::try_from(device_handle).map_err(|_| PciConfigError::InvalidHandle)?;
+    task.handles
+        .read()
+        .get(&device_handle)
+        .ok_or(PciConfigError::InvalidHandle)?
+        .clone()
+        .downcast_arc::<PciDevice>()
+        .ok()
+        .ok_or(PciConfigError::NotAPciDevice)
+}
+
+fn pci_attach_dma_memory<P>(
+    task: &Arc<Task<P>>,
+    device_handle: usize,
+    memory_object_handle: usize,
+) -> Result<(), PciAttachDmaMemoryError>
+where
+    P: Platform,
+{
+    let device_handle = Handle::try_from(device_handle).map_err(|_| PciAttachDmaMemoryError::InvalidHandle)?;
+    let memory_object_handle =
+        Handle::try_from(memory_object_handle).map_err(|_| PciAttachDmaMemoryError::InvalidHandle)?;
+
+    let device = task
+        .handles
+        .read()
+        .get(&device_handle)
+        .ok_or(PciAttachDmaMemoryError::InvalidHandle)?
+        .clone()
+        .downcast_arc::<PciDevice>()
+        .ok()
+        .ok_or(PciAttachDmaMemoryError::NotAPciDevice)?;
+    let memory_object = task
+        .handles
+        .read()
+        .get(&memory_object_handle)
+        .ok_or(PciAttachDmaMemoryError::InvalidHandle)?
+        .clone()
+        .downcast_arc::<MemoryObject>()
+        .ok()
+        .ok_or(PciAttachDmaMemoryError::NotAMemoryObject)?;
+
+    /*
+     * Memory objects that don't own their memory describe things like other devices' BARs and the framebuffer,
+     * which a driver shouldn't be able to point its device at.
+     */
+    if !memory_object.owns_memory() {
+        return Err(PciAttachDmaMemoryError::NotDmaMemory);
+    }
+
+    let mut dma_memory = device.dma_memory.lock();
+    if dma_memory.iter().any(|attached| attached.id == memory_object.id) {
+        return Ok(());
+    }
+
+    if let Some(Some(iommu)) = crate::IOMMU.try_get() {
+        let mut iommu = iommu.lock();
+        for range in memory_object.physical_ranges() {
+            iommu.map(device.address, range, memory_object.flags.writable);
+        }
+    }
+    dma_memory.push(memory_object);
+
+    Ok(())
+}
+
+fn set_syscall_tracing<P>(
+    task: &Arc<Task<P>>,
+    name_length: usize,
+    name_ptr: usize,
+    enabled: usize,
+) -> Result<(), SetSyscallTracingError>
+where
+    P: Platform,
+{
+    use libpebble::syscall::TRACED_TASK_NAME_MAX_LENGTH;
+
+    if !task.capabilities.contains(&Capability::SyscallTracing) {
+        return Err(SetSyscallTracingError::TaskDoesNotHaveCorrectCapability);
+    }
+
+    if name_length == 0 || name_length > TRACED_TASK_NAME_MAX_LENGTH {
+        return Err(SetSyscallTracingError::NameLengthNotValid);
+    }
+
+    let task_name = UserString::new(&task.address_space, name_ptr as *mut u8, name_length)
+        .validate()
+        .map_err(|()| SetSyscallTracingError::NamePointerNotValid)?;
+
+    let enabled = enabled != 0;
+    info!(
+        "Task {} has turned syscall tracing {} for tasks called {}",
+        task.name,
+        if enabled { "on" } else { "off" },
+        task_name
+    );
+    trace::set_tracing(task_name, enabled);
+    Ok(())
+}
+
+fn drain_kernel_log<P>(
+    task: &Arc<Task<P>>,
+    buffer_address: usize,
+    buffer_length: usize,
+) -> Result<usize, DrainKernelLogError>
+where
+    P: Platform,
+{
+    if !task.capabilities.contains(&Capability::ReadKernelLog) {
+        return Err(DrainKernelLogError::TaskDoesNotHaveCorrectCapability);
+    }
+
+    /*
+     * We only remove the entries from the kernel log once they've been successfully written into the buffer, so
+     * that they're not lost if the buffer turns out to be invalid.
+     */
+    let entries = crate::kernel_log::peek_entries::<P>(buffer_length);
+    let last_sequence = match entries.last() {
+        Some(entry) => entry.sequence,
+        None => return Ok(0),
+    };
+
+    UserSlice::new(&task.address_space, buffer_address as *mut KernelLogEntry, buffer_length)
+        .write(&entries)
+        .map_err(|()| DrainKernelLogError::BufferAddressInvalid)?;
+    crate::kernel_log::remove_entries_up_to::<P>(last_sequence);
+
+    let mut status = 0;
+    status.set_bits(16..48, entries.len());
+    Ok(status)
+}
+
+fn get_task_info<P>(task: &Arc<Task<P>>, task_handle: usize, info_address: usize) -> Result<(), GetTaskInfoError>
+where
+    P: Platform,
+{
+    use libpebble::syscall::task_info::TASK_INFO_NAME_MAX_LENGTH;
+
+    let task_handle = Handle::try_from(task_handle).map_err(|_| GetTaskInfoError::InvalidHandle)?;
+
+    /*
+     * If the handle is the zero handle, we return the info of the calling task.
+     */
+    let target = if task_handle == ZERO_HANDLE {
+        task.clone()
+    } else {
+        task.handles
+            .read()
+            .get(&task_handle)
+            .ok_or(GetTaskInfoError::InvalidHandle)?
+            .clone()
+            .downcast_arc::<Task<P>>()
+            .ok()
+            .ok_or(GetTaskInfoError::NotATask)?
+    };
+
+    let mut name = [0; TASK_INFO_NAME_MAX_LENGTH];
+    let name_length = usize::min(target.name.len(), TASK_INFO_NAME_MAX_LENGTH);
+    name[0..name_length].copy_from_slice(&target.name.as_bytes()[0..name_length]);
+
+    let info = TaskInfo {
+        cpu_time: target.cpu_time(),
+        context_switches: target.stats.context_switches.load(Ordering::Relaxed),
+        syscalls: target.stats.syscalls.load(Ordering::Relaxed),
+        committed_memory: target.committed_memory() as u64,
+        num_handles: target.handles.read().len() as u64,
+        name_length: name_length as u8,
+        name,
+        _reserved: [0; 7],
+    };
+
+    UserPointer::new(&task.address_space, info_address as *mut TaskInfo, true)
+        .write(info)
+        .map_err(|()| GetTaskInfoError::InfoAddressInvalid)?;
+    Ok(())
+}
+
+fn power_control<P>(task: &Arc<Task<P>>, action: usize) -> Result<(), PowerControlError>
+where
+    P: Platform,
+{
+    if !task.capabilities.contains(&Capability::PowerControl) {
+        return Err(PowerControlError::TaskDoesNotHaveCorrectCapability);
+    }
+
+    let action = PowerAction::try_from(action).map_err(|()| PowerControlError::InvalidAction)?;
+    info!("Task '{}' requested power action: {:?}", task.name, action);
+
+    match action {
+        PowerAction::Shutdown => {
+            P::power_off();
+            Err(PowerControlError::ActionFailed)
+        }
+        PowerAction::Reboot => P::reboot(),
+    }
+}
+
+fn acpi_get_info<P>(
+    task: &Arc<Task<P>>,
+    buffer_address: usize,
+    buffer_size: usize,
+) -> Result<usize, AcpiGetInfoError>
+where
+    P: Platform,
+{
+    use crate::acpi::AcpiResource;
+    use libpebble::syscall::acpi::{AcpiDeviceInfo, ACPI_MAX_CIDS, ACPI_MAX_RESOURCES, ACPI_PATH_MAX_LENGTH};
+
+    if !task.capabilities.contains(&Capability::AcpiBusDriver) {
+        return Err(AcpiGetInfoError::TaskDoesNotHaveCorrectCapability);
+    }
+
+    let acpi_info = crate::ACPI_INFO.read();
+    let acpi_info = acpi_info.as_ref().ok_or(AcpiGetInfoError::PlatformDoesNotSupportAcpi)?;
+
+    /*
+     * Devices are passed to userspace in fixed-size structures, so we can't pass out devices with paths or
+     * Hardware IDs that are too long. These are very unusual.
+     */
+    let devices: Vec<&crate::acpi::AcpiDevice> = acpi_info
+        .devices
+        .iter()
+        .filter(|device| device.path.len() <= ACPI_PATH_MAX_LENGTH && device.hid.len() <= ACPI_ID_MAX_LENGTH)
+        .collect();
+    let num_descriptors = devices.len();
+
+    if buffer_size == 0 || buffer_address == 0x0 || buffer_size < num_descriptors {
+        return Err(AcpiGetInfoError::BufferNotLargeEnough(num_descriptors as u32));
+    }
 
-        if buffer_size > 0 && buffer_address != 0x0 {
-            if buffer_size < num_descriptors {
-                return Err(PciGetInfoError::BufferNotLargeEnough(num_descriptors as u32));
+    /*
+     * Check the buffer before we hand anything out, so we don't create handles the task never finds out about.
+     */
+    let mut buffer = UserSlice::new(&task.address_space, buffer_address as *mut AcpiDeviceInfo, buffer_size);
+    buffer.validate_write(num_descriptors).map_err(|()| AcpiGetInfoError::BufferPointerInvalid)?;
+
+    /*
+     * Handles to each device's resources are only handed out once. If they've already been claimed, the resources
+     * are still described, but without handles.
+     */
+    let mut added_handles = Vec::new();
+    let mut claimed_devices = Vec::new();
+    let mut descriptors = Vec::with_capacity(num_descriptors);
+    for device in devices {
+        let mut descriptor =
+            AcpiDeviceInfo::new(AcpiString::new(&device.path).unwrap(), AcpiString::new(&device.hid).unwrap());
+
+        for cid in device.cids.iter().filter_map(|cid| AcpiString::new(cid)).take(ACPI_MAX_CIDS) {
+            descriptor.add_cid(cid).unwrap();
+        }
+        if let Some(uid) = device.uid.as_ref().and_then(|uid| AcpiString::new(uid)) {
+            descriptor.set_uid(uid);
+        }
+
+        if device.resources.len() > ACPI_MAX_RESOURCES {
+            warn!("ACPI device {} has too many resources. Not all will be passed to userspace", device.path);
+        }
+        let claim = !device.resources_claimed.swap(true, Ordering::AcqRel);
+        if claim {
+            claimed_devices.push(device);
+        }
+        let mut add_handle = |object: Arc<dyn KernelObject>| {
+            if claim {
+                let handle = task.add_handle(object);
+                added_handles.push(handle);
+                handle
+            } else {
+                ZERO_HANDLE
             }
+        };
 
-            let descriptor_buffer = UserSlice::new(buffer_address as *mut PciDeviceInfo, buffer_size)
-                .validate_write()
-                .map_err(|()| PciGetInfoError::BufferPointerInvalid)?;
-
-            for (i, (&address, device)) in pci_info.devices.iter().enumerate() {
-                let mut device_descriptor = libpebble::syscall::PciDeviceInfo {
-                    address,
-                    vendor_id: device.vendor_id,
-                    device_id: device.device_id,
-                    revision: device.revision,
-                    class: device.class,
-                    sub_class: device.sub_class,
-                    interface: device.interface,
-                    bars: [NONE_BAR; MAX_BARS],
-                };
-
-                for i in 0..MAX_BARS {
-                    match device.bars[i] {
-                        Some(Bar::Memory32 { address, size, prefetchable }) => {
-                            let flags = Flags {
-                                writable: true,
-                                executable: false,
-                                user_accessible: true,
-                                cached: prefetchable,
-                            };
-                            // TODO: should the requesting task own the BAR memory objects, or should the kernel?
-                            let memory_object = MemoryObject::new(
-                                task.id(),
-                                None,
-                                PhysicalAddress::new(address as usize).unwrap(),
-                                size as usize,
-                                flags,
-                            );
-                            let handle = task.add_handle(memory_object);
-                            device_descriptor.bars[i] =
-                                Some(libpebble::syscall::pci::Bar::Memory32 { memory_object: handle, size });
-                        }
-                        Some(Bar::Memory64 { address, size, prefetchable }) => {
-                            let flags = Flags {
-                                writable: true,
-                                executable: false,
-                                user_accessible: true,
-                                cached: prefetchable,
-                            };
-                            // TODO: should the requesting task own the BAR memory objects, or should the kernel?
-                            let memory_object = MemoryObject::new(
-                                task.id(),
-                                None,
-                                PhysicalAddress::new(address as usize).unwrap(),
-                                size as usize,
-                                flags,
-                            );
-                            let handle = task.add_handle(memory_object);
-                            device_descriptor.bars[i] =
-                                Some(libpebble::syscall::pci::Bar::Memory64 { memory_object: handle, size });
-                        }
-                        Some(Bar::Io { .. }) => warn!("PCI device at {} has an I/O BAR. We don't support these, and so they're not passed out to userspace", address),
-                        None => (),
+        for resource in device.resources.iter().take(ACPI_MAX_RESOURCES) {
+            let resource = match resource {
+                AcpiResource::Memory { memory_object, offset, size } => {
+                    libpebble::syscall::acpi::AcpiResource::Memory {
+                        memory_object: add_handle(memory_object.clone()),
+                        offset: *offset,
+                        size: *size,
                     }
                 }
+                AcpiResource::Io(io_port_range) => libpebble::syscall::acpi::AcpiResource::Io {
+                    io_port_range: add_handle(io_port_range.clone()),
+                    base: io_port_range.base,
+                    length: io_port_range.length,
+                },
+                AcpiResource::Irq(irq) => libpebble::syscall::acpi::AcpiResource::Irq(*irq),
+            };
+            descriptor.add_resource(resource).unwrap();
+        }
 
-                descriptor_buffer[i] = device_descriptor;
-            }
+        descriptors.push(descriptor);
+    }
 
-            let mut status = 0;
-            status.set_bits(16..48, num_descriptors);
-            Ok(status)
-        } else {
-            Err(PciGetInfoError::BufferNotLargeEnough(num_descriptors as u32))
+    /*
+     * The write can still fail if the task's mappings changed since we checked. If it does, take back what we
+     * handed out, so it can be claimed again.
+     */
+    if buffer.write(&descriptors).is_err() {
+        let mut task_handles = task.handles.write();
+        for handle in added_handles {
+            task_handles.remove(&handle);
         }
-    } else {
-        Err(PciGetInfoError::PlatformDoesNotSupportPci)
+        for device in claimed_devices {
+            device.resources_claimed.store(false, Ordering::Release);
+        }
+        return Err(AcpiGetInfoError::BufferPointerInvalid);
     }
+
+    let mut status = 0;
+    status.set_bits(16..48, descriptors.len());
+    Ok(status)
 }
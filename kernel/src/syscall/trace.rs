@@ -0,0 +1,217 @@
+//! Support for tracing the system calls made by chosen tasks. Tracing is turned on and off by task name (using
+//! the `set_syscall_tracing` system call), and each system call made by a traced task is decoded, logged, and
+//! recorded in a trace buffer. The trace buffer only holds the most recent `TRACE_BUFFER_LENGTH` records.
+
+use crate::object::KernelObjectId;
+use alloc::{collections::BTreeSet, string::String, vec::Vec};
+use bit_field::BitField;
+use core::{
+    convert::TryFrom,
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use libpebble::syscall;
+use log::{info, trace};
+use spin::{Mutex, RwLock};
+
+pub const TRACE_BUFFER_LENGTH: usize = 256;
+
+/// The names of the tasks whose system calls are being traced.
+static TRACED_TASKS: RwLock<BTreeSet<String>> = RwLock::new(BTreeSet::new());
+/// Set if any tasks are being traced. This means we don't need to take the lock on `TRACED_TASKS` on every system
+/// call when nothing is being traced, which is almost always the case.
+static TRACING_ENABLED: AtomicBool = AtomicBool::new(false);
+static TRACE_BUFFER: Mutex<TraceBuffer> = Mutex::new(TraceBuffer::new());
+
+pub fn set_tracing(task_name: String, enabled: bool) {
+    let mut traced_tasks = TRACED_TASKS.write();
+
+    if enabled {
+        traced_tasks.insert(task_name);
+    } else {
+        traced_tasks.remove(&task_name);
+    }
+
+    TRACING_ENABLED.store(!traced_tasks.is_empty(), Ordering::Release);
+}
+
+pub fn is_traced(task_name: &str) -> bool {
+    TRACING_ENABLED.load(Ordering::Acquire) && TRACED_TASKS.read().contains(task_name)
+}
+
+pub struct TraceRecord {
+    pub task_id: KernelObjectId,
+    pub task_name: String,
+    pub number: usize,
+    pub params: [usize; 6],
+    pub result: usize,
+    /// How long the system call took, in the platform's timestamp units (see `Platform::read_timestamp`). This
+    /// includes any time the task spent blocked in the system call.
+    pub duration: u64,
+}
+
+/// Log a system call made by a traced task, and add it to the trace buffer.
+pub fn record(record: TraceRecord) {
+    trace!("{}", record);
+    TRACE_BUFFER.lock().push(record);
+}
+
+/// Log the contents of the trace buffer, oldest record first. This doesn't wait for the trace buffer's lock, so
+/// it can be used from places like the panic handler, where the lock may already be held.
+pub fn dump_trace_buffer() {
+    if let Some(buffer) = TRACE_BUFFER.try_lock() {
+        if buffer.records.len() == 0 {
+            return;
+        }
+
+        info!("Most recent traced system calls (oldest first):");
+        for record in buffer.iter() {
+            info!("    {}", record);
+        }
+    }
+}
+
+struct TraceBuffer {
+    records: Vec<TraceRecord>,
+    /// The index of the oldest record, which is overwritten by the next record once the buffer is full.
+    next: usize,
+}
+
+impl TraceBuffer {
+    const fn new() -> TraceBuffer {
+        TraceBuffer { records: Vec::new(), next: 0 }
+    }
+
+    fn push(&mut self, record: TraceRecord) {
+        if self.records.len() < TRACE_BUFFER_LENGTH {
+            self.records.push(record);
+        } else {
+            self.records[self.next] = record;
+            self.next = (self.next + 1) % TRACE_BUFFER_LENGTH;
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &TraceRecord> {
+        self.records[self.next..].iter().chain(self.records[..self.next].iter())
+    }
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (name, num_params) = syscall_name_and_num_params(self.number);
+
+        write!(f, "[{} ({:?})] {}(", self.task_name, self.task_id, name)?;
+        for (i, param) in self.params[0..num_params].iter().enumerate() {
+            if i != 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{:#x}", param)?;
+        }
+        write!(f, ") -> ")?;
+        write_result(f, self.number, self.result)?;
+        write!(f, " (took {} ticks)", self.duration)
+    }
+}
+
+fn syscall_name_and_num_params(number: usize) -> (&'static str, usize) {
+    match number {
+        syscall::SYSCALL_YIELD => ("yield", 0),
+        syscall::SYSCALL_EARLY_LOG => ("early_log", 2),
+        syscall::SYSCALL_GET_FRAMEBUFFER => ("get_framebuffer", 1),
+        syscall::SYSCALL_CREATE_MEMORY_OBJECT => ("create_memory_object", 4),
+        syscall::SYSCALL_MAP_MEMORY_OBJECT => ("map_memory_object", 4),
+        syscall::SYSCALL_CREATE_CHANNEL => ("create_channel", 0),
+        syscall::SYSCALL_SEND_MESSAGE => ("send_message", 6),
+        syscall::SYSCALL_GET_MESSAGE => ("get_message", 5),
+        syscall::SYSCALL_WAIT_FOR_MESSAGE => ("wait_for_message", 5),
+        syscall::SYSCALL_REGISTER_SERVICE => ("register_service", 2),
+        syscall::SYSCALL_SUBSCRIBE_TO_SERVICE => ("subscribe_to_service", 2),
+        syscall::SYSCALL_PCI_GET_INFO => ("pci_get_info", 2),
+        syscall::SYSCALL_CHANNEL_CALL => ("channel_call", 2),
+        syscall::SYSCALL_SET_SYSCALL_TRACING => ("set_syscall_tracing", 3),
+        syscall::SYSCALL_DRAIN_KERNEL_LOG => ("drain_kernel_log", 2),
+        syscall::SYSCALL_GET_TASK_INFO => ("get_task_info", 2),
+        syscall::SYSCALL_POWER_CONTROL => ("power_control", 1),
+        syscall::SYSCALL_ACPI_GET_INFO => ("acpi_get_info", 2),
+        syscall::SYSCALL_PCI_CONFIG_READ => ("pci_config_read", 2),
+        syscall::SYSCALL_PCI_CONFIG_WRITE => ("pci_config_write", 3),
+        syscall::SYSCALL_MAP_IO_PORT_RANGE => ("map_io_port_range", 1),
+        syscall::SYSCALL_PCI_ATTACH_DMA_MEMORY => ("pci_attach_dma_memory", 2),
+        _ => ("<invalid system call>", 6),
+    }
+}
+
+/// Decode the result of a system call. Each system call encodes its result in one of a few ways (see
+/// `libpebble::syscall::result`), and errors are decoded back into the error types used by `libpebble`.
+fn write_result(f: &mut fmt::Formatter, number: usize, result: usize) -> fmt::Result {
+    use libpebble::syscall::*;
+
+    match number {
+        SYSCALL_YIELD => write!(f, "{}", result),
+        SYSCALL_EARLY_LOG => write_status::<EarlyLogError>(f, result),
+        SYSCALL_GET_FRAMEBUFFER => write_handle::<GetFramebufferError>(f, result),
+        SYSCALL_CREATE_MEMORY_OBJECT => write_handle::<CreateMemoryObjectError>(f, result),
+        SYSCALL_MAP_MEMORY_OBJECT => write_status::<MapMemoryObjectError>(f, result),
+        SYSCALL_SEND_MESSAGE => write_status::<SendMessageError>(f, result),
+        SYSCALL_GET_MESSAGE | SYSCALL_WAIT_FOR_MESSAGE => {
+            write_payload::<GetMessageError>(f, result, result.get_bits(0..16))
+        }
+        SYSCALL_REGISTER_SERVICE => write_handle::<RegisterServiceError>(f, result),
+        SYSCALL_SUBSCRIBE_TO_SERVICE => write_handle::<SubscribeToServiceError>(f, result),
+        // `PciGetInfoError` carries a payload, so it decodes the whole result itself
+        SYSCALL_PCI_GET_INFO => write_payload::<PciGetInfoError>(f, result, result),
+        SYSCALL_CHANNEL_CALL => write_payload::<ChannelCallError>(f, result, result.get_bits(0..16)),
+        SYSCALL_SET_SYSCALL_TRACING => write_status::<SetSyscallTracingError>(f, result),
+        SYSCALL_DRAIN_KERNEL_LOG => write_payload::<DrainKernelLogError>(f, result, result.get_bits(0..16)),
+        SYSCALL_GET_TASK_INFO => write_status::<GetTaskInfoError>(f, result),
+        SYSCALL_POWER_CONTROL => write_status::<PowerControlError>(f, result),
+        SYSCALL_ACPI_GET_INFO => write_payload::<AcpiGetInfoError>(f, result, result),
+        SYSCALL_PCI_CONFIG_READ => write_payload::<PciConfigError>(f, result, result.get_bits(0..16)),
+        SYSCALL_PCI_CONFIG_WRITE => write_status::<PciConfigError>(f, result),
+        SYSCALL_MAP_IO_PORT_RANGE => write_status::<MapIoPortRangeError>(f, result),
+        SYSCALL_PCI_ATTACH_DMA_MEMORY => write_status::<PciAttachDmaMemoryError>(f, result),
+        _ => write!(f, "{:#x}", result),
+    }
+}
+
+fn write_status<E>(f: &mut fmt::Formatter, result: usize) -> fmt::Result
+where
+    E: TryFrom<usize, Error = ()> + fmt::Debug,
+{
+    if result == 0 {
+        write!(f, "Ok")
+    } else {
+        write_error::<E>(f, result)
+    }
+}
+
+fn write_handle<E>(f: &mut fmt::Formatter, result: usize) -> fmt::Result
+where
+    E: TryFrom<usize, Error = ()> + fmt::Debug,
+{
+    match result.get_bits(0..32) {
+        0 => write!(f, "Ok(Handle({}))", result.get_bits(32..64)),
+        status => write_error::<E>(f, status),
+    }
+}
+
+fn write_payload<E>(f: &mut fmt::Formatter, result: usize, error: usize) -> fmt::Result
+where
+    E: TryFrom<usize, Error = ()> + fmt::Debug,
+{
+    if result.get_bits(0..16) == 0 {
+        write!(f, "Ok({:#x})", result)
+    } else {
+        write_error::<E>(f, error)
+    }
+}
+
+fn write_error<E>(f: &mut fmt::Formatter, status: usize) -> fmt::Result
+where
+    E: TryFrom<usize, Error = ()> + fmt::Debug,
+{
+    match E::try_from(status) {
+        Ok(error) => write!(f, "Err({:?})", error),
+        Err(()) => write!(f, "Err(<unknown error {:#x}>)", status),
+    }
+}
@@ -3,80 +3,183 @@
 //! address from userspace, we should make sure it's mapped (so we don't page-fault) and an address
 //! that userspace could ordinarily access itself (otherwise, we could leak information to a
 //! userspace task that it shouldn't be able to access).
+//!
+//! Every access is checked against the task's page tables before it is made, and then made using the platform's
+//! user-copy routines, which turn any fault that does still occur into an error instead of a kernel panic.
+//!
+//! NOTE: these types copy `T`s to and from userspace byte-by-byte, so they must only be used with types for which
+//! any bit-pattern is valid (e.g. integers and `Handle`s, but not `bool`s or most enums).
 
-use core::{marker::PhantomData, ptr, slice, str};
+use crate::{object::address_space::AddressSpace, Platform};
+use alloc::{string::String, vec::Vec};
+use core::{marker::PhantomData, mem, mem::MaybeUninit};
+use hal::memory::VirtualAddress;
 
-pub struct UserPointer<T> {
+/// Check that a region of `size` bytes starting at `address` is a valid region for userspace to pass to the
+/// kernel:
+///     - the address is canonical
+///     - the whole region is in the userspace part of the address space
+///     - the address is correctly aligned to `align`
+///     - the region is actually mapped, and accessible to userspace
+///     - if we're writing, that the mapping is writable
+fn validate_region<P>(
+    address_space: &AddressSpace<P>,
+    address: usize,
+    size: usize,
+    align: usize,
+    needs_write: bool,
+) -> Result<(), ()>
+where
+    P: Platform,
+{
+    if size == 0 {
+        return Ok(());
+    }
+
+    let start = VirtualAddress::try_new(address).ok_or(())?;
+    let end = VirtualAddress::try_new(address.checked_add(size - 1).ok_or(())?).ok_or(())?;
+
+    if end > P::USER_ADDRESS_SPACE_TOP || !start.is_aligned(align) {
+        return Err(());
+    }
+
+    if address_space.is_region_accessible(start, end, needs_write) {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
+
+/// Check that a page-aligned region of `size` bytes starting at `address` lies entirely within the userspace part
+/// of the address space, so userspace can map memory there. Unlike the other checks in this module, this doesn't
+/// require anything to already be mapped.
+pub fn validate_user_region<P>(address: usize, size: usize) -> Result<VirtualAddress, ()>
+where
+    P: Platform,
+{
+    use hal::memory::{FrameSize, Size4KiB};
+
+    let last_byte = address.checked_add(size.checked_sub(1).ok_or(())?).ok_or(())?;
+    let start = VirtualAddress::try_new(address).ok_or(())?;
+    let end = VirtualAddress::try_new(last_byte).ok_or(())?;
+
+    if !start.is_aligned(Size4KiB::SIZE) || end > P::USER_ADDRESS_SPACE_TOP {
+        return Err(());
+    }
+
+    Ok(start)
+}
+
+pub struct UserPointer<'a, P, T>
+where
+    P: Platform,
+{
+    address_space: &'a AddressSpace<P>,
     ptr: *mut T,
     can_write: bool,
 }
 
-impl<T> UserPointer<T> {
-    pub fn new(ptr: *mut T, needs_write: bool) -> UserPointer<T> {
-        // TODO: validate that this is a valid pointer:
-        //  - the address is canonical
-        //  - the address is in user-space
-        //  - the address is actually mapped for a size of `T`
-        //  - the address is correctly aligned for `T`
-        //  - if we're writing, that the mapping is writable
-        UserPointer { ptr, can_write: needs_write }
+impl<'a, P, T> UserPointer<'a, P, T>
+where
+    P: Platform,
+{
+    pub fn new(address_space: &'a AddressSpace<P>, ptr: *mut T, needs_write: bool) -> UserPointer<'a, P, T> {
+        UserPointer { address_space, ptr, can_write: needs_write }
     }
 
     pub fn read(&self) -> Result<T, ()> {
-        Ok(unsafe { ptr::read_volatile(self.ptr) })
+        validate_region(self.address_space, self.ptr as usize, mem::size_of::<T>(), mem::align_of::<T>(), false)?;
+
+        let mut value = MaybeUninit::<T>::uninit();
+        unsafe {
+            P::copy_from_user(value.as_mut_ptr() as *mut u8, self.ptr as *const u8, mem::size_of::<T>())?;
+            Ok(value.assume_init())
+        }
     }
 
     pub fn write(&mut self, value: T) -> Result<(), ()> {
         if !self.can_write {
             return Err(());
         }
+        validate_region(self.address_space, self.ptr as usize, mem::size_of::<T>(), mem::align_of::<T>(), true)?;
 
         /*
-         * This has two subtleties:
-         *    - Using `write_volatile` instead of `write` makes sure the compiler doesn't think it can elide the
-         *      write, as the data is read and written to from both the kernel and userspace.
-         *    - Using `ptr::write_volatile(x, ...)` instead of `*x = ...` makes sure we don't attempt to drop
-         *      the existing value, which could read uninitialized memory.
+         * We copy the value's bytes out, so we shouldn't drop it here as well.
          */
-        unsafe { ptr::write_volatile(self.ptr, value) }
-        Ok(())
+        let value = mem::ManuallyDrop::new(value);
+        unsafe { P::copy_to_user(self.ptr as *mut u8, &*value as *const T as *const u8, mem::size_of::<T>()) }
     }
 }
 
 /// Represents a slice of `T`s in userspace.
-pub struct UserSlice<'a, T> {
+pub struct UserSlice<'a, P, T>
+where
+    P: Platform,
+{
+    address_space: &'a AddressSpace<P>,
     ptr: *mut T,
     length: usize,
-    _phantom: PhantomData<&'a ()>,
+    _phantom: PhantomData<T>,
 }
 
-impl<'a, T> UserSlice<'a, T> {
-    pub fn new(ptr: *mut T, length: usize) -> UserSlice<'a, T> {
-        UserSlice { ptr, length, _phantom: PhantomData }
+impl<'a, P, T> UserSlice<'a, P, T>
+where
+    P: Platform,
+{
+    pub fn new(address_space: &'a AddressSpace<P>, ptr: *mut T, length: usize) -> UserSlice<'a, P, T> {
+        UserSlice { address_space, ptr, length, _phantom: PhantomData }
+    }
+
+    /// Copy the contents of this slice into the kernel.
+    pub fn read(&self) -> Result<Vec<T>, ()> {
+        let size = self.length.checked_mul(mem::size_of::<T>()).ok_or(())?;
+        validate_region(self.address_space, self.ptr as usize, size, mem::align_of::<T>(), false)?;
+
+        let mut buffer = Vec::with_capacity(self.length);
+        unsafe {
+            P::copy_from_user(buffer.as_mut_ptr() as *mut u8, self.ptr as *const u8, size)?;
+            buffer.set_len(self.length);
+        }
+        Ok(buffer)
     }
 
-    pub fn validate_read(&self) -> Result<&'a [T], ()> {
-        // TODO: validate access is valid
-        Ok(unsafe { slice::from_raw_parts(self.ptr, self.length) })
+    /// Check that `length` `T`s could be written into the start of this slice. This lets system calls check the
+    /// buffer they're writing into before doing work that's hard to undo (e.g. handing out handles), but `write`
+    /// can still fail if the task's mappings change in the meantime.
+    pub fn validate_write(&self, length: usize) -> Result<(), ()> {
+        if length > self.length {
+            return Err(());
+        }
+
+        let size = length.checked_mul(mem::size_of::<T>()).ok_or(())?;
+        validate_region(self.address_space, self.ptr as usize, size, mem::align_of::<T>(), true)
     }
 
-    /// Validate this slice for a write, BUT DOES NOT ACTUALLY WRITE ANYTHING INTO IT. You must write into the
-    /// returned mutable reference, generally using either `copy_from_slice` if `T: Copy`, or `clone_from_slice`
-    /// otherwise.
-    pub fn validate_write(&mut self) -> Result<&'a mut [T], ()> {
-        // TODO: validate access is valid
-        Ok(unsafe { slice::from_raw_parts_mut(self.ptr, self.length) })
+    /// Copy `data` into the start of this slice. Fails if `data` is longer than the slice.
+    pub fn write(&mut self, data: &[T]) -> Result<(), ()> {
+        if data.len() > self.length {
+            return Err(());
+        }
+
+        let size = data.len() * mem::size_of::<T>();
+        validate_region(self.address_space, self.ptr as usize, size, mem::align_of::<T>(), true)?;
+        unsafe { P::copy_to_user(self.ptr as *mut u8, data.as_ptr() as *const u8, size) }
     }
 }
 
-pub struct UserString<'a>(UserSlice<'a, u8>);
+pub struct UserString<'a, P>(UserSlice<'a, P, u8>)
+where
+    P: Platform;
 
-impl<'a> UserString<'a> {
-    pub fn new(ptr: *mut u8, length: usize) -> UserString<'a> {
-        UserString(UserSlice::new(ptr, length))
+impl<'a, P> UserString<'a, P>
+where
+    P: Platform,
+{
+    pub fn new(address_space: &'a AddressSpace<P>, ptr: *mut u8, length: usize) -> UserString<'a, P> {
+        UserString(UserSlice::new(address_space, ptr, length))
     }
 
-    pub fn validate(&self) -> Result<&'a str, ()> {
-        str::from_utf8(self.0.validate_read()?).map_err(|_| ())
+    pub fn validate(&self) -> Result<String, ()> {
+        String::from_utf8(self.0.read()?).map_err(|_| ())
     }
 }
@@ -47,13 +47,13 @@ where
     F: Format,
 {
     fn write_str(&mut self, s: &str) -> Result<(), fmt::Error> {
-        /*
-         * We include a small font that only includes ASCII characters, which also allows us to take some shortcuts
-         * here.
-         */
-        assert!(s.is_ascii());
-
         for c in s.chars() {
+            /*
+             * We include a small font that only includes ASCII characters, which also allows us to take some
+             * shortcuts here. Anything else is drawn as a `?`.
+             */
+            let c = if c.is_ascii() { c } else { '?' };
+
             if c != '\n' {
                 self.framebuffer.draw_glyph(
                     c,
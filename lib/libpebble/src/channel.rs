@@ -1,5 +1,12 @@
 use crate::{
-    syscall::{self, GetMessageError, RegisterServiceError, SendMessageError, CHANNEL_MAX_NUM_HANDLES},
+    syscall::{
+        self,
+        ChannelCallError as ChannelCallSyscallError,
+        GetMessageError,
+        RegisterServiceError,
+        SendMessageError,
+        CHANNEL_MAX_NUM_HANDLES,
+    },
     Handle,
 };
 use core::{marker::PhantomData, mem};
@@ -17,6 +24,13 @@ pub enum ChannelReceiveError {
     ReceiveError(GetMessageError),
 }
 
+#[derive(Debug)]
+pub enum ChannelCallError {
+    FailedToSerialize(ptah::ser::Error),
+    FailedToDeserialize(ptah::de::Error),
+    CallError(ChannelCallSyscallError),
+}
+
 pub struct Channel<S, R>(Handle, PhantomData<(S, R)>)
 where
     S: Serialize + DeserializeOwned,
@@ -38,6 +52,23 @@ where
             .map_err(|err| ChannelSendError::SendError(err))
     }
 
+    /// Send a message down the channel, and block until the other end replies to it. This is much cheaper than
+    /// sending a message and then polling for the reply, as the kernel can switch straight to the receiving task.
+    pub fn call(&self, message: &S) -> Result<R, ChannelCallError> {
+        let mut writer = ChannelWriter::new();
+        ptah::to_wire(message, &mut writer).map_err(|err| ChannelCallError::FailedToSerialize(err))?;
+
+        let mut byte_buffer = [0u8; BYTES_BUFFER_SIZE];
+        let mut handle_buffer = [crate::ZERO_HANDLE; CHANNEL_MAX_NUM_HANDLES];
+        let (bytes, handles) =
+            syscall::channel_call(&self.0, writer.bytes(), writer.handles(), &mut byte_buffer, &mut handle_buffer)
+                .map_err(|err| ChannelCallError::CallError(err))?;
+
+        // TODO: see the note in `try_receive` about this
+        let ptah_handles: &[u32] = unsafe { mem::transmute(handles) };
+        ptah::from_wire(bytes, ptah_handles).map_err(|err| ChannelCallError::FailedToDeserialize(err))
+    }
+
     /// Receive a message from the channel, if there's one waiting. Returns `Ok(None)` if there are no pending
     /// messages to be received.
     pub fn try_receive(&self) -> Result<Option<R>, ChannelReceiveError> {
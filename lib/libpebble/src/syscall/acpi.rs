@@ -0,0 +1,291 @@
+use super::{raw, SYSCALL_ACPI_GET_INFO};
+use crate::{bit_field::BitField, Handle};
+use core::{convert::TryFrom, fmt, str};
+
+pub const ACPI_PATH_MAX_LENGTH: usize = 64;
+pub const ACPI_ID_MAX_LENGTH: usize = 16;
+pub const ACPI_MAX_CIDS: usize = 4;
+pub const ACPI_MAX_RESOURCES: usize = 8;
+
+/// Describes a device found in the platform's ACPI namespace. These are devices that can't be found by enumerating
+/// a bus, such as the RTC or the PS/2 controller.
+///
+/// These are copied out of the kernel byte-for-byte, so they have a fixed layout with no padding, and no enums or
+/// `Option`s (whose padding and unused payloads would be left uninitialized). The Compatible IDs, Unique ID, and
+/// resources should be accessed through the methods on this type.
+#[repr(C)]
+pub struct AcpiDeviceInfo {
+    /// The absolute path of the device's object in the namespace (e.g. `\_SB_.PCI0.SF8_.KBD_`).
+    pub path: AcpiString<ACPI_PATH_MAX_LENGTH>,
+    /// The device's Hardware ID (`_HID`), such as `PNP0303`.
+    pub hid: AcpiString<ACPI_ID_MAX_LENGTH>,
+    cids: [AcpiString<ACPI_ID_MAX_LENGTH>; ACPI_MAX_CIDS],
+    num_cids: u8,
+    uid: AcpiString<ACPI_ID_MAX_LENGTH>,
+    has_uid: u8,
+    num_resources: u8,
+    _reserved: [u8; 6],
+    resources: [RawAcpiResource; ACPI_MAX_RESOURCES],
+}
+
+impl AcpiDeviceInfo {
+    /// Create an `AcpiDeviceInfo` for a device with no Compatible IDs, Unique ID, or resources.
+    pub fn new(path: AcpiString<ACPI_PATH_MAX_LENGTH>, hid: AcpiString<ACPI_ID_MAX_LENGTH>) -> AcpiDeviceInfo {
+        AcpiDeviceInfo {
+            path,
+            hid,
+            cids: [AcpiString::empty(); ACPI_MAX_CIDS],
+            num_cids: 0,
+            uid: AcpiString::empty(),
+            has_uid: 0,
+            num_resources: 0,
+            _reserved: [0; 6],
+            resources: [RawAcpiResource::EMPTY; ACPI_MAX_RESOURCES],
+        }
+    }
+
+    /// Add a Compatible ID. Returns `Err(())` if the device already has `ACPI_MAX_CIDS` Compatible IDs.
+    pub fn add_cid(&mut self, cid: AcpiString<ACPI_ID_MAX_LENGTH>) -> Result<(), ()> {
+        let slot = self.cids.get_mut(self.num_cids as usize).ok_or(())?;
+        *slot = cid;
+        self.num_cids += 1;
+        Ok(())
+    }
+
+    pub fn set_uid(&mut self, uid: AcpiString<ACPI_ID_MAX_LENGTH>) {
+        self.uid = uid;
+        self.has_uid = 1;
+    }
+
+    /// Add a resource. Returns `Err(())` if the device already has `ACPI_MAX_RESOURCES` resources.
+    pub fn add_resource(&mut self, resource: AcpiResource) -> Result<(), ()> {
+        let slot = self.resources.get_mut(self.num_resources as usize).ok_or(())?;
+        *slot = RawAcpiResource::from(resource);
+        self.num_resources += 1;
+        Ok(())
+    }
+
+    /// The device's Compatible IDs (`_CID`). Drivers that match against a Hardware ID should usually also check
+    /// these.
+    pub fn cids(&self) -> &[AcpiString<ACPI_ID_MAX_LENGTH>] {
+        &self.cids[0..(self.num_cids as usize)]
+    }
+
+    /// The device's Unique ID (`_UID`), which distinguishes it from other devices with the same Hardware ID.
+    pub fn uid(&self) -> Option<&AcpiString<ACPI_ID_MAX_LENGTH>> {
+        if self.has_uid != 0 {
+            Some(&self.uid)
+        } else {
+            None
+        }
+    }
+
+    /// Take the device's resources. This consumes the `AcpiDeviceInfo`, as the resources contain handles.
+    pub fn into_resources(self) -> impl Iterator<Item = AcpiResource> {
+        let resources = self.resources;
+        (0..(self.num_resources as usize)).filter_map(move |i| resources[i].to_resource())
+    }
+}
+
+impl fmt::Debug for AcpiDeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AcpiDeviceInfo")
+            .field("path", &self.path)
+            .field("hid", &self.hid)
+            .field("cids", &self.cids())
+            .field("uid", &self.uid())
+            .field("num_resources", &self.num_resources)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub enum AcpiResource {
+    /// A range of memory-mapped registers. Memory objects must start on a page boundary, so the registers start
+    /// `offset` bytes into `memory_object`.
+    Memory {
+        memory_object: Handle,
+        offset: usize,
+        size: usize,
+    },
+    /// A range of I/O ports. The ports can be accessed once `io_port_range` has been mapped with
+    /// `map_io_port_range`.
+    Io {
+        io_port_range: Handle,
+        base: u16,
+        length: u16,
+    },
+    Irq(u32),
+}
+
+/// The fixed layout an `AcpiResource` is passed out of the kernel in. The meaning of `a` and `b` depends on `kind`.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct RawAcpiResource {
+    kind: u32,
+    handle: u32,
+    a: u64,
+    b: u64,
+}
+
+impl RawAcpiResource {
+    const KIND_NONE: u32 = 0;
+    const KIND_MEMORY: u32 = 1;
+    const KIND_IO: u32 = 2;
+    const KIND_IRQ: u32 = 3;
+
+    const EMPTY: RawAcpiResource = RawAcpiResource { kind: Self::KIND_NONE, handle: 0, a: 0, b: 0 };
+
+    fn to_resource(&self) -> Option<AcpiResource> {
+        match self.kind {
+            Self::KIND_MEMORY => Some(AcpiResource::Memory {
+                memory_object: Handle(self.handle),
+                offset: self.a as usize,
+                size: self.b as usize,
+            }),
+            Self::KIND_IO => Some(AcpiResource::Io {
+                io_port_range: Handle(self.handle),
+                base: self.a as u16,
+                length: self.b as u16,
+            }),
+            Self::KIND_IRQ => Some(AcpiResource::Irq(self.a as u32)),
+            _ => None,
+        }
+    }
+}
+
+impl From<AcpiResource> for RawAcpiResource {
+    fn from(resource: AcpiResource) -> RawAcpiResource {
+        match resource {
+            AcpiResource::Memory { memory_object, offset, size } => RawAcpiResource {
+                kind: Self::KIND_MEMORY,
+                handle: memory_object.0,
+                a: offset as u64,
+                b: size as u64,
+            },
+            AcpiResource::Io { io_port_range, base, length } => {
+                RawAcpiResource { kind: Self::KIND_IO, handle: io_port_range.0, a: base as u64, b: length as u64 }
+            }
+            AcpiResource::Irq(irq) => RawAcpiResource { kind: Self::KIND_IRQ, handle: 0, a: irq as u64, b: 0 },
+        }
+    }
+}
+
+/// A string of at most `N` bytes, stored inline so it can be passed out of the kernel in an `AcpiDeviceInfo`.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct AcpiString<const N: usize> {
+    length: u8,
+    bytes: [u8; N],
+}
+
+impl<const N: usize> AcpiString<{ N }> {
+    pub const fn empty() -> AcpiString<{ N }> {
+        AcpiString { length: 0, bytes: [0; N] }
+    }
+
+    /// Create a new `AcpiString`. Returns `None` if `string` is longer than `N` bytes.
+    pub fn new(string: &str) -> Option<AcpiString<{ N }>> {
+        if string.len() > N {
+            return None;
+        }
+
+        let mut bytes = [0; N];
+        bytes[0..string.len()].copy_from_slice(string.as_bytes());
+        Some(AcpiString { length: string.len() as u8, bytes })
+    }
+
+    pub fn as_str(&self) -> &str {
+        /*
+         * These are only created from `&str`s by `new`, so they must be valid UTF-8.
+         */
+        unsafe { str::from_utf8_unchecked(&self.bytes[0..(self.length as usize)]) }
+    }
+}
+
+impl<const N: usize> fmt::Debug for AcpiString<{ N }> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AcpiGetInfoError {
+    TaskDoesNotHaveCorrectCapability,
+    BufferPointerInvalid,
+    BufferNotLargeEnough(u32),
+    PlatformDoesNotSupportAcpi,
+}
+
+impl TryFrom<usize> for AcpiGetInfoError {
+    type Error = ();
+
+    fn try_from(status: usize) -> Result<Self, Self::Error> {
+        match status.get_bits(0..16) {
+            1 => Ok(Self::TaskDoesNotHaveCorrectCapability),
+            2 => Ok(Self::BufferPointerInvalid),
+            3 => Ok(Self::BufferNotLargeEnough(status.get_bits(16..48) as u32)),
+            4 => Ok(Self::PlatformDoesNotSupportAcpi),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Into<usize> for AcpiGetInfoError {
+    fn into(self) -> usize {
+        match self {
+            Self::TaskDoesNotHaveCorrectCapability => 1,
+            Self::BufferPointerInvalid => 2,
+            Self::BufferNotLargeEnough(num_needed) => {
+                let mut result = 3;
+                result.set_bits(16..48, num_needed as usize);
+                result
+            }
+            Self::PlatformDoesNotSupportAcpi => 4,
+        }
+    }
+}
+
+/// Makes a raw `acpi_get_info` system call, given a pointer to a buffer and the size of the buffer. On success,
+/// returns the number of entries written into the buffer. For a nicer interface to this system call, see
+/// [`acpi_get_info_slice`] or [`acpi_get_info_vec`].
+pub fn acpi_get_info(buffer_ptr: *mut AcpiDeviceInfo, buffer_size: usize) -> Result<usize, AcpiGetInfoError> {
+    let result = unsafe { raw::syscall2(SYSCALL_ACPI_GET_INFO, buffer_ptr as usize, buffer_size) };
+
+    if result.get_bits(0..16) == 0 {
+        Ok(result.get_bits(16..48))
+    } else {
+        Err(AcpiGetInfoError::try_from(result).unwrap())
+    }
+}
+
+pub fn acpi_get_info_slice(buffer: &mut [AcpiDeviceInfo]) -> Result<&mut [AcpiDeviceInfo], AcpiGetInfoError> {
+    match acpi_get_info(
+        if buffer.len() == 0 { 0x0 as *mut AcpiDeviceInfo } else { buffer.as_mut_ptr() },
+        buffer.len(),
+    ) {
+        Ok(valid_entries) => Ok(&mut buffer[0..valid_entries]),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(feature = "can_alloc")]
+pub fn acpi_get_info_vec() -> Result<alloc::vec::Vec<AcpiDeviceInfo>, AcpiGetInfoError> {
+    use alloc::vec::Vec;
+
+    // Make an initial call to find out how many descriptors there are
+    let num_descriptors = match acpi_get_info(0x0 as *mut AcpiDeviceInfo, 0) {
+        Ok(_) => panic!("acpi_get_info with null buffer succeeded."),
+        Err(AcpiGetInfoError::BufferNotLargeEnough(num_descriptors)) => num_descriptors as usize,
+        Err(err) => return Err(err),
+    };
+
+    // Then actually fetch the data
+    let mut descriptors = Vec::with_capacity(num_descriptors);
+    assert_eq!(acpi_get_info(descriptors.as_mut_ptr(), num_descriptors)?, num_descriptors);
+    unsafe {
+        descriptors.set_len(num_descriptors);
+    }
+
+    Ok(descriptors)
+}
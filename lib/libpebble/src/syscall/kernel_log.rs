@@ -0,0 +1,80 @@
+use super::{raw, result::define_error_type, SYSCALL_DRAIN_KERNEL_LOG};
+use crate::bit_field::BitField;
+use core::{convert::TryFrom, str};
+
+pub const KERNEL_LOG_TASK_NAME_MAX_LENGTH: usize = 32;
+/// Messages longer than this are truncated when they're added to the kernel log.
+pub const KERNEL_LOG_MESSAGE_MAX_LENGTH: usize = 256;
+
+define_error_type!(DrainKernelLogError {
+    TaskDoesNotHaveCorrectCapability => 1,
+    BufferAddressInvalid => 2,
+});
+
+/// The level of a kernel log entry. These match the levels used by the `log` crate.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[repr(u8)]
+pub enum LogLevel {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+/// An entry in the kernel log. The kernel keeps the most recent entries in a ring buffer, which can be drained by
+/// userspace with `drain_kernel_log`.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct KernelLogEntry {
+    /// Each entry is given a sequence number one greater than the entry before it. Gaps in the sequence numbers of
+    /// drained entries mean that the kernel had to overwrite entries before they could be drained.
+    pub sequence: u64,
+    /// The value of the kernel's timestamp counter when the entry was logged. The rate of this is
+    /// platform-specific.
+    pub timestamp: u64,
+    /// The CPU the entry was logged on. On x86_64, this is the CPU's APIC ID.
+    pub cpu: u32,
+    pub level: LogLevel,
+    /// The length of the name of the task that was running when the entry was logged, or `0` if no task was.
+    pub task_name_length: u8,
+    pub message_length: u16,
+    pub task_name: [u8; KERNEL_LOG_TASK_NAME_MAX_LENGTH],
+    pub message: [u8; KERNEL_LOG_MESSAGE_MAX_LENGTH],
+}
+
+impl KernelLogEntry {
+    pub const EMPTY: KernelLogEntry = KernelLogEntry {
+        sequence: 0,
+        timestamp: 0,
+        cpu: 0,
+        level: LogLevel::Trace,
+        task_name_length: 0,
+        message_length: 0,
+        task_name: [0; KERNEL_LOG_TASK_NAME_MAX_LENGTH],
+        message: [0; KERNEL_LOG_MESSAGE_MAX_LENGTH],
+    };
+
+    pub fn task_name(&self) -> Option<&str> {
+        match self.task_name_length {
+            0 => None,
+            length => str::from_utf8(&self.task_name[0..(length as usize)]).ok(),
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        str::from_utf8(&self.message[0..(self.message_length as usize)]).unwrap_or("<invalid message>")
+    }
+}
+
+/// Move entries from the kernel log into `buffer`, oldest first, and remove them from the kernel log. Returns the
+/// number of entries written into the buffer, which will be less than the size of the buffer if there weren't
+/// enough entries to fill it.
+pub fn drain_kernel_log(buffer: &mut [KernelLogEntry]) -> Result<usize, DrainKernelLogError> {
+    let result = unsafe { raw::syscall2(SYSCALL_DRAIN_KERNEL_LOG, buffer.as_mut_ptr() as usize, buffer.len()) };
+
+    match result.get_bits(0..16) {
+        0 => Ok(result.get_bits(16..48)),
+        status => Err(DrainKernelLogError::try_from(status).expect("System call returned invalid status")),
+    }
+}
@@ -1,13 +1,33 @@
+pub mod acpi;
 pub mod get_framebuffer;
+pub mod kernel_log;
 #[cfg(feature = "pci")]
 pub mod pci;
+pub mod power;
 pub mod result;
+pub mod task_info;
 
+#[cfg(feature = "can_alloc")]
+pub use acpi::acpi_get_info_vec;
+pub use acpi::{acpi_get_info, acpi_get_info_slice, AcpiDeviceInfo, AcpiGetInfoError};
 pub use get_framebuffer::{get_framebuffer, FramebufferInfo, GetFramebufferError, PixelFormat};
+pub use kernel_log::{drain_kernel_log, DrainKernelLogError, KernelLogEntry, LogLevel};
 #[cfg(all(feature = "can_alloc", feature = "pci"))]
 pub use pci::pci_get_info_vec;
 #[cfg(feature = "pci")]
-pub use pci::{pci_get_info, pci_get_info_slice, PciDeviceInfo, PciGetInfoError};
+pub use pci::{
+    pci_attach_dma_memory,
+    pci_config_read,
+    pci_config_write,
+    pci_get_info,
+    pci_get_info_slice,
+    PciAttachDmaMemoryError,
+    PciConfigError,
+    PciDeviceInfo,
+    PciGetInfoError,
+};
+pub use power::{power_control, PowerAction, PowerControlError};
+pub use task_info::{get_task_info, GetTaskInfoError, TaskInfo};
 
 cfg_if::cfg_if! {
     if #[cfg(target_arch = "x86_64")] {
@@ -33,6 +53,16 @@ pub const SYSCALL_WAIT_FOR_MESSAGE: usize = 8;
 pub const SYSCALL_REGISTER_SERVICE: usize = 9;
 pub const SYSCALL_SUBSCRIBE_TO_SERVICE: usize = 10;
 pub const SYSCALL_PCI_GET_INFO: usize = 11;
+pub const SYSCALL_CHANNEL_CALL: usize = 12;
+pub const SYSCALL_SET_SYSCALL_TRACING: usize = 13;
+pub const SYSCALL_DRAIN_KERNEL_LOG: usize = 14;
+pub const SYSCALL_GET_TASK_INFO: usize = 15;
+pub const SYSCALL_POWER_CONTROL: usize = 16;
+pub const SYSCALL_ACPI_GET_INFO: usize = 17;
+pub const SYSCALL_PCI_CONFIG_READ: usize = 18;
+pub const SYSCALL_PCI_CONFIG_WRITE: usize = 19;
+pub const SYSCALL_MAP_IO_PORT_RANGE: usize = 20;
+pub const SYSCALL_PCI_ATTACH_DMA_MEMORY: usize = 21;
 
 pub fn yield_to_kernel() {
     unsafe {
@@ -59,6 +89,17 @@ define_error_type!(CreateMemoryObjectError {
     InvalidPhysicalAddressPointer => 4,
 });
 
+/// Limits the physical memory a `MemoryObject` can be allocated from, for memory that will be accessed by devices
+/// that can't address all of physical memory.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MemoryZone {
+    Any,
+    /// Memory below `4GiB`, for devices that can only perform 32-bit DMA.
+    Below4GiB,
+    /// Memory below `16MiB`, for legacy ISA DMA.
+    Below16MiB,
+}
+
 /// Create a MemoryObject kernel object at the given virtual address, with the given size (in bytes). Returns a
 /// handle to the new MemoryObject, if the call was successful.
 pub fn create_memory_object(
@@ -67,10 +108,37 @@ pub fn create_memory_object(
     writable: bool,
     executable: bool,
     physical_address_ptr: *mut usize,
+) -> Result<Handle, CreateMemoryObjectError> {
+    create_memory_object_in_zone(
+        virtual_address,
+        size,
+        writable,
+        executable,
+        MemoryZone::Any,
+        physical_address_ptr,
+    )
+}
+
+/// Like `create_memory_object`, but the MemoryObject's physical memory is allocated from `zone`.
+pub fn create_memory_object_in_zone(
+    virtual_address: usize,
+    size: usize,
+    writable: bool,
+    executable: bool,
+    zone: MemoryZone,
+    physical_address_ptr: *mut usize,
 ) -> Result<Handle, CreateMemoryObjectError> {
     let mut flags = 0usize;
     flags.set_bit(0, writable);
     flags.set_bit(1, executable);
+    flags.set_bits(
+        2..4,
+        match zone {
+            MemoryZone::Any => 0,
+            MemoryZone::Below4GiB => 1,
+            MemoryZone::Below16MiB => 2,
+        },
+    );
 
     handle_from_syscall_repr(unsafe {
         raw::syscall4(SYSCALL_CREATE_MEMORY_OBJECT, virtual_address, size, flags, physical_address_ptr as usize)
@@ -85,6 +153,9 @@ define_error_type!(MapMemoryObjectError {
     AddressPointerInvalid => 5,
     VirtualAddressNotSupplied => 6,
     VirtualAddressShouldNotBeSupplied => 7,
+    /// The supplied virtual address is not page-aligned, or the memory object would not lie entirely within the
+    /// userspace part of the address space if mapped there.
+    VirtualAddressNotValid => 8,
 });
 
 pub unsafe fn map_memory_object(
@@ -104,8 +175,22 @@ pub unsafe fn map_memory_object(
     })
 }
 
+define_error_type!(MapIoPortRangeError {
+    InvalidHandle => 1,
+    NotAnIoPortRange => 2,
+});
+
+/// Allow the calling task to access the I/O ports in an `IoPortRange`. Once the range is mapped, the ports can be
+/// accessed directly (e.g. using `hal_x86_64::hw::port::Port`).
+pub fn map_io_port_range(io_port_range: &Handle) -> Result<(), MapIoPortRangeError> {
+    status_from_syscall_repr(unsafe { raw::syscall1(SYSCALL_MAP_IO_PORT_RANGE, io_port_range.0 as usize) })
+}
+
 pub const CHANNEL_MAX_NUM_BYTES: usize = 4096;
-pub const CHANNEL_MAX_NUM_HANDLES: usize = 4;
+/// The maximum number of handles that can be sent in a single message. This is enough for bus drivers to pass on
+/// all the handles a device's resources need (a PCI function has a handle to its configuration space, and up to six
+/// BARs, and an ACPI device has up to eight resources).
+pub const CHANNEL_MAX_NUM_HANDLES: usize = 16;
 
 define_error_type!(SendMessageError {
     /// The `Channel` handle is invalid.
@@ -123,17 +208,36 @@ define_error_type!(SendMessageError {
     HandlesAddressInvalid => 8,
     TooManyHandles => 9,
     OtherEndDisconnected => 10,
+    /// The transaction ID doesn't fit in a `TransactionId`.
+    InvalidTransactionId => 11,
 });
 
+/// Identifies a call made with [`channel_call`], so the reply can be matched up with it. Transaction IDs are
+/// allocated by the kernel, and are only unique to the `Channel` end the call was made through. An ID of `0` is
+/// never allocated, and marks a message that is not part of a transaction.
+pub type TransactionId = u16;
+
 pub fn send_message(channel: &Handle, bytes: &[u8], handles: &[Handle]) -> Result<(), SendMessageError> {
+    send_reply(channel, 0, bytes, handles)
+}
+
+/// Send a message as the reply to the call with the given transaction ID (as returned from
+/// [`get_message_with_transaction`]). This wakes the caller, if it's blocked in [`channel_call`].
+pub fn send_reply(
+    channel: &Handle,
+    transaction_id: TransactionId,
+    bytes: &[u8],
+    handles: &[Handle],
+) -> Result<(), SendMessageError> {
     status_from_syscall_repr(unsafe {
-        raw::syscall5(
+        raw::syscall6(
             SYSCALL_SEND_MESSAGE,
             channel.0 as usize,
             if bytes.len() == 0 { 0x0 } else { bytes.as_ptr() as usize },
             bytes.len(),
             if handles.len() == 0 { 0x0 } else { handles.as_ptr() as usize },
             handles.len(),
+            transaction_id as usize,
         )
     })
 }
@@ -153,6 +257,16 @@ pub fn get_message<'b, 'h>(
     byte_buffer: &'b mut [u8],
     handle_buffer: &'h mut [Handle],
 ) -> Result<(&'b mut [u8], &'h mut [Handle]), GetMessageError> {
+    get_message_with_transaction(channel, byte_buffer, handle_buffer).map(|(bytes, handles, _)| (bytes, handles))
+}
+
+/// Like [`get_message`], but also returns the transaction ID of the message. If this is not `0`, the message was
+/// sent with [`channel_call`], and the sender is blocked until it is replied to with [`send_reply`].
+pub fn get_message_with_transaction<'b, 'h>(
+    channel: &Handle,
+    byte_buffer: &'b mut [u8],
+    handle_buffer: &'h mut [Handle],
+) -> Result<(&'b mut [u8], &'h mut [Handle], TransactionId), GetMessageError> {
     let result = unsafe {
         raw::syscall5(
             SYSCALL_GET_MESSAGE,
@@ -165,10 +279,106 @@ pub fn get_message<'b, 'h>(
     };
     status_from_syscall_repr(result.get_bits(0..16))?;
 
+    let valid_bytes_len = result.get_bits(16..32);
+    let valid_handles_len = result.get_bits(32..48);
+    let transaction_id = result.get_bits(48..64) as TransactionId;
+
+    Ok((&mut byte_buffer[0..valid_bytes_len], &mut handle_buffer[0..valid_handles_len], transaction_id))
+}
+
+/// Like [`get_message_with_transaction`], but blocks the calling task until a message arrives, instead of
+/// returning `GetMessageError::NoMessage`.
+pub fn wait_for_message<'b, 'h>(
+    channel: &Handle,
+    byte_buffer: &'b mut [u8],
+    handle_buffer: &'h mut [Handle],
+) -> Result<(&'b mut [u8], &'h mut [Handle], TransactionId), GetMessageError> {
+    let result = unsafe {
+        raw::syscall5(
+            SYSCALL_WAIT_FOR_MESSAGE,
+            channel.0 as usize,
+            if byte_buffer.len() == 0 { 0x0 } else { byte_buffer.as_ptr() as usize },
+            byte_buffer.len(),
+            if handle_buffer.len() == 0 { 0x0 } else { handle_buffer.as_ptr() as usize },
+            handle_buffer.len(),
+        )
+    };
+    status_from_syscall_repr(result.get_bits(0..16))?;
+
+    let valid_bytes_len = result.get_bits(16..32);
+    let valid_handles_len = result.get_bits(32..48);
+    let transaction_id = result.get_bits(48..64) as TransactionId;
+
+    Ok((&mut byte_buffer[0..valid_bytes_len], &mut handle_buffer[0..valid_handles_len], transaction_id))
+}
+
+define_error_type!(ChannelCallError {
+    InvalidChannelHandle => 1,
+    NotAChannel => 2,
+    BuffersAddressInvalid => 3,
+    SendBytesAddressInvalid => 4,
+    TooManyBytes => 5,
+    SendHandlesAddressInvalid => 6,
+    TooManyHandles => 7,
+    InvalidTransferredHandle => 8,
+    OtherEndDisconnected => 9,
+    ReplyBytesAddressInvalid => 10,
+    ReplyBytesBufferTooSmall => 11,
+    ReplyHandlesAddressInvalid => 12,
+    ReplyHandlesBufferTooSmall => 13,
+    /// Calls can't be made through a channel that only receives messages from the kernel.
+    CannotCallKernelChannel => 14,
+});
+
+/// Describes the buffers used by `channel_call`. We pass this by reference, as there are too many to pass in
+/// registers.
+#[derive(Debug)]
+#[repr(C)]
+pub struct ChannelCallBuffers {
+    pub send_bytes: *const u8,
+    pub send_bytes_len: usize,
+    pub send_handles: *const Handle,
+    pub send_handles_len: usize,
+    pub reply_bytes: *mut u8,
+    pub reply_bytes_len: usize,
+    pub reply_handles: *mut Handle,
+    pub reply_handles_len: usize,
+}
+
+/// Send a message down a `Channel`, and block until the other end replies to it (with [`send_reply`]). The reply
+/// is copied into the reply buffers, and the valid parts of them are returned. If the task on the other end of
+/// the `Channel` is blocked waiting for a message, the kernel switches straight to it.
+pub fn channel_call<'b, 'h>(
+    channel: &Handle,
+    bytes: &[u8],
+    handles: &[Handle],
+    reply_byte_buffer: &'b mut [u8],
+    reply_handle_buffer: &'h mut [Handle],
+) -> Result<(&'b mut [u8], &'h mut [Handle]), ChannelCallError> {
+    let buffers = ChannelCallBuffers {
+        send_bytes: if bytes.len() == 0 { 0x0 as *const u8 } else { bytes.as_ptr() },
+        send_bytes_len: bytes.len(),
+        send_handles: if handles.len() == 0 { 0x0 as *const Handle } else { handles.as_ptr() },
+        send_handles_len: handles.len(),
+        reply_bytes: if reply_byte_buffer.len() == 0 { 0x0 as *mut u8 } else { reply_byte_buffer.as_mut_ptr() },
+        reply_bytes_len: reply_byte_buffer.len(),
+        reply_handles: if reply_handle_buffer.len() == 0 {
+            0x0 as *mut Handle
+        } else {
+            reply_handle_buffer.as_mut_ptr()
+        },
+        reply_handles_len: reply_handle_buffer.len(),
+    };
+
+    let result = unsafe {
+        raw::syscall2(SYSCALL_CHANNEL_CALL, channel.0 as usize, &buffers as *const ChannelCallBuffers as usize)
+    };
+    status_from_syscall_repr(result.get_bits(0..16))?;
+
     let valid_bytes_len = result.get_bits(16..32);
     let valid_handles_len = result.get_bits(32..48);
 
-    Ok((&mut byte_buffer[0..valid_bytes_len], &mut handle_buffer[0..valid_handles_len]))
+    Ok((&mut reply_byte_buffer[0..valid_bytes_len], &mut reply_handle_buffer[0..valid_handles_len]))
 }
 
 pub const SERVICE_NAME_MAX_LENGTH: usize = 256;
@@ -199,3 +409,22 @@ pub fn subscribe_to_service(name: &str) -> Result<Handle, SubscribeToServiceErro
         raw::syscall2(SYSCALL_SUBSCRIBE_TO_SERVICE, name.len(), name.as_ptr() as usize)
     })
 }
+
+/// The maximum length of the task name that can be passed to `set_syscall_tracing`. This matches the maximum
+/// length of the name of an initial task.
+pub const TRACED_TASK_NAME_MAX_LENGTH: usize = 32;
+
+define_error_type!(SetSyscallTracingError {
+    TaskDoesNotHaveCorrectCapability => 1,
+    NamePointerNotValid => 2,
+    /// Name must be greater than `0` bytes, and not greater than `32` bytes.
+    NameLengthNotValid => 3,
+});
+
+/// Turn tracing of the system calls made by tasks called `task_name` on or off. The name does not need to belong
+/// to a task that is already running, so tracing can be turned on for a task before it is started.
+pub fn set_syscall_tracing(task_name: &str, enabled: bool) -> Result<(), SetSyscallTracingError> {
+    status_from_syscall_repr(unsafe {
+        raw::syscall3(SYSCALL_SET_SYSCALL_TRACING, task_name.len(), task_name.as_ptr() as usize, enabled as usize)
+    })
+}
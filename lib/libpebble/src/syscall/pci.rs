@@ -1,12 +1,25 @@
-use super::{raw, SYSCALL_PCI_GET_INFO};
+use super::{
+    raw,
+    result::{define_error_type, status_from_syscall_repr},
+    SYSCALL_PCI_ATTACH_DMA_MEMORY,
+    SYSCALL_PCI_CONFIG_READ,
+    SYSCALL_PCI_CONFIG_WRITE,
+    SYSCALL_PCI_GET_INFO,
+};
 use crate::{bit_field::BitField, Handle};
 use core::convert::TryFrom;
 use pci_types::{BaseClass, DeviceId, DeviceRevision, Interface, PciAddress, SubClass, VendorId};
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 #[repr(C)]
 pub struct PciDeviceInfo {
     pub address: PciAddress,
+    /// A handle to the device, which can be used to access its configuration space with [`pci_config_read`]
+    /// and [`pci_config_write`]. This should be handed on to the driver that owns the device. This is
+    /// `ZERO_HANDLE` for bridges (including host bridges), as their configuration is managed by the platform, and
+    /// if a previous call to `pci_get_info` has already handed the device out. The handles to the device's BARs
+    /// are only handed out along with it.
+    pub device: Handle,
     /// The ID of the manufacturer of the device. These are allocated by PCI SIG.
     pub vendor_id: VendorId,
     /// The ID of the particular device. These are allocated by the vendor.
@@ -22,13 +35,51 @@ pub struct PciDeviceInfo {
     /// device.
     pub interface: Interface,
     pub bars: [Option<Bar>; 6],
+    /// The platform interrupt the device's legacy interrupt pin is routed to. `interrupt.routed` is `false` if
+    /// the device doesn't use its pin, or it isn't routed anywhere we know about.
+    pub interrupt: PciInterrupt,
+}
+
+/// Describes where a PCI device's legacy interrupt pin is routed. This has an explicit layout with no padding
+/// (rather than being passed out in an `Option`), so the kernel never copies uninitialized bytes into userspace.
+///
+/// This is only a description: there isn't yet an interrupt object, or a system call to claim the interrupt, so
+/// drivers can't actually be notified when it fires.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct PciInterrupt {
+    /// The Global System Interrupt the interrupt pin is connected to.
+    pub gsi: u32,
+    /// Whether the pin is routed at all. If this is `false`, the other fields are zero.
+    pub routed: bool,
+    pub level_triggered: bool,
+    pub active_low: bool,
+    pub _reserved: u8,
+}
+
+impl PciInterrupt {
+    pub const NOT_ROUTED: PciInterrupt =
+        PciInterrupt { gsi: 0, routed: false, level_triggered: false, active_low: false, _reserved: 0 };
 }
 
 #[derive(Debug)]
 #[repr(C)]
 pub enum Bar {
-    Memory32 { memory_object: Handle, size: u32 },
-    Memory64 { memory_object: Handle, size: u64 },
+    Memory32 {
+        memory_object: Handle,
+        size: u32,
+    },
+    Memory64 {
+        memory_object: Handle,
+        size: u64,
+    },
+    /// A range of I/O ports. The ports can be accessed once `io_port_range` has been mapped with
+    /// `map_io_port_range`.
+    Io {
+        io_port_range: Handle,
+        port: u16,
+        size: u16,
+    },
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -112,3 +163,53 @@ pub fn pci_get_info_vec() -> Result<alloc::vec::Vec<PciDeviceInfo>, PciGetInfoEr
 
     Ok(descriptors)
 }
+
+define_error_type!(PciConfigError {
+    InvalidHandle => 1,
+    NotAPciDevice => 2,
+    /// The offset is not aligned to a 4-byte boundary, or is outside the function's configuration space.
+    InvalidOffset => 3,
+    /// The register can't be written from userspace. Only the Command and Status registers, and the power
+    /// management, MSI, and MSI-X capabilities, can be written to. Everything else, such as the BARs, is
+    /// configured by the platform.
+    RegisterNotWritable => 4,
+    PlatformDoesNotSupportPci => 5,
+});
+
+/// Read the 32-bit register at `offset` into the configuration space of a PCI function. `offset` must be aligned
+/// to a 4-byte boundary.
+pub fn pci_config_read(device: &Handle, offset: u16) -> Result<u32, PciConfigError> {
+    let result = unsafe { raw::syscall2(SYSCALL_PCI_CONFIG_READ, device.0 as usize, offset as usize) };
+
+    match result.get_bits(0..16) {
+        0 => Ok(result.get_bits(16..48) as u32),
+        status => Err(PciConfigError::try_from(status).expect("System call returned invalid status")),
+    }
+}
+
+/// Write to the 32-bit register at `offset` into the configuration space of a PCI function. `offset` must be
+/// aligned to a 4-byte boundary. Only the Command and Status registers, and the capabilities, can be written to.
+pub fn pci_config_write(device: &Handle, offset: u16, value: u32) -> Result<(), PciConfigError> {
+    status_from_syscall_repr(unsafe {
+        raw::syscall3(SYSCALL_PCI_CONFIG_WRITE, device.0 as usize, offset as usize, value as usize)
+    })
+}
+
+define_error_type!(PciAttachDmaMemoryError {
+    InvalidHandle => 1,
+    NotAPciDevice => 2,
+    NotAMemoryObject => 3,
+    /// Only memory objects that were allocated by `create_memory_object` can be used for DMA. Memory objects that
+    /// describe other memory, such as another device's BARs, can't be.
+    NotDmaMemory => 4,
+});
+
+/// Allow a PCI device to access the memory in a memory object through DMA. On platforms with an IOMMU, devices
+/// can only access memory that has been attached to them like this, and so drivers must attach any memory they
+/// give the device the physical address of. On platforms without an IOMMU, this does nothing, as devices can
+/// access all memory anyway.
+pub fn pci_attach_dma_memory(device: &Handle, memory_object: &Handle) -> Result<(), PciAttachDmaMemoryError> {
+    status_from_syscall_repr(unsafe {
+        raw::syscall2(SYSCALL_PCI_ATTACH_DMA_MEMORY, device.0 as usize, memory_object.0 as usize)
+    })
+}
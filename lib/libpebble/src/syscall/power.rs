@@ -0,0 +1,49 @@
+use super::{
+    raw,
+    result::{define_error_type, status_from_syscall_repr},
+    SYSCALL_POWER_CONTROL,
+};
+use core::convert::TryFrom;
+
+/// The actions that can be requested with `power_control`. The kernel can't put the machine into any of the
+/// sleep states (ACPI `S1` to `S4`) yet, so there are no actions for them.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PowerAction {
+    /// Turn the machine off.
+    Shutdown,
+    /// Reset the machine.
+    Reboot,
+}
+
+impl From<PowerAction> for usize {
+    fn from(action: PowerAction) -> usize {
+        match action {
+            PowerAction::Shutdown => 0,
+            PowerAction::Reboot => 1,
+        }
+    }
+}
+
+impl TryFrom<usize> for PowerAction {
+    type Error = ();
+
+    fn try_from(value: usize) -> Result<PowerAction, ()> {
+        match value {
+            0 => Ok(PowerAction::Shutdown),
+            1 => Ok(PowerAction::Reboot),
+            _ => Err(()),
+        }
+    }
+}
+
+define_error_type!(PowerControlError {
+    TaskDoesNotHaveCorrectCapability => 1,
+    InvalidAction => 2,
+    /// The platform doesn't support the action, or tried to perform it and failed.
+    ActionFailed => 3,
+});
+
+/// Ask the kernel to turn off or reset the machine. This does not return if it succeeds.
+pub fn power_control(action: PowerAction) -> Result<(), PowerControlError> {
+    status_from_syscall_repr(unsafe { raw::syscall1(SYSCALL_POWER_CONTROL, usize::from(action)) })
+}
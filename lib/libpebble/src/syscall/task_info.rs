@@ -0,0 +1,52 @@
+use super::{
+    raw,
+    result::{define_error_type, status_from_syscall_repr},
+    SYSCALL_GET_TASK_INFO,
+};
+use crate::Handle;
+use core::{mem::MaybeUninit, str};
+
+pub const TASK_INFO_NAME_MAX_LENGTH: usize = 32;
+
+define_error_type!(GetTaskInfoError {
+    InvalidHandle => 1,
+    NotATask => 2,
+    InfoAddressInvalid => 3,
+});
+
+/// Statistics about a task, as returned by `get_task_info`.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct TaskInfo {
+    /// The amount of CPU time the task has used, in the kernel's timestamp units. The rate of these is
+    /// platform-specific (on x86_64, they are ticks of the TSC).
+    pub cpu_time: u64,
+    /// The number of times the task has been scheduled onto a CPU.
+    pub context_switches: u64,
+    pub syscalls: u64,
+    /// The number of bytes of physical memory committed to the `MemoryObject`s owned by the task.
+    pub committed_memory: u64,
+    pub num_handles: u64,
+    pub name_length: u8,
+    pub name: [u8; TASK_INFO_NAME_MAX_LENGTH],
+    /// Makes the padding at the end of the structure explicit, so the kernel doesn't copy uninitialized bytes to
+    /// userspace. Always zero.
+    pub _reserved: [u8; 7],
+}
+
+impl TaskInfo {
+    pub fn name(&self) -> &str {
+        str::from_utf8(&self.name[0..(self.name_length as usize)]).unwrap_or("<invalid name>")
+    }
+}
+
+/// Get statistics about the task that `task` is a handle to. If `task` is `ZERO_HANDLE`, statistics about the
+/// calling task are returned.
+pub fn get_task_info(task: Handle) -> Result<TaskInfo, GetTaskInfoError> {
+    let mut info = MaybeUninit::<TaskInfo>::uninit();
+    status_from_syscall_repr(unsafe {
+        raw::syscall2(SYSCALL_GET_TASK_INFO, task.0 as usize, info.as_mut_ptr() as usize)
+    })?;
+
+    Ok(unsafe { info.assume_init() })
+}
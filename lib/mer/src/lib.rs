@@ -3,6 +3,7 @@
 pub mod header;
 pub mod note;
 pub mod program;
+pub mod relocation;
 pub mod section;
 pub mod symbol;
 
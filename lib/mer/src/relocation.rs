@@ -0,0 +1,33 @@
+use bit_field::BitField;
+use scroll_derive::Pread;
+
+/*
+ * These are the relocation types defined by the x86_64 psABI that we're interested in.
+ */
+pub const R_X86_64_NONE: u32 = 0;
+/// The relocation should be replaced with the load bias (the difference between the address the image was
+/// linked at and the address it was loaded at), plus the addend.
+pub const R_X86_64_RELATIVE: u32 = 8;
+
+/// A "Rela"-type relocation - one that includes its addend. These are found in sections of type
+/// `SectionType::Rela`.
+#[derive(Debug, Pread)]
+#[repr(C)]
+pub struct Rela {
+    /// The virtual address (in executables and shared objects) of the location to apply the relocation to.
+    pub offset: u64,
+    pub info: u64,
+    pub addend: i64,
+}
+
+impl Rela {
+    /// The index into the linked symbol table of the symbol this relocation refers to.
+    pub fn symbol(&self) -> u32 {
+        self.info.get_bits(32..64) as u32
+    }
+
+    /// The type of relocation. The meaning of this is processor-specific.
+    pub fn relocation_type(&self) -> u32 {
+        self.info.get_bits(0..32) as u32
+    }
+}
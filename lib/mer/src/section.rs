@@ -1,5 +1,7 @@
 use crate::Elf;
 use crate::ElfError;
+use crate::relocation::Rela;
+use crate::EntryIter;
 use core::str;
 use scroll_derive::Pread;
 use bit_field::BitField;
@@ -126,6 +128,17 @@ impl SectionHeader {
         Some(&elf.bytes[(self.offset as usize)..((self.offset + self.size) as usize)])
     }
 
+    /// Iterate over the relocations in this section. Returns an empty iterator if this isn't a section of type
+    /// `SectionType::Rela`.
+    pub fn rela_entries<'a>(&self, elf: &'a Elf) -> EntryIter<'a, Rela> {
+        match self.data(elf) {
+            Some(data) if self.section_type() == SectionType::Rela && self.entry_size != 0 => {
+                EntryIter::new(data, self.size / self.entry_size, self.entry_size)
+            }
+            _ => EntryIter::new(&[], 0, 0),
+        }
+    }
+
     /// Whether this section contains writable data
     pub fn is_writable(&self) -> bool {
         self.flags.get_bit(0)
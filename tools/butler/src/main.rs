@@ -77,13 +77,27 @@ pub fn main() -> Result<()> {
         .about("Host-side program for managing Pebble builds")
         .after_help(EXTRA_HELP)
         .subcommand(App::new("build").about("Build a project").arg(Arg::from_usage("[project]")))
-        .subcommand(App::new("run").about("Build and run a project").arg(Arg::from_usage("[project]")))
+        .subcommand(
+            App::new("run")
+                .about("Build and run a project")
+                .arg(Arg::from_usage("[project]"))
+                .arg(Arg::from_usage("--iommu 'Emulate an Intel VT-d IOMMU'"))
+                .arg(Arg::from_usage(
+                    "--qemu-exit-device 'Add the isa-debug-exit device, for the qemu_exit feature'",
+                )),
+        )
         .get_matches();
 
     if let Some(sub_matches) = matches.subcommand_matches("build") {
         project_from_name(sub_matches.value_of("project")).build();
     } else if let Some(sub_matches) = matches.subcommand_matches("run") {
         let mut project = project_from_name(sub_matches.value_of("project"));
+        if sub_matches.is_present("iommu") {
+            project.qemu.as_mut().unwrap().options.iommu = true;
+        }
+        if sub_matches.is_present("qemu-exit-device") {
+            project.qemu.as_mut().unwrap().options.qemu_exit_device = true;
+        }
         project.build();
         project.run();
     } else {
@@ -26,7 +26,11 @@ pub struct QemuOptions {
     /*
      * Devices
      */
+    /// Add QEMU's `isa-debug-exit` device, which kernels built with the `qemu_exit` feature use to exit QEMU.
+    /// Normal runs shut down through `power_control` instead, so this is off by default.
     pub qemu_exit_device: bool,
+    /// Emulate an Intel VT-d IOMMU, which remaps the DMA of the PCI devices.
+    pub iommu: bool,
 }
 
 impl QemuOptions {
@@ -50,7 +54,8 @@ impl Default for QemuOptions {
             ovmf_dir: PathBuf::from("bundled/ovmf/"),
             ovmf_debugcon_to_file: false,
 
-            qemu_exit_device: true,
+            qemu_exit_device: false,
+            iommu: false,
         }
     }
 }
@@ -106,6 +111,13 @@ impl RunQemuX64 {
          * TODO: it would be cool to define devices programmatically, and then have it emit the right config
          */
         qemu.args(&["-net", "none"]);
+        /*
+         * The IOMMU has to be added before any of the PCI devices it remaps. We don't support interrupt remapping,
+         * so we don't need to split the irqchip between QEMU and KVM.
+         */
+        if self.options.iommu {
+            qemu.args(&["-device", "intel-iommu,intremap=off"]);
+        }
         if self.options.qemu_exit_device {
             qemu.args(&["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"]);
         }
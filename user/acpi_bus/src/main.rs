@@ -0,0 +1,130 @@
+#![no_std]
+#![no_main]
+#![feature(const_generics, alloc_error_handler, never_type)]
+
+extern crate alloc;
+extern crate rlibc;
+
+use alloc::{collections::BTreeMap, format, string::ToString};
+use core::panic::PanicInfo;
+use libpebble::{
+    caps::{CapabilitiesRepr, CAP_ACPI_BUS_DRIVER, CAP_EARLY_LOGGING, CAP_PADDING, CAP_SERVICE_USER},
+    channel::Channel,
+    early_logger::EarlyLogger,
+    syscall,
+    syscall::acpi::AcpiResource,
+};
+use linked_list_allocator::LockedHeap;
+use log::{info, warn};
+use platform_bus::{BusDriverMessage, DeviceInfo, Property};
+
+#[global_allocator]
+static ALLOCATOR: LockedHeap = LockedHeap::empty();
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    // Initialise the heap
+    const HEAP_START: usize = 0x600000000;
+    const HEAP_SIZE: usize = 0x4000;
+    let heap_memory_object =
+        syscall::create_memory_object(HEAP_START, HEAP_SIZE, true, false, 0x0 as *mut usize).unwrap();
+    unsafe {
+        syscall::map_memory_object(&heap_memory_object, &libpebble::ZERO_HANDLE, None, 0x0 as *mut usize).unwrap();
+        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+    }
+
+    log::set_logger(&EarlyLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+    info!("ACPI bus driver is running!");
+
+    let platform_bus_channel: Channel<BusDriverMessage, !> =
+        Channel::from_handle(syscall::subscribe_to_service("platform_bus.bus_driver").unwrap());
+
+    let mut descriptors = syscall::acpi_get_info_vec().expect("Failed to get ACPI descriptors");
+    for descriptor in descriptors.drain(..) {
+        info!(
+            "ACPI device at {}: {} (uid = {:?})",
+            descriptor.path.as_str(),
+            descriptor.hid.as_str(),
+            descriptor.uid()
+        );
+
+        /*
+         * Register the device with the Platform Bus. Device drivers will usually want to match against the
+         * `acpi.hid` property.
+         */
+        let name = "acpi-".to_string() + descriptor.path.as_str();
+        let properties = {
+            let mut properties = BTreeMap::new();
+
+            properties.insert("acpi.path".to_string(), Property::String(descriptor.path.as_str().to_string()));
+            properties.insert("acpi.hid".to_string(), Property::String(descriptor.hid.as_str().to_string()));
+            if let Some(uid) = descriptor.uid() {
+                properties.insert("acpi.uid".to_string(), Property::String(uid.as_str().to_string()));
+            }
+
+            for (i, cid) in descriptor.cids().iter().enumerate() {
+                properties.insert(format!("acpi.cid{}", i), Property::String(cid.as_str().to_string()));
+            }
+
+            /*
+             * Each type of resource is numbered separately, so e.g. a device's first I/O port range is always
+             * `acpi.io0`, regardless of how many memory ranges it has.
+             */
+            let (mut num_memory, mut num_io, mut num_irqs) = (0, 0, 0);
+            for resource in descriptor.into_resources() {
+                match resource {
+                    AcpiResource::Memory { memory_object, offset, size } => {
+                        properties.insert(
+                            format!("acpi.memory{}.handle", num_memory),
+                            Property::MemoryObject(memory_object),
+                        );
+                        properties
+                            .insert(format!("acpi.memory{}.offset", num_memory), Property::Integer(offset as u64));
+                        properties
+                            .insert(format!("acpi.memory{}.size", num_memory), Property::Integer(size as u64));
+                        num_memory += 1;
+                    }
+                    AcpiResource::Io { io_port_range, base, length } => {
+                        properties
+                            .insert(format!("acpi.io{}.handle", num_io), Property::IoPortRange(io_port_range));
+                        properties.insert(format!("acpi.io{}.base", num_io), Property::Integer(base as u64));
+                        properties.insert(format!("acpi.io{}.size", num_io), Property::Integer(length as u64));
+                        num_io += 1;
+                    }
+                    AcpiResource::Irq(irq) => {
+                        properties.insert(format!("acpi.irq{}", num_irqs), Property::Integer(irq as u64));
+                        num_irqs += 1;
+                    }
+                }
+            }
+
+            properties
+        };
+        if let Err(err) =
+            platform_bus_channel.send(&BusDriverMessage::RegisterDevice(name.clone(), DeviceInfo::new(properties)))
+        {
+            warn!("Failed to register device {} with the Platform Bus: {:?}", name, err);
+        }
+    }
+
+    loop {
+        syscall::yield_to_kernel();
+    }
+}
+
+#[panic_handler]
+pub fn handle_panic(info: &PanicInfo) -> ! {
+    log::error!("PANIC: {}", info);
+    loop {}
+}
+
+#[alloc_error_handler]
+fn alloc_error(layout: core::alloc::Layout) -> ! {
+    panic!("Alloc error: {:?}", layout);
+}
+
+#[used]
+#[link_section = ".caps"]
+pub static mut CAPS: CapabilitiesRepr<4> =
+    CapabilitiesRepr::new([CAP_EARLY_LOGGING, CAP_ACPI_BUS_DRIVER, CAP_SERVICE_USER, CAP_PADDING]);
@@ -15,7 +15,7 @@ use libpebble::{
     syscall::pci::Bar,
 };
 use linked_list_allocator::LockedHeap;
-use log::info;
+use log::{info, warn};
 use pci_types::device_type::{DeviceType, UsbType};
 use platform_bus::{BusDriverMessage, DeviceInfo, Property};
 
@@ -66,6 +66,9 @@ pub extern "C" fn _start() -> ! {
         let properties = {
             let mut properties = BTreeMap::new();
 
+            if descriptor.device != libpebble::ZERO_HANDLE {
+                properties.insert("pci.device".to_string(), Property::PciDevice(descriptor.device));
+            }
             properties.insert("pci.vendor_id".to_string(), Property::Integer(descriptor.vendor_id as u64));
             properties.insert("pci.device_id".to_string(), Property::Integer(descriptor.device_id as u64));
             properties.insert("pci.class".to_string(), Property::Integer(descriptor.class as u64));
@@ -85,13 +88,33 @@ pub extern "C" fn _start() -> ! {
                                 .insert(format!("pci.bar{}.handle", i), Property::MemoryObject(memory_object));
                             properties.insert(format!("pci.bar{}.size", i), Property::Integer(size));
                         }
+                        Bar::Io { io_port_range, port, size } => {
+                            properties
+                                .insert(format!("pci.bar{}.handle", i), Property::IoPortRange(io_port_range));
+                            properties.insert(format!("pci.bar{}.port", i), Property::Integer(port as u64));
+                            properties.insert(format!("pci.bar{}.size", i), Property::Integer(size as u64));
+                        }
                     }
                 }
             }
 
+            if descriptor.interrupt.routed {
+                let interrupt = descriptor.interrupt;
+                properties.insert("pci.interrupt.gsi".to_string(), Property::Integer(interrupt.gsi as u64));
+                properties.insert(
+                    "pci.interrupt.level_triggered".to_string(),
+                    Property::Bool(interrupt.level_triggered),
+                );
+                properties.insert("pci.interrupt.active_low".to_string(), Property::Bool(interrupt.active_low));
+            }
+
             properties
         };
-        platform_bus_channel.send(&BusDriverMessage::RegisterDevice(name, DeviceInfo::new(properties))).unwrap();
+        if let Err(err) =
+            platform_bus_channel.send(&BusDriverMessage::RegisterDevice(name.clone(), DeviceInfo::new(properties)))
+        {
+            warn!("Failed to register device {} with the Platform Bus: {:?}", name, err);
+        }
     }
 
     loop {
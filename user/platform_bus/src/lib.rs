@@ -8,7 +8,8 @@
 //! `pci.class` and `pci.sub_class` as properties. A Device Driver could use the `class` and `subclass` properties
 //! to select all PCI devices of a particular type (e.g. useful for a driver for all EHCI controllers), or the
 //! `vendor_id` and `device_id` properties to select a specific device (e.g. useful for a graphics driver for a
-//! specific graphics card).
+//! specific graphics card). Similarly, devices created by the ACPI bus driver have `acpi.hid` and `acpi.cid<N>`
+//! properties, which can be used to select devices such as the RTC (`PNP0B00`) or a PS/2 keyboard (`PNP0303`).
 
 #![no_std]
 
@@ -38,6 +39,10 @@ pub enum Property {
     Integer(u64),
     String(String),
     MemoryObject(Handle),
+    /// A handle to a PCI device, which gives access to its configuration space.
+    PciDevice(Handle),
+    /// A handle to a range of I/O ports, which can be mapped with `map_io_port_range`.
+    IoPortRange(Handle),
 }
 
 impl Property {
@@ -68,6 +73,20 @@ impl Property {
             _ => None,
         }
     }
+
+    pub fn as_pci_device(&self) -> Option<&Handle> {
+        match self {
+            Property::PciDevice(ref value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_io_port_range(&self) -> Option<&Handle> {
+        match self {
+            Property::IoPortRange(ref value) => Some(value),
+            _ => None,
+        }
+    }
 }
 
 /// These are messages sent from Bus Drivers to the Platform Bus.
@@ -52,16 +52,8 @@ pub extern "C" fn _start() -> ! {
     let echo_channel = Channel::<TestMessage, TestMessage>::from_handle(
         syscall::subscribe_to_service("echo.echo").expect("Failed to subscribe to echo service :("),
     );
-    echo_channel.send(&TestMessage { id: 42, message: "Hello, World!".to_string() }).unwrap();
-    loop {
-        match echo_channel.try_receive().expect("Failed to receive message") {
-            Some(message) => {
-                info!("Echo sent message back: {:?}", message);
-                break;
-            }
-            None => syscall::yield_to_kernel(),
-        }
-    }
+    let reply = echo_channel.call(&TestMessage { id: 42, message: "Hello, World!".to_string() }).unwrap();
+    info!("Echo sent message back: {:?}", reply);
 
     let framebuffer = make_framebuffer();
     framebuffer.clear(Bgr32::pixel(0xaa, 0xaa, 0xaa, 0xff));
@@ -6,7 +6,7 @@ extern crate alloc;
 
 use core::panic::PanicInfo;
 use libpebble::{
-    caps::{CapabilitiesRepr, CAP_EARLY_LOGGING, CAP_PADDING},
+    caps::{CapabilitiesRepr, CAP_EARLY_LOGGING, CAP_PADDING, CAP_POWER_CONTROL},
     early_logger::EarlyLogger,
     syscall,
 };
@@ -33,8 +33,24 @@ pub extern "C" fn _start() -> ! {
     log::set_max_level(log::LevelFilter::Trace);
     info!("test2 is running");
 
+    /*
+     * In test images, give the other tasks a chance to run, and then turn the machine off. This lets test runs
+     * exit QEMU cleanly.
+     */
+    #[cfg(feature = "shutdown_when_done")]
+    {
+        const NUM_YIELDS: usize = 20;
+        for _ in 0..NUM_YIELDS {
+            info!("Yielding from test2");
+            syscall::yield_to_kernel();
+        }
+
+        info!("Shutting down from test2");
+        let result = syscall::power_control(syscall::PowerAction::Shutdown);
+        log::error!("Failed to shut down: {:?}", result);
+    }
+
     loop {
-        info!("Yielding from test2");
         syscall::yield_to_kernel();
     }
 }
@@ -50,7 +66,10 @@ fn alloc_error(layout: core::alloc::Layout) -> ! {
     panic!("Alloc error: {:?}", layout);
 }
 
+/// We only need to be able to turn the machine off in test images.
+const CAP_SHUTDOWN: u8 = if cfg!(feature = "shutdown_when_done") { CAP_POWER_CONTROL } else { CAP_PADDING };
+
 #[used]
 #[link_section = ".caps"]
 pub static mut CAPS: CapabilitiesRepr<4> =
-    CapabilitiesRepr::new([CAP_EARLY_LOGGING, CAP_PADDING, CAP_PADDING, CAP_PADDING]);
+    CapabilitiesRepr::new([CAP_EARLY_LOGGING, CAP_SHUTDOWN, CAP_PADDING, CAP_PADDING]);
@@ -102,7 +102,11 @@ pub extern "C" fn _start() -> ! {
         info!("Port {}: {:?}", i, operational.port(i).port_link_state());
     }
 
-    let memory_area = MemoryArea::new(capabilities.max_ports);
+    let memory_area = MemoryArea::new(
+        capabilities.max_ports,
+        controller_device.properties.get("pci.device").unwrap().as_pci_device().unwrap(),
+        capabilities.can_address_64bit,
+    );
     initialize_controller(&mut operational, &capabilities, &memory_area);
 
     loop {
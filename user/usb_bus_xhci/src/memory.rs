@@ -1,5 +1,8 @@
 use core::{mem, mem::MaybeUninit, ptr};
-use libpebble::{syscall, Handle};
+use libpebble::{
+    syscall::{self, MemoryZone},
+    Handle,
+};
 use log::info;
 
 const MEMORY_AREA_VIRTUAL_ADDRESS: usize = 0x50000000;
@@ -49,7 +52,9 @@ pub struct MemoryArea {
 }
 
 impl MemoryArea {
-    pub fn new(num_ports: u8) -> MemoryArea {
+    /// Create the memory area, and allow `controller` (a handle to the controller's PCI device) to access it. If
+    /// the controller can't address 64-bit memory, the area is allocated below `4GiB`.
+    pub fn new(num_ports: u8, controller: &Handle, can_address_64bit: bool) -> MemoryArea {
         use pebble_util::math::align_up;
 
         let bytes_for_device_context_base_address_array = (usize::from(num_ports) + 1) * mem::size_of::<u64>();
@@ -62,17 +67,21 @@ impl MemoryArea {
                 bytes_for_device_context_base_address_array + command_ring_head_padding + bytes_for_command_ring;
             let mut physical_address: MaybeUninit<usize> = MaybeUninit::uninit();
 
-            let handle = syscall::create_memory_object(
+            let zone = if can_address_64bit { MemoryZone::Any } else { MemoryZone::Below4GiB };
+
+            let handle = syscall::create_memory_object_in_zone(
                 MEMORY_AREA_VIRTUAL_ADDRESS,
                 size,
                 true,
                 false,
+                zone,
                 physical_address.as_mut_ptr(),
             )
             .unwrap();
             unsafe {
                 syscall::map_memory_object(&handle, &libpebble::ZERO_HANDLE, None, 0x0 as *mut usize).unwrap();
             }
+            syscall::pci_attach_dma_memory(controller, &handle).unwrap();
 
             (handle, unsafe { physical_address.assume_init() })
         };